@@ -14,23 +14,30 @@ Future<bool> walletExists({String? walletDir}) =>
     RustLib.instance.api.crateApiWalletExists(walletDir: walletDir);
 
 /// Initialize a new wallet and return the seed phrase
+/// `server_uri` can be a comma separated list of servers, in order of preference. The wallet uses the fastest one
+/// that is up, and fails over to the others when it can't reach it.
 Future<String> initializeNew({required String serverUri, String? walletDir}) =>
     RustLib.instance.api
         .crateApiInitializeNew(serverUri: serverUri, walletDir: walletDir);
 
 /// Initialize a new wallet and return both seed phrase and birthday
+/// `server_uri` can list several servers, like in `initialize_new`
 Future<String> initializeNewWithInfo(
         {required String serverUri, String? walletDir}) =>
     RustLib.instance.api.crateApiInitializeNewWithInfo(
         serverUri: serverUri, walletDir: walletDir);
 
 /// Initialize from an existing wallet
+/// `server_uri` can list several servers, like in `initialize_new`
 Future<String> initializeExisting(
         {required String serverUri, String? walletDir}) =>
     RustLib.instance.api
         .crateApiInitializeExisting(serverUri: serverUri, walletDir: walletDir);
 
-/// Initialize from an existing wallet with birthday height
+/// Initialize from an existing wallet with birthday height. If no server can be reached, the wallet is opened offline:
+/// balances, transactions, exports and messages work, and calls that need a server return an "Offline: " error until
+/// one can be reached again. If the wallet file is damaged, its most recent good backup is used instead, and returned
+/// as "recovered_from".
 Future<String> initializeExistingWithBirthday(
         {required String serverUri,
         String? walletDir,
@@ -38,6 +45,19 @@ Future<String> initializeExistingWithBirthday(
     RustLib.instance.api.crateApiInitializeExistingWithBirthday(
         serverUri: serverUri, walletDir: walletDir, birthday: birthday);
 
+/// Initialize from an existing wallet whose whole file is encrypted (see the "encryptfile" command). The password only
+/// unlocks it for viewing: spending still needs the "unlock" command.
+Future<String> initializeExistingWithPassword(
+        {required String serverUri,
+        String? walletDir,
+        required BigInt birthday,
+        required String password}) =>
+    RustLib.instance.api.crateApiInitializeExistingWithPassword(
+        serverUri: serverUri,
+        walletDir: walletDir,
+        birthday: birthday,
+        password: password);
+
 /// Initialize from seed phrase (simplified version without wallet_dir to avoid serialization issues)
 Future<String> initializeFromPhraseSimple(
         {required String serverUri, required String seedPhrase}) =>
@@ -45,6 +65,8 @@ Future<String> initializeFromPhraseSimple(
         serverUri: serverUri, seedPhrase: seedPhrase);
 
 /// Initialize from seed phrase
+/// `server_uri` can list several servers, like in `initialize_new`. `seed_phrase` can also be enough SLIP-39 shares of
+/// a seed, one per line (see the "splitseed" command).
 Future<String> initializeFromPhrase(
         {required String serverUri,
         required String seedPhrase,
@@ -58,6 +80,23 @@ Future<String> initializeFromPhrase(
         overwrite: overwrite,
         walletDir: walletDir);
 
+/// Initialize from a BIP39 seed phrase of 12 to 24 words in any BIP39 language, whose keys were derived with
+/// `passphrase`. The passphrase is kept in the wallet, encrypted along with the seed once the wallet is encrypted.
+Future<String> initializeFromPhraseWithPassphrase(
+        {required String serverUri,
+        required String seedPhrase,
+        required String passphrase,
+        required BigInt birthday,
+        required bool overwrite,
+        String? walletDir}) =>
+    RustLib.instance.api.crateApiInitializeFromPhraseWithPassphrase(
+        serverUri: serverUri,
+        seedPhrase: seedPhrase,
+        passphrase: passphrase,
+        birthday: birthday,
+        overwrite: overwrite,
+        walletDir: walletDir);
+
 /// Execute a command (main wallet interface)
 Future<String> execute({required String command, required String args}) =>
     RustLib.instance.api.crateApiExecute(command: command, args: args);
@@ -65,6 +104,10 @@ Future<String> execute({required String command, required String args}) =>
 /// Deinitialize the wallet
 Future<String> deinitialize() => RustLib.instance.api.crateApiDeinitialize();
 
+/// Whether the wallet was opened without a server, and none could be reached since. Returns false if there is no
+/// wallet.
+bool isOffline() => RustLib.instance.api.crateApiIsOffline();
+
 /// Get sync status
 String getSyncStatus() => RustLib.instance.api.crateApiGetSyncStatus();
 
@@ -92,6 +135,18 @@ String getAddresses() => RustLib.instance.api.crateApiGetAddresses();
 String newAddress({required String addressType}) =>
     RustLib.instance.api.crateApiNewAddress(addressType: addressType);
 
+/// Check that the user wrote the seed phrase down correctly. Returns {"result": "match"} or {"result": "no-match"},
+/// and works while the wallet is locked.
+String verifySeed({required String seedPhrase}) =>
+    RustLib.instance.api.crateApiVerifySeed(seedPhrase: seedPhrase);
+
+/// Have the wallet lock its spending keys again after `timeout_secs` seconds without a send, or after `max_sends`
+/// sends, since it was unlocked. 0 turns either off. Both apply to a wallet that is unlocked already, and the wallet
+/// event stream gets an "auto_locked" event when it locks.
+String setAutoLock({required BigInt timeoutSecs, required int maxSends}) =>
+    RustLib.instance.api
+        .crateApiSetAutoLock(timeoutSecs: timeoutSecs, maxSends: maxSends);
+
 /// Get wallet height
 int getHeight() => RustLib.instance.api.crateApiGetHeight();
 
@@ -103,6 +158,47 @@ String getInfo() => RustLib.instance.api.crateApiGetInfo();
 Future<String> getServerInfo({required String serverUri}) =>
     RustLib.instance.api.crateApiGetServerInfo(serverUri: serverUri);
 
+/// Cross-check the chain synced from the wallet's server against other servers, after every sync and right now.
+/// `server_uris` is a comma separated list, empty to turn the check off. With `refuse_send`, sending is refused while
+/// the servers disagree. Disagreements are also sent to the wallet event stream.
+Future<String> verifyServers(
+        {required String serverUris, required bool refuseSend}) =>
+    RustLib.instance.api
+        .crateApiVerifyServers(serverUris: serverUris, refuseSend: refuseSend);
+
+/// Route all connections to servers through a SOCKS5 proxy, like Tor's "socks5h://127.0.0.1:9050". An empty
+/// `proxy_uri` connects directly again. Call it before initializing the wallet, so that the first connection to the
+/// server already goes through the proxy.
+String setProxy({required String proxyUri}) =>
+    RustLib.instance.api.crateApiSetProxy(proxyUri: proxyUri);
+
+/// Trust https servers by `ca_cert_path` instead of the public CAs, require one of `spki_pins` (comma separated, like
+/// "sha256/base64hash") in their certificate chain, and authenticate with a client certificate. Empty values use the
+/// defaults. Like the proxy, call it before initializing the wallet.
+String setTlsOptions(
+        {required String caCertPath,
+        required String spkiPins,
+        required String clientCertPath,
+        required String clientKeyPath}) =>
+    RustLib.instance.api.crateApiSetTlsOptions(
+        caCertPath: caCertPath,
+        spkiPins: spkiPins,
+        clientCertPath: clientCertPath,
+        clientKeyPath: clientKeyPath);
+
+/// Set the timeouts for connections to servers and calls to them, the HTTP/2 keepalive interval, and how many times a
+/// call that failed with a transient error is tried. Applies to the connections made from now on.
+String setConnectionSettings(
+        {required BigInt rpcTimeoutSecs,
+        required BigInt connectTimeoutSecs,
+        required BigInt keepaliveIntervalSecs,
+        required int maxAttempts}) =>
+    RustLib.instance.api.crateApiSetConnectionSettings(
+        rpcTimeoutSecs: rpcTimeoutSecs,
+        connectTimeoutSecs: connectTimeoutSecs,
+        keepaliveIntervalSecs: keepaliveIntervalSecs,
+        maxAttempts: maxAttempts);
+
 /// Get send progress (synchronous version for polling)
 String getSendProgress() => RustLib.instance.api.crateApiGetSendProgress();
 
@@ -114,6 +210,11 @@ Future<String> initProgressStream() =>
 Future<String> getNextProgressUpdate() =>
     RustLib.instance.api.crateApiGetNextProgressUpdate();
 
+/// Stream the wallet's events to Dart as JSON strings. Call this after the wallet is initialized. The stream ends
+/// when the wallet is closed, or when Dart stops listening.
+Stream<String> walletEventStream() =>
+    RustLib.instance.api.crateApiWalletEventStream();
+
 /// Send progress update (called from transaction building)
 Future<String> sendProgressUpdate({required String progressData}) =>
     RustLib.instance.api.crateApiSendProgressUpdate(progressData: progressData);
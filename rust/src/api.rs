@@ -9,9 +9,12 @@ use tokio::time::{sleep, Duration};
 use futures::Stream;
 use serde_json;
 use chrono;
+use crate::frb_generated::StreamSink;
 use zecwalletlitelib::{commands, lightclient::LightClient, MainNetwork};
 use zecwalletlitelib::lightclient::lightclient_config::LightClientConfig;
-use zecwalletlitelib::grpc_connector::GrpcConnector;
+use zecwalletlitelib::grpc_connector::{self, ConnectOptions, GrpcConnector};
+use zecwalletlitelib::proxy::Socks5Proxy;
+use zecwalletlitelib::tls::{PinnedTls, TlsOptions};
 
 // Global reference to progress sender for use from zecwalletlitelib
 static mut GLOBAL_PROGRESS_SENDER: Option<broadcast::Sender<String>> = None;
@@ -22,6 +25,18 @@ lazy_static! {
         Mutex::new(RefCell::new(None));
     static ref PROGRESS_SENDER: Mutex<Option<broadcast::Sender<String>>> =
         Mutex::new(None);
+    // The proxy from set_proxy, for the wallets initialized after it
+    static ref PROXY: Mutex<Option<Socks5Proxy>> = Mutex::new(None);
+    // The TLS options from set_tls_options, if they aren't the defaults
+    static ref TLS: Mutex<Option<PinnedTls>> = Mutex::new(None);
+}
+
+// How the wallet being initialized connects to its servers
+fn connect_options() -> ConnectOptions {
+    ConnectOptions {
+        proxy: PROXY.lock().unwrap().clone(),
+        tls: TLS.lock().unwrap().clone(),
+    }
 }
 
 /// Check if a wallet exists
@@ -49,10 +64,12 @@ pub fn wallet_exists(wallet_dir: Option<String>) -> bool {
 }
 
 /// Initialize a new wallet and return the seed phrase
+/// `server_uri` can be a comma separated list of servers, in order of preference. The wallet uses the fastest one
+/// that is up, and fails over to the others when it can't reach it.
 pub fn initialize_new(server_uri: String, wallet_dir: Option<String>) -> String {
-    let server = LightClientConfig::<MainNetwork>::get_server_or_default(Some(server_uri));
+    let servers = LightClientConfig::<MainNetwork>::get_servers_or_default(Some(server_uri));
     
-    let (config, latest_block_height) = match LightClientConfig::create(MainNetwork, server, wallet_dir) {
+    let (config, latest_block_height) = match LightClientConfig::create_with_options(MainNetwork, servers, connect_options(), wallet_dir) {
         Ok((c, h)) => (c, h),
         Err(e) => return format!("Error: {}", e),
     };
@@ -86,10 +103,11 @@ pub fn initialize_new(server_uri: String, wallet_dir: Option<String>) -> String
 }
 
 /// Initialize a new wallet and return both seed phrase and birthday
+/// `server_uri` can list several servers, like in `initialize_new`
 pub fn initialize_new_with_info(server_uri: String, wallet_dir: Option<String>) -> String {
-    let server = LightClientConfig::<MainNetwork>::get_server_or_default(Some(server_uri));
+    let servers = LightClientConfig::<MainNetwork>::get_servers_or_default(Some(server_uri));
     
-    let (config, latest_block_height) = match LightClientConfig::create(MainNetwork, server, wallet_dir) {
+    let (config, latest_block_height) = match LightClientConfig::create_with_options(MainNetwork, servers, connect_options(), wallet_dir) {
         Ok((c, h)) => (c, h),
         Err(e) => return format!("Error: {}", e),
     };
@@ -165,12 +183,36 @@ pub fn initialize_new_with_info(server_uri: String, wallet_dir: Option<String>)
 }
 
 /// Initialize from an existing wallet
+/// `server_uri` can list several servers, like in `initialize_new`
 pub fn initialize_existing(server_uri: String, wallet_dir: Option<String>) -> String {
     initialize_existing_with_birthday(server_uri, wallet_dir, 0)
 }
 
-/// Initialize from an existing wallet with birthday height
+/// Initialize from an existing wallet with birthday height. If no server can be reached, the wallet is opened offline:
+/// balances, transactions, exports and messages work, and calls that need a server fail while `is_offline` is true,
+/// until one can be reached again. If the wallet file is damaged, its most recent good backup is used instead, and
+/// returned as "recovered_from".
 pub fn initialize_existing_with_birthday(server_uri: String, wallet_dir: Option<String>, birthday: u64) -> String {
+    initialize_existing_impl(server_uri, wallet_dir, birthday, None)
+}
+
+/// Initialize from an existing wallet whose whole file is encrypted (see the "encryptfile" command). The password only
+/// unlocks it for viewing: spending still needs the "unlock" command.
+pub fn initialize_existing_with_password(
+    server_uri: String,
+    wallet_dir: Option<String>,
+    birthday: u64,
+    password: String,
+) -> String {
+    initialize_existing_impl(server_uri, wallet_dir, birthday, Some(password))
+}
+
+fn initialize_existing_impl(
+    server_uri: String,
+    wallet_dir: Option<String>,
+    birthday: u64,
+    password: Option<String>,
+) -> String {
     // Log the wallet directory being used
     if let Some(ref dir) = wallet_dir {
         println!("📁 Attempting to load wallet from directory: {}", dir);
@@ -178,9 +220,9 @@ pub fn initialize_existing_with_birthday(server_uri: String, wallet_dir: Option<
         println!("📁 Attempting to load wallet from default directory");
     }
     
-    let server = LightClientConfig::<MainNetwork>::get_server_or_default(Some(server_uri));
+    let servers = LightClientConfig::<MainNetwork>::get_servers_or_default(Some(server_uri));
     
-    let (config, _latest_block_height) = match LightClientConfig::create(MainNetwork, server, wallet_dir.clone()) {
+    let (config, _latest_block_height) = match LightClientConfig::create_allow_offline(MainNetwork, servers, connect_options(), wallet_dir.clone()) {
         Ok((c, h)) => (c, h),
         Err(e) => return format!("Error: {}", e),
     };
@@ -197,7 +239,11 @@ pub fn initialize_existing_with_birthday(server_uri: String, wallet_dir: Option<
     println!("✅ Wallet file exists, attempting to read...");
 
     // Read existing wallet from disk instead of creating new
-    let mut lightclient = match LightClient::read_from_disk(&config) {
+    let read = match &password {
+        Some(password) => LightClient::read_from_disk_with_password(&config, password),
+        None => LightClient::read_from_disk(&config),
+    };
+    let mut lightclient = match read {
         Ok(l) => {
             println!("✅ Successfully read wallet from disk");
             l
@@ -221,7 +267,11 @@ pub fn initialize_existing_with_birthday(server_uri: String, wallet_dir: Option<
     // Initialize logging
     let _ = lightclient.init_logging();
 
-    // Start mempool monitor (CRITICAL for unconfirmed transactions!)
+    // If the wallet file was damaged, tell which backup it was recovered from
+    let recovered_from = lightclient.recovered_from().map(|p| p.display().to_string());
+
+    // Start mempool monitor (CRITICAL for unconfirmed transactions!). While offline, it waits for a server.
+    let offline = lightclient.is_offline();
     let lc = Arc::new(lightclient);
     #[cfg(debug_assertions)]
     println!("Starting mempool monitor for unconfirmed transaction detection...");
@@ -232,7 +282,13 @@ pub fn initialize_existing_with_birthday(server_uri: String, wallet_dir: Option<
     // Store the client globally
     LIGHTCLIENT.lock().unwrap().replace(Some(lc));
 
-    format!(r#"{{"status": "OK", "birthday": {}}}"#, birthday)
+    serde_json::json!({
+        "status": "OK",
+        "birthday": birthday,
+        "offline": offline,
+        "recovered_from": recovered_from,
+    })
+    .to_string()
 }
 
 /// Initialize from seed phrase (simplified version without wallet_dir to avoid serialization issues)
@@ -249,6 +305,8 @@ pub fn initialize_from_phrase_simple(
 }
 
 /// Initialize from seed phrase
+/// `server_uri` can list several servers, like in `initialize_new`. `seed_phrase` can also be enough SLIP-39 shares of
+/// a seed, one per line (see the "splitseed" command).
 pub fn initialize_from_phrase(
     server_uri: String, 
     seed_phrase: String, 
@@ -256,9 +314,33 @@ pub fn initialize_from_phrase(
     overwrite: bool,
     wallet_dir: Option<String>
 ) -> String {
-    let server = LightClientConfig::<MainNetwork>::get_server_or_default(Some(server_uri));
+    initialize_from_phrase_impl(server_uri, seed_phrase, String::new(), birthday, overwrite, wallet_dir)
+}
+
+/// Initialize from a BIP39 seed phrase of 12 to 24 words in any BIP39 language, whose keys were derived with
+/// `passphrase`. The passphrase is kept in the wallet, encrypted along with the seed once the wallet is encrypted.
+pub fn initialize_from_phrase_with_passphrase(
+    server_uri: String,
+    seed_phrase: String,
+    passphrase: String,
+    birthday: u64,
+    overwrite: bool,
+    wallet_dir: Option<String>,
+) -> String {
+    initialize_from_phrase_impl(server_uri, seed_phrase, passphrase, birthday, overwrite, wallet_dir)
+}
+
+fn initialize_from_phrase_impl(
+    server_uri: String,
+    seed_phrase: String,
+    passphrase: String,
+    birthday: u64,
+    overwrite: bool,
+    wallet_dir: Option<String>,
+) -> String {
+    let servers = LightClientConfig::<MainNetwork>::get_servers_or_default(Some(server_uri));
     
-    let (config, _latest_block_height) = match LightClientConfig::create(MainNetwork, server, wallet_dir) {
+    let (config, _latest_block_height) = match LightClientConfig::create_with_options(MainNetwork, servers, connect_options(), wallet_dir) {
         Ok((c, h)) => (c, h),
         Err(e) => return format!("Error: {}", e),
     };
@@ -269,8 +351,9 @@ pub fn initialize_from_phrase(
         // Delete wallet file if needed
     }
 
-    let lightclient = match LightClient::new_from_phrase(
+    let lightclient = match LightClient::new_from_phrase_with_passphrase(
         seed_phrase, 
+        passphrase,
         &config, 
         birthday, 
         false
@@ -314,6 +397,9 @@ pub fn execute(command: String, args: String) -> String {
     } else if command == "send" && args.starts_with('[') {
         // For send command with JSON format, pass as single argument
         vec![&args]
+    } else if command == "verifyseed" {
+        // A seed phrase can be SLIP-39 shares, one per line, which splitting would run together
+        vec![&args]
     } else {
         // For other commands, use normal whitespace splitting
         args.split_whitespace().collect()
@@ -329,6 +415,16 @@ pub fn deinitialize() -> String {
     "OK".to_string()
 }
 
+/// Whether the wallet was opened without a server, and none could be reached since. Returns false if there is no
+/// wallet.
+#[frb(sync)]
+pub fn is_offline() -> bool {
+    match LIGHTCLIENT.lock().unwrap().borrow().as_ref() {
+        Some(lc) => lc.is_offline(),
+        None => false,
+    }
+}
+
 /// Get sync status
 #[frb(sync)]
 pub fn get_sync_status() -> String {
@@ -431,6 +527,26 @@ pub fn new_address(address_type: String) -> String {
     execute("new".to_string(), address_type)
 }
 
+/// Check that the user wrote the seed phrase down correctly. Returns {"result": "match"} or {"result": "no-match"},
+/// and works while the wallet is locked.
+#[frb(sync)]
+pub fn verify_seed(seed_phrase: String) -> String {
+    execute("verifyseed".to_string(), seed_phrase)
+}
+
+/// Have the wallet lock its spending keys again after `timeout_secs` seconds without a send, or after `max_sends`
+/// sends, since it was unlocked. 0 turns either off. Both apply to a wallet that is unlocked already, and the wallet
+/// event stream gets an "auto_locked" event when it locks.
+#[frb(sync)]
+pub fn set_auto_lock(timeout_secs: u64, max_sends: u32) -> String {
+    let result = execute("setoption".to_string(), format!("auto_lock_timeout={}", timeout_secs));
+    if result.starts_with("Error") {
+        return result;
+    }
+
+    execute("setoption".to_string(), format!("auto_lock_sends={}", max_sends))
+}
+
 /// Get wallet height
 #[frb(sync)]
 pub fn get_height() -> u32 {
@@ -469,7 +585,7 @@ pub async fn get_server_info(server_uri: String) -> String {
     };
     
     // Use GrpcConnector to get server info
-    match GrpcConnector::get_info(uri).await {
+    match GrpcConnector::new(uri, connect_options()).get_info().await {
         Ok(lightd_info) => {
             println!("✅ Server connection successful");
             println!("   Version: {}", lightd_info.version);
@@ -508,6 +624,111 @@ pub async fn get_server_info(server_uri: String) -> String {
     }
 }
 
+/// Cross-check the chain synced from the wallet's server against other servers, after every sync and right now.
+/// `server_uris` is a comma separated list, empty to turn the check off. With `refuse_send`, sending is refused while
+/// the servers disagree. Disagreements are also sent to the wallet event stream.
+pub async fn verify_servers(server_uris: String, refuse_send: bool) -> String {
+    let servers = server_uris.split_whitespace().collect::<String>();
+    let args = if servers.is_empty() {
+        "off".to_string()
+    } else if refuse_send {
+        format!("{} refuse", servers)
+    } else {
+        servers
+    };
+
+    execute("verifyservers".to_string(), args)
+}
+
+/// Route all connections to servers through a SOCKS5 proxy, like Tor's "socks5h://127.0.0.1:9050". An empty
+/// `proxy_uri` connects directly again. It applies to the wallets initialized after it, so call it before initializing
+/// the wallet, and initialize it again to change a running wallet's proxy.
+#[frb(sync)]
+pub fn set_proxy(proxy_uri: String) -> String {
+    let proxy_uri = proxy_uri.trim();
+    if proxy_uri.is_empty() {
+        *PROXY.lock().unwrap() = None;
+        return serde_json::json!({ "proxy": null }).to_string();
+    }
+
+    match Socks5Proxy::parse(proxy_uri) {
+        Ok(p) => {
+            let uri = p.to_uri_string();
+            *PROXY.lock().unwrap() = Some(p);
+            serde_json::json!({ "proxy": uri }).to_string()
+        }
+        Err(e) => format!("Error: {}", e),
+    }
+}
+
+/// Trust https servers by `ca_cert_path` instead of the public CAs, require one of `spki_pins` (comma separated, like
+/// "sha256/base64hash") in their certificate chain, and authenticate with a client certificate. Empty values use the
+/// defaults. Like the proxy, call it before initializing the wallet.
+#[frb(sync)]
+pub fn set_tls_options(
+    ca_cert_path: String,
+    spki_pins: String,
+    client_cert_path: String,
+    client_key_path: String,
+) -> String {
+    let path = |p: &str| if p.trim().is_empty() { None } else { Some(Path::new(p.trim()).to_path_buf()) };
+
+    let pins = match spki_pins
+        .split(',')
+        .filter(|p| !p.trim().is_empty())
+        .map(TlsOptions::parse_pin)
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(pins) => pins,
+        Err(e) => return format!("Error: {}", e),
+    };
+
+    let options = TlsOptions {
+        ca_cert_path: path(&ca_cert_path),
+        spki_pins: pins,
+        client_cert_path: path(&client_cert_path),
+        client_key_path: path(&client_key_path),
+    };
+
+    let j = options.to_json().dump();
+    if options.is_default() {
+        *TLS.lock().unwrap() = None;
+        return j;
+    }
+
+    // Fail now on files that can't be used. They are read only here, and not again on every connection.
+    match PinnedTls::new(options) {
+        Ok(tls) => {
+            *TLS.lock().unwrap() = Some(tls);
+            j
+        }
+        Err(e) => format!("Error: {}", e),
+    }
+}
+
+/// Set the timeouts for connections to servers and calls to them, the HTTP/2 keepalive interval, and how many times a
+/// call that failed with a transient error is tried. Applies to the connections made from now on.
+#[frb(sync)]
+pub fn set_connection_settings(
+    rpc_timeout_secs: u64,
+    connect_timeout_secs: u64,
+    keepalive_interval_secs: u64,
+    max_attempts: u32,
+) -> String {
+    if rpc_timeout_secs == 0 || connect_timeout_secs == 0 || keepalive_interval_secs == 0 || max_attempts == 0 {
+        return "Error: timeouts, keepalive interval and attempts have to be more than 0".to_string();
+    }
+
+    let mut settings = grpc_connector::get_connection_settings();
+    settings.rpc_timeout = Duration::from_secs(rpc_timeout_secs);
+    settings.connect_timeout = Duration::from_secs(connect_timeout_secs);
+    settings.keepalive_interval = Duration::from_secs(keepalive_interval_secs);
+    settings.retry.max_attempts = max_attempts;
+    grpc_connector::set_connection_settings(settings);
+
+    settings.to_json().dump()
+}
+
 /// Get send progress (synchronous version for polling)
 #[frb(sync)]
 pub fn get_send_progress() -> String {
@@ -571,6 +792,37 @@ pub async fn get_next_progress_update() -> String {
     }
 }
 
+/// Stream the wallet's events to Dart as JSON strings. Call this after the wallet is initialized. The stream ends
+/// when the wallet is closed, or when Dart stops listening.
+pub async fn wallet_event_stream(sink: StreamSink<String>) {
+    let lightclient = LIGHTCLIENT.lock().unwrap().borrow().clone();
+
+    let lightclient = match lightclient {
+        Some(l) => l,
+        None => {
+            let _ = sink.add(r#"{"error": "Wallet not initialized"}"#.to_string());
+            return;
+        }
+    };
+
+    let mut rx = lightclient.subscribe_events().await;
+    loop {
+        match rx.recv().await {
+            Ok(event) => {
+                if sink.add(event.to_json().dump()).is_err() {
+                    return;
+                }
+            }
+            // The client was too slow and missed some events. Skip ahead to the ones still available.
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                println!("WALLET EVENTS: Skipped {} events", n);
+            }
+            // The wallet was closed
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
 /// Send progress update (called from transaction building)
 pub fn send_progress_update(progress_data: String) -> String {
     println!("PROGRESS STREAM: Sending progress update: {}", progress_data);
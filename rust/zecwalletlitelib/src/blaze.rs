@@ -1,7 +1,9 @@
 pub(super) mod block_witness_data;
+pub(crate) mod equihash;
 pub(super) mod fetch_compact_blocks;
 pub(super) mod fetch_full_tx;
 pub(super) mod fetch_taddr_txns;
+pub(crate) mod header_validation;
 pub(super) mod sync_status;
 pub(super) mod syncdata;
 pub(super) mod trial_decryptions;
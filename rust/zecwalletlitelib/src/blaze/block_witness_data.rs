@@ -3,8 +3,9 @@ use crate::{
     compact_formats::{CompactBlock, CompactTx, TreeState},
     grpc_connector::GrpcConnector,
     lightclient::{
-        checkpoints::get_all_main_checkpoints,
+        checkpoints::{get_all_main_checkpoints, ExtraCheckpoints},
         lightclient_config::{LightClientConfig, MAX_REORG},
+        wallet_events::{WalletEvent, WalletEvents},
     },
     lightwallet::{
         data::{BlockData, WalletTx, WitnessCache},
@@ -13,10 +14,10 @@ use crate::{
     },
 };
 use futures::{stream::FuturesOrdered, StreamExt};
-use http::Uri;
 use incrementalmerkletree::{bridgetree::BridgeTree, Tree};
 use log::info;
 use orchard::{note::ExtractedNoteCommitment, tree::MerkleHashOrchard};
+use prost::Message;
 use std::collections::HashMap;
 use std::{sync::Arc, time::Duration};
 use tokio::{
@@ -28,13 +29,27 @@ use tokio::{
     time::sleep,
 };
 use zcash_primitives::{
+    block::BlockHash,
     consensus::{self, BlockHeight},
     merkle_tree::{CommitmentTree, IncrementalWitness},
     sapling::{Node, Nullifier},
     transaction::TxId,
 };
 
-use super::{fixed_size_buffer::FixedSizeBuffer, sync_status::SyncStatus};
+use super::{fixed_size_buffer::FixedSizeBuffer, header_validation::HeaderValidator, sync_status::SyncStatus};
+
+/// Where the wallet's chain diverges from the server's chain
+#[derive(Debug, PartialEq)]
+pub enum ForkPoint {
+    /// The wallet's top block is on the server's chain
+    None,
+
+    /// The block at this height is the tallest one both chains share. Everything above it has to be invalidated.
+    At(u64),
+
+    /// Even the oldest block the wallet kept is not on the server's chain
+    BeyondRetained,
+}
 
 pub struct BlockAndWitnessData {
     // List of all blocks and their hashes/commitment trees. blocks[0] is the tallest block height in this batch
@@ -62,11 +77,23 @@ pub struct BlockAndWitnessData {
     // Link to the syncstatus where we can update progress
     sync_status: Arc<RwLock<SyncStatus>>,
 
+    // Where to report reorgs
+    events: WalletEvents,
+
     sapling_activation_height: u64,
+
+    // Validate the block headers from the last checkpoint on, instead of trusting the server for the chain
+    validate_headers: bool,
+    chain_name: String,
+    checkpoints: ExtraCheckpoints,
 }
 
 impl BlockAndWitnessData {
-    pub fn new<P: consensus::Parameters>(config: &LightClientConfig<P>, sync_status: Arc<RwLock<SyncStatus>>) -> Self {
+    pub fn new<P: consensus::Parameters>(
+        config: &LightClientConfig<P>,
+        sync_status: Arc<RwLock<SyncStatus>>,
+        events: WalletEvents,
+    ) -> Self {
         Self {
             blocks: Arc::new(RwLock::new(vec![])),
             existing_blocks: Arc::new(RwLock::new(vec![])),
@@ -76,18 +103,30 @@ impl BlockAndWitnessData {
             orchard_note_positions: Arc::new(RwLock::new(HashMap::new())),
             orchard_witnesses: Arc::new(RwLock::new(None)),
             sync_status,
+            events,
             sapling_activation_height: config.sapling_activation_height,
+            validate_headers: false,
+            chain_name: config.chain_name.clone(),
+            checkpoints: config.checkpoints.clone(),
         }
     }
 
     #[cfg(test)]
     pub fn new_with_batchsize<P: consensus::Parameters>(config: &LightClientConfig<P>, batch_size: u64) -> Self {
-        let mut s = Self::new(config, Arc::new(RwLock::new(SyncStatus::default())));
+        let mut s = Self::new(
+            config,
+            Arc::new(RwLock::new(SyncStatus::default())),
+            WalletEvents::new(),
+        );
         s.batch_size = batch_size;
 
         s
     }
 
+    pub fn set_validate_headers(&mut self, validate_headers: bool) {
+        self.validate_headers = validate_headers;
+    }
+
     pub async fn setup_sync(
         &mut self,
         existing_blocks: Vec<BlockData>,
@@ -276,6 +315,7 @@ impl BlockAndWitnessData {
         existing_blocks: Arc<RwLock<Vec<BlockData>>>,
         wallet_txns: Arc<RwLock<WalletTxns>>,
         orchard_witnesses: Arc<RwLock<Option<BridgeTree<MerkleHashOrchard, MERKLE_DEPTH>>>>,
+        events: &WalletEvents,
     ) {
         // First, pop the first block (which is the top block) in the existing_blocks.
         let top_wallet_block = existing_blocks.write().await.drain(0..1).next().unwrap();
@@ -299,6 +339,52 @@ impl BlockAndWitnessData {
         }
 
         info!("Invalidated block {}", reorg_height);
+        events.send(WalletEvent::ReorgInvalidatedBlock { height: reorg_height });
+    }
+
+    /// Find where the wallet's blocks diverge from the server's chain. `existing_blocks` are the wallet's blocks, tallest
+    /// first. Since each block commits to its parent, the blocks that are on the server's chain are always a suffix of
+    /// the list, so we binary search for the tallest one.
+    pub async fn find_fork_point(server: GrpcConnector, existing_blocks: &[BlockData]) -> Result<ForkPoint, String> {
+        if existing_blocks.is_empty() {
+            return Ok(ForkPoint::None);
+        }
+
+        let on_server_chain = |i: usize| {
+            let server = server.clone();
+            let block = &existing_blocks[i];
+            async move {
+                let server_block = server.get_block_id(block.height).await?;
+                if server_block.hash.len() != 32 {
+                    return Err(format!("Server returned a bad hash for block {}", block.height));
+                }
+
+                Ok::<_, String>(BlockHash::from_slice(&server_block.hash).to_string() == block.hash())
+            }
+        };
+
+        // Most of the time, the top block is still good
+        if on_server_chain(0).await? {
+            return Ok(ForkPoint::None);
+        }
+
+        let mut hi = existing_blocks.len() - 1;
+        if !on_server_chain(hi).await? {
+            return Ok(ForkPoint::BeyondRetained);
+        }
+
+        // blocks[lo] is not on the server's chain, blocks[hi] is
+        let mut lo = 0;
+        while hi - lo > 1 {
+            let mid = (lo + hi) / 2;
+            if on_server_chain(mid).await? {
+                hi = mid;
+            } else {
+                lo = mid;
+            }
+        }
+
+        Ok(ForkPoint::At(existing_blocks[hi].height))
     }
 
     /// Start a new sync where we ingest all the blocks
@@ -321,6 +407,15 @@ impl BlockAndWitnessData {
         let sync_status = self.sync_status.clone();
         sync_status.write().await.blocks_total = start_block - end_block + 1;
         let orchard_witnesses = self.orchard_witnesses.clone();
+        let events = self.events.clone();
+
+        let mut header_validator = if self.validate_headers {
+            let mut validator = HeaderValidator::for_chain(&self.chain_name, &self.checkpoints, start_block);
+            validator.set_wallet_blocks(&existing_blocks.read().await);
+            Some(validator)
+        } else {
+            None
+        };
 
         // Handle 0:
         // Process the incoming compact blocks, collect them into `BlockData` and pass them on
@@ -331,6 +426,7 @@ impl BlockAndWitnessData {
         let h0: JoinHandle<Result<u64, String>> = tokio::spawn(async move {
             // Temporary holding place for blocks while we process them.
             let mut blks = vec![];
+            let mut blks_bytes = 0;
             let mut earliest_block_height = 0;
 
             // Reorg stuff
@@ -346,7 +442,12 @@ impl BlockAndWitnessData {
                     // println!("Batch size hit at height {} with len {}", cb.height, blks.len());
                     if !blks.is_empty() {
                         // Add these blocks to the list
-                        sync_status.write().await.blocks_done += blks.len() as u64;
+                        let mut status = sync_status.write().await;
+                        status.record_download(blks.len() as u64, blks_bytes);
+                        status.add_blocks_done(blks.len() as u64);
+                        drop(status);
+
+                        blks_bytes = 0;
                         blocks.write().await.append(&mut blks);
                     }
                 }
@@ -376,17 +477,32 @@ impl BlockAndWitnessData {
                             existing_blocks.clone(),
                             wallet_txns.clone(),
                             orchard_witnesses,
+                            &events,
                         )
                         .await;
                         last_block_expecting = reorg_height;
+
+                        if let Some(validator) = header_validator.as_mut() {
+                            validator.set_wallet_blocks(&existing_blocks.read().await);
+                        }
                     }
                     reorg_tx.send(reorg_block).unwrap();
                 }
 
+                // A block with a bad header fails the whole sync
+                if let Some(validator) = header_validator.as_mut() {
+                    validator.validate(&cb)?;
+                }
+
                 earliest_block_height = cb.height;
+                blks_bytes += cb.encoded_len() as u64;
                 blks.push(BlockData::new(cb));
             }
 
+            if let Some(validator) = header_validator.as_mut() {
+                validator.finish()?;
+            }
+
             // println!(
             //     "Final block size at earliest-height {} with len {}",
             //     earliest_block_height,
@@ -394,7 +510,11 @@ impl BlockAndWitnessData {
             // );
             if !blks.is_empty() {
                 // We'll now dispatch these blocks for updating the witness
-                sync_status.write().await.blocks_done += blks.len() as u64;
+                let mut status = sync_status.write().await;
+                status.record_download(blks.len() as u64, blks_bytes);
+                status.add_blocks_done(blks.len() as u64);
+                drop(status);
+
                 blocks.write().await.append(&mut blks);
             }
 
@@ -580,7 +700,7 @@ impl BlockAndWitnessData {
 
     pub async fn get_note_witness(
         &self,
-        uri: Uri,
+        server: GrpcConnector,
         height: BlockHeight,
         tx_num: usize,
         output_num: usize,
@@ -593,7 +713,7 @@ impl BlockAndWitnessData {
             let tree = if prev_height < self.sapling_activation_height {
                 CommitmentTree::empty()
             } else {
-                let tree_state = GrpcConnector::get_merkle_tree(uri, prev_height).await?;
+                let tree_state = server.get_merkle_tree(prev_height).await?;
                 let sapling_tree = hex::decode(&tree_state.tree).unwrap();
                 // self.verification_list.write().await.push(tree_state);
                 CommitmentTree::read(&sapling_tree[..]).map_err(|e| format!("{}", e))?
@@ -742,6 +862,7 @@ mod test {
 
     use crate::blaze::sync_status::SyncStatus;
     use crate::lightclient::lightclient_config::UnitTestNetwork;
+    use crate::lightclient::wallet_events::{WalletEvent, WalletEvents};
     use crate::lightwallet::wallet_txns::WalletTxns;
     use crate::{
         blaze::test_utils::{FakeCompactBlock, FakeCompactBlockList},
@@ -810,7 +931,7 @@ mod test {
         let end_block = blocks.last().unwrap().height;
 
         let sync_status = Arc::new(RwLock::new(SyncStatus::default()));
-        let mut nw = BlockAndWitnessData::new(&config, sync_status);
+        let mut nw = BlockAndWitnessData::new(&config, sync_status, WalletEvents::new());
 
         let orchard_witnesses = Arc::new(RwLock::new(None));
         nw.setup_sync(vec![], None, orchard_witnesses).await;
@@ -955,7 +1076,9 @@ mod test {
         let end_block = blocks.last().unwrap().height;
 
         let sync_status = Arc::new(RwLock::new(SyncStatus::default()));
-        let mut nw = BlockAndWitnessData::new(&config, sync_status);
+        let events = WalletEvents::new();
+        let mut events_rx = events.subscribe();
+        let mut nw = BlockAndWitnessData::new(&config, sync_status, events);
 
         let orchard_witnesses = Arc::new(RwLock::new(None));
         nw.setup_sync(existing_blocks, None, orchard_witnesses).await;
@@ -1006,6 +1129,14 @@ mod test {
 
         try_join_all(vec![send_h]).await.unwrap();
 
+        // Every invalidated block was reported
+        for height in (46..=50).rev() {
+            assert_eq!(
+                events_rx.recv().await.unwrap(),
+                WalletEvent::ReorgInvalidatedBlock { height }
+            );
+        }
+
         let finished_blks = nw.finish_get_blocks(100).await;
         assert_eq!(finished_blks.len(), 100);
         assert_eq!(finished_blks.first().unwrap().height, start_block);
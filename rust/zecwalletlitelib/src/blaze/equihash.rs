@@ -0,0 +1,317 @@
+use blake2b_simd::{Params as Blake2bParams, State};
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+
+/// The parameters of an Equihash instance. This is the same algorithm that zcashd verifies, but the personalization of
+/// the BLAKE2b hash is a parameter, since BitcoinZ's Equihash (144,5) uses "BitcoinZ" where Zcash uses "ZcashPoW".
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EquihashParams {
+    pub n: u32,
+    pub k: u32,
+    pub personalization: [u8; 8],
+}
+
+pub const BITCOINZ_EQUIHASH: EquihashParams = EquihashParams {
+    n: 144,
+    k: 5,
+    personalization: *b"BitcoinZ",
+};
+
+impl EquihashParams {
+    fn collision_bit_length(&self) -> usize {
+        (self.n / (self.k + 1)) as usize
+    }
+
+    fn collision_byte_length(&self) -> usize {
+        (self.collision_bit_length() + 7) / 8
+    }
+
+    fn indices_per_hash_output(&self) -> u32 {
+        512 / self.n
+    }
+
+    fn hash_output(&self) -> usize {
+        (self.indices_per_hash_output() * self.n / 8) as usize
+    }
+
+    /// Length of a solution in bytes, without the length prefix it has in a block header
+    pub fn solution_length(&self) -> usize {
+        (1 << self.k) * (self.collision_bit_length() + 1) / 8
+    }
+
+    // BLAKE2b state that has hashed `input` and `nonce`, ready to hash the indices
+    fn state(&self, input: &[u8], nonce: &[u8]) -> State {
+        let mut personalization = [0u8; 16];
+        personalization[..8].copy_from_slice(&self.personalization);
+        (&mut personalization[8..12]).write_u32::<LittleEndian>(self.n).unwrap();
+        (&mut personalization[12..]).write_u32::<LittleEndian>(self.k).unwrap();
+
+        let mut state = Blake2bParams::new()
+            .hash_length(self.hash_output())
+            .personal(&personalization)
+            .to_state();
+        state.update(input);
+        state.update(nonce);
+
+        state
+    }
+
+    // The hash for index `i`, expanded so that each collision_bit_length chunk is in its own bytes
+    fn hash(&self, state: &State, i: u32) -> Vec<u8> {
+        let mut le_i = [0u8; 4];
+        (&mut le_i[..])
+            .write_u32::<LittleEndian>(i / self.indices_per_hash_output())
+            .unwrap();
+
+        let mut state = state.clone();
+        state.update(&le_i);
+        let hash = state.finalize();
+
+        let start = (i % self.indices_per_hash_output()) as usize * self.n as usize / 8;
+        let end = start + self.n as usize / 8;
+        expand_array(&hash.as_bytes()[start..end], self.collision_bit_length(), 0)
+    }
+}
+
+// Split `vin` into `bit_len` chunks, and write each one big endian into its own bytes, left padded with `byte_pad` bytes
+fn expand_array(vin: &[u8], bit_len: usize, byte_pad: usize) -> Vec<u8> {
+    assert!(bit_len >= 8 && bit_len + 7 <= 32);
+
+    let out_width = (bit_len + 7) / 8 + byte_pad;
+    let mut vout = vec![0u8; 8 * out_width * vin.len() / bit_len];
+    let bit_len_mask: u32 = (1 << bit_len) - 1;
+
+    let mut acc_bits = 0;
+    let mut acc_value: u32 = 0;
+    let mut j = 0;
+    for b in vin {
+        acc_value = (acc_value << 8) | u32::from(*b);
+        acc_bits += 8;
+
+        if acc_bits >= bit_len {
+            acc_bits -= bit_len;
+            for x in byte_pad..out_width {
+                let shift = 8 * (out_width - x - 1);
+                vout[j + x] = ((acc_value >> (acc_bits + shift)) & ((bit_len_mask >> shift) & 0xFF)) as u8;
+            }
+            j += out_width;
+        }
+    }
+
+    vout
+}
+
+fn indices_from_minimal(p: &EquihashParams, minimal: &[u8]) -> Vec<u32> {
+    let index_bits = p.collision_bit_length() + 1;
+    let byte_pad = 4 - (index_bits + 7) / 8;
+
+    expand_array(minimal, index_bits, byte_pad)
+        .chunks(4)
+        .map(|mut c| c.read_u32::<BigEndian>().unwrap())
+        .collect()
+}
+
+struct Node {
+    hash: Vec<u8>,
+    indices: Vec<u32>,
+}
+
+// Check the subtree of the solution with `indices`, and return its root
+fn tree_validator(p: &EquihashParams, state: &State, indices: &[u32]) -> Result<Node, String> {
+    if indices.len() == 1 {
+        return Ok(Node {
+            hash: p.hash(state, indices[0]),
+            indices: indices.to_vec(),
+        });
+    }
+
+    let (left, right) = indices.split_at(indices.len() / 2);
+    let a = tree_validator(p, state, left)?;
+    let b = tree_validator(p, state, right)?;
+
+    let trim = p.collision_byte_length();
+    if a.hash[..trim] != b.hash[..trim] {
+        return Err("Invalid Equihash solution: no collision".to_string());
+    }
+    if b.indices[0] < a.indices[0] {
+        return Err("Invalid Equihash solution: indices out of order".to_string());
+    }
+    if a.indices.iter().any(|i| b.indices.contains(i)) {
+        return Err("Invalid Equihash solution: duplicate indices".to_string());
+    }
+
+    Ok(Node {
+        hash: a.hash[trim..].iter().zip(&b.hash[trim..]).map(|(x, y)| x ^ y).collect(),
+        indices: [a.indices, b.indices].concat(),
+    })
+}
+
+/// Check that `solution` is a valid Equihash solution for `input` and `nonce`
+pub fn is_valid_solution(p: &EquihashParams, input: &[u8], nonce: &[u8], solution: &[u8]) -> Result<(), String> {
+    if solution.len() != p.solution_length() {
+        return Err(format!(
+            "Invalid Equihash solution: {} bytes, expected {}",
+            solution.len(),
+            p.solution_length()
+        ));
+    }
+
+    let state = p.state(input, nonce);
+    let root = tree_validator(p, &state, &indices_from_minimal(p, solution))?;
+    if root.hash.iter().any(|b| *b != 0) {
+        return Err("Invalid Equihash solution: root hash is not zero".to_string());
+    }
+
+    Ok(())
+}
+
+// Pack the indices of a solution the way it is stored in a header
+#[cfg(test)]
+pub(crate) fn minimal_from_indices(p: &EquihashParams, indices: &[u32]) -> Vec<u8> {
+    let index_bits = p.collision_bit_length() + 1;
+    let mut minimal = vec![0u8; p.solution_length()];
+
+    for (n, i) in indices.iter().enumerate() {
+        for b in 0..index_bits {
+            if (i >> (index_bits - 1 - b)) & 1 == 1 {
+                let pos = n * index_bits + b;
+                minimal[pos / 8] |= 0x80 >> (pos % 8);
+            }
+        }
+    }
+
+    minimal
+}
+
+// A plain Wagner solver, which is only fast enough for tiny parameters. Returns the solutions as indices.
+#[cfg(test)]
+pub(crate) fn solve(p: &EquihashParams, input: &[u8], nonce: &[u8]) -> Vec<Vec<u32>> {
+    let state = p.state(input, nonce);
+    let trim = p.collision_byte_length();
+
+    let mut rows = (0..1u32 << (p.collision_bit_length() + 1))
+        .map(|i| Node {
+            hash: p.hash(&state, i),
+            indices: vec![i],
+        })
+        .collect::<Vec<_>>();
+
+    for _ in 0..p.k {
+        rows.sort_by(|a, b| a.hash[..trim].cmp(&b.hash[..trim]));
+
+        let mut next = vec![];
+        for i in 0..rows.len() {
+            for j in i + 1..rows.len() {
+                if rows[i].hash[..trim] != rows[j].hash[..trim] {
+                    break;
+                }
+
+                let (a, b) = if rows[i].indices[0] < rows[j].indices[0] {
+                    (&rows[i], &rows[j])
+                } else {
+                    (&rows[j], &rows[i])
+                };
+                if a.indices.iter().any(|x| b.indices.contains(x)) {
+                    continue;
+                }
+
+                next.push(Node {
+                    hash: a.hash[trim..].iter().zip(&b.hash[trim..]).map(|(x, y)| x ^ y).collect(),
+                    indices: [a.indices.clone(), b.indices.clone()].concat(),
+                });
+            }
+        }
+        rows = next;
+    }
+
+    rows.into_iter()
+        .filter(|r| r.hash.iter().all(|b| *b == 0))
+        .map(|r| r.indices)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        indices_from_minimal, is_valid_solution, minimal_from_indices, solve, EquihashParams, BITCOINZ_EQUIHASH,
+    };
+    use crate::blaze::header_validation::tests::BITCOINZ_HEADER;
+
+    const TINY: EquihashParams = EquihashParams {
+        n: 32,
+        k: 3,
+        personalization: *b"BitcoinZ",
+    };
+
+    #[test]
+    fn solution_encoding() {
+        assert_eq!(BITCOINZ_EQUIHASH.solution_length(), 100);
+        let zcash = EquihashParams {
+            n: 200,
+            k: 9,
+            personalization: *b"ZcashPoW",
+        };
+        assert_eq!(zcash.solution_length(), 1344);
+
+        let indices = vec![1, 300, 511, 0, 2, 7, 256, 100];
+        let minimal = minimal_from_indices(&TINY, &indices);
+        assert_eq!(minimal.len(), TINY.solution_length());
+        assert_eq!(indices_from_minimal(&TINY, &minimal), indices);
+
+        let indices = (0..32).map(|i| i * 1_000_003 % (1 << 25)).collect::<Vec<u32>>();
+        let minimal = minimal_from_indices(&BITCOINZ_EQUIHASH, &indices);
+        assert_eq!(indices_from_minimal(&BITCOINZ_EQUIHASH, &minimal), indices);
+    }
+
+    #[test]
+    fn solutions() {
+        let input = b"block header up to the nonce";
+        let (nonce, indices) = (0u8..=255)
+            .find_map(|n| {
+                let nonce = [n; 32];
+                solve(&TINY, input, &nonce).into_iter().next().map(|s| (nonce, s))
+            })
+            .unwrap();
+
+        let solution = minimal_from_indices(&TINY, &indices);
+        assert_eq!(is_valid_solution(&TINY, input, &nonce, &solution), Ok(()));
+
+        // Anything else changing makes it invalid
+        assert!(is_valid_solution(&TINY, b"another header", &nonce, &solution).is_err());
+        assert!(is_valid_solution(&TINY, input, &[nonce[0].wrapping_add(1); 32], &solution).is_err());
+        assert!(is_valid_solution(&TINY, input, &nonce, &solution[1..]).is_err());
+
+        let zcash = EquihashParams {
+            personalization: *b"ZcashPoW",
+            ..TINY
+        };
+        assert!(is_valid_solution(&zcash, input, &nonce, &solution).is_err());
+
+        // The two halves of a solution have to be in order
+        let (a, b) = indices.split_at(indices.len() / 2);
+        let swapped = minimal_from_indices(&TINY, &[b, a].concat());
+        assert_eq!(
+            is_valid_solution(&TINY, input, &nonce, &swapped),
+            Err("Invalid Equihash solution: indices out of order".to_string())
+        );
+    }
+
+    #[test]
+    fn bitcoinz_solution() {
+        // The header up to the nonce, the nonce, and the solution after its one byte length prefix
+        let header = hex::decode(BITCOINZ_HEADER).unwrap();
+        let (input, nonce, solution) = (&header[..108], &header[108..140], &header[141..]);
+        assert_eq!(header[140] as usize, BITCOINZ_EQUIHASH.solution_length());
+        assert_eq!(is_valid_solution(&BITCOINZ_EQUIHASH, input, nonce, solution), Ok(()));
+
+        // It is only valid for BitcoinZ's personalization
+        let zcash = EquihashParams {
+            personalization: *b"ZcashPoW",
+            ..BITCOINZ_EQUIHASH
+        };
+        assert!(is_valid_solution(&zcash, input, nonce, solution).is_err());
+
+        let mut changed = solution.to_vec();
+        changed[50] ^= 0x10;
+        assert!(is_valid_solution(&BITCOINZ_EQUIHASH, input, nonce, &changed).is_err());
+    }
+}
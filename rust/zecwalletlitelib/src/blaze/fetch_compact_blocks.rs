@@ -1,7 +1,9 @@
 use std::{cmp::max, sync::Arc};
 
 use crate::{
-    compact_formats::CompactBlock, grpc_connector::GrpcConnector, lightclient::lightclient_config::LightClientConfig,
+    bitcoinzd_connector::BitcoinzdConnector,
+    compact_formats::CompactBlock,
+    lightclient::lightclient_config::{LightClientConfig, ServerBackend},
 };
 use log::info;
 use tokio::sync::mpsc::{Sender, UnboundedReceiver};
@@ -23,7 +25,7 @@ impl<P: consensus::Parameters> FetchCompactBlocks<P> {
         end_block: u64,
         spam_filter_threshold: i64,
     ) -> Result<(), String> {
-        let grpc_client = Arc::new(GrpcConnector::new(self.config.server.clone()));
+        let grpc_client = Arc::new(self.config.connector(self.config.get_server()));
         const STEP: u64 = 1_000;
 
         // We need the `rev()` here because rust ranges can only go up
@@ -36,9 +38,18 @@ impl<P: consensus::Parameters> FetchCompactBlocks<P> {
 
             info!("Fetching blocks {}-{}", start, end);
 
-            grpc_client
-                .get_block_range(start, end, spam_filter_threshold, receivers)
-                .await?;
+            match self.config.backend() {
+                ServerBackend::Lightwalletd => {
+                    grpc_client
+                        .get_block_range(start, end, spam_filter_threshold, receivers)
+                        .await?
+                }
+                ServerBackend::Bitcoinzd => {
+                    BitcoinzdConnector::new(&self.config.get_server(), self.config.connect_options.proxy.clone())?
+                        .get_block_range(start, end, &self.config.params, receivers)
+                        .await?
+                }
+            }
         }
 
         Ok(())
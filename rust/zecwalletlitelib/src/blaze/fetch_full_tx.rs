@@ -102,9 +102,14 @@ impl<P: consensus::Parameters + Send + Sync + 'static> FetchFullTxns<P> {
                     };
 
                     let progress = start_height - u64::from(height);
-                    if progress > last_progress.load(Ordering::SeqCst) {
-                        bsync_data.read().await.sync_status.write().await.txn_scan_done = progress;
-                        last_progress.store(progress, Ordering::SeqCst);
+                    {
+                        let bsync_data = bsync_data.read().await;
+                        let mut status = bsync_data.sync_status.write().await;
+                        status.record_txn_scan(1);
+                        if progress > last_progress.load(Ordering::SeqCst) {
+                            status.txn_scan_done = progress;
+                            last_progress.store(progress, Ordering::SeqCst);
+                        }
                     }
 
                     Self::scan_full_tx(config, tx, height, false, block_time, keys, wallet_txns, None).await;
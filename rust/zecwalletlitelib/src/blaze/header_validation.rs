@@ -0,0 +1,600 @@
+use std::collections::VecDeque;
+use std::convert::TryInto;
+
+use zcash_primitives::block::{BlockHash, BlockHeader};
+
+use super::equihash::{self, EquihashParams, BITCOINZ_EQUIHASH};
+use crate::compact_formats::CompactBlock;
+use crate::lightclient::checkpoints::{self, ExtraCheckpoints};
+use crate::lightwallet::data::BlockData;
+
+// Header bytes that go into the Equihash input, and the nonce that follows them. The wallet keeps the Equihash input
+// of its blocks, which is all the fields of the header.
+pub(crate) const EQUIHASH_INPUT_LEN: usize = 108;
+const NONCE_LEN: usize = 32;
+
+// Where the compact target is in the header
+const BITS_OFFSET: usize = 104;
+
+// The averaged difficulty is computed with integer math and stored in compact form, so allow a little rounding
+const DIFFICULTY_SLACK: f64 = 0.01;
+
+/// The consensus rules on block headers that can be checked without having the full chain
+#[derive(Clone, Copy, Debug)]
+pub struct HeaderRules {
+    pub equihash: EquihashParams,
+
+    // Easiest target a block can have, in compact form
+    pub pow_limit: u32,
+
+    // DigiShield averages the difficulty over this many blocks, and adjusts it by at most these percentages. An
+    // averaging window of 0 skips the difficulty bounds.
+    pub averaging_window: usize,
+    pub max_adjust_up: u64,
+    pub max_adjust_down: u64,
+}
+
+pub const BITCOINZ_RULES: HeaderRules = HeaderRules {
+    equihash: BITCOINZ_EQUIHASH,
+    pow_limit: 0x1f07ffff,
+    averaging_window: 17,
+    max_adjust_up: 16,
+    max_adjust_down: 32,
+};
+
+impl HeaderRules {
+    // Testnet allows minimum difficulty blocks, so only the main chain gets the difficulty bounds
+    pub fn for_chain(chain_name: &str) -> Self {
+        match chain_name {
+            "main" | "zs" | "bitcoinz" => BITCOINZ_RULES,
+            _ => HeaderRules {
+                averaging_window: 0,
+                ..BITCOINZ_RULES
+            },
+        }
+    }
+}
+
+/// Expand compact `bits` into a big endian target. None if they are negative or overflow.
+pub fn target_from_bits(bits: u32) -> Option<[u8; 32]> {
+    let exponent = (bits >> 24) as i64;
+    let mantissa = bits & 0x007fffff;
+    if bits & 0x00800000 != 0 && mantissa != 0 {
+        return None;
+    }
+
+    let mut target = [0u8; 32];
+    for (i, byte) in [(mantissa >> 16) as u8, (mantissa >> 8) as u8, mantissa as u8]
+        .iter()
+        .enumerate()
+    {
+        let pos = 32 - exponent + i as i64;
+        if pos < 0 {
+            if *byte != 0 {
+                return None;
+            }
+        } else if pos < 32 {
+            target[pos as usize] = *byte;
+        }
+    }
+
+    Some(target)
+}
+
+fn target_to_f64(target: &[u8; 32]) -> f64 {
+    target.iter().fold(0.0, |v, b| v * 256.0 + *b as f64)
+}
+
+/// Validates the headers of the blocks a sync downloads, so that the wallet doesn't have to trust its server for the
+/// chain. The blocks come in tallest first. Everything above the last checkpoint has to link up to it by prev hash,
+/// carry a valid Equihash solution, meet its target and stay within the difficulty bounds.
+pub struct HeaderValidator {
+    rules: HeaderRules,
+
+    // Blocks at or below the checkpoint are trusted
+    checkpoint: Option<(u64, BlockHash)>,
+
+    // Height and prev hash of the last block validated. The next block has to be its parent.
+    expected_parent: Option<(u64, BlockHash)>,
+
+    // Targets of the blocks that are still waiting for the averaging window below them, tallest first
+    targets: VecDeque<(u64, [u8; 32])>,
+
+    // The wallet's top block, which the sync has to build on, and the targets of the wallet's blocks from it down
+    wallet_block: Option<(u64, BlockHash)>,
+    wallet_targets: Vec<(u64, [u8; 32])>,
+}
+
+impl HeaderValidator {
+    pub fn new(rules: HeaderRules, checkpoint: Option<(u64, BlockHash)>) -> Self {
+        Self {
+            rules,
+            checkpoint,
+            expected_parent: None,
+            targets: VecDeque::new(),
+            wallet_block: None,
+            wallet_targets: vec![],
+        }
+    }
+
+    /// Validate a sync down from `start_block` on `chain_name`, starting from the closest checkpoint below it
+    pub fn for_chain(chain_name: &str, extra_checkpoints: &ExtraCheckpoints, start_block: u64) -> Self {
+        let closest = checkpoints::get_closest_checkpoint(chain_name, extra_checkpoints, start_block);
+        let checkpoint = closest.and_then(|(height, hash, _)| {
+            let mut bytes = hex::decode(hash).ok()?;
+            bytes.reverse();
+            (bytes.len() == 32).then(|| (height, BlockHash::from_slice(&bytes)))
+        });
+
+        Self::new(HeaderRules::for_chain(chain_name), checkpoint)
+    }
+
+    /// Build on the wallet's `blocks`, tallest first. The block above the top one has to be its child, and the
+    /// headers the wallet kept finish the difficulty checks of the lowest new blocks. Called again after a reorg
+    /// drops the top blocks.
+    pub fn set_wallet_blocks(&mut self, blocks: &[BlockData]) {
+        self.wallet_block = blocks.first().map(|b| (b.height, b.cb().hash()));
+
+        self.wallet_targets.clear();
+        for b in blocks.iter().take(self.rules.averaging_window) {
+            let header = b.cb().header;
+            let target = header
+                .get(BITS_OFFSET..BITS_OFFSET + 4)
+                .and_then(|bits| target_from_bits(u32::from_le_bytes(bits.try_into().unwrap())));
+            match target {
+                Some(target) if b.height + self.wallet_targets.len() as u64 == blocks[0].height => {
+                    self.wallet_targets.push((b.height, target))
+                }
+                _ => break,
+            }
+        }
+    }
+
+    /// Validate the next block of the sync
+    pub fn validate(&mut self, cb: &CompactBlock) -> Result<(), String> {
+        let height = cb.height;
+        if let Some((cp_height, cp_hash)) = self.checkpoint {
+            if height == cp_height && cb.hash.len() == 32 && BlockHash::from_slice(&cb.hash) != cp_hash {
+                return Err(format!("Block {} doesn't match the checkpoint", height));
+            }
+            if height <= cp_height {
+                return Ok(());
+            }
+        }
+
+        let header =
+            BlockHeader::read(&cb.header[..]).map_err(|e| format!("Block {} has a bad header: {}", height, e))?;
+        let hash = header.hash();
+        if cb.hash.len() == 32 && BlockHash::from_slice(&cb.hash) != hash {
+            return Err(format!("Block {}'s hash doesn't match its header", height));
+        }
+        if cb.prev_hash.len() == 32 && BlockHash::from_slice(&cb.prev_hash) != header.prev_block {
+            return Err(format!("Block {}'s prev hash doesn't match its header", height));
+        }
+
+        if let Some((child, parent)) = self.expected_parent {
+            if child != height + 1 || parent != hash {
+                return Err(format!("Block {} is not the parent of block {}", height, child));
+            }
+        }
+        if let Some((cp_height, cp_hash)) = self.checkpoint {
+            if height == cp_height + 1 && header.prev_block != cp_hash {
+                return Err(format!(
+                    "Block {} doesn't build on the checkpoint at {}",
+                    height, cp_height
+                ));
+            }
+        }
+        if let Some((wallet_height, wallet_hash)) = self.wallet_block {
+            if height == wallet_height + 1 && header.prev_block != wallet_hash {
+                return Err(format!(
+                    "Block {} doesn't build on the wallet's block {}",
+                    height, wallet_height
+                ));
+            }
+        }
+        self.expected_parent = Some((height, header.prev_block));
+
+        equihash::is_valid_solution(
+            &self.rules.equihash,
+            &cb.header[..EQUIHASH_INPUT_LEN],
+            &cb.header[EQUIHASH_INPUT_LEN..EQUIHASH_INPUT_LEN + NONCE_LEN],
+            &header.solution,
+        )
+        .map_err(|e| format!("Block {}: {}", height, e))?;
+
+        let target = target_from_bits(header.bits)
+            .ok_or_else(|| format!("Block {} has bad bits {:08x}", height, header.bits))?;
+        if target > target_from_bits(self.rules.pow_limit).unwrap() {
+            return Err(format!("Block {} is below the minimum difficulty", height));
+        }
+
+        let mut hash_be = hash.0;
+        hash_be.reverse();
+        if hash_be > target {
+            return Err(format!("Block {} doesn't meet its target", height));
+        }
+
+        self.check_difficulty(height, target)
+    }
+
+    /// Once the sync reached the wallet's blocks, check the difficulty of the lowest new blocks against them
+    pub fn finish(&mut self) -> Result<(), String> {
+        let wallet_height = match (self.wallet_block, self.expected_parent) {
+            (Some((wallet_height, _)), Some((lowest, _))) if lowest == wallet_height + 1 => wallet_height,
+            _ => return Ok(()),
+        };
+
+        for (height, target) in std::mem::take(&mut self.wallet_targets) {
+            if !matches!(self.targets.front(), Some((h, _)) if *h > wallet_height) {
+                break;
+            }
+            self.check_difficulty(height, target)?;
+        }
+
+        Ok(())
+    }
+
+    // Once the averaging window below a block is in, check that its target is within what DigiShield allows
+    fn check_difficulty(&mut self, height: u64, target: [u8; 32]) -> Result<(), String> {
+        let window = self.rules.averaging_window;
+        if window == 0 {
+            return Ok(());
+        }
+
+        self.targets.push_back((height, target));
+        if self.targets.len() <= window {
+            return Ok(());
+        }
+
+        let (checked_height, checked) = self.targets.pop_front().unwrap();
+        let avg = self.targets.iter().map(|(_, t)| target_to_f64(t)).sum::<f64>() / window as f64;
+        let min = avg * (100 - self.rules.max_adjust_up) as f64 / 100.0 * (1.0 - DIFFICULTY_SLACK);
+        let max = avg * (100 + self.rules.max_adjust_down) as f64 / 100.0 * (1.0 + DIFFICULTY_SLACK);
+
+        let t = target_to_f64(&checked);
+        if t < min || t > max {
+            return Err(format!(
+                "Block {}'s difficulty is out of bounds for the {} blocks before it",
+                checked_height, window
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use byteorder::{LittleEndian, WriteBytesExt};
+    use zcash_primitives::block::{BlockHash, BlockHeader};
+
+    use super::{target_from_bits, HeaderRules, HeaderValidator, BITCOINZ_RULES, EQUIHASH_INPUT_LEN, NONCE_LEN};
+    use crate::blaze::equihash::{minimal_from_indices, solve, EquihashParams, BITCOINZ_EQUIHASH};
+    use crate::compact_formats::CompactBlock;
+    use crate::lightclient::checkpoints::get_all_main_checkpoints;
+    use crate::lightwallet::data::BlockData;
+
+    // A header with an Equihash (144,5) solution for BitcoinZ's "BitcoinZ" personalization, on top of the main chain
+    // checkpoint at 1370000. It has the easiest possible target instead of a main chain one, so that it could be mined
+    // on a single CPU.
+    pub(crate) const BITCOINZ_HEADER: &str = concat!(
+        "040000000b6aff195ff0ce4dff88d0a79fab39a0d62a46f9214ee6a1dff9660200000000799a04ec1676eb17f6792c74",
+        "bbd3482bb9063e1274a084903d0bef83c5414231f84b0cad0a209031c9f55feea6d9b8625d6acd290432ba0b09f2b41b",
+        "d5d65ce100f15365ffff7f2000000000000000000000000000000000000000000000000000000000000000006405c2d5",
+        "33ee4ac6456b59a59291e51b82e233ec9280f301ce7f0b3a06dc4d53d8b1c14d59d2d1b7c6b6d91a1292ac79f3f83a10",
+        "c0bbd8b210cf5a379d7d712141a34e1a46ab149203d29a682bb4d146b68b4d6724de524c94795243ac7bd388f20dd0a5",
+        "19",
+    );
+    pub(crate) const BITCOINZ_HEADER_HEIGHT: u64 = 1_370_001;
+
+    // BitcoinZ's rules, except for a pow limit that lets BITCOINZ_HEADER's target through
+    pub(crate) const BITCOINZ_HEADER_RULES: HeaderRules = HeaderRules {
+        pow_limit: 0x207fffff,
+        averaging_window: 0,
+        ..BITCOINZ_RULES
+    };
+
+    // The checkpoint BITCOINZ_HEADER builds on
+    pub(crate) fn bitcoinz_header_checkpoint() -> (u64, BlockHash) {
+        let (height, hash, _) = get_all_main_checkpoints()
+            .into_iter()
+            .find(|(h, _, _)| *h == BITCOINZ_HEADER_HEIGHT - 1)
+            .unwrap();
+        let mut bytes = hex::decode(hash).unwrap();
+        bytes.reverse();
+
+        (height, BlockHash::from_slice(&bytes))
+    }
+
+    const TEST_RULES: HeaderRules = HeaderRules {
+        equihash: EquihashParams {
+            n: 32,
+            k: 3,
+            personalization: *b"BitcoinZ",
+        },
+        pow_limit: 0x207fffff,
+        averaging_window: 4,
+        ..BITCOINZ_RULES
+    };
+    const BITS: u32 = 0x2003ffff;
+
+    // Mine a block on top of `prev` with the test rules
+    fn mine(height: u64, prev: BlockHash, bits: u32) -> CompactBlock {
+        let target = target_from_bits(bits).unwrap();
+
+        let mut input = vec![];
+        input.write_i32::<LittleEndian>(4).unwrap();
+        input.extend_from_slice(&prev.0);
+        input.extend_from_slice(&[0u8; 64]);
+        input.write_u32::<LittleEndian>(height as u32).unwrap();
+        input.write_u32::<LittleEndian>(bits).unwrap();
+
+        for n in 0u64.. {
+            let mut nonce = [0u8; 32];
+            (&mut nonce[..]).write_u64::<LittleEndian>(n).unwrap();
+
+            for indices in solve(&TEST_RULES.equihash, &input, &nonce) {
+                let solution = minimal_from_indices(&TEST_RULES.equihash, &indices);
+                let mut header = input.clone();
+                header.extend_from_slice(&nonce);
+                header.push(solution.len() as u8);
+                header.extend_from_slice(&solution);
+
+                let hash = BlockHeader::read(&header[..]).unwrap().hash();
+                let mut hash_be = hash.0;
+                hash_be.reverse();
+                if hash_be <= target {
+                    let mut cb = CompactBlock::default();
+                    cb.height = height;
+                    cb.hash = hash.0.to_vec();
+                    cb.prev_hash = prev.0.to_vec();
+                    cb.header = header;
+                    return cb;
+                }
+            }
+        }
+
+        unreachable!()
+    }
+
+    // Blocks from `from` up to `from + bits.len() - 1`, tallest first
+    fn mine_chain(from: u64, prev: BlockHash, bits: &[u32]) -> Vec<CompactBlock> {
+        let mut prev = prev;
+        let mut blocks = vec![];
+        for (i, b) in bits.iter().enumerate() {
+            let cb = mine(from + i as u64, prev, *b);
+            prev = BlockHash::from_slice(&cb.hash);
+            blocks.insert(0, cb);
+        }
+
+        blocks
+    }
+
+    fn validate_all(validator: &mut HeaderValidator, blocks: &[CompactBlock]) -> Result<(), String> {
+        blocks.iter().map(|cb| validator.validate(cb)).collect()
+    }
+
+    #[test]
+    fn targets() {
+        let pow_limit = target_from_bits(0x1f07ffff).unwrap();
+        assert_eq!(pow_limit[..3], [0x00, 0x07, 0xff]);
+        assert_eq!(pow_limit[3], 0xff);
+        assert!(pow_limit[4..].iter().all(|b| *b == 0));
+
+        let target = target_from_bits(0x1d00ffff).unwrap();
+        assert_eq!(target[4..6], [0xff, 0xff]);
+        assert!(target[..4].iter().chain(target[6..].iter()).all(|b| *b == 0));
+
+        assert_eq!(target_from_bits(0x03000001).unwrap()[31], 0x01);
+        assert!(target_from_bits(0x1d80ffff).is_none());
+        assert!(target_from_bits(0x2301ffff).is_none());
+    }
+
+    #[test]
+    fn bitcoinz_rules() {
+        // Equihash (144,5) with the "BitcoinZ" personalization, which has 100 byte solutions
+        assert_eq!(BITCOINZ_RULES.equihash, BITCOINZ_EQUIHASH);
+        assert_eq!((BITCOINZ_EQUIHASH.n, BITCOINZ_EQUIHASH.k), (144, 5));
+        assert_eq!(&BITCOINZ_EQUIHASH.personalization, b"BitcoinZ");
+        assert_eq!(BITCOINZ_EQUIHASH.solution_length(), 100);
+
+        // DigiShield over 17 blocks, at most 16% up and 32% down
+        assert_eq!(BITCOINZ_RULES.pow_limit, 0x1f07ffff);
+        assert_eq!(BITCOINZ_RULES.averaging_window, 17);
+        assert_eq!(BITCOINZ_RULES.max_adjust_up, 16);
+        assert_eq!(BITCOINZ_RULES.max_adjust_down, 32);
+
+        for chain in ["main", "zs", "bitcoinz"] {
+            assert_eq!(HeaderRules::for_chain(chain).averaging_window, 17);
+        }
+        let testnet = HeaderRules::for_chain("ztestsapling");
+        assert_eq!(testnet.averaging_window, 0);
+        assert_eq!(testnet.equihash, BITCOINZ_EQUIHASH);
+        assert_eq!(testnet.pow_limit, 0x1f07ffff);
+    }
+
+    #[test]
+    fn bitcoinz_header() {
+        let header = hex::decode(BITCOINZ_HEADER).unwrap();
+        let mut cb = CompactBlock::default();
+        cb.height = BITCOINZ_HEADER_HEIGHT;
+        cb.hash = BlockHeader::read(&header[..]).unwrap().hash().0.to_vec();
+        cb.header = header;
+
+        let mut validator = HeaderValidator::new(BITCOINZ_HEADER_RULES, Some(bitcoinz_header_checkpoint()));
+        assert_eq!(validator.validate(&cb), Ok(()));
+
+        // The real rules accept the solution, and only refuse the easy target
+        assert_eq!(
+            HeaderValidator::new(BITCOINZ_RULES, None).validate(&cb),
+            Err("Block 1370001 is below the minimum difficulty".to_string())
+        );
+
+        // Changing any one byte of the header, the nonce or the solution invalidates it
+        for pos in [
+            4,
+            40,
+            100,
+            EQUIHASH_INPUT_LEN + 5,
+            EQUIHASH_INPUT_LEN + NONCE_LEN + 1,
+            cb.header.len() - 1,
+        ] {
+            let mut changed = cb.clone();
+            changed.hash.clear();
+            changed.header[pos] ^= 1;
+            assert!(HeaderValidator::new(BITCOINZ_HEADER_RULES, None)
+                .validate(&changed)
+                .unwrap_err()
+                .contains("Invalid Equihash solution"));
+        }
+    }
+
+    #[test]
+    fn valid_chain() {
+        let checkpoint = (10, BlockHash([7u8; 32]));
+        let blocks = mine_chain(11, checkpoint.1, &[BITS; 8]);
+
+        let mut validator = HeaderValidator::new(TEST_RULES, Some(checkpoint));
+        assert_eq!(validate_all(&mut validator, &blocks), Ok(()));
+
+        // Blocks at and below the checkpoint are trusted
+        let mut cb = CompactBlock::default();
+        cb.height = 10;
+        cb.hash = checkpoint.1 .0.to_vec();
+        assert_eq!(validator.validate(&cb), Ok(()));
+
+        cb.height = 9;
+        assert_eq!(validator.validate(&cb), Ok(()));
+    }
+
+    #[test]
+    fn bad_headers() {
+        let checkpoint = (10, BlockHash([7u8; 32]));
+        let blocks = mine_chain(11, checkpoint.1, &[BITS; 3]);
+
+        // A block that isn't the parent of the one before it
+        let mut validator = HeaderValidator::new(TEST_RULES, Some(checkpoint));
+        assert_eq!(
+            validate_all(&mut validator, &[blocks[0].clone(), blocks[2].clone()]),
+            Err("Block 11 is not the parent of block 13".to_string())
+        );
+
+        // A chain that doesn't build on the checkpoint
+        let mut validator = HeaderValidator::new(TEST_RULES, Some((10, BlockHash([8u8; 32]))));
+        assert_eq!(
+            validate_all(&mut validator, &blocks),
+            Err("Block 11 doesn't build on the checkpoint at 10".to_string())
+        );
+
+        // A block without a header
+        let mut validator = HeaderValidator::new(TEST_RULES, Some(checkpoint));
+        let mut cb = blocks[0].clone();
+        cb.header.clear();
+        assert!(validator.validate(&cb).is_err());
+
+        // A block whose hash doesn't match its header
+        let mut cb = blocks[0].clone();
+        cb.hash[0] ^= 1;
+        assert_eq!(
+            HeaderValidator::new(TEST_RULES, None).validate(&cb),
+            Err("Block 13's hash doesn't match its header".to_string())
+        );
+
+        // Changing the header invalidates the Equihash solution
+        let mut cb = blocks[0].clone();
+        cb.hash.clear();
+        cb.header[100] ^= 1;
+        assert!(HeaderValidator::new(TEST_RULES, None)
+            .validate(&cb)
+            .unwrap_err()
+            .contains("Invalid Equihash solution"));
+
+        // So does flipping a byte of the solution itself, after its length prefix
+        let mut cb = blocks[0].clone();
+        cb.hash.clear();
+        cb.header[EQUIHASH_INPUT_LEN + NONCE_LEN + 1] ^= 1;
+        assert!(HeaderValidator::new(TEST_RULES, None)
+            .validate(&cb)
+            .unwrap_err()
+            .contains("Invalid Equihash solution"));
+
+        // A target above the pow limit
+        let rules = HeaderRules {
+            pow_limit: 0x2001ffff,
+            ..TEST_RULES
+        };
+        assert_eq!(
+            HeaderValidator::new(rules, None).validate(&blocks[0]),
+            Err("Block 13 is below the minimum difficulty".to_string())
+        );
+    }
+
+    #[test]
+    fn difficulty_bounds() {
+        let checkpoint = (10, BlockHash([7u8; 32]));
+
+        // The difficulty drops to a quarter in one block, while DigiShield allows at most 32%
+        let blocks = mine_chain(11, checkpoint.1, &[BITS, BITS, BITS, BITS, 0x200fffff]);
+        let mut validator = HeaderValidator::new(TEST_RULES, Some(checkpoint));
+        assert_eq!(
+            validate_all(&mut validator, &blocks),
+            Err("Block 15's difficulty is out of bounds for the 4 blocks before it".to_string())
+        );
+
+        // Without the averaging window, nothing is checked
+        let rules = HeaderRules {
+            averaging_window: 0,
+            ..TEST_RULES
+        };
+        let mut validator = HeaderValidator::new(rules, Some(checkpoint));
+        assert_eq!(validate_all(&mut validator, &blocks), Ok(()));
+    }
+
+    #[test]
+    fn wallet_blocks() {
+        let checkpoint = (10, BlockHash([7u8; 32]));
+        let wallet_chain = mine_chain(11, checkpoint.1, &[BITS; 4]);
+        let wallet_blocks = wallet_chain.iter().cloned().map(BlockData::new).collect::<Vec<_>>();
+        let top = BlockHash::from_slice(&wallet_chain[0].hash);
+
+        // New blocks on top of the wallet's
+        let blocks = mine_chain(15, top, &[BITS; 2]);
+        let mut validator = HeaderValidator::new(TEST_RULES, Some(checkpoint));
+        validator.set_wallet_blocks(&wallet_blocks);
+        assert_eq!(validate_all(&mut validator, &blocks), Ok(()));
+        assert_eq!(validator.finish(), Ok(()));
+
+        // A chain that doesn't build on the wallet's top block
+        let blocks = mine_chain(15, BlockHash([8u8; 32]), &[BITS; 2]);
+        let mut validator = HeaderValidator::new(TEST_RULES, Some(checkpoint));
+        validator.set_wallet_blocks(&wallet_blocks);
+        assert_eq!(
+            validate_all(&mut validator, &blocks),
+            Err("Block 15 doesn't build on the wallet's block 14".to_string())
+        );
+
+        // The wallet's headers are the difficulty window of the first new block. Without them, its drop in
+        // difficulty can't be checked.
+        let blocks = mine_chain(15, top, &[0x200fffff]);
+        let mut validator = HeaderValidator::new(TEST_RULES, Some(checkpoint));
+        assert_eq!(validate_all(&mut validator, &blocks), Ok(()));
+        assert_eq!(validator.finish(), Ok(()));
+
+        let mut validator = HeaderValidator::new(TEST_RULES, Some(checkpoint));
+        validator.set_wallet_blocks(&wallet_blocks);
+        assert_eq!(validate_all(&mut validator, &blocks), Ok(()));
+        assert_eq!(
+            validator.finish(),
+            Err("Block 15's difficulty is out of bounds for the 4 blocks before it".to_string())
+        );
+
+        // A block whose prev hash doesn't match its header
+        let mut cb = blocks[0].clone();
+        cb.prev_hash[0] ^= 1;
+        assert_eq!(
+            HeaderValidator::new(TEST_RULES, None).validate(&cb),
+            Err("Block 15's prev hash doesn't match its header".to_string())
+        );
+    }
+}
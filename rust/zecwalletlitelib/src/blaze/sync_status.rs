@@ -1,5 +1,43 @@
 use core::fmt;
 use std::cmp;
+use std::time::{Duration, Instant};
+
+// How much weight a new throughput sample gets in the smoothed ETA rate
+const ETA_SMOOTHING: f64 = 0.3;
+
+// Don't take throughput samples more often than this, so short bursts don't swing the ETA
+const ETA_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Throughput of one stage of the sync, accumulated over all the batches of a sync
+#[derive(Clone, Debug, Default)]
+pub struct StageMetrics {
+    // Blocks, trial decryptions or txns processed, depending on the stage
+    pub items: u64,
+    pub bytes: u64,
+    pub elapsed: Duration,
+
+    started: Option<Instant>,
+}
+
+impl StageMetrics {
+    pub fn record(&mut self, items: u64, bytes: u64) {
+        let now = Instant::now();
+        let started = *self.started.get_or_insert(now);
+
+        self.items += items;
+        self.bytes += bytes;
+        self.elapsed = now - started;
+    }
+
+    pub fn per_sec(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.items as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
 
 #[derive(Clone, Debug, Default)]
 pub struct SyncStatus {
@@ -18,10 +56,22 @@ pub struct SyncStatus {
 
     pub batch_num: usize,
     pub batch_total: usize,
+
+    // The wallet's height when this sync started, and the server's tip it is syncing to
+    pub sync_start_height: u64,
+    pub sync_tip: u64,
+
+    pub download: StageMetrics,
+    pub trial_decryption: StageMetrics,
+    pub txn_scan: StageMetrics,
+
+    // Smoothed rate of fully processed blocks per second, and the last sample it was updated with
+    eta_rate: Option<f64>,
+    eta_sample: Option<(Instant, u64)>,
 }
 
 impl SyncStatus {
-    pub fn start_new(&mut self, batch_total: usize) {
+    pub fn start_new(&mut self, batch_total: usize, sync_start_height: u64, sync_tip: u64) {
         self.sync_id += 1;
         self.last_error = None;
         self.in_progress = true;
@@ -31,6 +81,14 @@ impl SyncStatus {
         self.txn_scan_done = 0;
         self.batch_num = 0;
         self.batch_total = batch_total;
+
+        self.sync_start_height = sync_start_height;
+        self.sync_tip = sync_tip;
+        self.download = StageMetrics::default();
+        self.trial_decryption = StageMetrics::default();
+        self.txn_scan = StageMetrics::default();
+        self.eta_rate = None;
+        self.eta_sample = None;
     }
 
     /// Setup a new sync status in prep for an upcoming sync
@@ -52,6 +110,67 @@ impl SyncStatus {
         self.in_progress = false;
     }
 
+    pub fn record_download(&mut self, blocks: u64, bytes: u64) {
+        self.download.record(blocks, bytes);
+    }
+
+    pub fn add_blocks_done(&mut self, blocks: u64) {
+        self.blocks_done += blocks;
+        self.update_eta(Instant::now());
+    }
+
+    pub fn add_trial_dec_done(&mut self, blocks: u64, decryptions: u64) {
+        self.trial_dec_done += blocks;
+        self.trial_decryption.record(decryptions, 0);
+        self.update_eta(Instant::now());
+    }
+
+    pub fn record_txn_scan(&mut self, txns: u64) {
+        self.txn_scan.record(txns, 0);
+    }
+
+    // Blocks that went through both the witness and the trial decryption stages, over all the batches of this sync.
+    // Batches are synced in increasing height, so everything below this batch is done.
+    pub fn blocks_synced(&self) -> u64 {
+        let prev_batches = self.end_block.saturating_sub(self.sync_start_height + 1);
+        prev_batches + cmp::min(self.blocks_done, self.trial_dec_done)
+    }
+
+    pub fn blocks_remaining(&self) -> u64 {
+        self.sync_tip
+            .saturating_sub(self.sync_start_height)
+            .saturating_sub(self.blocks_synced())
+    }
+
+    fn update_eta(&mut self, now: Instant) {
+        let synced = self.blocks_synced();
+
+        match self.eta_sample {
+            None => self.eta_sample = Some((now, synced)),
+            Some((at, prev_synced)) => {
+                let secs = (now - at).as_secs_f64();
+                if (now - at) < ETA_SAMPLE_INTERVAL || synced < prev_synced {
+                    return;
+                }
+
+                let rate = (synced - prev_synced) as f64 / secs;
+                self.eta_rate = Some(match self.eta_rate {
+                    Some(smoothed) => ETA_SMOOTHING * rate + (1.0 - ETA_SMOOTHING) * smoothed,
+                    None => rate,
+                });
+                self.eta_sample = Some((now, synced));
+            }
+        }
+    }
+
+    /// Estimated time until the wallet is synced to the server's tip. None until there is enough data for an estimate.
+    pub fn eta(&self) -> Option<Duration> {
+        match self.eta_rate {
+            Some(rate) if rate > 0.0 => Some(Duration::from_secs_f64(self.blocks_remaining() as f64 / rate)),
+            _ => None,
+        }
+    }
+
     #[allow(dead_code)]
     fn perct(&self, num: u64) -> u8 {
         let a = if self.blocks_total > 0 {
@@ -75,7 +194,7 @@ impl fmt::Display for SyncStatus {
         if self.blocks_total > 0 && self.in_progress {
             write!(
                 f,
-                "id: {}, batch: {}/{}, blocks: {}/{}, decryptions: {}, tx_scan: {}",
+                "id: {}, batch: {}/{}, blocks: {}/{}, decryptions: {}, tx_scan: {}, blocks/s: {:.1}, decryptions/s: {:.1}, downloaded: {} bytes, eta: {}",
                 self.sync_id,
                 self.batch_num,
                 self.batch_total,
@@ -83,6 +202,10 @@ impl fmt::Display for SyncStatus {
                 self.blocks_total,
                 self.trial_dec_done,
                 self.txn_scan_done,
+                self.download.per_sec(),
+                self.trial_decryption.per_sec(),
+                self.download.bytes,
+                self.eta().map(|d| format!("{}s", d.as_secs())).unwrap_or("unknown".to_string()),
             )
         } else {
             write!(
@@ -95,3 +218,64 @@ impl fmt::Display for SyncStatus {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::SyncStatus;
+
+    #[test]
+    fn smoothed_eta() {
+        let mut status = SyncStatus::default();
+        status.start_new(2, 1000, 3000);
+        status.new_sync_batch(2000, 1001, 0);
+
+        let t0 = Instant::now();
+        status.update_eta(t0);
+        assert_eq!(status.eta(), None);
+
+        // 100 blocks/s
+        status.blocks_done = 100;
+        status.trial_dec_done = 100;
+        status.update_eta(t0 + Duration::from_secs(1));
+        assert_eq!(status.blocks_remaining(), 1900);
+        assert_eq!(status.eta(), Some(Duration::from_secs(19)));
+
+        // Samples closer together than the interval are ignored
+        status.blocks_done = 1000;
+        status.trial_dec_done = 1000;
+        status.update_eta(t0 + Duration::from_millis(1500));
+        assert_eq!(status.eta(), Some(Duration::from_secs(10)));
+
+        // The trial decryptions are slower, so they set the pace. 200 blocks/s since the last sample,
+        // smoothed with the previous 100 blocks/s
+        status.blocks_done = 1000;
+        status.trial_dec_done = 300;
+        status.update_eta(t0 + Duration::from_secs(2));
+        assert_eq!(status.blocks_remaining(), 1700);
+        assert_eq!(status.eta().unwrap().as_secs(), 1700 / 130);
+
+        // The next batch starts above the first one
+        status.new_sync_batch(3000, 2001, 1);
+        status.blocks_done = 500;
+        status.trial_dec_done = 500;
+        assert_eq!(status.blocks_synced(), 1500);
+        assert_eq!(status.blocks_remaining(), 500);
+    }
+
+    #[test]
+    fn stage_metrics() {
+        let mut status = SyncStatus::default();
+        status.start_new(1, 0, 100);
+
+        status.record_download(10, 5_000);
+        status.record_download(10, 5_000);
+        assert_eq!(status.download.items, 20);
+        assert_eq!(status.download.bytes, 10_000);
+
+        status.add_trial_dec_done(10, 40);
+        assert_eq!(status.trial_dec_done, 10);
+        assert_eq!(status.trial_decryption.items, 40);
+    }
+}
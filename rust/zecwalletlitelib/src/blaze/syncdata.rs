@@ -3,35 +3,55 @@ use std::sync::Arc;
 use http::Uri;
 use incrementalmerkletree::bridgetree::BridgeTree;
 use orchard::tree::MerkleHashOrchard;
+use rayon::{ThreadPool, ThreadPoolBuilder};
 use tokio::sync::RwLock;
 use zcash_primitives::consensus;
 
 use super::{block_witness_data::BlockAndWitnessData, sync_status::SyncStatus};
 use crate::compact_formats::TreeState;
+use crate::grpc_connector::{ConnectOptions, GrpcConnector};
+use crate::lightclient::wallet_events::WalletEvents;
 use crate::lightwallet::{WalletOptions, MERKLE_DEPTH};
+use crate::server_pool::ServerPool;
 use crate::{lightclient::lightclient_config::LightClientConfig, lightwallet::data::BlockData};
 
 pub struct BlazeSyncData {
     pub(crate) sync_status: Arc<RwLock<SyncStatus>>,
     pub(crate) block_data: BlockAndWitnessData,
     uri: Uri,
+    servers: ServerPool,
+    connect_options: ConnectOptions,
     pub(crate) wallet_options: WalletOptions,
+    pub(crate) events: WalletEvents,
+
+    // Threads that trial decrypt the blocks, kept across syncs with the number of threads they were started with
+    trial_decryption_pool: Option<(u32, Arc<ThreadPool>)>,
 }
 
 impl BlazeSyncData {
     pub fn new<P: consensus::Parameters>(config: &LightClientConfig<P>) -> Self {
         let sync_status = Arc::new(RwLock::new(SyncStatus::default()));
+        let events = WalletEvents::new();
 
         Self {
             sync_status: sync_status.clone(),
             uri: config.server.clone(),
-            block_data: BlockAndWitnessData::new(config, sync_status),
+            servers: config.servers.clone(),
+            connect_options: config.connect_options.clone(),
+            block_data: BlockAndWitnessData::new(config, sync_status, events.clone()),
             wallet_options: WalletOptions::default(),
+            events,
+            trial_decryption_pool: None,
         }
     }
 
-    pub fn uri(&self) -> &'_ Uri {
-        &self.uri
+    // The server the sync is pinned to
+    pub fn uri(&self) -> Uri {
+        self.servers.current().unwrap_or_else(|| self.uri.clone())
+    }
+
+    pub fn connector(&self) -> GrpcConnector {
+        GrpcConnector::new(self.uri(), self.connect_options.clone())
     }
 
     pub async fn setup_for_sync(
@@ -55,12 +75,35 @@ impl BlazeSyncData {
             .new_sync_batch(start_block, end_block, batch_num);
 
         self.wallet_options = wallet_options;
+        self.block_data.set_validate_headers(wallet_options.validate_headers);
 
         self.block_data
             .setup_sync(existing_blocks, verified_tree, orchard_witnesses)
             .await;
     }
 
+    /// The threads to trial decrypt with. They are started on the first sync, and only restarted when the
+    /// trial_decryption_threads option changes.
+    pub(crate) fn trial_decryption_pool(&mut self) -> Result<Arc<ThreadPool>, String> {
+        let threads = self.wallet_options.trial_decryption_threads;
+        if let Some((started_with, pool)) = &self.trial_decryption_pool {
+            if *started_with == threads {
+                return Ok(pool.clone());
+            }
+        }
+
+        let pool = Arc::new(
+            ThreadPoolBuilder::new()
+                .num_threads(threads as usize)
+                .thread_name(|i| format!("trial-decrypt-{}", i))
+                .build()
+                .map_err(|e| format!("Couldn't start trial decryption threads: {}", e))?,
+        );
+        self.trial_decryption_pool = Some((threads, pool.clone()));
+
+        Ok(pool)
+    }
+
     // Finish up the sync
     pub async fn finish(&self) {
         self.sync_status.write().await.finish();
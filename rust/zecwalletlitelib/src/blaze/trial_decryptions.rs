@@ -5,17 +5,18 @@ use crate::{
 use futures::{stream::FuturesUnordered, StreamExt};
 use log::info;
 use orchard::{keys::IncomingViewingKey, note_encryption::OrchardDomain};
+use rayon::{prelude::*, ThreadPool};
+use std::collections::HashSet;
 use std::convert::TryFrom;
-use zcash_note_encryption::batch::try_compact_note_decryption;
+use zcash_note_encryption::{batch::try_compact_note_decryption, BatchDomain, ShieldedOutput, COMPACT_NOTE_SIZE};
 
 use std::sync::Arc;
 use tokio::{
-    runtime::Handle,
     sync::{
         mpsc::{channel, Sender, UnboundedSender},
         oneshot, RwLock,
     },
-    task::JoinHandle,
+    task::{spawn_blocking, JoinHandle},
 };
 
 use zcash_primitives::{
@@ -26,6 +27,39 @@ use zcash_primitives::{
 
 use super::syncdata::BlazeSyncData;
 
+// Number of blocks whose outputs are trial decrypted together
+const BLOCKS_PER_BATCH: usize = 50;
+
+// Number of outputs each thread trial decrypts in one go. Big enough to amortize the batched inversions
+// in try_compact_note_decryption, small enough to spread a batch over all the threads.
+const OUTPUTS_PER_CHUNK: usize = 64;
+
+/// Trial decrypt all the `outputs` with all the `ivks`, using the threads in `pool`. Returns, for each output, the
+/// decrypted note and the index of the ivk that decrypted it, if any.
+pub(crate) fn decrypt_in_parallel<D, Output>(
+    pool: &ThreadPool,
+    ivks: &[D::IncomingViewingKey],
+    outputs: &[(D, Output)],
+) -> Vec<Option<((D::Note, D::Recipient), usize)>>
+where
+    D: BatchDomain + Sync,
+    D::IncomingViewingKey: Sync,
+    D::Note: Send,
+    D::Recipient: Send,
+    Output: ShieldedOutput<D, COMPACT_NOTE_SIZE> + Sync,
+{
+    if ivks.is_empty() {
+        return outputs.iter().map(|_| None).collect();
+    }
+
+    pool.install(|| {
+        outputs
+            .par_chunks(OUTPUTS_PER_CHUNK)
+            .flat_map_iter(|chunk| try_compact_note_decryption(ivks, chunk))
+            .collect()
+    })
+}
+
 pub struct TrialDecryptions<P> {
     keys: Arc<RwLock<Keys<P>>>,
     wallet_txns: Arc<RwLock<WalletTxns>>,
@@ -54,6 +88,9 @@ impl<P: consensus::Parameters + Send + Sync + 'static> TrialDecryptions<P> {
             let mut workers = FuturesUnordered::new();
             let mut cbs = vec![];
 
+            // The trial decryptions are CPU bound, so they run on their own threads instead of the async runtime's
+            let pool = bsync_data.write().await.trial_decryption_pool()?;
+
             let s_ivks = Arc::new(
                 keys.read()
                     .await
@@ -69,7 +106,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> TrialDecryptions<P> {
                 //println!("trial_witness recieved {:?}", cb.height);
                 cbs.push(cb);
 
-                if cbs.len() >= 50 {
+                if cbs.len() >= BLOCKS_PER_BATCH {
                     let keys = keys.clone();
                     let s_ivks = s_ivks.clone();
                     let o_ivks = o_ivks.clone();
@@ -86,6 +123,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> TrialDecryptions<P> {
                         wallet_txns,
                         detected_txid_sender,
                         fulltx_fetcher.clone(),
+                        pool.clone(),
                     )));
                 }
             }
@@ -99,6 +137,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> TrialDecryptions<P> {
                 wallet_txns,
                 detected_txid_sender,
                 fulltx_fetcher,
+                pool,
             )));
 
             while let Some(r) = workers.next().await {
@@ -125,6 +164,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> TrialDecryptions<P> {
         wallet_txns: Arc<RwLock<WalletTxns>>,
         detected_txid_sender: Sender<(TxId, Option<sapling::Nullifier>, BlockHeight, Option<u32>)>,
         fulltx_fetcher: UnboundedSender<(TxId, oneshot::Sender<Result<Transaction, String>>)>,
+        pool: Arc<ThreadPool>,
     ) -> Result<(), String> {
         // println!("Starting batch at {}", temp_start);
         let config = keys.read().await.config().clone();
@@ -134,14 +174,20 @@ impl<P: consensus::Parameters + Send + Sync + 'static> TrialDecryptions<P> {
 
         let download_memos = bsync_data.read().await.wallet_options.download_memos;
 
-        for cb in cbs {
+        // Collect the shielded outputs of all the blocks, so they can be trial decrypted together. Each output's
+        // position is (index of the block in `blocks`, tx_num, output_num)
+        let mut blocks = vec![];
+        let mut s_outputs = vec![];
+        let mut s_positions = vec![];
+        let mut o_actions = vec![];
+        let mut o_positions = vec![];
+
+        for (blk_num, cb) in cbs.into_iter().enumerate() {
             let height = BlockHeight::from_u32(cb.height as u32);
+            let mut tx_hashes = vec![];
 
             for (tx_num, ctx) in cb.vtx.into_iter().enumerate() {
-                let tokio_handle = Handle::current();
-
-                let ctx_hash = ctx.hash;
-                let mut wallet_tx = false;
+                tx_hashes.push(ctx.hash);
 
                 // If the epk or ciphertext is missing, that means this was a spam Tx, so we can't decrypt it
                 if ctx.actions.len() > 0
@@ -149,135 +195,151 @@ impl<P: consensus::Parameters + Send + Sync + 'static> TrialDecryptions<P> {
                     && ctx.actions[0].ephemeral_key.len() > 0
                 {
                     // Orchard
-                    let orchard_actions = ctx
-                        .actions
-                        .into_iter()
-                        .map(|coa| {
-                            (
-                                OrchardDomain::for_nullifier(
-                                    orchard::note::Nullifier::from_bytes(
-                                        <&[u8; 32]>::try_from(&coa.nullifier[..]).unwrap(),
-                                    )
-                                    .unwrap(),
-                                ),
-                                coa,
-                            )
-                        })
-                        .collect::<Vec<_>>();
-
-                    let decrypts = try_compact_note_decryption(o_ivks.as_ref(), orchard_actions.as_ref());
-                    for (output_num, maybe_decrypted) in decrypts.into_iter().enumerate() {
-                        if let Some(((note, _to), ivk_num)) = maybe_decrypted {
-                            wallet_tx = true;
-
-                            let ctx_hash = ctx_hash.clone();
-
-                            let keys = keys.read().await;
-                            let detected_txid_sender = detected_txid_sender.clone();
-                            let timestamp = cb.time as u64;
-                            let fvk = keys.okeys[ivk_num].fvk();
-                            let have_spending_key = keys.have_orchard_spending_key(fvk);
-
-                            // Tell the orchard witness tree to track this note.
-                            bsync_data
-                                .read()
-                                .await
-                                .block_data
-                                .track_orchard_note(cb.height, tx_num, output_num as u32)
-                                .await;
-
-                            let txid = WalletTx::new_txid(&ctx_hash);
-                            wallet_txns.write().await.add_new_orchard_note(
-                                txid,
-                                height,
-                                false,
-                                timestamp,
-                                note,
-                                (height.into(), tx_num, output_num as u32),
-                                fvk,
-                                have_spending_key,
-                            );
-
-                            detected_txid_sender
-                                .send((txid, None, height, Some(output_num as u32)))
-                                .await
-                                .unwrap();
-                        }
+                    for (output_num, coa) in ctx.actions.into_iter().enumerate() {
+                        let domain = OrchardDomain::for_nullifier(
+                            orchard::note::Nullifier::from_bytes(<&[u8; 32]>::try_from(&coa.nullifier[..]).unwrap())
+                                .unwrap(),
+                        );
+                        o_actions.push((domain, coa));
+                        o_positions.push((blk_num, tx_num, output_num));
                     }
                 }
 
                 // If the epk or ciphertext is missing, that means this was a spam Tx, so we can't decrypt it
                 if ctx.outputs.len() > 0 && ctx.outputs[0].epk.len() > 0 && ctx.outputs[0].ciphertext.len() > 0 {
                     // Sapling
-                    let outputs_total = ctx.outputs.len();
-                    // if outputs_total < 100 {
-                    let outputs = ctx
-                        .outputs
-                        .into_iter()
-                        .map(|o| (SaplingDomain::for_height(params.clone(), height), o))
-                        .collect::<Vec<_>>();
-
-                    // Batch decryption for sapling
-                    let decrypts = try_compact_note_decryption(s_ivks.as_ref(), outputs.as_ref());
-
-                    for (dec_num, maybe_decrypted) in decrypts.into_iter().enumerate() {
-                        if let Some(((note, to), ivk_num)) = maybe_decrypted {
-                            wallet_tx = true;
-
-                            let ctx_hash = ctx_hash.clone();
-                            let output_num = dec_num % outputs_total;
-
-                            let keys = keys.clone();
-                            let bsync_data = bsync_data.clone();
-                            let wallet_txns = wallet_txns.clone();
-                            let detected_txid_sender = detected_txid_sender.clone();
-                            let timestamp = cb.time as u64;
-
-                            workers.push(tokio_handle.spawn(async move {
-                                let keys = keys.read().await;
-                                let extfvk = keys.zkeys[ivk_num].extfvk();
-                                let have_spending_key = keys.have_sapling_spending_key(extfvk);
-                                let uri = bsync_data.read().await.uri().clone();
-
-                                // Get the witness for the note
-                                let witness = bsync_data
-                                    .read()
-                                    .await
-                                    .block_data
-                                    .get_note_witness(uri, height, tx_num, output_num)
-                                    .await?;
-
-                                let txid = WalletTx::new_txid(&ctx_hash);
-                                let nullifier = note.nf(&extfvk.fvk.vk.nk, witness.position() as u64);
-
-                                wallet_txns.write().await.add_new_sapling_note(
-                                    txid.clone(),
-                                    height,
-                                    false,
-                                    timestamp,
-                                    note,
-                                    to,
-                                    &extfvk,
-                                    have_spending_key,
-                                    witness,
-                                );
-
-                                info!("Trial decrypt Detected txid {}", &txid);
-
-                                detected_txid_sender
-                                    .send((txid, Some(nullifier), height, Some(output_num as u32)))
-                                    .await
-                                    .unwrap();
-
-                                Ok::<_, String>(())
-                            }));
-                        }
+                    for (output_num, co) in ctx.outputs.into_iter().enumerate() {
+                        s_outputs.push((SaplingDomain::for_height(params.clone(), height), co));
+                        s_positions.push((blk_num, tx_num, output_num));
                     }
                 }
+            }
+
+            blocks.push((cb.height, cb.time as u64, tx_hashes));
+        }
+
+        let decryptions = (s_outputs.len() * s_ivks.len() + o_actions.len() * o_ivks.len()) as u64;
+
+        // Batch decryption for sapling and orchard, spread over the trial decryption threads
+        let (s_decrypts, o_decrypts) = {
+            let s_ivks = s_ivks.clone();
+            let o_ivks = o_ivks.clone();
+            spawn_blocking(move || {
+                (
+                    decrypt_in_parallel(&pool, s_ivks.as_ref(), &s_outputs),
+                    decrypt_in_parallel(&pool, o_ivks.as_ref(), &o_actions),
+                )
+            })
+            .await
+            .map_err(|e| e.to_string())?
+        };
+
+        // (blk_num, tx_num) of all the txns that had outputs for this wallet
+        let mut wallet_txs = HashSet::new();
+
+        for (maybe_decrypted, (blk_num, tx_num, output_num)) in o_decrypts.into_iter().zip(o_positions) {
+            if let Some(((note, _to), ivk_num)) = maybe_decrypted {
+                wallet_txs.insert((blk_num, tx_num));
+
+                let (block_height, timestamp, tx_hashes) = &blocks[blk_num];
+                let height = BlockHeight::from_u32(*block_height as u32);
+
+                let keys = keys.read().await;
+                let detected_txid_sender = detected_txid_sender.clone();
+                let fvk = keys.okeys[ivk_num].fvk();
+                let have_spending_key = keys.have_orchard_spending_key(fvk);
+
+                // Tell the orchard witness tree to track this note.
+                bsync_data
+                    .read()
+                    .await
+                    .block_data
+                    .track_orchard_note(*block_height, tx_num, output_num as u32)
+                    .await;
+
+                let txid = WalletTx::new_txid(&tx_hashes[tx_num]);
+                wallet_txns.write().await.add_new_orchard_note(
+                    txid,
+                    height,
+                    false,
+                    *timestamp,
+                    note,
+                    (*block_height, tx_num, output_num as u32),
+                    fvk,
+                    have_spending_key,
+                );
+
+                detected_txid_sender
+                    .send((txid, None, height, Some(output_num as u32)))
+                    .await
+                    .unwrap();
+            }
+        }
+
+        for (maybe_decrypted, (blk_num, tx_num, output_num)) in s_decrypts.into_iter().zip(s_positions) {
+            if let Some(((note, to), ivk_num)) = maybe_decrypted {
+                wallet_txs.insert((blk_num, tx_num));
+
+                let (block_height, timestamp, tx_hashes) = &blocks[blk_num];
+                let height = BlockHeight::from_u32(*block_height as u32);
+                let ctx_hash = tx_hashes[tx_num].clone();
+                let timestamp = *timestamp;
+
+                let keys = keys.clone();
+                let bsync_data = bsync_data.clone();
+                let wallet_txns = wallet_txns.clone();
+                let detected_txid_sender = detected_txid_sender.clone();
+
+                workers.push(tokio::spawn(async move {
+                    let keys = keys.read().await;
+                    let extfvk = keys.zkeys[ivk_num].extfvk();
+                    let have_spending_key = keys.have_sapling_spending_key(extfvk);
+                    let server = bsync_data.read().await.connector();
+
+                    // Get the witness for the note
+                    let witness = bsync_data
+                        .read()
+                        .await
+                        .block_data
+                        .get_note_witness(server, height, tx_num, output_num)
+                        .await?;
 
-                // Check option to see if we are fetching all txns.
-                if !wallet_tx && download_memos == MemoDownloadOption::AllMemos {
                     let txid = WalletTx::new_txid(&ctx_hash);
+                    let nullifier = note.nf(&extfvk.fvk.vk.nk, witness.position() as u64);
+
+                    wallet_txns.write().await.add_new_sapling_note(
+                        txid.clone(),
+                        height,
+                        false,
+                        timestamp,
+                        note,
+                        to,
+                        &extfvk,
+                        have_spending_key,
+                        witness,
+                    );
+
+                    info!("Trial decrypt Detected txid {}", &txid);
+
+                    detected_txid_sender
+                        .send((txid, Some(nullifier), height, Some(output_num as u32)))
+                        .await
+                        .unwrap();
+
+                    Ok::<_, String>(())
+                }));
+            }
+        }
+
+        // Check option to see if we are fetching all txns.
+        if download_memos == MemoDownloadOption::AllMemos {
+            for (blk_num, (_, _, tx_hashes)) in blocks.iter().enumerate() {
+                for (tx_num, ctx_hash) in tx_hashes.iter().enumerate() {
+                    if wallet_txs.contains(&(blk_num, tx_num)) {
+                        continue;
+                    }
+
+                    let txid = WalletTx::new_txid(ctx_hash);
                     let (tx, rx) = oneshot::channel();
                     fulltx_fetcher.send((txid, tx)).unwrap();
 
@@ -294,10 +356,155 @@ impl<P: consensus::Parameters + Send + Sync + 'static> TrialDecryptions<P> {
         }
 
         // Update sync status
-        bsync_data.read().await.sync_status.write().await.trial_dec_done += blk_count as u64;
+        bsync_data
+            .read()
+            .await
+            .sync_status
+            .write()
+            .await
+            .add_trial_dec_done(blk_count as u64, decryptions);
 
         // Return a nothing-value
         // println!("Finished batch at {}", temp_start);
         Ok::<(), String>(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    use rayon::ThreadPoolBuilder;
+    use zcash_note_encryption::batch::try_compact_note_decryption;
+    use zcash_primitives::{
+        consensus::BlockHeight,
+        sapling::{note_encryption::SaplingDomain, SaplingIvk},
+        zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
+    };
+
+    use crate::{
+        blaze::{
+            syncdata::BlazeSyncData,
+            test_utils::{random_u8_32, FakeCompactBlockList},
+        },
+        compact_formats::CompactSaplingOutput,
+        lightclient::lightclient_config::{LightClientConfig, UnitTestNetwork},
+    };
+
+    use super::decrypt_in_parallel;
+
+    fn extfvk(seed: [u8; 32]) -> ExtendedFullViewingKey {
+        ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&seed))
+    }
+
+    // The outputs of `num_blocks` blocks with one tx each. Every 10th tx pays one of `ours`, the rest pay a stranger.
+    fn sapling_outputs(
+        num_blocks: u64,
+        ours: &[ExtendedFullViewingKey],
+    ) -> Vec<(SaplingDomain<UnitTestNetwork>, CompactSaplingOutput)> {
+        let stranger = extfvk(random_u8_32());
+        let mut fcbl = FakeCompactBlockList::new(0);
+
+        for i in 0..num_blocks {
+            if i % 10 == 0 {
+                fcbl.add_tx_paying(&ours[(i / 10) as usize % ours.len()], 1_000 + i);
+            } else {
+                fcbl.add_tx_paying(&stranger, 1);
+            }
+        }
+
+        fcbl.into_compact_blocks()
+            .into_iter()
+            .flat_map(|cb| {
+                let height = BlockHeight::from_u32(cb.height as u32);
+                cb.vtx.into_iter().flat_map(move |ctx| {
+                    ctx.outputs
+                        .into_iter()
+                        .map(move |co| (SaplingDomain::for_height(UnitTestNetwork, height), co))
+                })
+            })
+            .collect()
+    }
+
+    // (output index, ivk index) of all the decrypted outputs
+    fn found<N, R>(decrypts: &[Option<((N, R), usize)>]) -> Vec<(usize, usize)> {
+        decrypts
+            .iter()
+            .enumerate()
+            .filter_map(|(i, d)| d.as_ref().map(|(_, ivk_num)| (i, *ivk_num)))
+            .collect()
+    }
+
+    #[test]
+    fn parallel_matches_sequential() {
+        let ours = vec![extfvk([1u8; 32]), extfvk([2u8; 32])];
+        let ivks = ours.iter().map(|e| e.fvk.vk.ivk()).collect::<Vec<SaplingIvk>>();
+        let outputs = sapling_outputs(100, &ours);
+
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let parallel = decrypt_in_parallel(&pool, &ivks, &outputs);
+        let sequential = try_compact_note_decryption(&ivks, &outputs);
+
+        assert_eq!(parallel.len(), outputs.len());
+        assert_eq!(found(&parallel), found(&sequential));
+
+        let found = found(&parallel);
+        assert_eq!(found.len(), 10);
+        assert_eq!(found.iter().filter(|(_, ivk_num)| *ivk_num == 0).count(), 5);
+        assert_eq!(found.iter().filter(|(_, ivk_num)| *ivk_num == 1).count(), 5);
+
+        // Without any ivks, nothing is decrypted
+        let none = decrypt_in_parallel(&pool, &[], &outputs);
+        assert_eq!(none.len(), outputs.len());
+        assert!(found(&none).is_empty());
+    }
+
+    #[test]
+    fn pool_is_kept_across_syncs() {
+        let mut bsync_data = BlazeSyncData::new(&LightClientConfig::create_unconnected(UnitTestNetwork, None));
+        bsync_data.wallet_options.trial_decryption_threads = 2;
+
+        let pool = bsync_data.trial_decryption_pool().unwrap();
+        assert_eq!(pool.current_num_threads(), 2);
+        assert!(Arc::ptr_eq(&pool, &bsync_data.trial_decryption_pool().unwrap()));
+
+        // Changing the option restarts the threads
+        bsync_data.wallet_options.trial_decryption_threads = 3;
+        let restarted = bsync_data.trial_decryption_pool().unwrap();
+        assert!(!Arc::ptr_eq(&pool, &restarted));
+        assert_eq!(restarted.current_num_threads(), 3);
+    }
+
+    // Run with `cargo test --release bench_trial_decryption -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_trial_decryption() {
+        let ours = (1..=5u8).map(|i| extfvk([i; 32])).collect::<Vec<_>>();
+        let ivks = ours.iter().map(|e| e.fvk.vk.ivk()).collect::<Vec<SaplingIvk>>();
+        let outputs = sapling_outputs(5_000, &ours);
+        let decryptions = (outputs.len() * ivks.len()) as f64;
+
+        // One batch per tx, the way the sync used to do it
+        let start = Instant::now();
+        for output in outputs.chunks(1) {
+            try_compact_note_decryption(&ivks, output);
+        }
+        let secs = start.elapsed().as_secs_f64();
+        println!("per tx: {:.3}s, {:.0} decryptions/s", secs, decryptions / secs);
+
+        for threads in [1, 2, 4, 0] {
+            let pool = ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+
+            let start = Instant::now();
+            decrypt_in_parallel(&pool, &ivks, &outputs);
+            let secs = start.elapsed().as_secs_f64();
+            println!(
+                "batched, {} threads: {:.3}s, {:.0} decryptions/s",
+                pool.current_num_threads(),
+                secs,
+                decryptions / secs
+            );
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use crate::lightclient::consistency::DEFAULT_SAMPLE_BLOCKS;
+use crate::lightclient::lightclient_config::LightClientConfig;
 use crate::lightwallet::keys::Keys;
 use crate::lightwallet::MemoDownloadOption;
 use crate::{lightclient::LightClient, lightwallet::utils};
@@ -102,7 +104,30 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for SyncStatus
                     "txn_scan_blocks" => status.txn_scan_done,
                     "total_blocks" => status.blocks_total,
                     "batch_num" => status.batch_num,
-                    "batch_total" => status.batch_total
+                    "batch_total" => status.batch_total,
+                    "sync_start_height" => status.sync_start_height,
+                    "sync_tip" => status.sync_tip,
+                    "blocks_synced" => status.blocks_synced(),
+                    "blocks_remaining" => status.blocks_remaining(),
+                    "eta_secs" => status.eta().map(|d| d.as_secs()),
+                    "metrics" => object! {
+                        "download" => object! {
+                            "blocks" => status.download.items,
+                            "bytes" => status.download.bytes,
+                            "elapsed_secs" => status.download.elapsed.as_secs_f64(),
+                            "blocks_per_sec" => status.download.per_sec(),
+                        },
+                        "trial_decryption" => object! {
+                            "decryptions" => status.trial_decryption.items,
+                            "elapsed_secs" => status.trial_decryption.elapsed.as_secs_f64(),
+                            "decryptions_per_sec" => status.trial_decryption.per_sec(),
+                        },
+                        "txn_scan" => object! {
+                            "txns" => status.txn_scan.items,
+                            "elapsed_secs" => status.txn_scan.elapsed.as_secs_f64(),
+                            "txns_per_sec" => status.txn_scan.per_sec(),
+                        },
+                    }
                 }
             } else {
                 object! {
@@ -471,6 +496,122 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for DecryptCom
     }
 }
 
+struct ChangePasswordCommand {}
+impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for ChangePasswordCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Change the password the wallet is encrypted with");
+        h.push("Note: The seed and the private keys are re-encrypted with the new password before the wallet");
+        h.push("      is saved, so they are never stored unencrypted. If the whole wallet file is encrypted, it");
+        h.push("      is re-encrypted too.");
+        h.push("Usage:");
+        h.push("changepassword old_password new_password");
+        h.push("");
+        h.push("Example:");
+        h.push("changepassword my_strong_password my_stronger_password");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Change the wallet's password".to_string()
+    }
+    fn exec(&self, args: &[&str], lightclient: &LightClient<P>) -> String {
+        if args.len() != 2 {
+            return Command::<P>::help(self);
+        }
+
+        let old_passwd = args[0].to_string();
+        let new_passwd = args[1].to_string();
+        RT.block_on(async move {
+            match lightclient.do_change_password(old_passwd, new_passwd).await {
+                Ok(_) => object! { "result" => "success" },
+                Err(e) => object! {
+                    "result" => "error",
+                    "error"  => e
+                },
+            }
+            .pretty(2)
+        })
+    }
+}
+
+struct EncryptFileCommand {}
+impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for EncryptFileCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Encrypt the whole wallet file with the wallet's password, not only the spending keys");
+        h.push("Note 1: The wallet has to be encrypted with 'encrypt' first. The addresses, transactions, memos and");
+        h.push("        balances are encrypted too, so the wallet can only be opened with the password. Opening it");
+        h.push("        only unlocks it for viewing. Use 'unlock' to spend.");
+        h.push("Note 2: Backups of the wallet that are not encrypted are deleted.");
+        h.push("Usage:");
+        h.push("encryptfile password");
+        h.push("");
+        h.push("Example:");
+        h.push("encryptfile my_strong_password");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Encrypt the whole wallet file with the wallet's password".to_string()
+    }
+    fn exec(&self, args: &[&str], lightclient: &LightClient<P>) -> String {
+        if args.len() != 1 {
+            return Command::<P>::help(self);
+        }
+
+        let passwd = args[0].to_string();
+        RT.block_on(async move {
+            match lightclient.do_encrypt_file(passwd).await {
+                Ok(_) => object! { "result" => "success" },
+                Err(e) => object! {
+                    "result" => "error",
+                    "error"  => e
+                },
+            }
+            .pretty(2)
+        })
+    }
+}
+
+struct DecryptFileCommand {}
+impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for DecryptFileCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Stop encrypting the whole wallet file. The spending keys stay encrypted.");
+        h.push("Usage:");
+        h.push("decryptfile password");
+        h.push("");
+        h.push("Example:");
+        h.push("decryptfile my_strong_password");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Stop encrypting the whole wallet file".to_string()
+    }
+    fn exec(&self, args: &[&str], lightclient: &LightClient<P>) -> String {
+        if args.len() != 1 {
+            return Command::<P>::help(self);
+        }
+
+        let passwd = args[0].to_string();
+        RT.block_on(async move {
+            match lightclient.do_remove_file_encryption(passwd).await {
+                Ok(_) => object! { "result" => "success" },
+                Err(e) => object! {
+                    "result" => "error",
+                    "error"  => e
+                },
+            }
+            .pretty(2)
+        })
+    }
+}
+
 struct UnlockCommand {}
 
 impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for UnlockCommand {
@@ -481,6 +622,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for UnlockComm
         h.push("        Use 'decrypt' to remove the encryption permanatly.");
         h.push("Note 2: If you've forgotten the password, the only way to recover the wallet is to restore");
         h.push("        from the seed phrase.");
+        h.push("Note 3: The wallet locks itself again as set by the auto_lock_timeout and auto_lock_sends options.");
         h.push("Usage:");
         h.push("unlock password");
         h.push("");
@@ -500,11 +642,11 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for UnlockComm
 
         let passwd = args[0].to_string();
         RT.block_on(async move {
-            match lightclient.wallet.unlock(passwd).await {
+            match lightclient.do_unlock(passwd).await {
                 Ok(_) => object! { "result" => "success" },
                 Err(e) => object! {
                     "result" => "error",
-                    "error"  => e.to_string()
+                    "error"  => e
                 },
             }
             .pretty(2)
@@ -543,11 +685,11 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for LockComman
         }
 
         RT.block_on(async move {
-            match lightclient.wallet.lock().await {
+            match lightclient.do_lock().await {
                 Ok(_) => object! { "result" => "success" },
                 Err(e) => object! {
                     "result" => "error",
-                    "error"  => e.to_string()
+                    "error"  => e
                 },
             }
             .pretty(2)
@@ -895,6 +1037,90 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for SeedComman
     }
 }
 
+struct VerifySeedCommand {}
+
+impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for VerifySeedCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Check that a seed phrase is this wallet's, without showing the wallet's seed phrase");
+        h.push("Usage:");
+        h.push("verifyseed seed_phrase");
+        h.push("");
+        h.push("Returns 'match' or 'no-match'. This works while the wallet is locked.");
+        h.push("SLIP-39 shares can be passed one per line, or separated by ';' on the command line.");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Check a written down seed phrase".to_string()
+    }
+    fn exec(&self, args: &[&str], lightclient: &LightClient<P>) -> String {
+        if args.is_empty() {
+            return Command::<P>::help(self);
+        }
+
+        // The API passes the phrase as it is, as one argument, so that shares on several lines stay apart. On the
+        // command line its words come split, and only ';' can separate shares.
+        let phrase = args.join(" ");
+        RT.block_on(async move {
+            match lightclient.do_verify_seed(phrase).await {
+                Ok(j) => j,
+                Err(e) => object! { "error" => e },
+            }
+            .pretty(2)
+        })
+    }
+}
+
+struct SplitSeedCommand {}
+
+impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for SplitSeedCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Split the wallet's seed into SLIP-39 shares, so that it can be backed up in several places");
+        h.push("Usage:");
+        h.push("splitseed threshold count");
+        h.push("");
+        h.push("Makes 'count' shares, any 'threshold' of which restore the wallet. Fewer shares reveal nothing about");
+        h.push("the seed. To restore, pass the shares, one per line, in place of the seed phrase.");
+        h.push("Note: A BIP39 passphrase is not part of the shares, and has to be kept separately.");
+        h.push("Note: The shares only restore in this wallet, not in a hardware wallet or other SLIP-39 software.");
+        h.push("Only wallets with an English seed phrase can be split.");
+        h.push("");
+        h.push("Example:");
+        h.push("splitseed 2 3");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Split the seed into SLIP-39 shares".to_string()
+    }
+    fn exec(&self, args: &[&str], lightclient: &LightClient<P>) -> String {
+        if args.len() != 2 {
+            return Command::<P>::help(self);
+        }
+
+        let threshold = match args[0].parse::<u8>() {
+            Ok(t) => t,
+            Err(e) => return format!("Couldn't parse threshold: {}", e),
+        };
+        let count = match args[1].parse::<u8>() {
+            Ok(c) => c,
+            Err(e) => return format!("Couldn't parse count: {}", e),
+        };
+
+        RT.block_on(async move {
+            match lightclient.do_split_seed(threshold, count).await {
+                Ok(j) => j,
+                Err(e) => object! { "error" => e },
+            }
+            .pretty(2)
+        })
+    }
+}
+
 struct TransactionsCommand {}
 
 impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for TransactionsCommand {
@@ -945,6 +1171,11 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for SetOptionC
         h.push("setoption <optionname>=<optionvalue>");
         h.push("List of available options:");
         h.push("download_memos : none | wallet | all");
+        h.push("trial_decryption_threads : <number>, or 0 for one per CPU core");
+        h.push("validate_headers : true | false");
+        h.push("auto_lock_timeout : seconds the unlocked wallet can be idle before it locks itself, 0 for never");
+        h.push("auto_lock_sends : number of sends after which the unlocked wallet locks itself, 0 for never");
+        h.push("A new auto_lock_timeout also applies to a wallet that is unlocked already.");
 
         h.join("\n")
     }
@@ -984,6 +1215,23 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for SetOptionC
                     let threshold = option_value.parse::<i64>().unwrap();
                     lightclient.wallet.set_spam_filter_threshold(threshold).await
                 }
+                "trial_decryption_threads" => match option_value.parse::<u32>() {
+                    Ok(threads) => lightclient.wallet.set_trial_decryption_threads(threads).await,
+                    Err(_) => return format!("Error: Couldn't understand {} value {}", option_name, option_value),
+                },
+                "validate_headers" => match option_value {
+                    "true" => lightclient.wallet.set_validate_headers(true).await,
+                    "false" => lightclient.wallet.set_validate_headers(false).await,
+                    _ => return format!("Error: Couldn't understand {} value {}", option_name, option_value),
+                },
+                "auto_lock_timeout" => match option_value.parse::<u64>() {
+                    Ok(secs) => lightclient.set_auto_lock_timeout(secs).await,
+                    Err(_) => return format!("Error: Couldn't understand {} value {}", option_name, option_value),
+                },
+                "auto_lock_sends" => match option_value.parse::<u32>() {
+                    Ok(sends) => lightclient.wallet.set_auto_lock_sends(sends).await,
+                    Err(_) => return format!("Error: Couldn't understand {} value {}", option_name, option_value),
+                },
                 _ => return format!("Error: Couldn't understand {}", option_name),
             }
 
@@ -1033,6 +1281,34 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for GetOptionC
                     .await
                     .spam_threshold
                     .to_string(),
+                "trial_decryption_threads" => lightclient
+                    .wallet
+                    .wallet_options
+                    .read()
+                    .await
+                    .trial_decryption_threads
+                    .to_string(),
+                "validate_headers" => lightclient
+                    .wallet
+                    .wallet_options
+                    .read()
+                    .await
+                    .validate_headers
+                    .to_string(),
+                "auto_lock_timeout" => lightclient
+                    .wallet
+                    .wallet_options
+                    .read()
+                    .await
+                    .auto_lock_timeout
+                    .to_string(),
+                "auto_lock_sends" => lightclient
+                    .wallet
+                    .wallet_options
+                    .read()
+                    .await
+                    .auto_lock_sends
+                    .to_string(),
                 _ => return format!("Error: Couldn't understand {}", option_name),
             };
 
@@ -1274,6 +1550,107 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for NotesComma
     }
 }
 
+struct CheckpointsCommand {}
+
+impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for CheckpointsCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Generate checkpoints from the tree states on one or more servers");
+        h.push("Usage:");
+        h.push("checkpoints generate <start_height> <interval> <output_file> [server ...]");
+        h.push("");
+        h.push("Fetches the tree state every <interval> blocks from <start_height> up to 100 blocks below the tip, from the current server and every extra [server].");
+        h.push("All the servers have to agree on every tree state, and with the built-in checkpoints.");
+        h.push("If <output_file> ends in .json, the checkpoints are written in the format the wallet loads from checkpoints.json in its data dir.");
+        h.push("Otherwise, the regenerated lightclient/checkpoints/main_checkpoints.rs module is written.");
+        h.push("");
+        h.push("Example:");
+        h.push("checkpoints generate 1400000 10000 checkpoints.json https://lightd.btcz.rocks:9067");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Generate checkpoints from the tree states on one or more servers".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient<P>) -> String {
+        if args.len() < 4 || args[0] != "generate" {
+            return format!("Wrong arguments\n{}", Command::<P>::help(self));
+        }
+
+        let start = match args[1].parse::<u64>() {
+            Ok(h) => h,
+            Err(e) => return format!("Couldn't parse start height: {}", e),
+        };
+        let interval = match args[2].parse::<u64>() {
+            Ok(i) => i,
+            Err(e) => return format!("Couldn't parse interval: {}", e),
+        };
+        let extra_servers = args[4..]
+            .iter()
+            .map(|s| LightClientConfig::<P>::get_server_or_default(Some(s.to_string())))
+            .collect();
+
+        RT.block_on(async move {
+            match lightclient
+                .do_generate_checkpoints(start, interval, args[3], extra_servers)
+                .await
+            {
+                Ok(j) => j,
+                Err(e) => object! { "error" => e },
+            }
+            .pretty(2)
+        })
+    }
+}
+
+struct VerifyServersCommand {}
+
+impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for VerifyServersCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Cross-check the chain synced from the wallet's server against other servers");
+        h.push("Usage:");
+        h.push("verifyservers [<server>[,<server>...] [refuse]]");
+        h.push("verifyservers off");
+        h.push("");
+        h.push("Compares a sample of the wallet's blocks, its sapling tree and the latest block against each of the servers.");
+        h.push("Once servers are set, the check also runs after every sync, and any disagreement is sent to the event stream.");
+        h.push("With 'refuse', sending is refused while the servers disagree. Without servers, checks against the servers set before.");
+        h.push("");
+        h.push("Example:");
+        h.push("verifyservers https://lightd.btcz.rocks:9067 refuse");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Cross-check the wallet's server against other servers".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient<P>) -> String {
+        match args {
+            [] => {}
+            ["off"] => {
+                lightclient.set_server_verification("", 0, false);
+                return object! { "result" => "success" }.pretty(2);
+            }
+            [servers] => lightclient.set_server_verification(servers, DEFAULT_SAMPLE_BLOCKS, false),
+            [servers, "refuse"] => lightclient.set_server_verification(servers, DEFAULT_SAMPLE_BLOCKS, true),
+            _ => return format!("Wrong arguments\n{}", Command::<P>::help(self)),
+        }
+
+        RT.block_on(async move {
+            if !lightclient.config.consistency.is_enabled() {
+                return object! { "error" => "No servers to verify against" }.pretty(2);
+            }
+
+            lightclient.do_verify_servers().await.pretty(2)
+        })
+    }
+}
+
 struct QuitCommand {}
 
 impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for QuitCommand {
@@ -1331,10 +1708,17 @@ pub fn get_commands<P: consensus::Parameters + Send + Sync + 'static>() -> Box<H
     map.insert("new".to_string(), Box::new(NewAddressCommand {}));
     map.insert("defaultfee".to_string(), Box::new(DefaultFeeCommand {}));
     map.insert("seed".to_string(), Box::new(SeedCommand {}));
+    map.insert("splitseed".to_string(), Box::new(SplitSeedCommand {}));
+    map.insert("verifyseed".to_string(), Box::new(VerifySeedCommand {}));
     map.insert("encrypt".to_string(), Box::new(EncryptCommand {}));
     map.insert("decrypt".to_string(), Box::new(DecryptCommand {}));
+    map.insert("changepassword".to_string(), Box::new(ChangePasswordCommand {}));
+    map.insert("encryptfile".to_string(), Box::new(EncryptFileCommand {}));
+    map.insert("decryptfile".to_string(), Box::new(DecryptFileCommand {}));
     map.insert("unlock".to_string(), Box::new(UnlockCommand {}));
     map.insert("lock".to_string(), Box::new(LockCommand {}));
+    map.insert("checkpoints".to_string(), Box::new(CheckpointsCommand {}));
+    map.insert("verifyservers".to_string(), Box::new(VerifyServersCommand {}));
 
     Box::new(map)
 }
@@ -1,46 +1,303 @@
 use std::cmp;
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::future::Future;
+use std::sync::RwLock;
+use std::time::Duration;
 
+use crate::bitcoinzd_connector::BitcoinzdConnector;
 use crate::compact_formats::compact_tx_streamer_client::CompactTxStreamerClient;
 use crate::compact_formats::{
     BlockId, BlockRange, ChainSpec, CompactBlock, Empty, LightdInfo, PriceRequest, PriceResponse, RawTransaction,
     TransparentAddressBlockFilter, TreeState, TxFilter,
 };
+use crate::proxy::{Socks5Connector, Socks5Proxy};
+use crate::tls::{PinnedTls, PinnedTlsConnector};
 use crate::ServerCert;
 use futures::stream::FuturesUnordered;
 use futures::StreamExt;
+use json::{object, JsonValue};
+use lazy_static::lazy_static;
 use log::{warn, info, error};
+use rand::Rng;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
 use tokio::sync::mpsc::{Sender, UnboundedReceiver};
 use tokio::sync::oneshot;
 use tokio::task::JoinHandle;
+use tokio::time::sleep;
 
 use tonic::transport::{Certificate, ClientTlsConfig};
 use tonic::{
-    transport::{Channel, Error},
+    transport::{Channel, Endpoint},
     Request,
 };
 use zcash_primitives::consensus::{self, BlockHeight, BranchId};
 use zcash_primitives::transaction::{Transaction, TxId};
 
+lazy_static! {
+    static ref CONNECTION_SETTINGS: RwLock<ConnectionSettings> = RwLock::new(ConnectionSettings::default());
+}
+
+/// Use `settings` for all the connections to servers made from now on
+pub fn set_connection_settings(settings: ConnectionSettings) {
+    *CONNECTION_SETTINGS.write().unwrap() = settings;
+}
+
+pub fn get_connection_settings() -> ConnectionSettings {
+    *CONNECTION_SETTINGS.read().unwrap()
+}
+
+/// Timeouts and keepalive for the connections to servers, and how failed calls are retried
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConnectionSettings {
+    // How long a call may wait for the server to answer. For streaming calls, this is until the stream starts.
+    pub rpc_timeout: Duration,
+    pub connect_timeout: Duration,
+
+    // HTTP/2 pings, so that a connection to a server that went away is noticed even while a stream is idle
+    pub keepalive_interval: Duration,
+    pub keepalive_timeout: Duration,
+
+    pub retry: RetryPolicy,
+}
+
+impl Default for ConnectionSettings {
+    fn default() -> Self {
+        Self {
+            rpc_timeout: Duration::from_secs(60),
+            connect_timeout: Duration::from_secs(10),
+            keepalive_interval: Duration::from_secs(30),
+            keepalive_timeout: Duration::from_secs(20),
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+impl ConnectionSettings {
+    pub fn to_json(&self) -> JsonValue {
+        object! {
+            "rpc_timeout_secs" => self.rpc_timeout.as_secs(),
+            "connect_timeout_secs" => self.connect_timeout.as_secs(),
+            "keepalive_interval_secs" => self.keepalive_interval.as_secs(),
+            "keepalive_timeout_secs" => self.keepalive_timeout.as_secs(),
+            "max_attempts" => self.retry.max_attempts,
+            "base_delay_ms" => self.retry.base_delay.as_millis() as u64,
+            "max_delay_ms" => self.retry.max_delay.as_millis() as u64,
+        }
+    }
+}
+
+/// Exponential backoff with jitter, for retrying calls that failed with a transient error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    // Including the first try, so 1 means no retries
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait before retry number `retry` (counting from 0). The delay doubles with every retry up to
+    /// `max_delay`, and a random half of it is taken off, so that clients that failed together don't retry together.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .checked_mul(1 << cmp::min(retry, 16))
+            .map_or(self.max_delay, |d| cmp::min(d, self.max_delay));
+
+        let half = delay / 2;
+        half + half.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+
+    /// Run `f` until it succeeds, fails with a permanent error or `max_attempts` runs out, backing off in between
+    pub async fn retry<T, F, Fut>(&self, mut f: F) -> Result<T, String>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, String>>,
+    {
+        let mut retry = 0;
+        loop {
+            match f().await {
+                Err(e) if retry + 1 < self.max_attempts && GrpcConnector::is_transient_error(&e) => {
+                    let delay = self.backoff(retry);
+                    warn!("Call failed ({}), retrying in {:?}", e, delay);
+                    sleep(delay).await;
+                    retry += 1;
+                }
+                r => return r,
+            }
+        }
+    }
+}
+
+/// Whether an error is worth trying again later
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FailureKind {
+    // The server couldn't be reached, timed out or was overloaded
+    Transient,
+
+    // The server answered with an error, or what it sent was wrong. Trying again won't help.
+    Permanent,
+}
+
+impl FailureKind {
+    pub const TRANSIENT_PREFIX: &'static str = "Transient error, try again later: ";
+    const PERMANENT_PREFIX: &'static str = "Permanent error: ";
+
+    pub fn of(e: &str) -> Self {
+        if e.starts_with(Self::PERMANENT_PREFIX) {
+            FailureKind::Permanent
+        } else if e.starts_with(Self::TRANSIENT_PREFIX) || GrpcConnector::is_transient_error(e) {
+            FailureKind::Transient
+        } else {
+            FailureKind::Permanent
+        }
+    }
+
+    /// Prefix `e` with what kind of failure it is, so that callers can tell without knowing about gRPC errors
+    pub fn tag(e: String) -> String {
+        Self::of(&e).prefix(e)
+    }
+
+    /// Prefix `e` with this kind of failure, for errors whose kind is known without looking at the message
+    pub fn prefix(self, e: String) -> String {
+        if e.starts_with(Self::TRANSIENT_PREFIX) || e.starts_with(Self::PERMANENT_PREFIX) {
+            return e;
+        }
+
+        match self {
+            FailureKind::Transient => format!("{}{}", Self::TRANSIENT_PREFIX, e),
+            FailureKind::Permanent => format!("{}{}", Self::PERMANENT_PREFIX, e),
+        }
+    }
+}
+
+/// How to reach the servers of a config. Every config has its own, so wallets configured differently don't share them.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectOptions {
+    // SOCKS5 proxy that all connections go through, like Tor
+    pub proxy: Option<Socks5Proxy>,
+
+    // How to trust https servers, if not the way tonic does by default
+    pub tls: Option<PinnedTls>,
+}
+
+/// A server, and the options to connect to it with
 #[derive(Clone)]
 pub struct GrpcConnector {
     uri: http::Uri,
+    options: ConnectOptions,
 }
 
 impl GrpcConnector {
-    pub fn new(uri: http::Uri) -> Self {
-        Self { uri }
+    pub fn new(uri: http::Uri, options: ConnectOptions) -> Self {
+        Self { uri, options }
+    }
+
+    pub fn uri(&self) -> &http::Uri {
+        &self.uri
+    }
+
+    // The bitcoinzd node to call instead, if the uri is one
+    fn bitcoinzd(&self) -> Option<Result<BitcoinzdConnector, String>> {
+        if BitcoinzdConnector::is_bitcoinzd_uri(&self.uri) {
+            Some(BitcoinzdConnector::new(&self.uri, self.options.proxy.clone()))
+        } else {
+            None
+        }
+    }
+
+    /// Whether an error returned by one of the calls below means that the server couldn't be reached, as opposed to
+    /// the server answering with an error. Only the former is worth retrying on another server.
+    pub fn is_transport_error(e: &str) -> bool {
+        e.starts_with("Error getting client")
+            || e.starts_with("Error calling bitcoinzd")
+            || [
+                "Unavailable",
+                "transport error",
+                "Connection refused",
+                "Connection reset",
+                "DeadlineExceeded",
+                "Timeout expired",
+                "timed out",
+            ]
+            .iter()
+            .any(|s| e.contains(s))
+    }
+
+    /// Whether a call that failed with `e` might succeed if it is tried again a bit later, on the same server or on
+    /// another one. Besides the server being unreachable, that is the server being overloaded or aborting the call.
+    pub fn is_transient_error(e: &str) -> bool {
+        // A server with another key is worth failing over from, but it won't get the pinned key back by itself
+        if e.contains("Certificate pin mismatch") {
+            return false;
+        }
+
+        Self::is_transport_error(e)
+            || ["ResourceExhausted", "Aborted", "Cancelled"]
+                .iter()
+                .any(|s| e.contains(s))
+    }
+
+    /// Whether sending a transaction failed with `e` because the server already has it. A try that timed out can
+    /// still reach the server, so a retry of the same transaction gets this instead of its txid.
+    pub fn is_already_sent(e: &str) -> bool {
+        [
+            "txn-already-in-mempool",
+            "txn-already-known",
+            "already in mempool",
+            "transaction already in block chain",
+        ]
+        .iter()
+        .any(|s| e.contains(s))
     }
 
-    async fn get_client(&self) -> Result<CompactTxStreamerClient<Channel>, Error> {
+    // The endpoint for `uri`, with the current timeouts and keepalive
+    fn endpoint(uri: http::Uri) -> Endpoint {
+        let settings = get_connection_settings();
+
+        Channel::builder(uri)
+            .timeout(settings.rpc_timeout)
+            .connect_timeout(settings.connect_timeout)
+            .http2_keep_alive_interval(settings.keepalive_interval)
+            .keep_alive_timeout(settings.keepalive_timeout)
+            .keep_alive_while_idle(true)
+    }
+
+    async fn get_client(&self) -> Result<CompactTxStreamerClient<Channel>, Box<dyn std::error::Error + Send + Sync>> {
+        let proxy = self.options.proxy.clone();
+        let host = self.uri.host().ok_or_else(|| format!("No host in {}", self.uri))?;
+
         let channel = if self.uri.scheme_str() == Some("http") {
             //println!("http");
-            Channel::builder(self.uri.clone()).connect().await?
+            let endpoint = Self::endpoint(self.uri.clone());
+            match proxy {
+                Some(proxy) => endpoint.connect_with_connector(Socks5Connector::new(proxy)).await?,
+                None => endpoint.connect().await?,
+            }
+        } else if let Some(tls) = &self.options.tls {
+            // Tonic can't check pins, so the TLS is done by the connector, and tonic sees a plain http uri
+            let mut parts = self.uri.clone().into_parts();
+            let authority = format!("{}:{}", host, self.uri.port_u16().unwrap_or(443));
+            parts.scheme = Some(http::uri::Scheme::HTTP);
+            parts.authority = Some(authority.parse()?);
+
+            let endpoint = Self::endpoint(http::Uri::from_parts(parts)?);
+            endpoint
+                .connect_with_connector(PinnedTlsConnector::new(tls.clone(), proxy))
+                .await?
         } else {
             //println!("https");
-            let mut tls = ClientTlsConfig::new().domain_name(self.uri.host().unwrap());
+            let mut tls = ClientTlsConfig::new().domain_name(host);
 
             let server_cert = ServerCert::get("fullchain.pem").unwrap().data;
             if server_cert.len() > 0 {
@@ -48,12 +305,11 @@ impl GrpcConnector {
                 tls = tls.ca_certificate(server_root_ca_cert);
             }
 
-            Channel::builder(self.uri.clone())
-                .tls_config(tls)?
-                // .timeout(Duration::from_secs(10))
-                // .connect_timeout(Duration::from_secs(10))
-                .connect()
-                .await?
+            let endpoint = Self::endpoint(self.uri.clone()).tls_config(tls)?;
+            match proxy {
+                Some(proxy) => endpoint.connect_with_connector(Socks5Connector::new(proxy)).await?,
+                None => endpoint.connect().await?,
+            }
         };
 
         Ok(CompactTxStreamerClient::new(channel))
@@ -66,14 +322,11 @@ impl GrpcConnector {
         UnboundedSender<(u64, oneshot::Sender<Result<TreeState, String>>)>,
     ) {
         let (tx, mut rx) = unbounded_channel::<(u64, oneshot::Sender<Result<TreeState, String>>)>();
-        let uri = self.uri.clone();
+        let server = self.clone();
 
         let h = tokio::spawn(async move {
-            let uri = uri.clone();
             while let Some((height, result_tx)) = rx.recv().await {
-                result_tx
-                    .send(Self::get_merkle_tree(uri.clone(), height).await)
-                    .unwrap()
+                result_tx.send(server.clone().get_merkle_tree(height).await).unwrap()
             }
         });
 
@@ -93,10 +346,9 @@ impl GrpcConnector {
             (Vec<String>, u64, u64),
             oneshot::Sender<Vec<UnboundedReceiver<Result<RawTransaction, String>>>>,
         )>();
-        let uri = self.uri.clone();
+        let server = self.clone();
 
         let h = tokio::spawn(async move {
-            let uri = uri.clone();
             if let Ok(((taddrs, start_height, end_height), result_tx)) = rx.await {
                 let mut tx_rs = vec![];
                 let mut tx_rs_workers = FuturesUnordered::new();
@@ -105,8 +357,7 @@ impl GrpcConnector {
                 for taddr in taddrs {
                     let (tx_s, tx_r) = unbounded_channel();
                     tx_rs.push(tx_r);
-                    tx_rs_workers.push(tokio::spawn(Self::get_taddr_txns(
-                        uri.clone(),
+                    tx_rs_workers.push(tokio::spawn(server.clone().get_taddr_txns(
                         taddr,
                         start_height,
                         end_height,
@@ -140,17 +391,15 @@ impl GrpcConnector {
         UnboundedSender<(TxId, oneshot::Sender<Result<Transaction, String>>)>,
     ) {
         let (tx, mut rx) = unbounded_channel::<(TxId, oneshot::Sender<Result<Transaction, String>>)>();
-        let uri = self.uri.clone();
+        let server = self.clone();
 
         let h = tokio::spawn(async move {
             let mut workers = FuturesUnordered::new();
             while let Some((txid, result_tx)) = rx.recv().await {
-                let uri = uri.clone();
+                let server = server.clone();
                 let parameters = parameters.clone();
                 workers.push(tokio::spawn(async move {
-                    result_tx
-                        .send(Self::get_full_tx(uri.clone(), &txid, parameters).await)
-                        .unwrap()
+                    result_tx.send(server.get_full_tx(&txid, parameters).await).unwrap()
                 }));
 
                 // Do only 16 API calls in parallel, otherwise it might overflow OS's limit of
@@ -219,11 +468,14 @@ impl GrpcConnector {
     }
 
     async fn get_full_tx<P: consensus::Parameters + Send + Sync + 'static>(
-        uri: http::Uri,
+        self,
         txid: &TxId,
         parameters: P,
     ) -> Result<Transaction, String> {
-        let client = Arc::new(GrpcConnector::new(uri));
+        if let Some(node) = self.bitcoinzd() {
+            return node?.get_full_tx(txid, &parameters).await;
+        }
+
         let request = Request::new(TxFilter {
             block: None,
             index: 0,
@@ -232,7 +484,7 @@ impl GrpcConnector {
 
         log::info!("Full fetching {}", txid);
 
-        let mut client = client
+        let mut client = self
             .get_client()
             .await
             .map_err(|e| format!("Error getting client: {:?}", e))?;
@@ -248,13 +500,15 @@ impl GrpcConnector {
     }
 
     async fn get_taddr_txns(
-        uri: http::Uri,
+        self,
         taddr: String,
         start_height: u64,
         end_height: u64,
         txns_sender: UnboundedSender<Result<RawTransaction, String>>,
     ) -> Result<(), String> {
-        let client = Arc::new(GrpcConnector::new(uri));
+        if let Some(node) = self.bitcoinzd() {
+            return node?.get_taddr_txns(taddr, start_height, end_height, txns_sender).await;
+        }
 
         // Make sure start_height is smaller than end_height, because the API expects it like that
         let (start_height, end_height) = if start_height < end_height {
@@ -282,7 +536,7 @@ impl GrpcConnector {
         };
         let request = Request::new(args.clone());
 
-        let mut client = client
+        let mut client = self
             .get_client()
             .await
             .map_err(|e| format!("Error getting client: {:?}", e))?;
@@ -309,10 +563,12 @@ impl GrpcConnector {
         Ok(())
     }
 
-    pub async fn get_info(uri: http::Uri) -> Result<LightdInfo, String> {
-        let client = Arc::new(GrpcConnector::new(uri));
+    pub async fn get_info(self) -> Result<LightdInfo, String> {
+        if let Some(node) = self.bitcoinzd() {
+            return node?.get_info().await;
+        }
 
-        let mut client = client
+        let mut client = self
             .get_client()
             .await
             .map_err(|e| format!("Error getting client: {:?}", e))?;
@@ -326,10 +582,12 @@ impl GrpcConnector {
         Ok(response.into_inner())
     }
 
-    pub async fn monitor_mempool(uri: http::Uri, mempool_tx: UnboundedSender<RawTransaction>) -> Result<(), String> {
-        let client = Arc::new(GrpcConnector::new(uri));
+    pub async fn monitor_mempool(self, mempool_tx: UnboundedSender<RawTransaction>) -> Result<(), String> {
+        if let Some(node) = self.bitcoinzd() {
+            return node?.monitor_mempool(mempool_tx).await;
+        }
 
-        let mut client = client
+        let mut client = self
             .get_client()
             .await
             .map_err(|e| format!("Error getting client: {:?}", e))?;
@@ -348,9 +606,12 @@ impl GrpcConnector {
         Ok(())
     }
 
-    pub async fn get_merkle_tree(uri: http::Uri, height: u64) -> Result<TreeState, String> {
-        let client = Arc::new(GrpcConnector::new(uri));
-        let mut client = client
+    pub async fn get_merkle_tree(self, height: u64) -> Result<TreeState, String> {
+        if let Some(node) = self.bitcoinzd() {
+            return node?.get_merkle_tree(height).await;
+        }
+
+        let mut client = self
             .get_client()
             .await
             .map_err(|e| format!("Error getting client: {:?}", e))?;
@@ -367,9 +628,12 @@ impl GrpcConnector {
         Ok(response.into_inner())
     }
 
-    pub async fn get_current_zec_price(uri: http::Uri) -> Result<PriceResponse, String> {
-        let client = Arc::new(GrpcConnector::new(uri));
-        let mut client = client
+    pub async fn get_current_zec_price(self) -> Result<PriceResponse, String> {
+        if BitcoinzdConnector::is_bitcoinzd_uri(&self.uri) {
+            return Err(format!("Prices are not available from bitcoinzd"));
+        }
+
+        let mut client = self
             .get_client()
             .await
             .map_err(|e| format!("Error getting client: {:?}", e))?;
@@ -384,12 +648,15 @@ impl GrpcConnector {
     }
 
     pub async fn get_historical_zec_prices(
-        uri: http::Uri,
+        self,
         txids: Vec<(TxId, u64)>,
         currency: String,
     ) -> Result<HashMap<TxId, Option<f64>>, String> {
-        let client = Arc::new(GrpcConnector::new(uri));
-        let mut client = client
+        if BitcoinzdConnector::is_bitcoinzd_uri(&self.uri) {
+            return Err(format!("Unsupported by server"));
+        }
+
+        let mut client = self
             .get_client()
             .await
             .map_err(|e| format!("Error getting client: {:?}", e))?;
@@ -431,9 +698,12 @@ impl GrpcConnector {
     }
 
     // get_latest_block GRPC call
-    pub async fn get_latest_block(uri: http::Uri) -> Result<BlockId, String> {
-        let client = Arc::new(GrpcConnector::new(uri));
-        let mut client = client
+    pub async fn get_latest_block(self) -> Result<BlockId, String> {
+        if let Some(node) = self.bitcoinzd() {
+            return node?.get_latest_block().await;
+        }
+
+        let mut client = self
             .get_client()
             .await
             .map_err(|e| format!("Error getting client: {:?}", e))?;
@@ -448,10 +718,38 @@ impl GrpcConnector {
         Ok(response.into_inner())
     }
 
-    pub async fn send_transaction(uri: http::Uri, tx_bytes: Box<[u8]>) -> Result<String, String> {
-        info!("Sending transaction to lightwalletd server: {}", uri);
-        let client = Arc::new(GrpcConnector::new(uri));
-        let mut client = client
+    // The height and hash of the server's block at `height`
+    pub async fn get_block_id(self, height: u64) -> Result<BlockId, String> {
+        if let Some(node) = self.bitcoinzd() {
+            return node?.get_block_id(height).await;
+        }
+
+        let mut client = self
+            .get_client()
+            .await
+            .map_err(|e| format!("Error getting client: {:?}", e))?;
+
+        let request = Request::new(BlockId { height, hash: vec![] });
+
+        let cb = client
+            .get_block(request)
+            .await
+            .map_err(|e| format!("Error with response: {:?}", e))?
+            .into_inner();
+
+        Ok(BlockId {
+            height: cb.height,
+            hash: cb.hash,
+        })
+    }
+
+    pub async fn send_transaction(self, tx_bytes: Box<[u8]>) -> Result<String, String> {
+        if let Some(node) = self.bitcoinzd() {
+            return node?.send_transaction(tx_bytes).await;
+        }
+
+        info!("Sending transaction to lightwalletd server: {}", self.uri);
+        let mut client = self
             .get_client()
             .await
             .map_err(|e| {
@@ -498,3 +796,150 @@ impl GrpcConnector {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::Duration;
+
+    use super::{ConnectOptions, FailureKind, GrpcConnector, RetryPolicy};
+
+    #[test]
+    fn classifying_errors() {
+        for e in [
+            "Error getting client: tonic::transport::Error(Transport, ConnectError(\"tcp connect error\"))",
+            "Error with response: Status { code: Cancelled, message: \"Timeout expired\" }",
+            "status: Unavailable, message: \"error trying to connect\"",
+            "status: ResourceExhausted, message: \"too many requests\"",
+            "Error calling bitcoinzd getblockcount: timed out",
+        ] {
+            assert!(GrpcConnector::is_transient_error(e), "{}", e);
+            assert_eq!(FailureKind::of(e), FailureKind::Transient);
+        }
+
+        for e in [
+            "Error getting client: Custom { kind: InvalidData, error: \"Certificate pin mismatch for localhost\" }",
+            "status: InvalidArgument, message: \"bad block range\"",
+            "Server's latest block(10) is behind ours(20)",
+            "bitcoinzd error for getblock: Block not found",
+        ] {
+            assert!(!GrpcConnector::is_transient_error(e), "{}", e);
+            assert_eq!(FailureKind::of(e), FailureKind::Permanent);
+        }
+
+        // Overloaded servers are worth retrying, but not on another server
+        assert!(!GrpcConnector::is_transport_error("status: ResourceExhausted"));
+
+        // A retried send that the server already got
+        for e in [
+            "Error: SendResponse { error_code: -26, error_message: \"txn-already-in-mempool\" }",
+            "Send Error: bitcoinzd error for sendrawtransaction: transaction already in block chain",
+        ] {
+            assert!(GrpcConnector::is_already_sent(e), "{}", e);
+        }
+        assert!(!GrpcConnector::is_already_sent(
+            "Error: SendResponse { error_code: -26, error_message: \"bad-txns-inputs-spent\" }"
+        ));
+
+        // Tagging is kept when an error is passed on
+        let tagged = FailureKind::tag("status: Unavailable".to_string());
+        assert!(tagged.starts_with("Transient error"));
+        assert_eq!(FailureKind::tag(tagged.clone()), tagged);
+        assert_eq!(FailureKind::of(&tagged), FailureKind::Transient);
+
+        let tagged = FailureKind::tag("Block not found".to_string());
+        assert!(tagged.starts_with("Permanent error"));
+        assert_eq!(
+            FailureKind::of(&FailureKind::tag(tagged.clone())),
+            FailureKind::Permanent
+        );
+    }
+
+    #[tokio::test]
+    async fn uri_without_host() {
+        let server = GrpcConnector::new("/no/host".parse().unwrap(), ConnectOptions::default());
+        let e = server.get_info().await.unwrap_err();
+        assert!(e.contains("No host in /no/host"), "{}", e);
+    }
+
+    #[test]
+    fn backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+        };
+
+        for _ in 0..100 {
+            // Between half and all of the doubled delay
+            let d = policy.backoff(0);
+            assert!(
+                d >= Duration::from_millis(50) && d <= Duration::from_millis(100),
+                "{:?}",
+                d
+            );
+            let d = policy.backoff(2);
+            assert!(
+                d >= Duration::from_millis(200) && d <= Duration::from_millis(400),
+                "{:?}",
+                d
+            );
+
+            // Capped at max_delay, even for a lot of retries
+            let d = policy.backoff(5);
+            assert!(
+                d >= Duration::from_millis(500) && d <= Duration::from_secs(1),
+                "{:?}",
+                d
+            );
+            assert!(policy.backoff(u32::MAX) <= Duration::from_secs(1));
+        }
+
+        // Not every client waits the same time
+        let delays = (0..20).map(|_| policy.backoff(3)).collect::<Vec<_>>();
+        assert!(delays.iter().any(|d| *d != delays[0]));
+    }
+
+    #[tokio::test]
+    async fn retrying() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+        };
+
+        // Transient errors are retried until it works
+        let calls = AtomicU32::new(0);
+        let r = policy
+            .retry(|| async {
+                match calls.fetch_add(1, Ordering::SeqCst) {
+                    0 | 1 => Err("status: Unavailable".to_string()),
+                    n => Ok(n),
+                }
+            })
+            .await;
+        assert_eq!(r, Ok(2));
+
+        // ... but not more than max_attempts times
+        let calls = AtomicU32::new(0);
+        let r: Result<(), _> = policy
+            .retry(|| async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err("Timeout expired".to_string())
+            })
+            .await;
+        assert_eq!(r, Err("Timeout expired".to_string()));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // Permanent errors aren't retried at all
+        let calls = AtomicU32::new(0);
+        let r: Result<(), _> = policy
+            .retry(|| async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err("status: InvalidArgument".to_string())
+            })
+            .await;
+        assert!(r.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,10 +1,14 @@
 #[macro_use]
 extern crate rust_embed;
 
+pub mod bitcoinzd_connector;
 pub mod blaze;
 pub mod commands;
 pub mod compact_formats;
 pub mod grpc_connector;
+pub mod proxy;
+pub mod server_pool;
+pub mod tls;
 pub mod lightclient;
 pub mod lightwallet;
 pub mod bitcoinz_params;
@@ -1,14 +1,25 @@
+use self::auto_lock::AutoLock;
 use self::lightclient_config::LightClientConfig;
+use self::mempool_conflicts::{MempoolConflicts, MEMPOOL_CONFLICT_EXPIRY};
+use self::wallet_events::{AutoLockReason, ReorgRecovery, WalletEvent, WalletEvents, WalletSnapshot};
 use crate::{
     blaze::{
-        block_witness_data::BlockAndWitnessData, fetch_compact_blocks::FetchCompactBlocks,
-        fetch_full_tx::FetchFullTxns, fetch_taddr_txns::FetchTaddrTxns, sync_status::SyncStatus,
-        syncdata::BlazeSyncData, trial_decryptions::TrialDecryptions, update_notes::UpdateNotes,
+        block_witness_data::{BlockAndWitnessData, ForkPoint},
+        fetch_compact_blocks::FetchCompactBlocks,
+        fetch_full_tx::FetchFullTxns,
+        fetch_taddr_txns::FetchTaddrTxns,
+        sync_status::SyncStatus,
+        syncdata::BlazeSyncData,
+        trial_decryptions::TrialDecryptions,
+        update_notes::UpdateNotes,
     },
     compact_formats::RawTransaction,
-    grpc_connector::GrpcConnector,
-    lightclient::lightclient_config::MAX_REORG,
-    lightwallet::{self, data::WalletTx, message::Message, now, LightWallet, MAX_CHECKPOINTS, MERKLE_DEPTH},
+    grpc_connector::{self, FailureKind, GrpcConnector},
+    lightclient::lightclient_config::{MAX_REORG, SERVER_HEALTH_CHECK_INTERVAL, WALLET_BACKUPS},
+    lightwallet::{
+        self, data::WalletTx, keys::Keys, message::Message, now, LightWallet, MAX_CHECKPOINTS, MERKLE_DEPTH,
+    },
+    tls::TlsOptions,
 };
 use futures::{stream::FuturesUnordered, StreamExt};
 use incrementalmerkletree::bridgetree::BridgeTree;
@@ -19,15 +30,15 @@ use std::{
     cmp,
     collections::HashSet,
     fs::File,
-    io::{self, BufReader, Error, ErrorKind, Read, Write},
-    path::Path,
+    io::{self, Error, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
     sync::Arc,
     time::Duration,
 };
 use tokio::{
     join,
     runtime::Runtime,
-    sync::{mpsc::unbounded_channel, oneshot, Mutex, RwLock},
+    sync::{broadcast, mpsc::unbounded_channel, oneshot, Mutex, RwLock},
     task::yield_now,
     time::sleep,
 };
@@ -41,8 +52,13 @@ use zcash_primitives::{
 };
 use zcash_proofs::prover::LocalTxProver;
 
+pub mod auto_lock;
 pub(crate) mod checkpoints;
+pub mod consistency;
 pub mod lightclient_config;
+pub mod mempool_conflicts;
+pub mod wallet_events;
+pub mod wallet_file;
 
 #[derive(Clone, Debug)]
 pub struct WalletStatus {
@@ -67,6 +83,15 @@ pub struct LightClient<P> {
 
     mempool_monitor: std::sync::RwLock<Option<std::thread::JoinHandle<()>>>,
 
+    // Inputs spent by the txns seen in the mempool, to tell when a payment is double spent
+    mempool_conflicts: Arc<RwLock<MempoolConflicts>>,
+
+    // When the spending keys were last used, to lock them again after the wallet options' limits
+    auto_lock: Arc<RwLock<AutoLock>>,
+
+    // The backup the wallet was read from, if the wallet file itself was damaged
+    recovered_from: Option<PathBuf>,
+
     sync_lock: Mutex<()>,
 
     bsync_data: Arc<RwLock<BlazeSyncData>>,
@@ -84,9 +109,12 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
         }
 
         let l = LightClient {
-            wallet: LightWallet::new(config.clone(), seed_phrase, height, 1, 1)?,
+            wallet: LightWallet::new(config.clone(), seed_phrase, String::new(), height, 1, 1)?,
             config: config.clone(),
             mempool_monitor: std::sync::RwLock::new(None),
+            mempool_conflicts: Arc::new(RwLock::new(MempoolConflicts::default())),
+            auto_lock: Arc::new(RwLock::new(AutoLock::default())),
+            recovered_from: None,
             bsync_data: Arc::new(RwLock::new(BlazeSyncData::new(&config))),
             sync_lock: Mutex::new(()),
         };
@@ -220,9 +248,19 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
     ) -> io::Result<Self> {
         Runtime::new().unwrap().block_on(async move {
             let l = LightClient {
-                wallet: LightWallet::new(config.clone(), None, latest_block, num_zaddrs, num_oaddrs)?,
+                wallet: LightWallet::new(
+                    config.clone(),
+                    None,
+                    String::new(),
+                    latest_block,
+                    num_zaddrs,
+                    num_oaddrs,
+                )?,
                 config: config.clone(),
                 mempool_monitor: std::sync::RwLock::new(None),
+                mempool_conflicts: Arc::new(RwLock::new(MempoolConflicts::default())),
+                auto_lock: Arc::new(RwLock::new(AutoLock::default())),
+                recovered_from: None,
                 sync_lock: Mutex::new(()),
                 bsync_data: Arc::new(RwLock::new(BlazeSyncData::new(&config))),
             };
@@ -262,6 +300,18 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
         config: &LightClientConfig<P>,
         birthday: u64,
         overwrite: bool,
+    ) -> io::Result<Self> {
+        Self::new_from_phrase_with_passphrase(seed_phrase, String::new(), config, birthday, overwrite)
+    }
+
+    /// Restore a wallet from a BIP39 seed phrase of any length and language, whose keys were derived with `passphrase`
+    /// (sometimes called the 25th word). The passphrase is stored in the wallet, and encrypted along with the seed.
+    pub fn new_from_phrase_with_passphrase(
+        seed_phrase: String,
+        passphrase: String,
+        config: &LightClientConfig<P>,
+        birthday: u64,
+        overwrite: bool,
     ) -> io::Result<Self> {
         #[cfg(all(not(target_os = "ios"), not(target_os = "android")))]
         {
@@ -289,9 +339,12 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
         } else {
             Runtime::new().unwrap().block_on(async move {
                 let l = LightClient {
-                    wallet: LightWallet::new(config.clone(), Some(seed_phrase), birthday, 1, 1)?,
+                    wallet: LightWallet::new(config.clone(), Some(seed_phrase), passphrase, birthday, 1, 1)?,
                     config: config.clone(),
                     mempool_monitor: std::sync::RwLock::new(None),
+                    mempool_conflicts: Arc::new(RwLock::new(MempoolConflicts::default())),
+                    auto_lock: Arc::new(RwLock::new(AutoLock::default())),
+                    recovered_from: None,
                     sync_lock: Mutex::new(()),
                     bsync_data: Arc::new(RwLock::new(BlazeSyncData::new(&config))),
                 };
@@ -312,14 +365,39 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
         lr
     }
 
-    pub fn read_from_buffer<R: Read>(config: &LightClientConfig<P>, mut reader: R) -> io::Result<Self> {
+    pub fn read_from_buffer<R: Read>(config: &LightClientConfig<P>, reader: R) -> io::Result<Self> {
+        Self::read_from_buffer_with(config, reader, None)
+    }
+
+    /// Read a wallet saved with `do_save_to_buffer` after its whole file was encrypted. It is only unlocked for viewing,
+    /// like with `read_from_disk_with_password`.
+    pub fn read_from_buffer_with_password<R: Read>(
+        config: &LightClientConfig<P>,
+        reader: R,
+        passwd: &str,
+    ) -> io::Result<Self> {
+        Self::read_from_buffer_with(config, reader, Some(passwd))
+    }
+
+    fn read_from_buffer_with<R: Read>(
+        config: &LightClientConfig<P>,
+        mut reader: R,
+        passwd: Option<&str>,
+    ) -> io::Result<Self> {
         let l = Runtime::new().unwrap().block_on(async move {
-            let wallet = LightWallet::read(&mut reader, config).await?;
+            let mut bytes = vec![];
+            reader.read_to_end(&mut bytes)?;
+            let (wallet_bytes, file_key) = wallet_file::open_with_password(&bytes, passwd)?;
+            let wallet = LightWallet::read(&wallet_bytes[..], config).await?;
+            wallet.set_file_key(file_key).await;
 
             let lc = LightClient {
                 wallet,
                 config: config.clone(),
                 mempool_monitor: std::sync::RwLock::new(None),
+                mempool_conflicts: Arc::new(RwLock::new(MempoolConflicts::default())),
+                auto_lock: Arc::new(RwLock::new(AutoLock::default())),
+                recovered_from: None,
                 sync_lock: Mutex::new(()),
                 bsync_data: Arc::new(RwLock::new(BlazeSyncData::new(&config))),
             };
@@ -334,6 +412,16 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
     }
 
     pub fn read_from_disk(config: &LightClientConfig<P>) -> io::Result<Self> {
+        Self::read_from_disk_with(config, None)
+    }
+
+    /// Read a wallet whose whole file is encrypted with `passwd`. This only unlocks it for viewing: the addresses, txns
+    /// and balances can be read, but the spending keys stay encrypted until `unlock` is called with the same password.
+    pub fn read_from_disk_with_password(config: &LightClientConfig<P>, passwd: &str) -> io::Result<Self> {
+        Self::read_from_disk_with(config, Some(passwd))
+    }
+
+    fn read_from_disk_with(config: &LightClientConfig<P>, passwd: Option<&str>) -> io::Result<Self> {
         let wallet_path = if config.wallet_exists() {
             config.get_wallet_path()
         } else {
@@ -344,14 +432,15 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
         };
 
         let l = Runtime::new().unwrap().block_on(async move {
-            let mut file_buffer = BufReader::new(File::open(wallet_path)?);
-
-            let wallet = LightWallet::read(&mut file_buffer, config).await?;
+            let (wallet, recovered_from) = Self::read_wallet_or_backup(config, &wallet_path, passwd).await?;
 
             let lc = LightClient {
                 wallet: wallet,
                 config: config.clone(),
                 mempool_monitor: std::sync::RwLock::new(None),
+                mempool_conflicts: Arc::new(RwLock::new(MempoolConflicts::default())),
+                auto_lock: Arc::new(RwLock::new(AutoLock::default())),
+                recovered_from,
                 sync_lock: Mutex::new(()),
                 bsync_data: Arc::new(RwLock::new(BlazeSyncData::new(&config))),
             };
@@ -365,6 +454,74 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
         l
     }
 
+    // Read the wallet at `path`. If it is damaged, fall back to the most recent of its backups that can be read, which
+    // then replaces it. Returns the backup that was used, if any. Only a wallet that is truncated, fails its checksum
+    // or can't be parsed is damaged. Any other error, like a wrong password, a wallet from a newer version or one
+    // that can't be read from the disk, is returned as it is, and the wallet is left alone.
+    async fn read_wallet_or_backup(
+        config: &LightClientConfig<P>,
+        path: &Path,
+        passwd: Option<&str>,
+    ) -> io::Result<(LightWallet<P>, Option<PathBuf>)> {
+        let candidates = wallet_file::candidates(path, WALLET_BACKUPS);
+
+        let mut wallet_err = None;
+        for candidate in &candidates {
+            // The error, and whether it is damage
+            let read = match wallet_file::read_with_password(candidate, passwd) {
+                Ok((bytes, file_key)) => match LightWallet::read(&bytes[..], config).await {
+                    Ok(wallet) => {
+                        wallet.set_file_key(file_key).await;
+                        Ok(wallet)
+                    }
+                    Err(e) => {
+                        let damaged = matches!(e.kind(), ErrorKind::UnexpectedEof | ErrorKind::InvalidData)
+                            && !LightWallet::<P>::is_newer_version(&bytes);
+                        Err((e, damaged))
+                    }
+                },
+                Err(e) if e.kind() == ErrorKind::PermissionDenied => return Err(e),
+                Err(e) => {
+                    let damaged = wallet_file::is_corrupted(&e);
+                    Err((e, damaged))
+                }
+            };
+
+            match read {
+                Ok(wallet) if candidate.as_path() == path => return Ok((wallet, None)),
+                Ok(wallet) => {
+                    let aside = wallet_file::restore(path, candidate)?;
+                    warn!(
+                        "Recovered the wallet from backup {}. The damaged wallet was moved to {}",
+                        candidate.display(),
+                        aside.display()
+                    );
+                    return Ok((wallet, Some(candidate.clone())));
+                }
+                Err((e, false)) if candidate.as_path() == path => return Err(e),
+                Err((e, _)) => {
+                    error!("Couldn't read wallet {}: {}", candidate.display(), e);
+                    wallet_err.get_or_insert(e);
+                }
+            }
+        }
+
+        let e = wallet_err.unwrap();
+        if candidates.len() > 1 {
+            Err(Error::new(
+                e.kind(),
+                format!("{}, and none of its {} backups could be read", e, candidates.len() - 1),
+            ))
+        } else {
+            Err(e)
+        }
+    }
+
+    /// The backup the wallet was read from by `read_from_disk`, because the wallet file itself was damaged
+    pub fn recovered_from(&self) -> Option<&Path> {
+        self.recovered_from.as_deref()
+    }
+
     pub fn init_logging(&self) -> io::Result<()> {
         // Configure logging first.
         let log_config = self.config.get_log_config()?;
@@ -373,7 +530,9 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
         Ok(())
     }
 
-    // Export private keys
+    /// Export the private keys of all the addresses, or only of `addr`. The wallet wipes its own copies of the keys,
+    /// but not the ones in the returned JSON: its strings can't be wiped, and once the keys are handed to the caller,
+    /// and through the API to the app, they are out of the wallet's reach. Drop them as soon as they have been shown.
     pub async fn do_export(&self, addr: Option<String>) -> Result<JsonValue, &str> {
         if !self.wallet.is_unlocked_for_spending().await {
             error!("Wallet is locked");
@@ -394,7 +553,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
             .map(|(addr, pk, vk)| {
                 object! {
                     "address"     => addr.clone(),
-                    "private_key" => pk.clone(),
+                    "private_key" => pk.as_str(),
                     "viewing_key" => vk.clone(),
                 }
             })
@@ -415,14 +574,13 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
             .map(|(addr, sk)| {
                 object! {
                     "address"     => addr.clone(),
-                    "private_key" => sk.clone(),
+                    "private_key" => sk.as_str(),
                 }
             })
             .collect::<Vec<JsonValue>>();
 
-        let mut all_keys = vec![];
-        all_keys.extend_from_slice(&z_keys);
-        all_keys.extend_from_slice(&t_keys);
+        let mut all_keys = z_keys;
+        all_keys.extend(t_keys);
 
         Ok(all_keys.into())
     }
@@ -511,7 +669,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
             {
                 if self.wallet.is_encrypted().await && self.wallet.is_unlocked_for_spending().await {
                     match self.wallet.lock().await {
-                        Ok(_) => {}
+                        Ok(_) => self.auto_lock.write().await.locked(),
                         Err(e) => {
                             let err = format!("ERR: {}", e);
                             error!("{}", err);
@@ -521,32 +679,63 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
                 }
             }
 
-            {
-                // Prevent any overlapping syncs during save, and don't save in the middle of a sync
-                let _lock = if grab_lock {
-                    Some(self.sync_lock.lock().await)
-                } else {
-                    None
+            self.save_wallet(grab_lock).await
+        } else {
+            // On ios and android just return OK
+            Ok(())
+        }
+    }
+
+    // Save the wallet as it is, without locking it first
+    async fn save_wallet(&self, grab_lock: bool) -> Result<(), String> {
+        // Prevent any overlapping syncs during save, and don't save in the middle of a sync
+        let _lock = if grab_lock {
+            Some(self.sync_lock.lock().await)
+        } else {
+            None
+        };
+
+        // Taken before the wallet is written, so a change made during the save is cleaned up by the next one
+        let encryption_changed = self.wallet.take_encryption_changed();
+
+        let mut wallet_bytes = vec![];
+        let saved = match self.wallet.write(&mut wallet_bytes).await {
+            Ok(_) => {
+                let path = self.config.get_wallet_path();
+                let file_key = self.wallet.file_key().await;
+                let sealed = match &file_key {
+                    Some(key) => wallet_file::seal_encrypted(&wallet_bytes, key),
+                    None => wallet_file::seal(&wallet_bytes),
                 };
 
-                let mut wallet_bytes = vec![];
-                match self.wallet.write(&mut wallet_bytes).await {
-                    Ok(_) => {
-                        let mut file = File::create(self.config.get_wallet_path()).unwrap();
-                        file.write_all(&wallet_bytes).map_err(|e| format!("{}", e))?;
-                        Ok(())
-                    }
-                    Err(e) => {
-                        let err = format!("ERR: {}", e);
+                // The copies from before the keys' encryption changed have them the old way
+                wallet_file::save(&path, &sealed, WALLET_BACKUPS)
+                    .and_then(|_| {
+                        if encryption_changed {
+                            wallet_file::remove_copies(&path, WALLET_BACKUPS)
+                        } else if file_key.is_some() {
+                            wallet_file::remove_plaintext_copies(&path, WALLET_BACKUPS)
+                        } else {
+                            Ok(())
+                        }
+                    })
+                    .map_err(|e| {
+                        let err = format!("Couldn't save wallet to {}: {}", path.display(), e);
                         error!("{}", err);
-                        Err(e.to_string())
-                    }
-                }
+                        err
+                    })
             }
-        } else {
-            // On ios and android just return OK
-            Ok(())
+            Err(e) => {
+                let err = format!("ERR: {}", e);
+                error!("{}", err);
+                Err(e.to_string())
+            }
+        };
+
+        if saved.is_err() && encryption_changed {
+            self.wallet.set_encryption_changed();
         }
+        saved
     }
 
     pub fn do_save_to_buffer_sync(&self) -> Result<Vec<u8>, String> {
@@ -560,7 +749,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
         {
             if self.wallet.is_encrypted().await && self.wallet.is_unlocked_for_spending().await {
                 match self.wallet.lock().await {
-                    Ok(_) => {}
+                    Ok(_) => self.auto_lock.write().await.locked(),
                     Err(e) => {
                         let err = format!("ERR: {}", e);
                         error!("{}", err);
@@ -572,7 +761,10 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
 
         let mut buffer: Vec<u8> = vec![];
         match self.wallet.write(&mut buffer).await {
-            Ok(_) => Ok(buffer),
+            Ok(_) => match self.wallet.file_key().await {
+                Some(key) => Ok(wallet_file::seal_encrypted(&buffer, &key)),
+                None => Ok(wallet_file::seal(&buffer)),
+            },
             Err(e) => {
                 let err = format!("ERR: {}", e);
                 error!("{}", err);
@@ -582,7 +774,12 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
     }
 
     pub fn get_server_uri(&self) -> http::Uri {
-        self.config.server.clone()
+        self.config.get_server()
+    }
+
+    /// Whether the client was opened without a server, and none could be reached since
+    pub fn is_offline(&self) -> bool {
+        self.config.is_offline()
     }
 
     pub async fn do_zec_price(&self) -> String {
@@ -590,6 +787,10 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
 
         // If there is no price, try to fetch it first.
         if price.zec_price.is_none() {
+            if let Err(e) = self.config.ensure_online().await {
+                return format!("Error: {}", e);
+            }
+
             self.update_current_price().await;
             price = self.wallet.price.read().await.clone();
         }
@@ -609,7 +810,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
     }
 
     pub async fn do_info(&self) -> String {
-        match GrpcConnector::get_info(self.get_server_uri()).await {
+        match self.config.with_failover(GrpcConnector::get_info).await {
             Ok(i) => {
                 let o = object! {
                     "version" => i.version,
@@ -621,7 +822,14 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
                     "chain_name" => i.chain_name,
                     "sapling_activation_height" => i.sapling_activation_height,
                     "consensus_branch_id" => i.consensus_branch_id,
-                    "latest_block_height" => i.block_height
+                    "latest_block_height" => i.block_height,
+                    "servers" => self.config.servers.to_json(),
+                    "proxy" => self.config.connect_options.proxy.as_ref().map(|p| p.to_uri_string()),
+                    "connection" => grpc_connector::get_connection_settings().to_json(),
+                    "tls" => self.config.connect_options.tls.as_ref().map_or_else(
+                        || TlsOptions::default().to_json(),
+                        |tls| tls.options().to_json()
+                    ),
                 };
                 o.pretty(2)
             }
@@ -629,6 +837,112 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
         }
     }
 
+    /// Cross-check what the wallet synced against other servers, in addition to the wallet's own server. `servers` is
+    /// a comma separated list, and an empty list turns the check off.
+    pub fn set_server_verification(&self, servers: &str, sample_blocks: usize, refuse_send: bool) {
+        let servers = servers
+            .split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .map(|s| LightClientConfig::<P>::get_server_or_default(Some(s.to_string())))
+            .collect();
+
+        self.config.consistency.configure(servers, sample_blocks, refuse_send);
+    }
+
+    /// Compare the wallet's blocks and sapling tree against the servers in the consistency check, and send the
+    /// result to the event stream
+    pub async fn do_verify_servers(&self) -> JsonValue {
+        if let Err(e) = self.config.ensure_online().await {
+            return object! { "error" => e.to_string() };
+        }
+
+        let check = &self.config.consistency;
+        let blocks = self.wallet.blocks.read().await.clone();
+        let verified_tree = self.wallet.verified_tree.read().await.clone();
+
+        let divergences = check.run(&blocks, verified_tree, &self.config.connect_options).await;
+
+        let events = self.bsync_data.read().await.events.clone();
+        if divergences.is_empty() {
+            events.send(WalletEvent::ServersConsistent {
+                servers: check.servers().len(),
+                height: self.wallet.last_scanned_height().await,
+            });
+        }
+        for divergence in &divergences {
+            warn!("Servers disagree: {:?}", divergence);
+            events.send(WalletEvent::ServerDivergence {
+                divergence: divergence.clone(),
+            });
+        }
+
+        object! {
+            "consistent" => divergences.is_empty(),
+            "servers" => check.servers().iter().map(|s| s.to_string()).collect::<Vec<_>>(),
+            "divergences" => JsonValue::Array(divergences.iter().map(|d| d.to_json()).collect()),
+        }
+    }
+
+    /// Fetch checkpoints every `interval` blocks from `start` up to MAX_REORG blocks below the tip, from this
+    /// client's server and all the `extra_servers`, and write them to `output`. A `.json` output is the file format
+    /// that can be loaded at runtime, anything else gets the regenerated main checkpoints module.
+    pub async fn do_generate_checkpoints(
+        &self,
+        start: u64,
+        interval: u64,
+        output: &str,
+        extra_servers: Vec<http::Uri>,
+    ) -> Result<JsonValue, String> {
+        let mut servers = vec![self.get_server_uri()];
+        servers.extend(extra_servers);
+
+        let latest = self
+            .config
+            .connector(self.get_server_uri())
+            .get_latest_block()
+            .await?
+            .height;
+        let end = latest.saturating_sub(MAX_REORG as u64);
+        if start > end {
+            return Err(format!("Start height {} is above the last safe height {}", start, end));
+        }
+
+        let is_main = matches!(&self.config.chain_name[..], "zs" | "main");
+        let connectors = servers
+            .iter()
+            .map(|s| self.config.connector(s.clone()))
+            .collect::<Vec<_>>();
+        let fetched = checkpoints::fetch_checkpoints(&connectors, start, end, interval).await?;
+
+        // Even if they're not written out, the fetched checkpoints have to agree with the built-in ones
+        let builtin = if is_main {
+            checkpoints::get_all_main_checkpoints()
+        } else {
+            vec![]
+        };
+        let merged = checkpoints::merge_checkpoints(&builtin, fetched.clone())?;
+
+        let (contents, written) = if output.ends_with(".json") {
+            (checkpoints::checkpoints_to_json(&fetched).pretty(2), fetched.len())
+        } else if is_main {
+            (checkpoints::checkpoints_module(&merged), merged.len())
+        } else {
+            return Err("The checkpoints module is only for the main chain. Write a .json file instead".to_string());
+        };
+        std::fs::write(output, contents).map_err(|e| format!("Couldn't write {}: {}", output, e))?;
+
+        Ok(object! {
+            "result" => "success",
+            "output" => output,
+            "servers" => servers.iter().map(|s| s.to_string()).collect::<Vec<_>>(),
+            "start_height" => start,
+            "end_height" => end,
+            "fetched" => fetched.len(),
+            "written" => written,
+        })
+    }
+
     pub async fn do_send_progress(&self) -> Result<JsonValue, String> {
         let progress = self.wallet.get_send_progress().await;
 
@@ -648,6 +962,8 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
             .block_on(async move { self.do_seed_phrase().await })
     }
 
+    /// The seed phrase and the wallet's birthday. Like the keys from `do_export`, the phrase in the returned JSON isn't
+    /// wiped, since it is out of the wallet's reach once it is handed to the caller.
     pub async fn do_seed_phrase(&self) -> Result<JsonValue, &str> {
         if !self.wallet.is_unlocked_for_spending().await {
             error!("Wallet is locked");
@@ -655,11 +971,32 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
         }
 
         Ok(object! {
-            "seed"     => self.wallet.keys().read().await.get_seed_phrase(),
+            "seed"     => self.wallet.keys().read().await.get_seed_phrase().as_str(),
             "birthday" => self.wallet.get_birthday().await
         })
     }
 
+    /// Check that the user wrote the seed phrase down correctly. Only whether it matches is returned, never anything
+    /// derived from `phrase`.
+    pub async fn do_verify_seed(&self, phrase: String) -> Result<JsonValue, String> {
+        let matches = self.wallet.keys().read().await.verify_seed_phrase(&phrase)?;
+
+        Ok(object! {
+            "result" => if matches { "match" } else { "no-match" }
+        })
+    }
+
+    /// Split the seed into SLIP-39 shares, any `threshold` of which can be passed to `new_from_phrase` to restore
+    pub async fn do_split_seed(&self, threshold: u8, count: u8) -> Result<JsonValue, String> {
+        let shares = self.wallet.keys().read().await.split_seed(threshold, count)?;
+
+        Ok(object! {
+            "threshold" => threshold,
+            "shares"    => shares,
+            "birthday"  => self.wallet.get_birthday().await
+        })
+    }
+
     // Return a list of all notes, spent and unspent
     pub async fn do_list_notes(&self, all_notes: bool) -> JsonValue {
         let mut unspent_notes: Vec<JsonValue> = vec![];
@@ -862,12 +1199,136 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
 
     pub async fn do_encryption_status(&self) -> JsonValue {
         object! {
-            "encrypted" => self.wallet.is_encrypted().await,
-            "locked"    => !self.wallet.is_unlocked_for_spending().await
+            "encrypted"      => self.wallet.is_encrypted().await,
+            "locked"         => !self.wallet.is_unlocked_for_spending().await,
+            "file_encrypted" => self.wallet.is_file_encrypted().await
+        }
+    }
+
+    /// Re-encrypt the wallet with a new password, and save it right away so the old password stops working. The
+    /// backups, which are still on the old password, are deleted. The wallet stays locked or unlocked like it was.
+    pub async fn do_change_password(&self, old_passwd: String, new_passwd: String) -> Result<(), String> {
+        self.wallet
+            .change_password(old_passwd, new_passwd)
+            .await
+            .map_err(|e| e.to_string())?;
+        self.save_wallet(true).await
+    }
+
+    /// Encrypt the whole wallet file with the wallet's password, and save it right away so nothing is left in plaintext
+    pub async fn do_encrypt_file(&self, passwd: String) -> Result<(), String> {
+        self.wallet.encrypt_file(passwd).await.map_err(|e| e.to_string())?;
+        self.do_save(true).await
+    }
+
+    /// Go back to only encrypting the spending keys in the wallet file
+    pub async fn do_remove_file_encryption(&self, passwd: String) -> Result<(), String> {
+        self.wallet
+            .remove_file_encryption(passwd)
+            .await
+            .map_err(|e| e.to_string())?;
+        self.do_save(true).await
+    }
+
+    /// Unlock the spending keys. If the `auto_lock_timeout` option is set, they are locked again once they haven't
+    /// been used for that long.
+    pub async fn do_unlock(&self, passwd: String) -> Result<(), String> {
+        self.wallet.unlock(passwd).await.map_err(|e| e.to_string())?;
+
+        self.auto_lock.write().await.unlocked();
+        self.start_auto_lock_timer().await;
+
+        Ok(())
+    }
+
+    pub async fn do_lock(&self) -> Result<(), String> {
+        self.wallet.lock().await.map_err(|e| e.to_string())?;
+        self.auto_lock.write().await.locked();
+
+        Ok(())
+    }
+
+    /// Set the `auto_lock_timeout` option. It applies to an unlocked wallet right away, counting from when its spending
+    /// keys were last used, and 0 stops its timer.
+    pub async fn set_auto_lock_timeout(&self, secs: u64) {
+        self.wallet.set_auto_lock_timeout(secs).await;
+        self.start_auto_lock_timer().await;
+    }
+
+    // Start the timer that locks the wallet once it has been idle for the `auto_lock_timeout`, in place of the one
+    // that was running, if any
+    async fn start_auto_lock_timer(&self) {
+        let generation = self.auto_lock.write().await.new_timer();
+        let timeout = self.wallet.wallet_options.read().await.auto_lock_timeout;
+        if timeout == 0 {
+            return;
+        }
+
+        let auto_lock = self.auto_lock.clone();
+        let keys = self.wallet.keys();
+        let events = self.bsync_data.read().await.events.clone();
+
+        tokio::spawn(async move {
+            let timeout = Duration::from_secs(timeout);
+            loop {
+                let mut state = auto_lock.write().await;
+                match state.remaining(generation, timeout) {
+                    // Locked, or replaced by a newer timer
+                    None => return,
+                    Some(remaining) if remaining > Duration::ZERO => {
+                        drop(state);
+                        sleep(remaining).await;
+                    }
+                    Some(_) => {
+                        if Self::auto_lock_keys(&keys, &events, AutoLockReason::Idle).await {
+                            state.locked();
+                        }
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
+    // The spending keys are about to be used to send a txn. The idle timer doesn't lock them until
+    // `auto_lock_after_send` is called for this send.
+    async fn auto_lock_before_send(&self) {
+        self.auto_lock.write().await.using();
+    }
+
+    // The spending keys were just used to send a txn, which was sent if `sent`. This restarts the idle timer, and
+    // locks the wallet once it sent as many txns as the `auto_lock_sends` option allows.
+    async fn auto_lock_after_send(&self, sent: bool) {
+        let mut auto_lock = self.auto_lock.write().await;
+        let sends = auto_lock.used(sent);
+
+        let max_sends = self.wallet.wallet_options.read().await.auto_lock_sends;
+        if sent && max_sends > 0 && sends >= max_sends {
+            let events = self.bsync_data.read().await.events.clone();
+            if Self::auto_lock_keys(&self.wallet.keys(), &events, AutoLockReason::Sends).await {
+                auto_lock.locked();
+            }
+        }
+    }
+
+    // Returns whether the keys are locked now
+    async fn auto_lock_keys(keys: &Arc<RwLock<Keys<P>>>, events: &WalletEvents, reason: AutoLockReason) -> bool {
+        match keys.write().await.lock() {
+            Ok(()) => {
+                info!("Locked the wallet automatically: {:?}", reason);
+                events.send(WalletEvent::AutoLocked { reason });
+                true
+            }
+            Err(e) => {
+                warn!("Couldn't lock the wallet automatically: {}", e);
+                false
+            }
         }
     }
 
     pub async fn do_list_transactions(&self, include_memo_hex: bool) -> JsonValue {
+        let mempool_conflicts = self.mempool_conflicts.read().await;
+
         // Create a list of TransactionItems from wallet txns
         let mut tx_list = self
             .wallet
@@ -1008,6 +1469,26 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
                     })
                 }
 
+                // How much to trust unconfirmed txns, since their inputs might be double spent
+                let risk = mempool_conflicts.risk(&v.txid, v.unconfirmed);
+                let conflicts = mempool_conflicts.conflicts(&v.txid);
+                let replaced_by = mempool_conflicts.replaced_by(&v.txid);
+                for o in txns.iter_mut() {
+                    o.insert("mempool_risk", risk.as_str()).unwrap();
+                    if v.unconfirmed && !conflicts.is_empty() {
+                        o.insert(
+                            "conflicting_txids",
+                            conflicts.iter().map(|c| c.to_string()).collect::<Vec<_>>(),
+                        )
+                        .unwrap();
+                    }
+                    if v.unconfirmed {
+                        if let Some(r) = replaced_by {
+                            o.insert("replaced_by", r.to_string()).unwrap();
+                        }
+                    }
+                }
+
                 txns
             })
             .collect::<Vec<JsonValue>>();
@@ -1169,7 +1650,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
 
     async fn update_current_price(&self) {
         // Get the zec price from the server
-        match GrpcConnector::get_current_zec_price(self.get_server_uri()).await {
+        match self.config.with_failover(GrpcConnector::get_current_zec_price).await {
             Ok(p) => {
                 self.wallet.set_latest_zec_price(p.price).await;
             }
@@ -1201,32 +1682,34 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
 
         info!("Fetching historical prices for {} txids", txids_to_fetch.len());
 
-        let retry_count_increase =
-            match GrpcConnector::get_historical_zec_prices(self.get_server_uri(), txids_to_fetch, price.currency).await
-            {
-                Ok(prices) => {
-                    let mut any_failed = false;
-
-                    for (txid, p) in prices {
-                        match p {
-                            None => any_failed = true,
-                            Some(p) => {
-                                // Update the price
-                                // info!("Historical price at txid {} was {}", txid, p);
-                                self.wallet.txns.write().await.current.get_mut(&txid).unwrap().zec_price = Some(p);
-                            }
+        let retry_count_increase = match self
+            .config
+            .with_failover(|server| server.get_historical_zec_prices(txids_to_fetch.clone(), price.currency.clone()))
+            .await
+        {
+            Ok(prices) => {
+                let mut any_failed = false;
+
+                for (txid, p) in prices {
+                    match p {
+                        None => any_failed = true,
+                        Some(p) => {
+                            // Update the price
+                            // info!("Historical price at txid {} was {}", txid, p);
+                            self.wallet.txns.write().await.current.get_mut(&txid).unwrap().zec_price = Some(p);
                         }
                     }
+                }
 
-                    // If any of the txids failed, increase the retry_count by 1.
-                    if any_failed {
-                        1
-                    } else {
-                        0
-                    }
+                // If any of the txids failed, increase the retry_count by 1.
+                if any_failed {
+                    1
+                } else {
+                    0
                 }
-                Err(_) => 1,
-            };
+            }
+            Err(_) => 1,
+        };
 
         {
             let mut p = self.wallet.price.write().await;
@@ -1239,6 +1722,79 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
         self.bsync_data.read().await.sync_status.read().await.clone()
     }
 
+    /// Subscribe to the stream of wallet events (new mempool txns, confirmations, balance changes, reorgs...)
+    pub async fn subscribe_events(&self) -> broadcast::Receiver<WalletEvent> {
+        self.bsync_data.read().await.events.subscribe()
+    }
+
+    async fn wallet_snapshot(&self) -> WalletSnapshot {
+        let balances = [
+            self.wallet.zbalance(None).await,
+            self.wallet.verified_zbalance(None).await,
+            self.wallet.tbalance(None).await,
+            self.wallet.uabalance(None).await,
+        ];
+
+        WalletSnapshot::new(&*self.wallet.txns.read().await, balances)
+    }
+
+    // Send the events for everything that changed in the wallet since the `before` snapshot, and return the new snapshot
+    async fn send_wallet_change_events(&self, before: &WalletSnapshot) -> WalletSnapshot {
+        let after = self.wallet_snapshot().await;
+        let changes = before.diff(&after, &*self.wallet.txns.read().await);
+
+        let events = self.bsync_data.read().await.events.clone();
+        for event in changes {
+            events.send(event);
+        }
+
+        after
+    }
+
+    // Tell about the wallet's unconfirmed txns that `txid` conflicts with, or `txid` itself if it is the wallet's
+    async fn send_conflict_events(&self, txid: TxId, conflicts: Vec<TxId>) {
+        let events = self.bsync_data.read().await.events.clone();
+        let mempool_conflicts = self.mempool_conflicts.read().await;
+        let wallet_txns = self.wallet.txns.read().await;
+
+        for t in conflicts.iter().chain(std::iter::once(&txid)) {
+            if wallet_txns.current.get(t).map(|wtx| wtx.unconfirmed).unwrap_or(false) {
+                warn!("Unconfirmed txn {} is conflicted", t);
+                events.send(WalletEvent::MempoolConflict {
+                    txid: t.to_string(),
+                    conflicting_txids: mempool_conflicts.conflicts(t).iter().map(|c| c.to_string()).collect(),
+                });
+            }
+        }
+    }
+
+    // Find the mempool txns that can't be mined anymore because a conflicting txn was, after a sync
+    async fn update_mempool_conflicts(&self) {
+        let replaced = {
+            let blocks = self.wallet.blocks.read().await;
+            let mut mempool_conflicts = self.mempool_conflicts.write().await;
+            mempool_conflicts.expire(MEMPOOL_CONFLICT_EXPIRY);
+            mempool_conflicts.mined_in_blocks(&blocks)
+        };
+
+        let events = self.bsync_data.read().await.events.clone();
+        let wallet_txns = self.wallet.txns.read().await;
+        for (txid, replaced_by) in replaced {
+            if wallet_txns
+                .current
+                .get(&txid)
+                .map(|wtx| wtx.unconfirmed)
+                .unwrap_or(false)
+            {
+                warn!("Unconfirmed txn {} was replaced by {}", txid, replaced_by);
+                events.send(WalletEvent::TxReplaced {
+                    txid: txid.to_string(),
+                    replaced_by: replaced_by.to_string(),
+                });
+            }
+        }
+    }
+
     pub fn start_mempool_monitor(lc: Arc<LightClient<P>>) {
         if !lc.config.monitor_mempool {
             return;
@@ -1250,7 +1806,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
 
         let config = lc.config.clone();
         let parameters = config.get_params();
-        let uri = config.server.clone();
+        let server_config = config.clone();
         let lci = lc.clone();
 
         info!("Mempool monitoring starting");
@@ -1275,6 +1831,21 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
                             let price = price.read().await.clone();
                             //info!("Mempool attempting to scan {}", tx.txid());
 
+                            let txid = tx.txid();
+                            let before = lc1.wallet_snapshot().await;
+                            let is_new = !wallet_txns.read().await.current.contains_key(&txid);
+                            // Only tell about conflicts the first time a txn is seen, the mempool is fetched again and again
+                            let conflicts = {
+                                let mut mempool_conflicts = lc1.mempool_conflicts.write().await;
+                                let seen = mempool_conflicts.contains(&txid);
+                                let conflicts = mempool_conflicts.add(txid, MempoolConflicts::inputs_of(&tx));
+                                if seen {
+                                    vec![]
+                                } else {
+                                    conflicts
+                                }
+                            };
+
                             FetchFullTxns::<P>::scan_full_tx(
                                 config.clone(),
                                 tx,
@@ -1287,19 +1858,61 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
                                 WalletTx::get_price(now(), &price),
                             )
                             .await;
+
+                            if is_new && wallet_txns.read().await.current.contains_key(&txid) {
+                                let events = lc1.bsync_data.read().await.events.clone();
+                                events.send(WalletEvent::MempoolTx { txid: txid.to_string() });
+                            }
+                            if !conflicts.is_empty() {
+                                lc1.send_conflict_events(txid, conflicts).await;
+                            }
+                            lc1.send_wallet_change_events(&before).await;
                         }
                     }
                 });
 
                 let h2 = tokio::spawn(async move {
+                    // Failures in a row, to back off from a server that keeps failing
+                    let mut failures = 0;
                     loop {
+                        // While offline, wait for a server to come back, and then catch up on what was missed
+                        if server_config.is_offline() {
+                            match server_config.ensure_online().await {
+                                Ok(()) => {
+                                    failures = 0;
+                                    let _ = lci.do_sync(false).await;
+                                }
+                                Err(e) => {
+                                    let delay = grpc_connector::get_connection_settings().retry.backoff(failures);
+                                    info!("{}, checking again in {:?}", e, delay);
+                                    failures += 1;
+                                    sleep(delay).await;
+                                    continue;
+                                }
+                            }
+                        }
+
                         //info!("Monitoring mempool");
-                        let r = GrpcConnector::monitor_mempool(uri.clone(), mempool_tx.clone()).await;
+                        let uri = server_config.get_server();
+                        let r = server_config
+                            .connector(uri.clone())
+                            .monitor_mempool(mempool_tx.clone())
+                            .await;
+
+                        if let Err(e) = &r {
+                            if GrpcConnector::is_transport_error(e) {
+                                server_config.failover(&uri, e).await;
+                            }
 
-                        if r.is_err() {
-                            warn!("Mempool monitor returned {:?}, will restart listening", r);
-                            sleep(Duration::from_secs(10)).await;
+                            let delay = grpc_connector::get_connection_settings().retry.backoff(failures);
+                            warn!(
+                                "Mempool monitor returned {:?}, will restart listening in {:?}",
+                                r, delay
+                            );
+                            failures += 1;
+                            sleep(delay).await;
                         } else {
+                            failures = 0;
                             let _ = lci.do_sync(false).await;
                         }
                     }
@@ -1364,17 +1977,68 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
     }
 
     /// Start syncing in batches with the max size, so we don't consume memory more than
-    // wha twe can handle.
+    // wha twe can handle. Errors start with whether they are transient, so worth syncing again later, or permanent.
     async fn start_sync(&self) -> Result<JsonValue, String> {
         // We can only do one sync at a time because we sync blocks in serial order
         // If we allow multiple syncs, they'll all get jumbled up.
         let _lock = self.sync_lock.lock().await;
 
+        // A server might be reachable again later
+        if let Err(e) = self.config.ensure_online().await {
+            return Err(FailureKind::Transient.prefix(e.to_string()));
+        }
+
+        // Pick the best server before the sync, and then stick to it for the whole sync, unless it goes down
+        self.config.check_health_if_stale(SERVER_HEALTH_CHECK_INTERVAL).await;
+        let _pin = self.config.servers.pin();
+
+        let policy = grpc_connector::get_connection_settings().retry;
+        match policy.retry(|| self.sync_with_failover()).await {
+            Err(e) => Err(FailureKind::tag(e)),
+            Ok(j) => {
+                self.update_mempool_conflicts().await;
+                if self.config.consistency.is_enabled() {
+                    self.do_verify_servers().await;
+                }
+                Ok(j)
+            }
+        }
+    }
+
+    // Sync with the current server, and with the next one every time a server can't be reached, until a sync succeeds
+    // or all of them were tried. The caller holds the sync lock.
+    async fn sync_with_failover(&self) -> Result<JsonValue, String> {
+        let mut attempts = cmp::max(1, self.config.servers.len());
+        loop {
+            let uri = self.get_server_uri();
+            let e = match self.sync_with_server(uri.clone()).await {
+                Ok(j) => return Ok(j),
+                Err(e) => e,
+            };
+
+            // Clear the failed sync, so that the next one starts from the wallet's last block
+            self.bsync_data.read().await.finish().await;
+            if attempts <= 1 || !GrpcConnector::is_transport_error(&e) {
+                return Err(e);
+            }
+
+            attempts -= 1;
+            self.config.failover(&uri, &e).await;
+            warn!(
+                "Sync with {} failed ({}), retrying with {}",
+                uri,
+                e,
+                self.get_server_uri()
+            );
+        }
+    }
+
+    // Sync in batches from `uri`. The caller holds the sync lock.
+    async fn sync_with_server(&self, uri: http::Uri) -> Result<JsonValue, String> {
         // The top of the wallet
         let last_scanned_height = self.wallet.last_scanned_height().await;
 
-        let uri = self.config.server.clone();
-        let latest_blockid = GrpcConnector::get_latest_block(uri.clone()).await?;
+        let latest_blockid = self.config.connector(uri.clone()).get_latest_block().await?;
         if latest_blockid.height < last_scanned_height {
             let w = format!(
                 "Server's latest block({}) is behind ours({})",
@@ -1384,21 +2048,15 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
             return Err(w);
         }
 
-        if latest_blockid.height == last_scanned_height {
-            if !latest_blockid.hash.is_empty()
-                && BlockHash::from_slice(&latest_blockid.hash).to_string() != self.wallet.last_scanned_hash().await
-            {
-                warn!("One block reorg at height {}", last_scanned_height);
-                // This is a one-block reorg, so pop the last block. Even if there are more blocks to reorg, this is enough
-                // to trigger a sync, which will then reorg the remaining blocks
-                BlockAndWitnessData::invalidate_block(
-                    last_scanned_height,
-                    self.wallet.blocks.clone(),
-                    self.wallet.txns.clone(),
-                    self.wallet.orchard_witnesses.clone(),
-                )
-                .await;
-            }
+        // Changes to the wallet are reported after each batch, including the txns removed by a reorg
+        let mut snapshot = self.wallet_snapshot().await;
+
+        // If the server's tip is exactly our top block, there's nothing to check
+        let at_tip = latest_blockid.height == last_scanned_height
+            && !latest_blockid.hash.is_empty()
+            && BlockHash::from_slice(&latest_blockid.hash).to_string() == self.wallet.last_scanned_hash().await;
+        if !at_tip {
+            self.recover_from_reorg(last_scanned_height).await?;
         }
 
         // Re-read the last scanned height
@@ -1440,10 +2098,12 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
             let mut l2 = l1.sync_status.write().await;
             // println!("l2");
 
-            l2.start_new(latest_block_batches.len());
+            l2.start_new(latest_block_batches.len(), last_scanned_height, latest_blockid.height);
         }
         // println!("Started new sync");
 
+        let batch_total = latest_block_batches.len();
+
         let mut res = Err("No batches were run!".to_string());
         for (batch_num, batch_latest_block) in latest_block_batches.into_iter().enumerate() {
             // println!("Starting batch {}", batch_num);
@@ -1454,15 +2114,103 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
             } else {
                 self.do_save(false).await?;
             }
+
+            self.bsync_data.read().await.events.send(WalletEvent::SyncBatchDone {
+                batch_num,
+                batch_total,
+                latest_block: batch_latest_block,
+            });
+            snapshot = self.send_wallet_change_events(&snapshot).await;
         }
 
         res
     }
 
+    // Check that the wallet's blocks are still on the server's chain. If they're not, roll the wallet back to the
+    // fork point, or if the fork is deeper than the blocks we keep, restart from a checkpoint or the wallet birthday.
+    async fn recover_from_reorg(&self, last_scanned_height: u64) -> Result<(), String> {
+        let existing_blocks = self.wallet.blocks.read().await.clone();
+        let fork_point =
+            BlockAndWitnessData::find_fork_point(self.config.connector(self.get_server_uri()), &existing_blocks)
+                .await?;
+
+        let recovery = match fork_point {
+            ForkPoint::None => return Ok(()),
+            ForkPoint::At(fork_height) => {
+                warn!(
+                    "Reorg at height {}, rolling back to {}",
+                    last_scanned_height, fork_height
+                );
+
+                let events = self.bsync_data.read().await.events.clone();
+                while self.wallet.last_scanned_height().await > fork_height {
+                    BlockAndWitnessData::invalidate_block(
+                        self.wallet.last_scanned_height().await,
+                        self.wallet.blocks.clone(),
+                        self.wallet.txns.clone(),
+                        self.wallet.orchard_witnesses.clone(),
+                        &events,
+                    )
+                    .await;
+                }
+
+                ReorgRecovery::Rollback { fork_height }
+            }
+            ForkPoint::BeyondRetained => {
+                let oldest_block = existing_blocks.last().unwrap().height;
+                let earliest_tx_height = self
+                    .wallet
+                    .txns
+                    .read()
+                    .await
+                    .current
+                    .values()
+                    .filter(|wtx| !wtx.unconfirmed)
+                    .map(|wtx| u64::from(wtx.block))
+                    .min();
+
+                warn!(
+                    "Reorg at height {} forks below the oldest block we have ({})",
+                    last_scanned_height, oldest_block
+                );
+
+                match checkpoints::get_reorg_checkpoint(
+                    &self.config.chain_name,
+                    &self.config.checkpoints,
+                    oldest_block,
+                    earliest_tx_height,
+                ) {
+                    Some((height, hash, tree)) => {
+                        info!("Restarting sync from checkpoint {}", height);
+                        self.wallet.clear_all().await;
+                        self.wallet.set_initial_block(height, &hash, &tree).await;
+
+                        ReorgRecovery::Checkpoint { height }
+                    }
+                    None => {
+                        info!("No usable checkpoint, rescanning");
+                        self.clear_state().await;
+
+                        ReorgRecovery::Rescan {
+                            birthday: self.wallet.get_birthday().await,
+                        }
+                    }
+                }
+            }
+        };
+
+        self.bsync_data.read().await.events.send(WalletEvent::Reorg {
+            height: last_scanned_height,
+            recovery,
+        });
+
+        Ok(())
+    }
+
     /// start_sync will start synchronizing the blockchain from the wallet's last height. This function will return immediately after starting the sync
     /// Use the `sync_status` command to get the status of the sync
     async fn start_sync_batch(&self, latest_block: u64, batch_num: usize) -> Result<JsonValue, String> {
-        let uri = self.config.server.clone();
+        let uri = self.get_server_uri();
 
         // The top of the wallet
         // println!("Trying to get last scanned height");
@@ -1493,7 +2241,12 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
                 info!("Attempting to get orchard tree from block {}", last_scanned_height);
 
                 // Populate the orchard witnesses from the previous block's frontier
-                let orchard_tree = match GrpcConnector::get_merkle_tree(uri.clone(), last_scanned_height).await {
+                let orchard_tree = match self
+                    .config
+                    .connector(uri.clone())
+                    .get_merkle_tree(last_scanned_height)
+                    .await
+                {
                     Ok(tree_state) => hex::decode(tree_state.orchard_tree).unwrap(),
                     Err(_) => vec![],
                 };
@@ -1536,7 +2289,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
         self.update_current_price().await;
 
         // Sapling Tree GRPC Fetcher
-        let grpc_connector = GrpcConnector::new(uri.clone());
+        let grpc_connector = self.config.connector(uri.clone());
 
         // A signal to detect reorgs, and if so, ask the block_fetcher to fetch new blocks.
         let (reorg_tx, reorg_rx) = unbounded_channel();
@@ -1685,6 +2438,9 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
     }
 
     pub async fn do_shield(&self, address: Option<String>) -> Result<String, String> {
+        self.config.consistency.check_send_allowed()?;
+        self.config.ensure_online().await?;
+
         let fee = u64::from(DEFAULT_FEE);
         let tbal = self.wallet.tbalance(None).await;
 
@@ -1707,57 +2463,70 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
                 .map(|s| s.clone()))
             .unwrap();
 
+        let (sapling_output, sapling_spend) = self.read_sapling_params()?;
+
+        self.auto_lock_before_send().await;
         let result = {
             let _lock = self.sync_lock.lock().await;
-            let (sapling_output, sapling_spend) = self.read_sapling_params()?;
-
             let prover = LocalTxProver::from_bytes(&sapling_spend, &sapling_output);
 
             self.wallet
                 .send_to_address(prover, true, vec![(&addr, tbal - fee, None)], |txbytes| {
-                    GrpcConnector::send_transaction(self.get_server_uri(), txbytes)
+                    self.config
+                        .with_failover(move |server| server.send_transaction(txbytes.clone()))
                 })
                 .await
         };
+        self.auto_lock_after_send(result.is_ok()).await;
 
         result.map(|(txid, _)| txid)
     }
 
     pub async fn do_send(&self, addrs: Vec<(&str, u64, Option<String>)>) -> Result<String, String> {
+        self.config.consistency.check_send_allowed()?;
+        self.config.ensure_online().await?;
         info!("Creating transaction");
 
         // println!("BranchID {:x}", branch_id);
 
+        let (sapling_output, sapling_spend) = self.read_sapling_params()?;
+
+        self.auto_lock_before_send().await;
         let result = {
             let _lock = self.sync_lock.lock().await;
-            let (sapling_output, sapling_spend) = self.read_sapling_params()?;
-
             let prover = LocalTxProver::from_bytes(&sapling_spend, &sapling_output);
 
             self.wallet
                 .send_to_address(prover, false, addrs, |txbytes| {
-                    GrpcConnector::send_transaction(self.get_server_uri(), txbytes)
+                    self.config
+                        .with_failover(move |server| server.send_transaction(txbytes.clone()))
                 })
                 .await
         };
+        self.auto_lock_after_send(result.is_ok()).await;
 
         result.map(|(txid, _)| txid)
     }
 
     #[cfg(test)]
     pub async fn test_do_send(&self, addrs: Vec<(&str, u64, Option<String>)>) -> Result<String, String> {
+        self.config.consistency.check_send_allowed()?;
+        self.config.ensure_online().await?;
         info!("Creating transaction");
 
+        self.auto_lock_before_send().await;
         let result = {
             let _lock = self.sync_lock.lock().await;
             let prover = crate::blaze::test_utils::FakeTxProver {};
 
             self.wallet
                 .send_to_address(prover, false, addrs, |txbytes| {
-                    GrpcConnector::send_transaction(self.get_server_uri(), txbytes)
+                    self.config
+                        .with_failover(move |server| server.send_transaction(txbytes.clone()))
                 })
                 .await
         };
+        self.auto_lock_after_send(result.is_ok()).await;
 
         result.map(|(txid, _)| txid)
     }
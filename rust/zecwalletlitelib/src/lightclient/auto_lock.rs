@@ -0,0 +1,122 @@
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// When the wallet's spending keys were last used and how many txns were sent with them, to lock them again once
+/// the wallet has been idle or has sent for too long. Kept in memory only; the limits are in the wallet options.
+#[derive(Debug, Default)]
+pub struct AutoLock {
+    // When the wallet was unlocked, or the spending keys were last used since. None while the wallet is locked.
+    last_used: Option<Instant>,
+
+    // Txns sent since the wallet was unlocked
+    sends: u32,
+
+    // Sends that are building or sending a txn with the spending keys. The wallet isn't idle while there are any.
+    spending: u32,
+
+    // Bumped whenever a timer is started, on unlock or when the timeout changes, so that the earlier timer stops
+    generation: u64,
+}
+
+impl AutoLock {
+    pub fn unlocked(&mut self) {
+        self.last_used = Some(Instant::now());
+        self.sends = 0;
+    }
+
+    /// A timer is about to be started. Returns the generation it should watch, which stops all the earlier timers.
+    pub fn new_timer(&mut self) -> u64 {
+        self.generation += 1;
+
+        self.generation
+    }
+
+    pub fn locked(&mut self) {
+        self.last_used = None;
+    }
+
+    /// A send is about to use the spending keys. The wallet isn't idle until `used` is called for it.
+    pub fn using(&mut self) {
+        self.spending += 1;
+    }
+
+    /// The spending keys were used, and a txn was sent with them if `sent`. Returns how many txns were sent since the
+    /// wallet was unlocked.
+    pub fn used(&mut self, sent: bool) -> u32 {
+        self.spending = self.spending.saturating_sub(1);
+        if self.last_used.is_some() {
+            self.last_used = Some(Instant::now());
+            if sent {
+                self.sends += 1;
+            }
+        }
+
+        self.sends
+    }
+
+    /// How long until the wallet has been idle for `timeout`, or None if it is locked or the timer of `generation` was
+    /// replaced. While a send is using the keys, all of `timeout` remains.
+    pub fn remaining(&self, generation: u64, timeout: Duration) -> Option<Duration> {
+        if self.generation != generation {
+            return None;
+        }
+        if self.spending > 0 {
+            return self.last_used.map(|_| timeout);
+        }
+
+        self.last_used
+            .map(|t| timeout.checked_sub(t.elapsed()).unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    use super::AutoLock;
+
+    #[test]
+    fn idle_time_and_sends() {
+        let hour = Duration::from_secs(60 * 60);
+
+        let mut auto_lock = AutoLock::default();
+        assert_eq!(auto_lock.remaining(0, hour), None);
+        assert_eq!(auto_lock.used(true), 0);
+
+        auto_lock.unlocked();
+        let generation = auto_lock.new_timer();
+        assert!(auto_lock.remaining(generation, hour).unwrap() > Duration::from_secs(59 * 60));
+        assert_eq!(auto_lock.remaining(generation, Duration::ZERO), Some(Duration::ZERO));
+
+        assert_eq!(auto_lock.used(false), 0);
+        assert_eq!(auto_lock.used(true), 1);
+        assert_eq!(auto_lock.used(true), 2);
+
+        // A new timer stops the earlier one, and unlocking again starts over
+        let next = auto_lock.new_timer();
+        assert_eq!(auto_lock.remaining(generation, hour), None);
+        assert!(auto_lock.remaining(next, hour).is_some());
+        assert_eq!(auto_lock.used(true), 3);
+
+        auto_lock.unlocked();
+        assert_eq!(auto_lock.used(true), 1);
+
+        // Not idle at all while the keys are being used, until the last send is done
+        let second = Duration::from_secs(1);
+        auto_lock.last_used = Some(Instant::now() - second);
+        assert_eq!(auto_lock.remaining(next, second), Some(Duration::ZERO));
+        auto_lock.using();
+        auto_lock.using();
+        assert_eq!(auto_lock.remaining(next, second), Some(second));
+        auto_lock.used(false);
+        auto_lock.last_used = Some(Instant::now() - second);
+        assert_eq!(auto_lock.remaining(next, second), Some(second));
+        auto_lock.used(false);
+        auto_lock.last_used = Some(Instant::now() - second);
+        assert_eq!(auto_lock.remaining(next, second), Some(Duration::ZERO));
+
+        auto_lock.locked();
+        assert_eq!(auto_lock.remaining(next, hour), None);
+    }
+}
@@ -0,0 +1,204 @@
+use std::sync::{Arc, RwLock};
+
+use json::{object, JsonValue};
+use log::warn;
+use zcash_primitives::block::BlockHash;
+
+use crate::compact_formats::TreeState;
+use crate::grpc_connector::{ConnectOptions, GrpcConnector};
+use crate::lightwallet::data::BlockData;
+
+// How far another server's tip can be ahead of the block we synced to before it looks like our server is hiding
+// blocks. The other servers are asked after the sync finished, so they may have seen a few new blocks since.
+pub const TIP_TOLERANCE: u64 = 10;
+
+// How many of the wallet's blocks are compared against each server by default
+pub const DEFAULT_SAMPLE_BLOCKS: usize = 5;
+
+/// Something another server disagrees with the wallet's server on
+#[derive(Clone, Debug, PartialEq)]
+pub enum Divergence {
+    /// The other server has a different block at `height` than the one the wallet synced
+    BlockHash { server: String, height: u64 },
+
+    /// The other server has a different sapling tree at `height` than the one the wallet verified
+    TreeState { server: String, height: u64 },
+
+    /// The other server's tip is `theirs`, well above the tip at `ours` that the wallet synced to
+    Tip { server: String, ours: u64, theirs: u64 },
+}
+
+impl Divergence {
+    pub fn to_json(&self) -> JsonValue {
+        match self {
+            Divergence::BlockHash { server, height } => object! {
+                "check" => "block_hash",
+                "server" => server.clone(),
+                "height" => *height,
+            },
+            Divergence::TreeState { server, height } => object! {
+                "check" => "tree_state",
+                "server" => server.clone(),
+                "height" => *height,
+            },
+            Divergence::Tip { server, ours, theirs } => object! {
+                "check" => "tip",
+                "server" => server.clone(),
+                "height" => *ours,
+                "server_height" => *theirs,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct CheckState {
+    servers: Vec<http::Uri>,
+    sample_blocks: usize,
+    refuse_send: bool,
+    divergences: Vec<Divergence>,
+}
+
+/// Cross-checks what the wallet synced from its server against other servers, so that a single lying or broken server
+/// can't hide txns or feed the wallet a fake chain. It is off until it is given servers to check against. All the
+/// clones of a check share the same settings and results.
+#[derive(Clone, Debug, Default)]
+pub struct ConsistencyCheck {
+    state: Arc<RwLock<CheckState>>,
+}
+
+impl ConsistencyCheck {
+    /// Compare `sample_blocks` of the wallet's blocks against each of the `servers`. If `refuse_send` is set, sending
+    /// is refused while they disagree, otherwise the disagreement is only reported.
+    pub fn configure(&self, servers: Vec<http::Uri>, sample_blocks: usize, refuse_send: bool) {
+        let mut state = self.state.write().unwrap();
+        state.servers = servers;
+        state.sample_blocks = sample_blocks;
+        state.refuse_send = refuse_send;
+        state.divergences.clear();
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.state.read().unwrap().servers.is_empty()
+    }
+
+    pub fn servers(&self) -> Vec<http::Uri> {
+        self.state.read().unwrap().servers.clone()
+    }
+
+    /// What the servers disagreed on in the last check
+    pub fn divergences(&self) -> Vec<Divergence> {
+        self.state.read().unwrap().divergences.clone()
+    }
+
+    pub fn check_send_allowed(&self) -> Result<(), String> {
+        let state = self.state.read().unwrap();
+        if state.refuse_send && !state.divergences.is_empty() {
+            return Err(format!(
+                "Refusing to send, because other servers disagree with this wallet's server: {}",
+                JsonValue::Array(state.divergences.iter().map(|d| d.to_json()).collect()).dump()
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Compare the wallet's blocks (tallest first) and its verified sapling tree against every server, connecting with
+    /// `options`, and remember what didn't match. Servers that can't be reached are skipped.
+    pub async fn run(
+        &self,
+        blocks: &[BlockData],
+        verified_tree: Option<TreeState>,
+        options: &ConnectOptions,
+    ) -> Vec<Divergence> {
+        let (servers, sample_blocks) = {
+            let state = self.state.read().unwrap();
+            (state.servers.clone(), state.sample_blocks)
+        };
+
+        let sampled = sample_indexes(blocks.len(), sample_blocks)
+            .into_iter()
+            .map(|i| &blocks[i])
+            .collect::<Vec<_>>();
+
+        let mut divergences = vec![];
+        for server in servers {
+            let connector = GrpcConnector::new(server.clone(), options.clone());
+            match check_server(connector, &sampled, &verified_tree).await {
+                Ok(d) => divergences.extend(d),
+                Err(e) => warn!("Couldn't check against server {}: {}", server, e),
+            }
+        }
+
+        self.state.write().unwrap().divergences = divergences.clone();
+        divergences
+    }
+}
+
+// `n` indexes spread evenly over `len` blocks, always including the first and the last one
+fn sample_indexes(len: usize, n: usize) -> Vec<usize> {
+    match (len, n) {
+        (0, _) | (_, 0) => vec![],
+        (_, 1) => vec![0],
+        (len, n) if n >= len => (0..len).collect(),
+        (len, n) => (0..n).map(|i| i * (len - 1) / (n - 1)).collect(),
+    }
+}
+
+async fn check_server(
+    server: GrpcConnector,
+    blocks: &[&BlockData],
+    verified_tree: &Option<TreeState>,
+) -> Result<Vec<Divergence>, String> {
+    let mut divergences = vec![];
+    let name = server.uri().to_string();
+
+    let theirs = server.clone().get_latest_block().await?.height;
+    if let Some(ours) = blocks.first().map(|b| b.height) {
+        if theirs > ours + TIP_TOLERANCE {
+            divergences.push(Divergence::Tip {
+                server: name.clone(),
+                ours,
+                theirs,
+            });
+        }
+    }
+
+    // Blocks above the other server's tip can't be compared
+    for block in blocks.iter().filter(|b| b.height <= theirs) {
+        let server_block = server.clone().get_block_id(block.height).await?;
+        if server_block.hash.len() != 32 || BlockHash::from_slice(&server_block.hash).to_string() != block.hash() {
+            divergences.push(Divergence::BlockHash {
+                server: name.clone(),
+                height: block.height,
+            });
+        }
+    }
+
+    if let Some(tree) = verified_tree.as_ref().filter(|t| t.height <= theirs) {
+        let server_tree = server.get_merkle_tree(tree.height).await?;
+        if server_tree.hash != tree.hash || server_tree.tree != tree.tree {
+            divergences.push(Divergence::TreeState {
+                server: name.clone(),
+                height: tree.height,
+            });
+        }
+    }
+
+    Ok(divergences)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sample_indexes;
+
+    #[test]
+    fn sampling() {
+        assert_eq!(sample_indexes(0, 5), Vec::<usize>::new());
+        assert_eq!(sample_indexes(20, 0), Vec::<usize>::new());
+        assert_eq!(sample_indexes(20, 1), vec![0]);
+        assert_eq!(sample_indexes(3, 5), vec![0, 1, 2]);
+        assert_eq!(sample_indexes(20, 5), vec![0, 4, 9, 14, 19]);
+        assert_eq!(sample_indexes(100, 2), vec![0, 99]);
+    }
+}
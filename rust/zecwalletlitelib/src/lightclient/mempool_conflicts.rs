@@ -0,0 +1,311 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use zcash_primitives::transaction::{Transaction, TxId};
+
+use crate::lightwallet::data::BlockData;
+
+// Mempool txns that were neither mined nor replaced after this long are forgotten
+pub const MEMPOOL_CONFLICT_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// An input a txn spends. Two txns that spend the same input can't both be mined.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SpentInput {
+    Transparent { txid: TxId, index: u32 },
+    Sapling { nullifier: [u8; 32] },
+}
+
+/// How safe it is to count on a txn, for merchants that accept payments before they are mined
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MempoolRisk {
+    Confirmed,
+    // In the mempool, and no other txn seen spends any of its inputs
+    Unconfirmed,
+    // Another txn in the mempool spends some of the same inputs, so at most one of them will be mined
+    Conflicted,
+    // A txn spending some of the same inputs was mined, so this one never will be
+    Replaced,
+}
+
+impl MempoolRisk {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MempoolRisk::Confirmed => "confirmed",
+            MempoolRisk::Unconfirmed => "unconfirmed",
+            MempoolRisk::Conflicted => "conflicted",
+            MempoolRisk::Replaced => "replaced",
+        }
+    }
+}
+
+/// The inputs spent by all the txns seen in the mempool, not only the wallet's, since the txn that double spends a
+/// payment to the wallet usually doesn't involve the wallet at all. Kept in memory only.
+#[derive(Debug, Default)]
+pub struct MempoolConflicts {
+    // The mempool txns spending each input
+    spenders: HashMap<SpentInput, HashSet<TxId>>,
+
+    // The inputs of each mempool txn, and when it was first seen
+    inputs: HashMap<TxId, (Vec<SpentInput>, Instant)>,
+
+    // Txns that can't be mined anymore, and the mined txn that replaced each of them
+    replaced: HashMap<TxId, TxId>,
+}
+
+impl MempoolConflicts {
+    pub fn inputs_of(tx: &Transaction) -> Vec<SpentInput> {
+        let mut inputs = vec![];
+
+        if let Some(t_bundle) = tx.transparent_bundle() {
+            inputs.extend(t_bundle.vin.iter().map(|vin| SpentInput::Transparent {
+                txid: TxId::from_bytes(*vin.prevout.hash()),
+                index: vin.prevout.n(),
+            }));
+        }
+        if let Some(s_bundle) = tx.sapling_bundle() {
+            inputs.extend(s_bundle.shielded_spends.iter().map(|s| SpentInput::Sapling {
+                nullifier: s.nullifier.0,
+            }));
+        }
+
+        inputs
+    }
+
+    /// Remember the inputs of a mempool txn. Returns the other mempool txns it conflicts with.
+    pub fn add(&mut self, txid: TxId, inputs: Vec<SpentInput>) -> Vec<TxId> {
+        if self.contains(&txid) {
+            return self.conflicts(&txid);
+        }
+
+        for input in &inputs {
+            self.spenders.entry(*input).or_default().insert(txid);
+        }
+        self.inputs.insert(txid, (inputs, Instant::now()));
+
+        self.conflicts(&txid)
+    }
+
+    /// The other mempool txns that spend some of the same inputs as `txid`
+    pub fn conflicts(&self, txid: &TxId) -> Vec<TxId> {
+        let mut conflicts = match self.inputs.get(txid) {
+            Some((inputs, _)) => inputs
+                .iter()
+                .filter_map(|i| self.spenders.get(i))
+                .flatten()
+                .filter(|t| *t != txid)
+                .cloned()
+                .collect::<HashSet<_>>()
+                .into_iter()
+                .collect::<Vec<_>>(),
+            None => vec![],
+        };
+        conflicts.sort_by_key(|t| t.to_string());
+
+        conflicts
+    }
+
+    pub fn replaced_by(&self, txid: &TxId) -> Option<TxId> {
+        self.replaced.get(txid).cloned()
+    }
+
+    pub fn risk(&self, txid: &TxId, unconfirmed: bool) -> MempoolRisk {
+        if !unconfirmed {
+            MempoolRisk::Confirmed
+        } else if self.replaced.contains_key(txid) {
+            MempoolRisk::Replaced
+        } else if !self.conflicts(txid).is_empty() {
+            MempoolRisk::Conflicted
+        } else {
+            MempoolRisk::Unconfirmed
+        }
+    }
+
+    /// `txid` was mined, spending `inputs`. Every other txn spending one of them is replaced, and all of them are
+    /// forgotten. The inputs of txns seen in the mempool are known already, so `inputs` only needs the ones in the
+    /// block. Returns the txns that were replaced.
+    pub fn mined(&mut self, txid: TxId, inputs: &[SpentInput]) -> Vec<TxId> {
+        let mut all_inputs = inputs.to_vec();
+        if let Some((seen, _)) = self.inputs.get(&txid) {
+            all_inputs.extend(seen.iter().cloned());
+        }
+
+        let losers = all_inputs
+            .iter()
+            .filter_map(|i| self.spenders.get(i))
+            .flatten()
+            .filter(|t| **t != txid)
+            .cloned()
+            .collect::<HashSet<_>>();
+
+        self.forget(&txid);
+        self.replaced.remove(&txid);
+        for loser in &losers {
+            self.forget(loser);
+            self.replaced.insert(*loser, txid);
+        }
+
+        let mut losers = losers.into_iter().collect::<Vec<_>>();
+        losers.sort_by_key(|t| t.to_string());
+        losers
+    }
+
+    /// Look for the wallet's `blocks` txns. The compact blocks only have the sapling spends, so a transparent double
+    /// spend is only noticed if the replacing txn was seen in the mempool before it was mined.
+    pub fn mined_in_blocks(&mut self, blocks: &[BlockData]) -> Vec<(TxId, TxId)> {
+        let mut replaced = vec![];
+
+        for block in blocks {
+            for ctx in block.cb().vtx {
+                let mut hash = [0u8; 32];
+                if ctx.hash.len() != 32 {
+                    continue;
+                }
+                hash.copy_from_slice(&ctx.hash);
+                let txid = TxId::from_bytes(hash);
+
+                let inputs = ctx
+                    .spends
+                    .iter()
+                    .filter(|s| s.nf.len() == 32)
+                    .map(|s| {
+                        let mut nullifier = [0u8; 32];
+                        nullifier.copy_from_slice(&s.nf);
+                        SpentInput::Sapling { nullifier }
+                    })
+                    .collect::<Vec<_>>();
+
+                replaced.extend(self.mined(txid, &inputs).into_iter().map(|loser| (loser, txid)));
+            }
+        }
+
+        replaced
+    }
+
+    /// Forget the txns first seen more than `max_age` ago, which were probably dropped from the mempool
+    pub fn expire(&mut self, max_age: Duration) {
+        let expired = self
+            .inputs
+            .iter()
+            .filter(|(_, (_, seen))| seen.elapsed() > max_age)
+            .map(|(txid, _)| *txid)
+            .collect::<Vec<_>>();
+
+        for txid in expired {
+            self.forget(&txid);
+        }
+    }
+
+    pub fn contains(&self, txid: &TxId) -> bool {
+        self.inputs.contains_key(txid) || self.replaced.contains_key(txid)
+    }
+
+    pub fn len(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    fn forget(&mut self, txid: &TxId) {
+        if let Some((inputs, _)) = self.inputs.remove(txid) {
+            for input in inputs {
+                if let Some(spenders) = self.spenders.get_mut(&input) {
+                    spenders.remove(txid);
+                    if spenders.is_empty() {
+                        self.spenders.remove(&input);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use zcash_primitives::transaction::TxId;
+
+    use super::{MempoolConflicts, MempoolRisk, SpentInput};
+
+    fn txid(n: u8) -> TxId {
+        TxId::from_bytes([n; 32])
+    }
+
+    fn utxo(n: u8, index: u32) -> SpentInput {
+        SpentInput::Transparent { txid: txid(n), index }
+    }
+
+    fn nf(n: u8) -> SpentInput {
+        SpentInput::Sapling { nullifier: [n; 32] }
+    }
+
+    #[test]
+    fn conflicts() {
+        let mut c = MempoolConflicts::default();
+
+        // Txns spending different inputs don't conflict, even if they spend other outputs of the same txn
+        assert!(c.add(txid(1), vec![utxo(100, 0), nf(1)]).is_empty());
+        assert!(c.add(txid(2), vec![utxo(100, 1)]).is_empty());
+        assert_eq!(c.risk(&txid(1), true), MempoolRisk::Unconfirmed);
+
+        // A double spend of a utxo
+        assert_eq!(c.add(txid(3), vec![utxo(100, 0)]), vec![txid(1)]);
+        assert_eq!(c.conflicts(&txid(1)), vec![txid(3)]);
+        assert_eq!(c.risk(&txid(1), true), MempoolRisk::Conflicted);
+        assert_eq!(c.risk(&txid(3), true), MempoolRisk::Conflicted);
+        assert_eq!(c.risk(&txid(2), true), MempoolRisk::Unconfirmed);
+
+        // ... and of a sapling note
+        assert_eq!(c.add(txid(4), vec![nf(1), nf(2)]), vec![txid(1)]);
+        assert_eq!(c.conflicts(&txid(1)), vec![txid(3), txid(4)]);
+
+        // Seeing a txn again doesn't change anything
+        assert!(c.contains(&txid(3)));
+        assert_eq!(c.add(txid(3), vec![utxo(100, 0)]), vec![txid(1)]);
+        assert_eq!(c.len(), 4);
+
+        // Mined txns are confirmed, whatever they conflicted with
+        assert_eq!(c.risk(&txid(1), false), MempoolRisk::Confirmed);
+    }
+
+    #[test]
+    fn replacing() {
+        let mut c = MempoolConflicts::default();
+        c.add(txid(1), vec![utxo(100, 0), nf(1)]);
+        c.add(txid(2), vec![utxo(100, 0)]);
+        c.add(txid(3), vec![nf(1)]);
+        c.add(txid(4), vec![nf(9)]);
+
+        // The double spend of the utxo gets mined, which replaces the payment
+        assert_eq!(c.mined(txid(2), &[]), vec![txid(1)]);
+        assert_eq!(c.risk(&txid(1), true), MempoolRisk::Replaced);
+        assert_eq!(c.replaced_by(&txid(1)), Some(txid(2)));
+
+        // Which also means txid(3) doesn't conflict with anything anymore
+        assert_eq!(c.risk(&txid(3), true), MempoolRisk::Unconfirmed);
+        assert_eq!(c.len(), 2);
+
+        // A txn that was never in the mempool can replace one, by the nullifiers in its block
+        assert_eq!(c.mined(txid(5), &[nf(9)]), vec![txid(4)]);
+        assert_eq!(c.risk(&txid(4), true), MempoolRisk::Replaced);
+        assert_eq!(c.mined(txid(3), &[nf(1)]), vec![]);
+        assert_eq!(c.len(), 0);
+    }
+
+    #[test]
+    fn expiring() {
+        let mut c = MempoolConflicts::default();
+        c.add(txid(1), vec![utxo(100, 0)]);
+        c.add(txid(2), vec![utxo(100, 0)]);
+
+        c.expire(Duration::from_secs(60));
+        assert_eq!(c.len(), 2);
+
+        std::thread::sleep(Duration::from_millis(10));
+        c.expire(Duration::from_millis(1));
+        assert_eq!(c.len(), 0);
+        assert_eq!(c.risk(&txid(1), true), MempoolRisk::Unconfirmed);
+    }
+}
@@ -138,6 +138,21 @@ pub async fn mine_pending_blocks<P: consensus::Parameters + Send + Sync + 'stati
     lc.do_sync(true).await.unwrap();
 }
 
+// Switch the server to a fork of its chain that branches off after `height`. The blocks above `height` (and the txns
+// mined in them) are dropped, and `fcbl` will mine the next blocks on top of the block at `height`.
+pub async fn fork_chain_at<P: consensus::Parameters + Send + Sync + 'static>(
+    fcbl: &mut FakeCompactBlockList,
+    data: &Arc<RwLock<TestServerData<P>>>,
+    height: u64,
+) {
+    let fork_hash = data.write().await.fork_at(height);
+
+    fcbl.blocks.clear();
+    fcbl.txns.clear();
+    fcbl.next_height = height + 1;
+    fcbl.prev_hash = fork_hash;
+}
+
 #[derive(Debug)]
 pub struct TestServerData<P> {
     pub blocks: Vec<CompactBlock>,
@@ -146,6 +161,7 @@ pub struct TestServerData<P> {
     pub config: LightClientConfig<P>,
     pub zec_price: f64,
     pub tree_states: Vec<(u64, String, String)>,
+    pub mempool: Vec<RawTransaction>,
 }
 
 impl<P: consensus::Parameters> TestServerData<P> {
@@ -157,6 +173,7 @@ impl<P: consensus::Parameters> TestServerData<P> {
             config,
             zec_price: 140.5,
             tree_states: vec![],
+            mempool: vec![],
         };
 
         data
@@ -173,6 +190,17 @@ impl<P: consensus::Parameters> TestServerData<P> {
         }
     }
 
+    pub fn add_mempool_txns(&mut self, txns: Vec<(Transaction, u64)>) {
+        for (tx, height) in txns {
+            let mut rtx = RawTransaction::default();
+            let mut data = vec![];
+            tx.write(&mut data).unwrap();
+            rtx.data = data;
+            rtx.height = height;
+            self.mempool.push(rtx);
+        }
+    }
+
     pub fn add_blocks(&mut self, cbs: Vec<CompactBlock>) {
         if cbs.is_empty() {
             panic!("No blocks");
@@ -202,6 +230,21 @@ impl<P: consensus::Parameters> TestServerData<P> {
             self.blocks.insert(0, blk);
         }
     }
+
+    // Drop everything above `height`, and return the hash of the block at `height`
+    pub fn fork_at(&mut self, height: u64) -> BlockHash {
+        self.blocks.retain(|b| b.height <= height);
+        self.txns.retain(|_, (_, rtx)| rtx.height <= height);
+        self.tree_states.retain(|(h, _, _)| *h <= height);
+
+        let fork_block = self
+            .blocks
+            .first()
+            .unwrap_or_else(|| panic!("No block at fork height {}", height));
+        assert_eq!(fork_block.height, height);
+
+        BlockHash::from_slice(&fork_block.hash)
+    }
 }
 
 #[derive(Debug)]
@@ -507,6 +550,28 @@ impl<P: consensus::Parameters + Send + Sync + 'static> CompactTxStreamer for Tes
         &self,
         _request: tonic::Request<crate::compact_formats::Empty>,
     ) -> Result<tonic::Response<Self::GetMempoolStreamStream>, tonic::Status> {
-        todo!()
+        let (tx, rx) = mpsc::channel(cmp::max(self.data.read().await.mempool.len(), 1));
+
+        let data = self.data.clone();
+        tokio::spawn(async move {
+            let latest_height = |blocks: &Vec<CompactBlock>| blocks.iter().map(|b| b.height).max();
+
+            let (mempool, height) = {
+                let data = data.read().await;
+                (data.mempool.clone(), latest_height(&data.blocks))
+            };
+            for rtx in mempool {
+                if tx.send(Ok(rtx)).await.is_err() {
+                    return;
+                }
+            }
+
+            // Like lightwalletd, keep the stream open until the next block is mined
+            while !tx.is_closed() && latest_height(&data.read().await.blocks) == height {
+                sleep(std::time::Duration::from_millis(100)).await;
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
     }
 }
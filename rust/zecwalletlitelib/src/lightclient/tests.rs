@@ -1,6 +1,9 @@
 use std::fs;
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
+use bip39::{Language, Mnemonic, MnemonicType};
 use ff::{Field, PrimeField};
 use group::GroupEncoding;
 use json::JsonValue;
@@ -8,6 +11,7 @@ use rand::rngs::OsRng;
 use rand::RngCore;
 use tempdir::TempDir;
 use tokio::runtime::Runtime;
+use tokio::time;
 use tonic::transport::Channel;
 use tonic::Request;
 
@@ -27,21 +31,31 @@ use zcash_primitives::sapling::Node;
 use zcash_primitives::sapling::{Note, Rseed, ValueCommitment};
 use zcash_primitives::transaction::components::amount::DEFAULT_FEE;
 use zcash_primitives::transaction::components::{OutputDescription, GROTH_PROOF_SIZE};
-use zcash_primitives::transaction::{Transaction, TransactionData};
+use zcash_primitives::transaction::{Transaction, TransactionData, TxId};
 use zcash_primitives::zip32::{ExtendedFullViewingKey, ExtendedSpendingKey};
 
 use crate::blaze::fetch_full_tx::FetchFullTxns;
-use crate::blaze::test_utils::{FakeCompactBlockList, FakeTransaction};
+use crate::blaze::test_utils::{random_u8_32, FakeCompactBlockList, FakeTransaction};
+use crate::commands;
 use crate::compact_formats::compact_tx_streamer_client::CompactTxStreamerClient;
 
-use crate::compact_formats::{CompactSaplingOutput, CompactTx, Empty};
+use crate::compact_formats::{CompactSaplingOutput, CompactTx, Empty, LightdInfo};
+use crate::grpc_connector::{ConnectOptions, FailureKind, GrpcConnector};
+use crate::lightclient::consistency::Divergence;
 use crate::lightclient::faketx::new_transactiondata;
-use crate::lightclient::test_server::{create_test_server, mine_pending_blocks, mine_random_blocks};
+use crate::lightclient::test_server::{create_test_server, fork_chain_at, mine_pending_blocks, mine_random_blocks};
+use crate::lightclient::wallet_events::{AutoLockReason, ReorgRecovery, WalletEvent};
 use crate::lightclient::LightClient;
 use crate::lightwallet::data::WalletTx;
+use crate::lightwallet::{LightWallet, WalletOptions};
+use crate::proxy::tests::start_socks5_standin;
+use crate::server_pool::ServerPool;
 
 use super::checkpoints;
-use super::lightclient_config::{LightClientConfig, UnitTestNetwork};
+use super::lightclient_config::{
+    LightClientConfig, OfflineError, UnitTestNetwork, WrongChain, MAX_REORG, WALLET_BACKUPS,
+};
+use super::wallet_file;
 
 #[test]
 fn new_wallet_from_phrase() {
@@ -73,6 +87,262 @@ fn new_wallet_from_phrase() {
     });
 }
 
+#[test]
+fn new_wallet_from_shares() {
+    let data_dir = |name| {
+        TempDir::new(name)
+            .unwrap()
+            .into_path()
+            .canonicalize()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string()
+    };
+
+    let config = LightClientConfig::create_unconnected(UnitTestNetwork, Some(data_dir("original")));
+    let lc = LightClient::new_from_phrase(TEST_SEED.to_string(), &config, 0, false).unwrap();
+
+    let rt = Runtime::new().unwrap();
+    let (shares, addresses) = rt.block_on(async {
+        assert!(lc.do_split_seed(3, 2).await.is_err());
+
+        let split = lc.do_split_seed(2, 3).await.unwrap();
+        assert_eq!(split["shares"].len(), 3);
+        let shares = split["shares"]
+            .members()
+            .map(|s| s.as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+
+        (shares, lc.do_address().await)
+    });
+
+    // Any two of the shares restore the same wallet
+    let config = LightClientConfig::create_unconnected(UnitTestNetwork, Some(data_dir("restored")));
+    let restored = LightClient::new_from_phrase(shares[1..].join("\n"), &config, 0, false).unwrap();
+    rt.block_on(async {
+        assert_eq!(restored.do_address().await, addresses);
+        assert_eq!(restored.do_seed_phrase().await.unwrap()["seed"], TEST_SEED);
+    });
+
+    // ... but one doesn't
+    let config = LightClientConfig::create_unconnected(UnitTestNetwork, Some(data_dir("one_share")));
+    assert!(LightClient::new_from_phrase(shares[0].clone(), &config, 0, false).is_err());
+}
+
+#[test]
+fn verifying_seed() {
+    let data_dir = TempDir::new("verifying_seed")
+        .unwrap()
+        .into_path()
+        .canonicalize()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let config = LightClientConfig::create_unconnected(UnitTestNetwork, Some(data_dir));
+    let lc = LightClient::new_from_phrase(TEST_SEED.to_string(), &config, 0, false).unwrap();
+
+    Runtime::new().unwrap().block_on(async move {
+        let other = Mnemonic::new(MnemonicType::Words24, Language::English)
+            .phrase()
+            .to_string();
+        let verify = |phrase: &str| {
+            let phrase = phrase.to_string();
+            let lc = &lc;
+            async move { lc.do_verify_seed(phrase).await.unwrap()["result"].to_string() }
+        };
+
+        assert_eq!(verify(TEST_SEED).await, "match");
+        assert_eq!(verify(&other).await, "no-match");
+        assert_eq!(verify("not a seed phrase").await, "no-match");
+
+        // The stored addresses are enough while the wallet is locked
+        lc.wallet.encrypt("pw".to_string()).await.unwrap();
+        assert!(!lc.wallet.is_unlocked_for_spending().await);
+        assert_eq!(verify(TEST_SEED).await, "match");
+        assert_eq!(verify(&other).await, "no-match");
+    });
+}
+
+#[test]
+fn verifying_seed_with_passphrase() {
+    let data_dir = TempDir::new("verifying_seed_with_passphrase")
+        .unwrap()
+        .into_path()
+        .canonicalize()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let config = LightClientConfig::create_unconnected(UnitTestNetwork, Some(data_dir));
+    let lc =
+        LightClient::new_from_phrase_with_passphrase(TEST_SEED.to_string(), "hunter2".to_string(), &config, 0, false)
+            .unwrap();
+
+    let rt = Runtime::new().unwrap();
+    let shares = rt.block_on(async {
+        let split = lc.do_split_seed(2, 3).await.unwrap();
+        let shares = split["shares"]
+            .members()
+            .map(|s| s.as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+
+        lc.wallet.encrypt("pw".to_string()).await.unwrap();
+        assert!(!lc.wallet.is_unlocked_for_spending().await);
+        lc.do_save(true).await.unwrap();
+
+        shares
+    });
+    let other = Mnemonic::new(MnemonicType::Words24, Language::English)
+        .phrase()
+        .to_string();
+
+    // The passphrase is encrypted, so the addresses can't be derived while the wallet is locked. It still works, from
+    // the wallet that was saved and read back too. Shares are passed through the command as they are, one per line.
+    let lc2 = LightClient::read_from_disk(&config).unwrap();
+    for lc in [&lc, &lc2] {
+        let verify = |phrase: &str| {
+            let result = commands::do_user_command("verifyseed", &vec![phrase], lc);
+            json::parse(&result).unwrap()["result"].to_string()
+        };
+
+        assert_eq!(verify(TEST_SEED), "match");
+        assert_eq!(verify(&other), "no-match");
+        assert_eq!(verify(&shares[1..].join("\n")), "match");
+        assert_eq!(verify(&shares[0]), "no-match");
+    }
+
+    // ... and unlocked, against the addresses again
+    rt.block_on(async {
+        lc.wallet.unlock("pw".to_string()).await.unwrap();
+        assert_eq!(
+            lc.do_verify_seed(TEST_SEED.to_string()).await.unwrap()["result"],
+            "match"
+        );
+        assert_eq!(lc.do_verify_seed(other).await.unwrap()["result"], "no-match");
+    });
+}
+
+#[tokio::test]
+async fn auto_lock() {
+    let config = LightClientConfig::create_unconnected(UnitTestNetwork, None);
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    let mut events = lc.subscribe_events().await;
+
+    lc.wallet.encrypt("pw".to_string()).await.unwrap();
+    lc.set_auto_lock_timeout(60).await;
+    lc.wallet.set_auto_lock_sends(2).await;
+
+    // The timers only see the time that the test lets pass
+    time::pause();
+    let idle = |secs| async move {
+        time::advance(Duration::from_secs(secs)).await;
+        // Lets the timers that are due run before the test goes on
+        time::sleep(Duration::from_millis(1)).await;
+    };
+
+    // 1. Locks itself after a minute without a send. A send restarts the minute.
+    lc.do_unlock("pw".to_string()).await.unwrap();
+    idle(40).await;
+    lc.auto_lock_after_send(false).await;
+    idle(40).await;
+    assert!(lc.wallet.is_unlocked_for_spending().await);
+    idle(30).await;
+    assert!(!lc.wallet.is_unlocked_for_spending().await);
+    assert_eq!(
+        events.try_recv().unwrap(),
+        WalletEvent::AutoLocked {
+            reason: AutoLockReason::Idle
+        }
+    );
+
+    // 2. Locks itself after 2 sends. Failed sends don't count.
+    lc.set_auto_lock_timeout(0).await;
+    lc.do_unlock("pw".to_string()).await.unwrap();
+    lc.auto_lock_after_send(true).await;
+    lc.auto_lock_after_send(false).await;
+    assert!(lc.wallet.is_unlocked_for_spending().await);
+    lc.auto_lock_after_send(true).await;
+    assert!(!lc.wallet.is_unlocked_for_spending().await);
+    assert_eq!(
+        events.try_recv().unwrap(),
+        WalletEvent::AutoLocked {
+            reason: AutoLockReason::Sends
+        }
+    );
+
+    // 3. A timer doesn't lock the wallet after it was locked and unlocked again
+    lc.wallet.set_auto_lock_sends(0).await;
+    lc.set_auto_lock_timeout(60).await;
+    lc.do_unlock("pw".to_string()).await.unwrap();
+    idle(30).await;
+    lc.do_lock().await.unwrap();
+    lc.set_auto_lock_timeout(0).await;
+    lc.do_unlock("pw".to_string()).await.unwrap();
+    idle(120).await;
+    assert!(lc.wallet.is_unlocked_for_spending().await);
+    assert!(events.try_recv().is_err());
+
+    // 4. Changing the timeout applies to the unlocked wallet right away, counting from its last use, and 0 cancels it
+    lc.do_lock().await.unwrap();
+    lc.do_unlock("pw".to_string()).await.unwrap();
+    lc.set_auto_lock_timeout(60).await;
+    idle(30).await;
+    lc.set_auto_lock_timeout(0).await;
+    idle(120).await;
+    assert!(lc.wallet.is_unlocked_for_spending().await);
+
+    lc.set_auto_lock_timeout(300).await;
+    idle(100).await;
+    assert!(lc.wallet.is_unlocked_for_spending().await);
+    lc.set_auto_lock_timeout(60).await;
+    idle(1).await;
+    assert!(!lc.wallet.is_unlocked_for_spending().await);
+    assert_eq!(
+        events.try_recv().unwrap(),
+        WalletEvent::AutoLocked {
+            reason: AutoLockReason::Idle
+        }
+    );
+
+    // 5. Doesn't lock itself while a send is using the keys, and the minute starts over once the send is done
+    lc.do_unlock("pw".to_string()).await.unwrap();
+    lc.auto_lock_before_send().await;
+    idle(120).await;
+    assert!(lc.wallet.is_unlocked_for_spending().await);
+    lc.auto_lock_after_send(true).await;
+    idle(40).await;
+    assert!(lc.wallet.is_unlocked_for_spending().await);
+    idle(30).await;
+    assert!(!lc.wallet.is_unlocked_for_spending().await);
+    assert_eq!(
+        events.try_recv().unwrap(),
+        WalletEvent::AutoLocked {
+            reason: AutoLockReason::Idle
+        }
+    );
+
+    // 6. A wallet that can't be locked isn't marked as locked
+    lc.set_auto_lock_timeout(0).await;
+    lc.wallet.remove_encryption("pw".to_string()).await.unwrap();
+    lc.wallet.set_auto_lock_sends(1).await;
+    lc.auto_lock.write().await.unlocked();
+    lc.auto_lock_after_send(true).await;
+    assert_eq!(lc.auto_lock.write().await.used(true), 2);
+    assert!(events.try_recv().is_err());
+
+    // The options are saved with the wallet
+    lc.set_auto_lock_timeout(300).await;
+    lc.wallet.set_auto_lock_sends(3).await;
+    let mut options = vec![];
+    lc.wallet.wallet_options.read().await.write(&mut options).unwrap();
+    let options = WalletOptions::read(&options[..]).unwrap();
+    assert_eq!((options.auto_lock_timeout, options.auto_lock_sends), (300, 3));
+}
+
 #[test]
 fn new_wallet_from_sk() {
     let temp_dir = TempDir::new("test").unwrap();
@@ -170,6 +440,980 @@ async fn basic_no_wallet_txns() {
     h1.await.unwrap();
 }
 
+#[tokio::test]
+async fn wallet_events() {
+    let (data, config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+
+    ready_rx.await.unwrap();
+
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    let mut events = lc.subscribe_events().await;
+    let mut fcbl = FakeCompactBlockList::new(0);
+
+    // 1. Mine 10 blocks. The sync finishes, but nothing changes in the wallet
+    mine_random_blocks(&mut fcbl, &data, &lc, 10).await;
+    let mut got = vec![];
+    while let Ok(e) = events.try_recv() {
+        got.push(e);
+    }
+    assert!(got
+        .iter()
+        .any(|e| matches!(e, WalletEvent::SyncBatchDone { latest_block: 10, .. })));
+    assert!(!got.iter().any(|e| matches!(e, WalletEvent::BalanceChanged { .. })));
+
+    // 2. An incoming tx changes the balance
+    let extfvk1 = lc.wallet.keys().read().await.get_all_extfvks()[0].clone();
+    let value = 100_000;
+    fcbl.add_tx_paying(&extfvk1, value);
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+
+    let mut got = vec![];
+    while let Ok(e) = events.try_recv() {
+        got.push(e);
+    }
+    assert!(got
+        .iter()
+        .any(|e| matches!(e, WalletEvent::SyncBatchDone { latest_block: 11, .. })));
+    assert!(got
+        .iter()
+        .any(|e| matches!(e, WalletEvent::BalanceChanged { zbalance, .. } if *zbalance == value)));
+
+    // Events serialize to json with their name
+    assert!(got[0].to_json()["event"].as_str().is_some());
+
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+}
+
+#[tokio::test]
+async fn multi_block_reorg() {
+    let (data, config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+
+    ready_rx.await.unwrap();
+
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    let mut fcbl = FakeCompactBlockList::new(0);
+
+    // 1. Mine 10 blocks, receive a tx in block 11, and mine 5 more blocks on top of it
+    mine_random_blocks(&mut fcbl, &data, &lc, 10).await;
+    let extfvk1 = lc.wallet.keys().read().await.get_all_extfvks()[0].clone();
+    let value = 100_000;
+    fcbl.add_tx_paying(&extfvk1, value);
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+    mine_random_blocks(&mut fcbl, &data, &lc, 5).await;
+
+    assert_eq!(lc.wallet.last_scanned_height().await, 16);
+    assert_eq!(lc.do_balance().await["zbalance"].as_u64().unwrap(), value);
+
+    // 2. The server switches to a longer chain that forks after block 10, and doesn't have the tx
+    let mut events = lc.subscribe_events().await;
+    fork_chain_at(&mut fcbl, &data, 10).await;
+    mine_random_blocks(&mut fcbl, &data, &lc, 8).await;
+
+    // 3. The wallet rolled back to block 10, and then synced the new chain
+    assert_eq!(lc.wallet.last_scanned_height().await, 18);
+    assert_eq!(lc.do_balance().await["zbalance"].as_u64().unwrap(), 0);
+    assert!(lc.wallet.txns.read().await.current.is_empty());
+
+    let mut got = vec![];
+    while let Ok(e) = events.try_recv() {
+        got.push(e);
+    }
+    assert!(got.contains(&WalletEvent::Reorg {
+        height: 16,
+        recovery: ReorgRecovery::Rollback { fork_height: 10 }
+    }));
+    assert_eq!(
+        got.iter()
+            .filter(|e| matches!(e, WalletEvent::ReorgInvalidatedBlock { .. }))
+            .count(),
+        6
+    );
+    assert!(got
+        .iter()
+        .any(|e| matches!(e, WalletEvent::BalanceChanged { zbalance: 0, .. })));
+
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+}
+
+#[tokio::test]
+async fn reorg_deeper_than_retained_blocks() {
+    let (data, config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+
+    ready_rx.await.unwrap();
+
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    let mut fcbl = FakeCompactBlockList::new(0);
+
+    // 1. Mine 10 blocks, receive a tx in block 11, and bury it under MAX_REORG blocks, so the wallet no longer
+    //    keeps any block from before the tx
+    mine_random_blocks(&mut fcbl, &data, &lc, 10).await;
+    let extfvk1 = lc.wallet.keys().read().await.get_all_extfvks()[0].clone();
+    let value = 100_000;
+    fcbl.add_tx_paying(&extfvk1, value);
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+    mine_random_blocks(&mut fcbl, &data, &lc, MAX_REORG as u64).await;
+
+    assert_eq!(lc.wallet.last_scanned_height().await, 111);
+    assert_eq!(lc.wallet.blocks.read().await.len(), MAX_REORG);
+    assert_eq!(lc.do_balance().await["zbalance"].as_u64().unwrap(), value);
+
+    // 2. The server switches to a longer chain that forks after block 5
+    let mut events = lc.subscribe_events().await;
+    fork_chain_at(&mut fcbl, &data, 5).await;
+    mine_random_blocks(&mut fcbl, &data, &lc, 110).await;
+
+    // 3. There's no checkpoint on the test chain, so the wallet rescanned the new chain from its birthday
+    assert_eq!(lc.wallet.last_scanned_height().await, 115);
+    assert_eq!(lc.do_balance().await["zbalance"].as_u64().unwrap(), 0);
+    assert!(lc.wallet.txns.read().await.current.is_empty());
+
+    let mut got = vec![];
+    while let Ok(e) = events.try_recv() {
+        got.push(e);
+    }
+    assert!(got.iter().any(|e| matches!(
+        e,
+        WalletEvent::Reorg {
+            height: 111,
+            recovery: ReorgRecovery::Rescan { .. }
+        }
+    )));
+
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+}
+
+#[tokio::test]
+async fn generate_checkpoints() {
+    let (data1, config1, ready_rx1, stop_tx1, h1) = create_test_server(UnitTestNetwork).await;
+    let (data2, config2, ready_rx2, stop_tx2, h2) = create_test_server(UnitTestNetwork).await;
+
+    ready_rx1.await.unwrap();
+    ready_rx2.await.unwrap();
+
+    let lc = LightClient::test_new(&config1, None, 0).await.unwrap();
+    let mut fcbl = FakeCompactBlockList::new(0);
+
+    // 1. Both servers have the same 150 blocks
+    mine_random_blocks(&mut fcbl, &data1, &lc, 150).await;
+    let blocks = data1.read().await.blocks.clone();
+    data2.write().await.blocks = blocks;
+
+    let servers = vec![
+        config1.connector(config1.server.clone()),
+        config1.connector(config2.server.clone()),
+    ];
+    let fetched = checkpoints::fetch_checkpoints(&servers, 10, 50, 10).await.unwrap();
+    assert_eq!(
+        fetched.iter().map(|(h, _, _)| *h).collect::<Vec<_>>(),
+        vec![10, 20, 30, 40, 50]
+    );
+
+    // 2. Write them out and load them back. Checkpoints stop MAX_REORG blocks below the tip
+    let dir = TempDir::new("checkpoints").unwrap();
+    let output = dir.path().join("checkpoints.json");
+    let r = lc
+        .do_generate_checkpoints(10, 10, output.to_str().unwrap(), vec![config2.server.clone()])
+        .await
+        .unwrap();
+    assert_eq!(r["end_height"].as_u64().unwrap(), 50);
+    assert_eq!(r["written"].as_u64().unwrap(), 5);
+    let written = checkpoints::parse_checkpoints_json(&fs::read_to_string(&output).unwrap()).unwrap();
+    assert_eq!(written, fetched);
+
+    // 3. The second server switches to a fork after block 30, so the servers no longer agree from block 40 on
+    let mut fcbl2 = FakeCompactBlockList::new(0);
+    fork_chain_at(&mut fcbl2, &data2, 30).await;
+    let cbs = fcbl2.add_blocks(120).into_compact_blocks();
+    data2.write().await.add_blocks(cbs);
+
+    assert!(checkpoints::fetch_checkpoints(&servers, 10, 30, 10).await.is_ok());
+    let e = checkpoints::fetch_checkpoints(&servers, 10, 50, 10).await.unwrap_err();
+    assert!(e.contains("disagree on the tree state at 40"));
+
+    stop_tx1.send(true).unwrap();
+    stop_tx2.send(true).unwrap();
+    h1.await.unwrap();
+    h2.await.unwrap();
+}
+
+#[tokio::test]
+async fn server_failover() {
+    let (data1, config1, ready_rx1, stop_tx1, h1) = create_test_server(UnitTestNetwork).await;
+    let (data2, config2, ready_rx2, stop_tx2, h2) = create_test_server(UnitTestNetwork).await;
+
+    ready_rx1.await.unwrap();
+    ready_rx2.await.unwrap();
+
+    let mut config = config1.clone();
+    config.servers = ServerPool::new(
+        vec![config1.server.clone(), config2.server.clone()],
+        ConnectOptions::default(),
+    );
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+
+    // 1. Both servers have the same chain. The sync checks them both and picks the fastest.
+    let mut fcbl = FakeCompactBlockList::new(0);
+    let cbs = fcbl.add_blocks(10).into_compact_blocks();
+    data1.write().await.add_blocks(cbs.clone());
+    data2.write().await.add_blocks(cbs);
+
+    lc.do_sync(true).await.unwrap();
+    assert_eq!(lc.wallet.last_scanned_height().await, 10);
+    assert!(config.servers.health().iter().all(|s| s.latency.is_some()));
+    assert!(!config.servers.is_pinned());
+
+    // 2. The server the wallet is using goes down
+    let mut servers = vec![
+        (config1.server.clone(), stop_tx1, h1),
+        (config2.server.clone(), stop_tx2, h2),
+    ];
+    let down = servers
+        .iter()
+        .position(|(uri, _, _)| *uri == lc.get_server_uri())
+        .unwrap();
+    let (down_uri, stop_tx, h) = servers.remove(down);
+    stop_tx.send(true).unwrap();
+    h.await.unwrap();
+
+    // 3. The next sync fails over to the other server
+    let cbs = fcbl.add_blocks(10).into_compact_blocks();
+    data1.write().await.add_blocks(cbs.clone());
+    data2.write().await.add_blocks(cbs);
+
+    lc.do_sync(true).await.unwrap();
+    assert_eq!(lc.wallet.last_scanned_height().await, 20);
+    assert_eq!(lc.get_server_uri(), servers[0].0);
+    assert!(config.servers.health().iter().any(|s| s.uri == down_uri && s.is_down()));
+
+    let (_, stop_tx, h) = servers.remove(0);
+    stop_tx.send(true).unwrap();
+    h.await.unwrap();
+}
+
+#[tokio::test]
+async fn failover_checks_chain() {
+    let (_data1, config1, ready_rx1, stop_tx1, h1) = create_test_server(UnitTestNetwork).await;
+    let (data2, config2, ready_rx2, stop_tx2, h2) = create_test_server(UnitTestNetwork).await;
+
+    ready_rx1.await.unwrap();
+    ready_rx2.await.unwrap();
+
+    // 1. The second server is for another chain, so the health check never makes it current
+    data2.write().await.config.chain_name = "ztestsapling".to_string();
+
+    let mut config = config1.clone();
+    config.servers = ServerPool::new(
+        vec![config1.server.clone(), config2.server.clone()],
+        ConnectOptions::default(),
+    );
+    config.check_health().await.unwrap();
+    assert_eq!(config.get_server(), config1.server);
+
+    // 2. Nor does failing over to it, even though it is the only other server. It is dropped instead.
+    assert_eq!(
+        config.failover(&config1.server, "unreachable").await,
+        Some(config1.server.clone())
+    );
+    assert_eq!(config.servers.len(), 1);
+    assert!(config.servers.health()[0].chain_verified);
+
+    stop_tx1.send(true).unwrap();
+    stop_tx2.send(true).unwrap();
+    h1.await.unwrap();
+    h2.await.unwrap();
+}
+
+#[tokio::test]
+async fn cross_server_consistency() {
+    let (data1, config1, ready_rx1, stop_tx1, h1) = create_test_server(UnitTestNetwork).await;
+    let (data2, config2, ready_rx2, stop_tx2, h2) = create_test_server(UnitTestNetwork).await;
+
+    ready_rx1.await.unwrap();
+    ready_rx2.await.unwrap();
+
+    let lc = LightClient::test_new(&config1, None, 0).await.unwrap();
+    lc.set_server_verification(&config2.server.to_string(), 5, true);
+    let mut events = lc.subscribe_events().await;
+
+    // 1. Both servers have the same chain, so the check after the sync passes
+    let mut fcbl = FakeCompactBlockList::new(0);
+    let cbs = fcbl.add_blocks(20).into_compact_blocks();
+    data1.write().await.add_blocks(cbs.clone());
+    data2.write().await.add_blocks(cbs);
+
+    lc.do_sync(true).await.unwrap();
+    assert_eq!(lc.wallet.last_scanned_height().await, 20);
+
+    let mut got = vec![];
+    while let Ok(e) = events.try_recv() {
+        got.push(e);
+    }
+    assert!(got.contains(&WalletEvent::ServersConsistent { servers: 1, height: 20 }));
+    assert!(!got.iter().any(|e| matches!(e, WalletEvent::ServerDivergence { .. })));
+
+    // 2. The second server is on a different chain after block 15
+    let mut fcbl2 = FakeCompactBlockList::new(0);
+    fork_chain_at(&mut fcbl2, &data2, 15).await;
+    let cbs = fcbl2.add_blocks(5).into_compact_blocks();
+    data2.write().await.add_blocks(cbs);
+
+    let r = lc.do_verify_servers().await;
+    assert!(!r["consistent"].as_bool().unwrap());
+
+    let server = config2.server.to_string();
+    let mut got = vec![];
+    while let Ok(e) = events.try_recv() {
+        got.push(e);
+    }
+    assert!(got.contains(&WalletEvent::ServerDivergence {
+        divergence: Divergence::BlockHash {
+            server: server.clone(),
+            height: 20
+        }
+    }));
+    assert!(got.contains(&WalletEvent::ServerDivergence {
+        divergence: Divergence::BlockHash {
+            server: server.clone(),
+            height: 16
+        }
+    }));
+    assert!(!got.iter().any(|e| matches!(
+        e,
+        WalletEvent::ServerDivergence {
+            divergence: Divergence::BlockHash { height: 11, .. }
+        }
+    )));
+
+    // 3. Sending is refused while they disagree
+    let addr = lc.wallet.keys().read().await.get_all_zaddresses()[0].clone();
+    let e = lc.test_do_send(vec![(&addr, 1000, None)]).await.unwrap_err();
+    assert!(e.contains("Refusing to send"));
+
+    // 4. The second server is far ahead of the tip the wallet's server gave it
+    let cbs = fcbl2.add_blocks(20).into_compact_blocks();
+    data2.write().await.add_blocks(cbs);
+
+    lc.do_verify_servers().await;
+    assert!(lc.config.consistency.divergences().contains(&Divergence::Tip {
+        server: server.clone(),
+        ours: 20,
+        theirs: 40
+    }));
+
+    // 5. Once the check is turned off, sending is allowed again
+    lc.set_server_verification("", 0, false);
+    assert!(lc.config.consistency.check_send_allowed().is_ok());
+
+    stop_tx1.send(true).unwrap();
+    stop_tx2.send(true).unwrap();
+    h1.await.unwrap();
+    h2.await.unwrap();
+}
+
+#[tokio::test]
+async fn verify_server_chain() {
+    let (data, config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+    ready_rx.await.unwrap();
+
+    let params = UnitTestNetwork;
+    let uri = config.connector(config.server.clone());
+    let mut fcbl = FakeCompactBlockList::new(0);
+    data.write().await.add_blocks(fcbl.add_blocks(10).into_compact_blocks());
+
+    async fn info(uri: &GrpcConnector) -> LightdInfo {
+        uri.clone().get_info().await.unwrap()
+    }
+
+    // 1. The test server is on the unit test chain
+    assert_eq!(
+        LightClientConfig::verify_server_chain(&params, &config.checkpoints, uri.clone(), &info(&uri).await).await,
+        Ok(())
+    );
+
+    // 2. A server for another chain
+    data.write().await.config.chain_name = "ztestsapling".to_string();
+    assert_eq!(
+        LightClientConfig::verify_server_chain(&params, &config.checkpoints, uri.clone(), &info(&uri).await).await,
+        Err(WrongChain::ChainName {
+            expected: "main".to_string(),
+            server: "ztestsapling".to_string()
+        })
+    );
+    data.write().await.config.chain_name = "main".to_string();
+
+    // 3. A server with the same chain name, but a different sapling activation
+    data.write().await.config.sapling_activation_height = 419_200;
+    assert_eq!(
+        LightClientConfig::verify_server_chain(&params, &config.checkpoints, uri.clone(), &info(&uri).await).await,
+        Err(WrongChain::SaplingActivation {
+            expected: 1,
+            server: 419_200
+        })
+    );
+    data.write().await.config.sapling_activation_height = 1;
+
+    // 4. The branch id has to be the one for the server's tip, if the server reports it
+    let mut i = info(&uri).await;
+    i.consensus_branch_id = "C2D6D0B4".to_string();
+    assert_eq!(
+        LightClientConfig::verify_server_chain(&params, &config.checkpoints, uri.clone(), &i).await,
+        Ok(())
+    );
+    i.consensus_branch_id = "76b809bb".to_string();
+    assert_eq!(
+        LightClientConfig::verify_server_chain(&params, &config.checkpoints, uri.clone(), &i).await,
+        Err(WrongChain::BranchId {
+            expected: "c2d6d0b4".to_string(),
+            server: "76b809bb".to_string()
+        })
+    );
+
+    // 5. Once the server is past a checkpoint, its block there has to match
+    let (checkpoint, hash, _) = checkpoints::get_closest_checkpoint("zs", &config.checkpoints, 610_000).unwrap();
+    fcbl.next_height = checkpoint;
+    let cbs = fcbl.add_blocks(1).into_compact_blocks();
+    let server_hash = fcbl.blocks.last().unwrap().block.hash().to_string();
+    data.write().await.add_blocks(cbs);
+    assert_eq!(
+        LightClientConfig::verify_server_chain(&params, &config.checkpoints, uri.clone(), &info(&uri).await).await,
+        Err(WrongChain::CheckpointHash {
+            height: checkpoint,
+            expected: hash,
+            server: server_hash
+        })
+    );
+
+    // The error makes it through the io::Error that LightClientConfig::create returns
+    let e = std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        WrongChain::Unverifiable("".to_string()),
+    );
+    assert!(matches!(
+        WrongChain::from_io_error(&e),
+        Some(WrongChain::Unverifiable(_))
+    ));
+
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+}
+
+#[tokio::test]
+async fn socks5_proxy() {
+    let (data, config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+    ready_rx.await.unwrap();
+
+    // 1. A client whose config has a proxy makes all its connections through it: sync, prices and sends
+    let (standin, seen) = start_socks5_standin(None);
+    let mut proxied_config = config.clone();
+    proxied_config.connect_options = ConnectOptions {
+        proxy: Some(standin.clone()),
+    };
+
+    let server = config.server.authority().unwrap().to_string();
+    let through_proxy = || seen.lock().unwrap().iter().filter(|s| **s == server).count();
+
+    let lc = LightClient::test_new(&proxied_config, None, 0).await.unwrap();
+    let mut fcbl = FakeCompactBlockList::new(0);
+    mine_random_blocks(&mut fcbl, &data, &lc, 10).await;
+    let mut count = through_proxy();
+    assert!(count > 0);
+
+    // Fund the wallet, so that it can send
+    let extfvk1 = lc.wallet.keys().read().await.get_all_extfvks()[0].clone();
+    fcbl.add_tx_paying(&extfvk1, 100_000);
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+    mine_random_blocks(&mut fcbl, &data, &lc, 5).await;
+    assert_eq!(lc.wallet.last_scanned_height().await, 16);
+    assert!(through_proxy() > count);
+    count = through_proxy();
+
+    lc.do_zec_price().await;
+    assert!(through_proxy() > count);
+    count = through_proxy();
+
+    lc.test_do_send(vec![(EXT_ZADDR, 1000, None)]).await.unwrap();
+    assert_eq!(data.read().await.sent_txns.len(), 1);
+    assert!(through_proxy() > count);
+
+    assert!(lc.do_info().await.contains(&standin.to_uri_string()));
+
+    // 2. A client of the same server whose config has no proxy connects directly
+    let direct = LightClient::test_new(&config, None, 0).await.unwrap();
+    count = through_proxy();
+    mine_random_blocks(&mut fcbl, &data, &direct, 5).await;
+    assert_eq!(direct.wallet.last_scanned_height().await, 21);
+    assert_eq!(through_proxy(), count);
+
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+}
+
+#[tokio::test]
+async fn sync_errors() {
+    let (data, config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+    ready_rx.await.unwrap();
+
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    let mut fcbl = FakeCompactBlockList::new(0);
+    mine_random_blocks(&mut fcbl, &data, &lc, 10).await;
+
+    // 1. A server that is behind the wallet won't catch up by trying again
+    data.write().await.fork_at(5);
+    let e = lc.do_sync(false).await.unwrap_err();
+    assert!(
+        e.starts_with("Permanent error: Server's latest block(5) is behind ours(10)"),
+        "{}",
+        e
+    );
+    assert_eq!(FailureKind::of(&e), FailureKind::Permanent);
+
+    // 2. A server that is down might come back, so it's worth syncing again later
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+
+    let e = lc.do_sync(false).await.unwrap_err();
+    assert!(e.starts_with("Transient error"), "{}", e);
+    assert_eq!(FailureKind::of(&e), FailureKind::Transient);
+    assert_eq!(lc.wallet.last_scanned_height().await, 10);
+}
+
+#[test]
+fn offline_mode() {
+    let data_dir = TempDir::new("offline")
+        .unwrap()
+        .into_path()
+        .canonicalize()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let config = LightClientConfig::create_unconnected(UnitTestNetwork, Some(data_dir.clone()));
+    LightClient::new_from_phrase(TEST_SEED.to_string(), &config, 0, false).unwrap();
+
+    // 1. With no server up, the wallet opens offline
+    let nothing_there: http::Uri = format!("http://127.0.0.1:{}", portpicker::pick_unused_port().unwrap())
+        .parse()
+        .unwrap();
+    let (config, height) = LightClientConfig::create_allow_offline(
+        UnitTestNetwork,
+        vec![nothing_there],
+        ConnectOptions::default(),
+        Some(data_dir.clone()),
+    )
+    .unwrap();
+    assert_eq!(height, None);
+    assert!(config.is_offline());
+
+    let lc = LightClient::read_from_disk(&config).unwrap();
+    assert!(lc.is_offline());
+
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        // Everything local works
+        let zaddr = lc.do_address().await["z_addresses"][0].as_str().unwrap().to_string();
+        assert_eq!(
+            zaddr,
+            "zs1q6xk3q783t5k92kjqt2rkuuww8pdw2euzy5rk6jytw97enx8fhpazdv3th4xe7vsk6e9sfpawfg"
+        );
+        assert_eq!(lc.do_balance().await["zbalance"].as_u64(), Some(0));
+        assert!(lc.do_export(None).await.is_ok());
+        assert!(lc.do_seed_phrase().await.is_ok());
+
+        let enc = lc.do_encrypt_message(zaddr.clone(), Memo::from_bytes(b"offline").unwrap());
+        let dec = lc
+            .do_decrypt_message(enc["encrypted_base64"].as_str().unwrap().to_string())
+            .await;
+        assert_eq!(dec["memo"], "offline");
+
+        // Anything that needs a server fails with why the client is offline, and syncing is worth trying again
+        let offline: OfflineError = config.ensure_online().await.unwrap_err();
+        let e = lc.do_sync(false).await.unwrap_err();
+        assert_eq!(e, FailureKind::Transient.prefix(offline.to_string()));
+        let e = lc.test_do_send(vec![(EXT_ZADDR, 1000, None)]).await.unwrap_err();
+        assert_eq!(e, offline.to_string());
+        assert_eq!(lc.do_zec_price().await, format!("Error: {}", offline));
+        assert_eq!(lc.do_info().await, offline.to_string());
+        assert!(lc.is_offline());
+    });
+
+    // 2. Once a server can be reached, the client goes online by itself
+    let (data, server_config, ready_rx, stop_tx, h1) = rt.block_on(create_test_server(UnitTestNetwork));
+    rt.block_on(ready_rx).unwrap();
+
+    // The extra checkpoints are loaded while offline too, to check the chain of the server it goes online with
+    let extra = vec![(9_000_000, "00".repeat(32), "000000".to_string())];
+    let checkpoints_path =
+        LightClientConfig::create_unconnected(UnitTestNetwork, Some(data_dir.clone())).get_checkpoints_path();
+    fs::write(checkpoints_path, checkpoints::checkpoints_to_json(&extra).dump()).unwrap();
+
+    let config = LightClientConfig::create_offline(
+        UnitTestNetwork,
+        vec![server_config.server.clone()],
+        ConnectOptions::default(),
+        Some(data_dir),
+    );
+    let closest = checkpoints::get_closest_checkpoint(&config.chain_name, &config.checkpoints, 10_000_000);
+    assert_eq!(closest, Some(extra[0].clone()));
+
+    let lc = LightClient::read_from_disk(&config).unwrap();
+    assert!(lc.is_offline());
+
+    rt.block_on(async {
+        let mut fcbl = FakeCompactBlockList::new(0);
+        mine_random_blocks(&mut fcbl, &data, &lc, 10).await;
+
+        assert!(!lc.is_offline());
+        assert_eq!(lc.wallet.last_scanned_height().await, 10);
+
+        stop_tx.send(true).unwrap();
+        h1.await.unwrap();
+    });
+}
+
+#[test]
+fn saving_with_backups() {
+    let data_dir = TempDir::new("saving")
+        .unwrap()
+        .into_path()
+        .canonicalize()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let config = LightClientConfig::create_unconnected(UnitTestNetwork, Some(data_dir));
+    let lc = LightClient::new_from_phrase(TEST_SEED.to_string(), &config, 0, false).unwrap();
+    let path = config.get_wallet_path();
+
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        // Every save keeps the previous one as a backup, up to WALLET_BACKUPS of them
+        for _ in 0..WALLET_BACKUPS + 2 {
+            lc.do_save(true).await.unwrap();
+        }
+        assert_eq!(wallet_file::backups(&path, WALLET_BACKUPS + 1).len(), WALLET_BACKUPS);
+        assert!(!wallet_file::temp_path(&path).exists());
+    });
+
+    // The saved wallet and its backups can all be read back
+    let lc2 = LightClient::read_from_disk(&config).unwrap();
+    for backup in wallet_file::backups(&path, WALLET_BACKUPS) {
+        rt.block_on(LightWallet::read(&fs::read(backup).unwrap()[..], &config))
+            .unwrap();
+    }
+    rt.block_on(async {
+        assert_eq!(lc2.do_address().await, lc.do_address().await);
+    });
+
+    // A wallet that can't be written is an error, not a panic
+    fs::remove_file(&path).unwrap();
+    for backup in wallet_file::backups(&path, WALLET_BACKUPS) {
+        fs::remove_file(backup).unwrap();
+    }
+    fs::create_dir(&path).unwrap();
+    rt.block_on(async {
+        let e = lc.do_save(true).await.unwrap_err();
+        assert!(e.starts_with("Couldn't save wallet"), "{}", e);
+    });
+}
+
+#[test]
+fn encrypted_wallet_file() {
+    let data_dir = TempDir::new("encrypted")
+        .unwrap()
+        .into_path()
+        .canonicalize()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let config = LightClientConfig::create_unconnected(UnitTestNetwork, Some(data_dir));
+    let lc = LightClient::new_from_phrase(TEST_SEED.to_string(), &config, 0, false).unwrap();
+    let path = config.get_wallet_path();
+
+    let rt = Runtime::new().unwrap();
+    let addresses = rt.block_on(async {
+        // Only an encrypted wallet can have its whole file encrypted, and only with the same password
+        assert!(lc.do_encrypt_file("pw".to_string()).await.is_err());
+        lc.wallet.encrypt("pw".to_string()).await.unwrap();
+        lc.do_save(true).await.unwrap();
+        assert!(lc.do_encrypt_file("wrong".to_string()).await.is_err());
+        assert!(!lc.wallet.is_file_encrypted().await);
+
+        lc.do_encrypt_file("pw".to_string()).await.unwrap();
+        assert_eq!(lc.do_encryption_status().await["file_encrypted"], true);
+
+        // The backups from before were deleted
+        assert!(wallet_file::backups(&path, WALLET_BACKUPS).is_empty());
+        lc.do_save(true).await.unwrap();
+
+        lc.do_address().await
+    });
+
+    // Neither the wallet nor its backups can be read without the password
+    assert_eq!(wallet_file::backups(&path, WALLET_BACKUPS).len(), 1);
+    for p in wallet_file::candidates(&path, WALLET_BACKUPS) {
+        assert!(wallet_file::is_encrypted(&fs::read(&p).unwrap()), "{}", p.display());
+    }
+    let e = LightClient::read_from_disk(&config).err().unwrap();
+    assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied);
+    let e = LightClient::read_from_disk_with_password(&config, "wrong")
+        .err()
+        .unwrap();
+    assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied);
+
+    // ... and a wrong password isn't mistaken for a damaged wallet
+    assert!(!wallet_file::corrupt_path(&path).exists());
+
+    // The password unlocks it for viewing only
+    let lc = LightClient::read_from_disk_with_password(&config, "pw").unwrap();
+    assert!(lc.recovered_from().is_none());
+    let buffer = rt.block_on(async {
+        assert_eq!(lc.do_address().await, addresses);
+        assert!(lc.do_seed_phrase().await.is_err());
+        assert_eq!(lc.do_encryption_status().await["locked"], true);
+        assert_eq!(lc.do_encryption_status().await["file_encrypted"], true);
+
+        // Unlocking for spending, and locking again, works like before
+        lc.wallet.unlock("pw".to_string()).await.unwrap();
+        assert!(lc.do_seed_phrase().await.is_ok());
+
+        // Saving keeps the file encrypted
+        lc.do_save(true).await.unwrap();
+        assert!(wallet_file::is_encrypted(&fs::read(&path).unwrap()));
+
+        let buffer = lc.do_save_to_buffer().await.unwrap();
+        assert!(wallet_file::is_encrypted(&buffer));
+
+        // Removing the encryption of the wallet removes the encryption of the file too
+        lc.wallet.remove_encryption("pw".to_string()).await.unwrap();
+        assert!(!lc.wallet.is_file_encrypted().await);
+        lc.do_save(true).await.unwrap();
+
+        buffer
+    });
+
+    assert!(LightClient::read_from_buffer(&config, &buffer[..]).is_err());
+    LightClient::read_from_buffer_with_password(&config, &buffer[..], "pw").unwrap();
+
+    let lc = LightClient::read_from_disk(&config).unwrap();
+    rt.block_on(async {
+        assert_eq!(lc.do_address().await, addresses);
+    });
+}
+
+#[test]
+fn encrypting_keys_removes_old_copies() {
+    let data_dir = TempDir::new("encrypting_keys")
+        .unwrap()
+        .into_path()
+        .canonicalize()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let config = LightClientConfig::create_unconnected(UnitTestNetwork, Some(data_dir));
+    let lc = LightClient::new_from_phrase(TEST_SEED.to_string(), &config, 0, false).unwrap();
+    let path = config.get_wallet_path();
+
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        for _ in 0..WALLET_BACKUPS {
+            lc.do_save(true).await.unwrap();
+        }
+        fs::write(wallet_file::temp_path(&path), fs::read(&path).unwrap()).unwrap();
+        assert_eq!(wallet_file::backups(&path, WALLET_BACKUPS).len(), WALLET_BACKUPS);
+
+        // Only the keys are encrypted, not the file, but the older copies still have them in plaintext
+        lc.wallet.encrypt("pw".to_string()).await.unwrap();
+        lc.do_save(true).await.unwrap();
+        assert!(!lc.wallet.is_file_encrypted().await);
+        assert!(wallet_file::backups(&path, WALLET_BACKUPS).is_empty());
+        assert!(!wallet_file::temp_path(&path).exists());
+
+        // Later saves keep backups again
+        lc.do_save(true).await.unwrap();
+        assert_eq!(wallet_file::backups(&path, WALLET_BACKUPS).len(), 1);
+
+        // Decrypting is a change too, the copies that have the keys encrypted are gone after the next save
+        lc.wallet.remove_encryption("pw".to_string()).await.unwrap();
+        lc.do_save(true).await.unwrap();
+        assert!(wallet_file::backups(&path, WALLET_BACKUPS).is_empty());
+    });
+}
+
+#[test]
+fn changing_password() {
+    let data_dir = TempDir::new("changing_password")
+        .unwrap()
+        .into_path()
+        .canonicalize()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let config = LightClientConfig::create_unconnected(UnitTestNetwork, Some(data_dir));
+    let lc = LightClient::new_from_phrase(TEST_SEED.to_string(), &config, 0, false).unwrap();
+    let path = config.get_wallet_path();
+
+    let rt = Runtime::new().unwrap();
+    let addresses = rt.block_on(async {
+        assert!(lc
+            .do_change_password("pw".to_string(), "new pw".to_string())
+            .await
+            .is_err());
+
+        lc.wallet.encrypt("pw".to_string()).await.unwrap();
+        lc.do_encrypt_file("pw".to_string()).await.unwrap();
+        for _ in 0..WALLET_BACKUPS {
+            lc.do_save(true).await.unwrap();
+        }
+        assert_eq!(wallet_file::backups(&path, WALLET_BACKUPS).len(), WALLET_BACKUPS);
+        lc.wallet.unlock("pw".to_string()).await.unwrap();
+
+        assert!(lc
+            .do_change_password("wrong".to_string(), "new pw".to_string())
+            .await
+            .is_err());
+        lc.do_change_password("pw".to_string(), "new pw".to_string())
+            .await
+            .unwrap();
+
+        // It is still unlocked, like it was before
+        assert_eq!(lc.do_encryption_status().await["locked"], false);
+        assert!(lc.do_seed_phrase().await.is_ok());
+
+        // The backups on the old password were deleted, and the next save keeps one on the new password
+        assert!(wallet_file::backups(&path, WALLET_BACKUPS).is_empty());
+        lc.do_save(true).await.unwrap();
+        assert_eq!(wallet_file::backups(&path, WALLET_BACKUPS).len(), 1);
+
+        lc.do_address().await
+    });
+
+    // The wallet was saved right away, with both its keys and its file on the new password
+    let e = LightClient::read_from_disk_with_password(&config, "pw").err().unwrap();
+    assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied);
+
+    // ... and so are all its backups
+    for p in wallet_file::candidates(&path, WALLET_BACKUPS) {
+        let e = wallet_file::read_with_password(&p, Some("pw")).err().unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied, "{}", p.display());
+        wallet_file::read_with_password(&p, Some("new pw")).unwrap();
+    }
+
+    let lc = LightClient::read_from_disk_with_password(&config, "new pw").unwrap();
+    rt.block_on(async {
+        assert_eq!(lc.do_address().await, addresses);
+        assert!(lc.wallet.unlock("pw".to_string()).await.is_err());
+        lc.wallet.unlock("new pw".to_string()).await.unwrap();
+        assert!(lc.do_seed_phrase().await.is_ok());
+    });
+}
+
+#[test]
+fn recovering_from_backup() {
+    let data_dir = TempDir::new("recovering")
+        .unwrap()
+        .into_path()
+        .canonicalize()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let config = LightClientConfig::create_unconnected(UnitTestNetwork, Some(data_dir));
+    let lc = LightClient::new_from_phrase(TEST_SEED.to_string(), &config, 0, false).unwrap();
+    let path = config.get_wallet_path();
+
+    let rt = Runtime::new().unwrap();
+    let (addresses, legacy) = rt.block_on(async {
+        // Which saves the wallet, keeping the one from before as a backup
+        lc.do_new_address("z").await.unwrap();
+
+        let mut legacy = vec![];
+        lc.wallet.write(&mut legacy).await.unwrap();
+        (lc.do_address().await, legacy)
+    });
+    assert!(LightClient::read_from_disk(&config).unwrap().recovered_from().is_none());
+
+    // 1. The wallet is truncated, so the backup from before the new address is read instead, and replaces it
+    let bytes = fs::read(&path).unwrap();
+    fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+
+    let lc2 = LightClient::read_from_disk(&config).unwrap();
+    assert_eq!(lc2.recovered_from(), Some(wallet_file::backup_path(&path, 1).as_path()));
+    rt.block_on(async {
+        assert_eq!(lc2.do_address().await["z_addresses"].len(), 1);
+    });
+    assert_eq!(
+        fs::read(&path).unwrap(),
+        fs::read(wallet_file::backup_path(&path, 1)).unwrap()
+    );
+    assert!(LightClient::read_from_disk(&config).unwrap().recovered_from().is_none());
+
+    // 2. A flipped bit in the wallet and in all its backups can't be recovered from
+    for p in wallet_file::candidates(&path, WALLET_BACKUPS) {
+        let mut bytes = fs::read(&p).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        fs::write(&p, bytes).unwrap();
+    }
+    let e = LightClient::read_from_disk(&config).err().unwrap();
+    assert!(e.to_string().contains("checksum mismatch"), "{}", e);
+    assert!(e.to_string().contains("backups could be read"), "{}", e);
+
+    // 3. Wallets saved before the checksum are still read
+    fs::write(&path, &legacy).unwrap();
+    let lc3 = LightClient::read_from_disk(&config).unwrap();
+    assert!(lc3.recovered_from().is_none());
+    rt.block_on(async {
+        assert_eq!(lc3.do_address().await, addresses);
+    });
+
+    // ... and so are buffers, either way
+    let lc4 = LightClient::read_from_buffer(&config, &legacy[..]).unwrap();
+    let sealed = rt.block_on(lc4.do_save_to_buffer()).unwrap();
+    assert_eq!(&sealed[..8], b"BTCZWLET");
+    let lc5 = LightClient::read_from_buffer(&config, &sealed[..]).unwrap();
+    rt.block_on(async {
+        assert_eq!(lc5.do_address().await, addresses);
+    });
+
+    // 4. A wallet from a newer version isn't damaged. It is an error, and the backup doesn't replace it.
+    fs::write(wallet_file::backup_path(&path, 1), wallet_file::seal(&legacy)).unwrap();
+
+    let mut newer_version = legacy.clone();
+    newer_version[..8].copy_from_slice(&(LightWallet::<UnitTestNetwork>::serialized_version() + 1).to_le_bytes());
+    let newer_wallet = wallet_file::seal(&newer_version);
+    let mut newer_envelope = wallet_file::seal(&legacy);
+    newer_envelope[8..12].copy_from_slice(&(wallet_file::ENCRYPTED_ENVELOPE + 1).to_le_bytes());
+
+    for (newer, msg) in vec![
+        (newer_wallet, "Don't know how to read wallet version"),
+        (newer_envelope, "is newer than the supported version"),
+    ] {
+        fs::write(&path, &newer).unwrap();
+        let e = LightClient::read_from_disk(&config).err().unwrap();
+        assert!(e.to_string().contains(msg), "{}", e);
+        assert!(!e.to_string().contains("backups"), "{}", e);
+        assert_eq!(fs::read(&path).unwrap(), newer);
+    }
+
+    // ... while a damaged one still falls back to the backup
+    let sealed = wallet_file::seal(&legacy);
+    fs::write(&path, &sealed[..sealed.len() / 2]).unwrap();
+    let lc6 = LightClient::read_from_disk(&config).unwrap();
+    assert_eq!(lc6.recovered_from(), Some(wallet_file::backup_path(&path, 1).as_path()));
+}
+
 #[tokio::test]
 async fn z_incoming_z_outgoing() {
     let (data, config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
@@ -309,6 +1553,43 @@ async fn z_incoming_z_outgoing() {
     h1.await.unwrap();
 }
 
+#[tokio::test]
+async fn send_while_locked() {
+    let (data, config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+    ready_rx.await.unwrap();
+
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    let mut fcbl = FakeCompactBlockList::new(0);
+    mine_random_blocks(&mut fcbl, &data, &lc, 10).await;
+
+    let extfvk1 = lc.wallet.keys().read().await.get_all_extfvks()[0].clone();
+    fcbl.add_tx_paying(&extfvk1, 100_000);
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+    mine_random_blocks(&mut fcbl, &data, &lc, 5).await;
+
+    // 1. A locked wallet won't even select notes
+    lc.wallet.encrypt("pw".to_string()).await.unwrap();
+    assert!(!lc.wallet.is_unlocked_for_spending().await);
+    let e = lc.test_do_send(vec![(EXT_ZADDR, 1000, None)]).await.unwrap_err();
+    assert_eq!(e, "Cannot spend while wallet is locked");
+
+    // 2. If the wallet gets locked after the notes were selected, their spending keys are gone, and nothing is sent.
+    //    Leaving the wallet marked as unlocked gets the send past the first check, like a lock in between would
+    lc.wallet.keys().write().await.unlocked = true;
+    let e = lc.test_do_send(vec![(EXT_ZADDR, 1000, None)]).await.unwrap_err();
+    assert_eq!(e, "Wallet is locked");
+    assert_eq!(data.read().await.sent_txns.len(), 0);
+
+    // 3. Once it is unlocked for real, the same send goes through
+    lc.wallet.keys().write().await.unlocked = false;
+    lc.wallet.unlock("pw".to_string()).await.unwrap();
+    lc.test_do_send(vec![(EXT_ZADDR, 1000, None)]).await.unwrap();
+    assert_eq!(data.read().await.sent_txns.len(), 1);
+
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+}
+
 #[tokio::test]
 async fn multiple_incoming_same_tx() {
     let (data, config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
@@ -1435,6 +2716,100 @@ async fn mempool_and_balance() {
     h1.await.unwrap();
 }
 
+#[tokio::test]
+async fn mempool_double_spend() {
+    let (data, config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+
+    ready_rx.await.unwrap();
+
+    let lc = Arc::new(LightClient::test_new(&config, None, 0).await.unwrap());
+    let mut fcbl = FakeCompactBlockList::new(0);
+
+    // 1. Mine 10 blocks
+    mine_random_blocks(&mut fcbl, &data, &lc, 10).await;
+    let mut events = lc.subscribe_events().await;
+
+    // 2. Two txns in the mempool spend the same outpoint. The first one pays the wallet, the second one doesn't,
+    // and is also put in the next block.
+    let sk = lc.wallet.keys().read().await.tkeys[0].clone();
+    let pk = sk.pubkey().unwrap();
+    let taddr = sk.address;
+    let value = 100_000;
+    let spent_txid = TxId::from_bytes(random_u8_32());
+
+    let mut ftx = FakeTransaction::new();
+    ftx.add_t_input(spent_txid, 0, EXT_TADDR.to_string());
+    ftx.add_t_output(&pk, taddr.clone(), value);
+    let (_, paying_tx, _) = ftx.into_tx();
+
+    let mut ftx = FakeTransaction::new();
+    ftx.add_t_input(spent_txid, 0, EXT_TADDR.to_string());
+    let (replacing_tx, _) = fcbl.add_ftx(ftx);
+
+    let paying_txid = paying_tx.txid().to_string();
+    let replacing_txid = replacing_tx.txid().to_string();
+    data.write()
+        .await
+        .add_mempool_txns(vec![(paying_tx, 11), (replacing_tx, 11)]);
+
+    // 3. The mempool monitor tells that the wallet's txn is conflicted
+    LightClient::start_mempool_monitor(lc.clone());
+    let event = time::timeout(Duration::from_secs(30), async {
+        loop {
+            if let Ok(e @ WalletEvent::MempoolConflict { .. }) = events.recv().await {
+                return e;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(
+        event,
+        WalletEvent::MempoolConflict {
+            txid: paying_txid.clone(),
+            conflicting_txids: vec![replacing_txid.clone()],
+        }
+    );
+
+    let list = lc.do_list_transactions(false).await;
+    assert_eq!(list.len(), 1);
+    assert_eq!(list[0]["txid"], paying_txid);
+    assert_eq!(list[0]["unconfirmed"].as_bool().unwrap(), true);
+    assert_eq!(list[0]["mempool_risk"], "conflicted");
+    assert_eq!(list[0]["conflicting_txids"][0], replacing_txid);
+    assert!(list[0]["replaced_by"].is_null());
+
+    // 4. Mine the other txn, which replaces the wallet's
+    data.write().await.mempool.clear();
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+
+    let event = time::timeout(Duration::from_secs(30), async {
+        loop {
+            if let Ok(e @ WalletEvent::TxReplaced { .. }) = events.recv().await {
+                return e;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(
+        event,
+        WalletEvent::TxReplaced {
+            txid: paying_txid.clone(),
+            replaced_by: replacing_txid.clone(),
+        }
+    );
+
+    let list = lc.do_list_transactions(false).await;
+    assert_eq!(list[0]["txid"], paying_txid);
+    assert_eq!(list[0]["mempool_risk"], "replaced");
+    assert_eq!(list[0]["replaced_by"], replacing_txid);
+
+    // Shutdown everything cleanly
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+}
+
 pub const EXT_TADDR: &str = "t1NoS6ZgaUTpmjkge2cVpXGcySasdYDrXqh";
 pub const EXT_ZADDR: &str = "zs1va5902apnzlhdu0pw9r9q7ca8s4vnsrp2alr6xndt69jnepn2v2qrj9vg3wfcnjyks5pg65g9dc";
 pub const EXT_ZADDR2: &str = "zs1fxgluwznkzm52ux7jkf4st5znwzqay8zyz4cydnyegt2rh9uhr9458z0nk62fdsssx0cqhy6lyv";
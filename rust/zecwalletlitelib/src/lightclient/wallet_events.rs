@@ -0,0 +1,310 @@
+use std::collections::{HashMap, HashSet};
+
+use json::{object, JsonValue};
+use tokio::sync::broadcast;
+use zcash_primitives::transaction::TxId;
+
+use crate::lightclient::consistency::Divergence;
+use crate::lightwallet::wallet_txns::WalletTxns;
+
+// How many events a slow subscriber can fall behind before it starts missing them
+const EVENT_CHANNEL_SIZE: usize = 256;
+
+/// A change to the wallet, delivered to everyone subscribed to the LightClient's events
+#[derive(Clone, Debug, PartialEq)]
+pub enum WalletEvent {
+    /// A transaction involving this wallet was detected in the mempool
+    MempoolTx { txid: String },
+
+    /// A previously unconfirmed transaction was mined
+    TxConfirmed { txid: String, height: u64 },
+
+    /// A note or utxo received in `txid` was spent by `spent_txid`
+    NoteSpent {
+        txid: String,
+        spent_txid: String,
+        height: u64,
+    },
+
+    /// The wallet balance changed
+    BalanceChanged {
+        zbalance: u64,
+        verified_zbalance: u64,
+        tbalance: u64,
+        uabalance: u64,
+    },
+
+    /// A reorg invalidated the block at `height`, along with all the wallet txns in it
+    ReorgInvalidatedBlock { height: u64 },
+
+    /// The server switched to a different chain while the wallet was synced up to `height`
+    Reorg { height: u64, recovery: ReorgRecovery },
+
+    /// A sync batch finished, and the wallet is now synced up to `latest_block`
+    SyncBatchDone {
+        batch_num: usize,
+        batch_total: usize,
+        latest_block: u64,
+    },
+
+    /// Another server disagrees with the chain the wallet synced from its server
+    ServerDivergence { divergence: Divergence },
+
+    /// All the `servers` in the consistency check agree with the chain the wallet synced up to `height`
+    ServersConsistent { servers: usize, height: u64 },
+
+    /// Other mempool txns spend some of the same inputs as the wallet's unconfirmed `txid`, so not all of them will
+    /// be mined
+    MempoolConflict {
+        txid: String,
+        conflicting_txids: Vec<String>,
+    },
+
+    /// The wallet's unconfirmed `txid` will never be mined, because `replaced_by` spent some of the same inputs
+    TxReplaced { txid: String, replaced_by: String },
+
+    /// The wallet locked its spending keys by itself. It has to be unlocked again to spend.
+    AutoLocked { reason: AutoLockReason },
+}
+
+/// How the wallet got back onto the server's chain after a reorg
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReorgRecovery {
+    /// The wallet's blocks above `fork_height` were invalidated, and will be synced again from the new chain
+    Rollback { fork_height: u64 },
+
+    /// The fork was deeper than the blocks the wallet keeps, so the wallet restarted its sync from this checkpoint
+    Checkpoint { height: u64 },
+
+    /// The fork was deeper than the blocks the wallet keeps, so the wallet is rescanning from its birthday
+    Rescan { birthday: u64 },
+}
+
+/// Why the wallet locked itself
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AutoLockReason {
+    /// The spending keys weren't used for the `auto_lock_timeout` option's number of seconds
+    Idle,
+
+    /// The `auto_lock_sends` option's number of txns were sent since the wallet was unlocked
+    Sends,
+}
+
+impl WalletEvent {
+    pub fn to_json(&self) -> JsonValue {
+        match self {
+            WalletEvent::MempoolTx { txid } => object! {
+                "event" => "mempool_tx",
+                "txid" => txid.clone(),
+            },
+            WalletEvent::TxConfirmed { txid, height } => object! {
+                "event" => "tx_confirmed",
+                "txid" => txid.clone(),
+                "height" => *height,
+            },
+            WalletEvent::NoteSpent {
+                txid,
+                spent_txid,
+                height,
+            } => object! {
+                "event" => "note_spent",
+                "txid" => txid.clone(),
+                "spent_txid" => spent_txid.clone(),
+                "height" => *height,
+            },
+            WalletEvent::BalanceChanged {
+                zbalance,
+                verified_zbalance,
+                tbalance,
+                uabalance,
+            } => object! {
+                "event" => "balance_changed",
+                "zbalance" => *zbalance,
+                "verified_zbalance" => *verified_zbalance,
+                "tbalance" => *tbalance,
+                "uabalance" => *uabalance,
+            },
+            WalletEvent::ReorgInvalidatedBlock { height } => object! {
+                "event" => "block_invalidated",
+                "height" => *height,
+            },
+            WalletEvent::Reorg { height, recovery } => {
+                let (kind, recovery_height) = match recovery {
+                    ReorgRecovery::Rollback { fork_height } => ("rollback", *fork_height),
+                    ReorgRecovery::Checkpoint { height } => ("checkpoint", *height),
+                    ReorgRecovery::Rescan { birthday } => ("rescan", *birthday),
+                };
+                object! {
+                    "event" => "reorg",
+                    "height" => *height,
+                    "recovery" => kind,
+                    "recovery_height" => recovery_height,
+                }
+            }
+            WalletEvent::SyncBatchDone {
+                batch_num,
+                batch_total,
+                latest_block,
+            } => object! {
+                "event" => "sync_batch_done",
+                "batch_num" => *batch_num,
+                "batch_total" => *batch_total,
+                "latest_block" => *latest_block,
+            },
+            WalletEvent::ServerDivergence { divergence } => {
+                let mut j = divergence.to_json();
+                j["event"] = "server_divergence".into();
+                j
+            }
+            WalletEvent::ServersConsistent { servers, height } => object! {
+                "event" => "servers_consistent",
+                "servers" => *servers,
+                "height" => *height,
+            },
+            WalletEvent::MempoolConflict {
+                txid,
+                conflicting_txids,
+            } => object! {
+                "event" => "mempool_conflict",
+                "txid" => txid.clone(),
+                "conflicting_txids" => conflicting_txids.clone(),
+            },
+            WalletEvent::TxReplaced { txid, replaced_by } => object! {
+                "event" => "tx_replaced",
+                "txid" => txid.clone(),
+                "replaced_by" => replaced_by.clone(),
+            },
+            WalletEvent::AutoLocked { reason } => object! {
+                "event" => "auto_locked",
+                "reason" => match reason {
+                    AutoLockReason::Idle => "idle",
+                    AutoLockReason::Sends => "sends",
+                },
+            },
+        }
+    }
+}
+
+/// Broadcast bus for `WalletEvent`s. Cloning it gives another handle to the same bus.
+#[derive(Clone)]
+pub struct WalletEvents {
+    tx: broadcast::Sender<WalletEvent>,
+}
+
+impl WalletEvents {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(EVENT_CHANNEL_SIZE);
+        Self { tx }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<WalletEvent> {
+        self.tx.subscribe()
+    }
+
+    pub fn send(&self, event: WalletEvent) {
+        // This only fails if nobody is listening, which is fine.
+        let _ = self.tx.send(event);
+    }
+}
+
+// Notes are identified by the txid they were received in, their pool and their position in the tx
+type NoteId = (TxId, char, usize);
+
+/// The parts of the wallet state that are compared before and after a change to generate events.
+pub struct WalletSnapshot {
+    unconfirmed: HashSet<TxId>,
+    unspent: HashSet<NoteId>,
+    balances: [u64; 4],
+}
+
+impl WalletSnapshot {
+    // balances are [zbalance, verified_zbalance, tbalance, uabalance]
+    pub fn new(txns: &WalletTxns, balances: [u64; 4]) -> Self {
+        let unconfirmed = txns
+            .current
+            .values()
+            .filter(|wtx| wtx.unconfirmed)
+            .map(|wtx| wtx.txid)
+            .collect();
+
+        let mut unspent = HashSet::new();
+        for wtx in txns.current.values() {
+            for (i, nd) in wtx.s_notes.iter().enumerate() {
+                if nd.spent.is_none() {
+                    unspent.insert((wtx.txid, 's', i));
+                }
+            }
+            for (i, nd) in wtx.o_notes.iter().enumerate() {
+                if nd.spent.is_none() {
+                    unspent.insert((wtx.txid, 'o', i));
+                }
+            }
+            for (i, utxo) in wtx.utxos.iter().enumerate() {
+                if utxo.spent.is_none() {
+                    unspent.insert((wtx.txid, 't', i));
+                }
+            }
+        }
+
+        Self {
+            unconfirmed,
+            unspent,
+            balances,
+        }
+    }
+
+    /// The events that happened between this snapshot and `after`, which was taken from `txns`
+    pub fn diff(&self, after: &WalletSnapshot, txns: &WalletTxns) -> Vec<WalletEvent> {
+        let mut events = vec![];
+
+        for txid in self.unconfirmed.difference(&after.unconfirmed) {
+            if let Some(wtx) = txns.current.get(txid) {
+                events.push(WalletEvent::TxConfirmed {
+                    txid: txid.to_string(),
+                    height: u64::from(wtx.block),
+                });
+            }
+        }
+
+        let mut spent: HashMap<NoteId, (TxId, u64)> = HashMap::new();
+        for wtx in txns.current.values() {
+            for (i, nd) in wtx.s_notes.iter().enumerate() {
+                if let Some((spent_txid, height)) = nd.spent {
+                    spent.insert((wtx.txid, 's', i), (spent_txid, height as u64));
+                }
+            }
+            for (i, nd) in wtx.o_notes.iter().enumerate() {
+                if let Some((spent_txid, height)) = nd.spent {
+                    spent.insert((wtx.txid, 'o', i), (spent_txid, height as u64));
+                }
+            }
+            for (i, utxo) in wtx.utxos.iter().enumerate() {
+                if let Some(spent_txid) = utxo.spent {
+                    let height = utxo.spent_at_height.unwrap_or(0) as u64;
+                    spent.insert((wtx.txid, 't', i), (spent_txid, height));
+                }
+            }
+        }
+
+        for note_id in self.unspent.difference(&after.unspent) {
+            if let Some((spent_txid, height)) = spent.get(note_id) {
+                events.push(WalletEvent::NoteSpent {
+                    txid: note_id.0.to_string(),
+                    spent_txid: spent_txid.to_string(),
+                    height: *height,
+                });
+            }
+        }
+
+        if self.balances != after.balances {
+            events.push(WalletEvent::BalanceChanged {
+                zbalance: after.balances[0],
+                verified_zbalance: after.balances[1],
+                tbalance: after.balances[2],
+                uabalance: after.balances[3],
+            });
+        }
+
+        events
+    }
+}
@@ -0,0 +1,640 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use sha2::{Digest, Sha256};
+use sodiumoxide::crypto::secretbox;
+
+use crate::lightwallet::kdf::Kdf;
+
+// Wallet files start with this, followed by the envelope version, the length of the wallet and its sha256. Files
+// written before the envelope start with the wallet's own version instead, which never looks like this.
+const MAGIC: &[u8; 8] = b"BTCZWLET";
+const HEADER_LEN: usize = 8 + 4 + 8 + 32;
+
+// The envelope holds the serialized wallet
+pub const PLAIN_ENVELOPE: u32 = 1;
+// The envelope holds how the key is derived from the wallet's password, a nonce and the encrypted serialized wallet
+pub const ENCRYPTED_ENVELOPE: u32 = 2;
+
+/// The key the whole wallet file is encrypted with, not only the spending keys. It is derived from the wallet's
+/// password, and kept in memory while the wallet is open so that it can be saved again without deriving it again.
+#[derive(Clone)]
+pub struct FileKey {
+    kdf: Kdf,
+    key: secretbox::Key,
+}
+
+impl FileKey {
+    /// A key for a wallet file that is being encrypted, with a new salt
+    pub fn from_password(passwd: &str) -> io::Result<Self> {
+        Self::derive(Kdf::new(), passwd)
+    }
+
+    fn derive(kdf: Kdf, passwd: &str) -> io::Result<Self> {
+        // Not the same key the spending keys are encrypted with
+        let mut input = b"BitcoinZ wallet file ".to_vec();
+        input.extend_from_slice(passwd.as_bytes());
+
+        let key = kdf.derive_key(&input)?;
+        Ok(FileKey { kdf, key })
+    }
+
+    fn encrypt(&self, wallet: &[u8]) -> Vec<u8> {
+        let mut body = vec![];
+        self.kdf.write(&mut body).unwrap();
+
+        let nonce = secretbox::gen_nonce();
+        body.extend_from_slice(nonce.as_ref());
+        body.extend(secretbox::seal(wallet, &nonce, &self.key));
+
+        body
+    }
+
+    fn decrypt(&self, body: &[u8]) -> io::Result<Vec<u8>> {
+        if body.len() < secretbox::NONCEBYTES {
+            return Err(corrupted("no nonce".to_string()));
+        }
+
+        let nonce = secretbox::Nonce::from_slice(&body[..secretbox::NONCEBYTES]).unwrap();
+        secretbox::open(&body[secretbox::NONCEBYTES..], &nonce, &self.key).map_err(|_| {
+            io::Error::new(
+                ErrorKind::PermissionDenied,
+                "Decryption failed. Is your password correct?",
+            )
+        })
+    }
+}
+
+/// Wrap the serialized `wallet` in an envelope that lets truncation and bit-rot be detected on load
+pub fn seal(wallet: &[u8]) -> Vec<u8> {
+    seal_body(PLAIN_ENVELOPE, wallet)
+}
+
+/// Like `seal`, but the wallet is encrypted with `key` first, so that nothing in it can be read without the password
+pub fn seal_encrypted(wallet: &[u8], key: &FileKey) -> Vec<u8> {
+    seal_body(ENCRYPTED_ENVELOPE, &key.encrypt(wallet))
+}
+
+fn seal_body(version: u32, body: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_LEN + body.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.write_u32::<LittleEndian>(version).unwrap();
+    bytes.write_u64::<LittleEndian>(body.len() as u64).unwrap();
+    bytes.extend_from_slice(&Sha256::digest(body));
+    bytes.extend_from_slice(body);
+
+    bytes
+}
+
+/// Check the envelope around a wallet and return the serialized wallet inside it. Wallets saved before there was an
+/// envelope are returned as they are. Encrypted wallets need `open_with_password`.
+pub fn open(bytes: &[u8]) -> io::Result<&[u8]> {
+    match open_body(bytes)? {
+        (ENCRYPTED_ENVELOPE, _) => Err(needs_password()),
+        (_, wallet) => Ok(wallet),
+    }
+}
+
+/// Like `open`, but encrypted wallets are decrypted with `passwd`. The key that the wallet should be saved with from
+/// now on is returned with it, if the wallet is encrypted. Files encrypted with a legacy KDF get a new key, so that
+/// the next save upgrades them.
+pub fn open_with_password(bytes: &[u8], passwd: Option<&str>) -> io::Result<(Vec<u8>, Option<FileKey>)> {
+    match (open_body(bytes)?, passwd) {
+        ((ENCRYPTED_ENVELOPE, mut body), Some(passwd)) => {
+            let key = FileKey::derive(Kdf::read(&mut body)?, passwd)?;
+            let wallet = key.decrypt(body)?;
+
+            let key = if key.kdf.is_legacy() {
+                FileKey::from_password(passwd)?
+            } else {
+                key
+            };
+            Ok((wallet, Some(key)))
+        }
+        ((ENCRYPTED_ENVELOPE, _), None) => Err(needs_password()),
+        ((_, wallet), _) => Ok((wallet.to_vec(), None)),
+    }
+}
+
+/// Whether `bytes` are an encrypted wallet file. Only the header is looked at, so this is true of damaged ones too.
+pub fn is_encrypted(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC) && bytes.len() >= MAGIC.len() + 4 && {
+        let mut version = &bytes[MAGIC.len()..];
+        version.read_u32::<LittleEndian>().ok() == Some(ENCRYPTED_ENVELOPE)
+    }
+}
+
+// Returns the envelope version and what's inside the envelope, after checking it. Files from before the envelope are
+// version 0.
+fn open_body(bytes: &[u8]) -> io::Result<(u32, &[u8])> {
+    if !bytes.starts_with(MAGIC) {
+        return Ok((0, bytes));
+    }
+
+    if bytes.len() < HEADER_LEN {
+        return Err(corrupted(format!("truncated to {} bytes", bytes.len())));
+    }
+
+    let mut header = &bytes[MAGIC.len()..HEADER_LEN];
+    let version = header.read_u32::<LittleEndian>()?;
+    if version > ENCRYPTED_ENVELOPE {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Wallet file envelope version {} is newer than the supported version {}",
+                version, ENCRYPTED_ENVELOPE
+            ),
+        ));
+    }
+
+    let len = header.read_u64::<LittleEndian>()?;
+    let body = &bytes[HEADER_LEN..];
+    if (body.len() as u64) < len {
+        return Err(corrupted(format!("truncated to {} of {} bytes", body.len(), len)));
+    }
+    if body.len() as u64 != len {
+        return Err(corrupted(format!("{} bytes after the end", body.len() as u64 - len)));
+    }
+    if Sha256::digest(body)[..] != header[..] {
+        return Err(corrupted("checksum mismatch".to_string()));
+    }
+
+    Ok((version, body))
+}
+
+/// The wallet file was damaged after it was written: it is truncated, or doesn't match its checksum. Only then is it
+/// worth reading one of its backups instead, a file from a newer version or one that can't be read at all is left as
+/// it is.
+#[derive(Debug)]
+pub struct Corrupted(String);
+
+impl fmt::Display for Corrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Wallet file is corrupted: {}", self.0)
+    }
+}
+
+impl std::error::Error for Corrupted {}
+
+/// Whether `e` is a `Corrupted` wallet file
+pub fn is_corrupted(e: &io::Error) -> bool {
+    e.get_ref().map(|e| e.is::<Corrupted>()).unwrap_or(false)
+}
+
+fn corrupted(msg: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, Corrupted(msg))
+}
+
+fn needs_password() -> io::Error {
+    io::Error::new(
+        ErrorKind::PermissionDenied,
+        "Wallet file is encrypted. It can only be opened with the wallet's password",
+    )
+}
+
+/// Read the wallet file at `path` and check its envelope
+pub fn read(path: &Path) -> io::Result<Vec<u8>> {
+    read_with_password(path, None).map(|(wallet, _)| wallet)
+}
+
+/// Read the wallet file at `path`, check its envelope and decrypt it with `passwd` if it is encrypted. Returns the key
+/// to save it with as well, like `open_with_password`.
+pub fn read_with_password(path: &Path, passwd: Option<&str>) -> io::Result<(Vec<u8>, Option<FileKey>)> {
+    let bytes = fs::read(path)?;
+    open_with_password(&bytes, passwd)
+}
+
+/// Delete the copies of the wallet at `path` that are not encrypted: its backups, what a crashed save left behind and
+/// a damaged wallet set aside by `restore`. Once a wallet file is encrypted, the older copies would otherwise still
+/// give its history away.
+pub fn remove_plaintext_copies(path: &Path, max_backups: usize) -> io::Result<()> {
+    for copy in copies(path, max_backups) {
+        if !is_encrypted(&fs::read(&copy)?) {
+            fs::remove_file(&copy)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Delete all the copies of the wallet at `path`, like `remove_plaintext_copies`, encrypted or not. Once the keys are
+/// encrypted or decrypted, or the password changes, the older copies still have them the old way.
+pub fn remove_copies(path: &Path, max_backups: usize) -> io::Result<()> {
+    for copy in copies(path, max_backups) {
+        fs::remove_file(&copy)?;
+    }
+
+    Ok(())
+}
+
+// The copies of the wallet at `path` that exist, besides the wallet itself
+fn copies(path: &Path, max_backups: usize) -> Vec<PathBuf> {
+    let mut copies = backups(path, max_backups);
+    copies.push(temp_path(path));
+    copies.push(corrupt_path(path));
+
+    copies.into_iter().filter(|p| p.exists()).collect()
+}
+
+/// Where to look for a wallet, in order: the wallet itself, then its backups, most recent first
+pub fn candidates(path: &Path, max_backups: usize) -> Vec<PathBuf> {
+    let mut candidates = vec![path.to_path_buf()];
+    candidates.extend(backups(path, max_backups));
+
+    candidates
+}
+
+/// Replace the wallet at `path`, which couldn't be read, with its `backup`. The bad wallet is moved out of the way
+/// rather than becoming a backup on the next save, and kept in case it can be repaired by hand. Returns where it went.
+pub fn restore(path: &Path, backup: &Path) -> io::Result<PathBuf> {
+    let bytes = fs::read(backup)?;
+
+    let aside = corrupt_path(path);
+    fs::rename(path, &aside)?;
+    save(path, &bytes, 0)?;
+
+    Ok(aside)
+}
+
+/// Where `restore` moves a damaged wallet
+pub fn corrupt_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".corrupt")
+}
+
+/// The file a save is written to before it is renamed over the wallet
+pub fn temp_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".tmp")
+}
+
+/// The `n`th most recent backup of the wallet at `path`, starting at 1
+pub fn backup_path(path: &Path, n: usize) -> PathBuf {
+    with_suffix(path, &format!(".bak{}", n))
+}
+
+/// The backups of the wallet at `path` that exist, most recent first
+pub fn backups(path: &Path, max_backups: usize) -> Vec<PathBuf> {
+    (1..=max_backups)
+        .map(|n| backup_path(path, n))
+        .filter(|p| p.exists())
+        .collect()
+}
+
+/// Save `bytes` to the wallet at `path` so that a crash or a full disk never leaves it half written: they are written
+/// to a temp file that is synced to disk and then renamed over the wallet. The previous wallet is kept as the most
+/// recent of `max_backups` backups.
+pub fn save(path: &Path, bytes: &[u8], max_backups: usize) -> io::Result<()> {
+    save_with(path, max_backups, |file| file.write_all(bytes))
+}
+
+fn save_with<F>(path: &Path, max_backups: usize, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut File) -> io::Result<()>,
+{
+    let tmp = temp_path(path);
+
+    // Whatever a crashed save left behind is overwritten
+    let written = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp)
+        .and_then(|mut file| {
+            write(&mut file)?;
+            file.sync_all()
+        });
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+
+    if path.exists() && max_backups > 0 {
+        rotate_backups(path, max_backups)?;
+    }
+
+    fs::rename(&tmp, path)?;
+    sync_dir(path);
+
+    Ok(())
+}
+
+// Shift the backups down by one, dropping the oldest, and copy the wallet into the most recent one. The wallet is
+// copied rather than moved, so there is always a wallet at `path` even if this is interrupted.
+fn rotate_backups(path: &Path, max_backups: usize) -> io::Result<()> {
+    for n in (1..max_backups).rev() {
+        let from = backup_path(path, n);
+        if from.exists() {
+            fs::rename(&from, backup_path(path, n + 1))?;
+        }
+    }
+
+    let latest = backup_path(path, 1);
+    fs::copy(path, &latest)?;
+    File::open(&latest)?.sync_all()
+}
+
+// Make the renames durable. Directories can't be opened on all platforms, so this is best effort.
+fn sync_dir(path: &Path) {
+    if cfg!(unix) {
+        if let Some(dir) = path.parent() {
+            if let Ok(dir) = File::open(dir) {
+                let _ = dir.sync_all();
+            }
+        }
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{self, ErrorKind, Write};
+
+    use tempdir::TempDir;
+
+    use super::{
+        backup_path, backups, candidates, corrupt_path, is_corrupted, is_encrypted, open, open_with_password, read,
+        read_with_password, remove_copies, remove_plaintext_copies, restore, save, save_with, seal, seal_encrypted,
+        temp_path, FileKey, ENCRYPTED_ENVELOPE, HEADER_LEN,
+    };
+    use crate::lightwallet::kdf::Kdf;
+
+    #[test]
+    fn saving_and_rotating() {
+        let dir = TempDir::new("wallet_file").unwrap();
+        let path = dir.path().join("wallet.dat");
+
+        save(&path, b"one", 2).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"one");
+        assert!(backups(&path, 2).is_empty());
+        assert!(!temp_path(&path).exists());
+
+        save(&path, b"two", 2).unwrap();
+        save(&path, b"three", 2).unwrap();
+        save(&path, b"four", 2).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"four");
+        assert_eq!(backups(&path, 2), vec![backup_path(&path, 1), backup_path(&path, 2)]);
+        assert_eq!(fs::read(backup_path(&path, 1)).unwrap(), b"three");
+        assert_eq!(fs::read(backup_path(&path, 2)).unwrap(), b"two");
+        assert!(!backup_path(&path, 3).exists());
+
+        // No backups at all
+        let path = dir.path().join("nobackups.dat");
+        save(&path, b"one", 0).unwrap();
+        save(&path, b"two", 0).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"two");
+        assert!(!backup_path(&path, 1).exists());
+    }
+
+    #[test]
+    fn interrupted_writes() {
+        let dir = TempDir::new("wallet_file").unwrap();
+        let path = dir.path().join("wallet.dat");
+        save(&path, b"good wallet", 3).unwrap();
+
+        // The disk fills up half way through the write
+        let e = save_with(&path, 3, |file| {
+            file.write_all(b"half a wal")?;
+            Err(io::Error::new(io::ErrorKind::Other, "No space left on device"))
+        })
+        .unwrap_err();
+        assert_eq!(e.to_string(), "No space left on device");
+
+        // The wallet and its backups are untouched, and the partial write is cleaned up
+        assert_eq!(fs::read(&path).unwrap(), b"good wallet");
+        assert!(backups(&path, 3).is_empty());
+        assert!(!temp_path(&path).exists());
+
+        // The process dies after writing the temp file, before renaming it
+        fs::write(temp_path(&path), b"half a wal").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"good wallet");
+
+        // The next save overwrites what it left behind
+        save(&path, b"better wallet", 3).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"better wallet");
+        assert_eq!(fs::read(backup_path(&path, 1)).unwrap(), b"good wallet");
+        assert!(!temp_path(&path).exists());
+    }
+
+    #[test]
+    fn envelope() {
+        let wallet = b"\x19\x00\x00\x00\x00\x00\x00\x00 a version 25 wallet".to_vec();
+        let sealed = seal(&wallet);
+        assert_eq!(sealed.len(), HEADER_LEN + wallet.len());
+        assert_eq!(open(&sealed).unwrap(), &wallet[..]);
+
+        // Files written before the envelope are read as they are
+        assert_eq!(open(&wallet).unwrap(), &wallet[..]);
+
+        // Truncation, anywhere
+        for len in [sealed.len() - 1, HEADER_LEN, HEADER_LEN - 1, 10] {
+            let e = open(&sealed[..len]).unwrap_err();
+            assert!(e.to_string().contains("truncated"), "{}", e);
+            assert!(is_corrupted(&e));
+        }
+
+        // A flipped bit, in the wallet or in the checksum
+        for i in [HEADER_LEN + 3, HEADER_LEN - 1] {
+            let mut rotten = sealed.clone();
+            rotten[i] ^= 0x10;
+            assert_eq!(
+                open(&rotten).unwrap_err().to_string(),
+                "Wallet file is corrupted: checksum mismatch"
+            );
+        }
+
+        // Garbage after the end
+        let mut longer = sealed.clone();
+        longer.push(0);
+        assert!(is_corrupted(&open(&longer).unwrap_err()));
+
+        // A newer envelope isn't damage
+        let mut newer = sealed.clone();
+        newer[8] = ENCRYPTED_ENVELOPE as u8 + 1;
+        let e = open(&newer).unwrap_err();
+        assert!(e.to_string().contains("newer"));
+        assert!(!is_corrupted(&e));
+    }
+
+    #[test]
+    fn encrypted_envelope() {
+        let wallet = b"a wallet with the whole transaction history".to_vec();
+        let key = FileKey::from_password("correct horse").unwrap();
+
+        let sealed = seal_encrypted(&wallet, &key);
+        assert!(is_encrypted(&sealed));
+        assert!(!is_encrypted(&seal(&wallet)));
+        assert!(!is_encrypted(&wallet));
+        assert!(!sealed.windows(11).any(|w| w == b"transaction"));
+        let (opened, reopened_key) = open_with_password(&sealed, Some("correct horse")).unwrap();
+        assert_eq!(opened, wallet);
+
+        // The salt is kept, so saving again doesn't need a new key
+        assert_eq!(reopened_key.as_ref().unwrap().kdf, key.kdf);
+        assert_eq!(
+            open_with_password(&seal_encrypted(&wallet, &reopened_key.unwrap()), Some("correct horse"))
+                .unwrap()
+                .0,
+            wallet
+        );
+
+        // Every save has a new nonce, and every new key a new salt
+        assert_ne!(seal_encrypted(&wallet, &key), sealed);
+        assert_ne!(FileKey::from_password("correct horse").unwrap().kdf, key.kdf);
+
+        // Without the right password, there's nothing to read
+        assert_eq!(open(&sealed).unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(
+            open_with_password(&sealed, None).unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            open_with_password(&sealed, Some("wrong horse")).unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+
+        // Wallets that aren't encrypted don't need the password, and aren't encrypted when saved again
+        for plain in vec![seal(&wallet), wallet.clone()] {
+            let (opened, key) = open_with_password(&plain, Some("correct horse")).unwrap();
+            assert_eq!(opened, wallet);
+            assert!(key.is_none());
+        }
+
+        // Bit-rot is found before decrypting, so it isn't mistaken for a wrong password
+        let mut rotten = sealed.clone();
+        *rotten.last_mut().unwrap() ^= 1;
+        assert!(is_encrypted(&rotten));
+        assert_eq!(
+            open_with_password(&rotten, Some("correct horse")).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn upgrading_legacy_file_key() {
+        let wallet = b"a wallet".to_vec();
+        let legacy = FileKey::derive(Kdf::DoubleSha256, "correct horse").unwrap();
+        let sealed = seal_encrypted(&wallet, &legacy);
+
+        // Still readable, but saved with argon2id from now on
+        let (opened, key) = open_with_password(&sealed, Some("correct horse")).unwrap();
+        assert_eq!(opened, wallet);
+        let key = key.unwrap();
+        assert!(!key.kdf.is_legacy());
+        assert_eq!(
+            open_with_password(&seal_encrypted(&wallet, &key), Some("correct horse"))
+                .unwrap()
+                .0,
+            wallet
+        );
+
+        assert_eq!(
+            open_with_password(&sealed, Some("wrong horse")).unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+    }
+
+    #[test]
+    fn removing_plaintext_copies() {
+        let dir = TempDir::new("wallet_file").unwrap();
+        let path = dir.path().join("wallet.dat");
+        let key = FileKey::from_password("correct horse").unwrap();
+
+        save(&path, &seal(b"one"), 3).unwrap();
+        save(&path, &seal(b"two"), 3).unwrap();
+        save(&path, &seal_encrypted(b"three", &key), 3).unwrap();
+        save(&path, &seal_encrypted(b"four", &key), 3).unwrap();
+        fs::write(corrupt_path(&path), seal(b"rotten")).unwrap();
+        fs::write(temp_path(&path), seal(b"half")).unwrap();
+        assert_eq!(backups(&path, 3).len(), 3);
+
+        remove_plaintext_copies(&path, 3).unwrap();
+        assert_eq!(backups(&path, 3), vec![backup_path(&path, 1)]);
+        assert!(!corrupt_path(&path).exists());
+        assert!(!temp_path(&path).exists());
+        assert_eq!(read_with_password(&path, Some("correct horse")).unwrap().0, b"four");
+        assert_eq!(
+            read_with_password(&backup_path(&path, 1), Some("correct horse"))
+                .unwrap()
+                .0,
+            b"three"
+        );
+    }
+
+    #[test]
+    fn removing_all_copies() {
+        let dir = TempDir::new("wallet_file").unwrap();
+        let path = dir.path().join("wallet.dat");
+        let key = FileKey::from_password("correct horse").unwrap();
+
+        save(&path, &seal(b"one"), 3).unwrap();
+        save(&path, &seal_encrypted(b"two", &key), 3).unwrap();
+        save(&path, &seal_encrypted(b"three", &key), 3).unwrap();
+        fs::write(corrupt_path(&path), seal_encrypted(b"rotten", &key)).unwrap();
+        fs::write(temp_path(&path), seal(b"half")).unwrap();
+
+        remove_copies(&path, 3).unwrap();
+        assert!(backups(&path, 3).is_empty());
+        assert!(!corrupt_path(&path).exists());
+        assert!(!temp_path(&path).exists());
+        assert_eq!(read_with_password(&path, Some("correct horse")).unwrap().0, b"three");
+
+        // Nothing to delete is fine
+        remove_copies(&path, 3).unwrap();
+    }
+
+    #[test]
+    fn finding_a_good_copy() {
+        let dir = TempDir::new("wallet_file").unwrap();
+        let path = dir.path().join("wallet.dat");
+        save(&path, &seal(b"one"), 3).unwrap();
+        save(&path, &seal(b"two"), 3).unwrap();
+        save(&path, &seal(b"three"), 3).unwrap();
+
+        assert_eq!(
+            candidates(&path, 3),
+            vec![path.clone(), backup_path(&path, 1), backup_path(&path, 2)]
+        );
+        assert_eq!(read(&path).unwrap(), b"three");
+
+        // The wallet rots, so the first good copy is the latest backup
+        let mut rotten = fs::read(&path).unwrap();
+        *rotten.last_mut().unwrap() ^= 1;
+        fs::write(&path, rotten).unwrap();
+        let good = candidates(&path, 3).into_iter().find(|p| read(p).is_ok()).unwrap();
+        assert_eq!(good, backup_path(&path, 1));
+        assert_eq!(read(&good).unwrap(), b"two");
+
+        // The backup takes its place, and the rotten wallet is kept out of the backups
+        let aside = restore(&path, &good).unwrap();
+        assert_eq!(read(&path).unwrap(), b"two");
+        assert!(read(&aside).is_err());
+        save(&path, &seal(b"two and a half"), 3).unwrap();
+        assert_eq!(read(&backup_path(&path, 1)).unwrap(), b"two");
+        assert_eq!(read(&backup_path(&path, 2)).unwrap(), b"two");
+        assert_eq!(read(&backup_path(&path, 3)).unwrap(), b"one");
+    }
+
+    #[test]
+    fn unwritable() {
+        let dir = TempDir::new("wallet_file").unwrap();
+
+        // Errors are returned, not panics
+        let path = dir.path().join("missing").join("wallet.dat");
+        assert!(save(&path, b"wallet", 3).is_err());
+        assert!(!path.exists());
+
+        // The wallet path is a directory, so the temp file can't be renamed over it
+        let path = dir.path().join("dir.dat");
+        fs::create_dir(&path).unwrap();
+        assert!(save(&path, b"wallet", 0).is_err());
+        assert!(path.is_dir());
+    }
+}
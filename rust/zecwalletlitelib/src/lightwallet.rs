@@ -3,7 +3,11 @@ use crate::lightwallet::data::WalletTx;
 use crate::lightwallet::wallettkey::WalletTKey;
 use crate::{
     blaze::fetch_full_tx::FetchFullTxns,
-    lightclient::lightclient_config::LightClientConfig,
+    grpc_connector::GrpcConnector,
+    lightclient::{
+        lightclient_config::{self, LightClientConfig},
+        wallet_file::FileKey,
+    },
     lightwallet::{
         data::SpendableSaplingNote,
         walletzkey::{WalletZKey, WalletZKeyType},
@@ -43,7 +47,10 @@ use std::{
     cmp,
     collections::HashMap,
     io::{Error, ErrorKind, Read, Write},
-    sync::{atomic::AtomicU64, Arc},
+    sync::{
+        atomic::{AtomicBool, AtomicU64},
+        Arc,
+    },
     time::SystemTime,
 };
 use tokio::sync::RwLock;
@@ -79,8 +86,10 @@ use self::{
 
 pub(crate) mod data;
 mod extended_key;
+pub(crate) mod kdf;
 pub(crate) mod keys;
 pub(crate) mod message;
+pub(crate) mod slip39;
 pub(crate) mod utils;
 pub(crate) mod wallet_txns;
 mod walletokey;
@@ -137,6 +146,13 @@ pub enum MemoDownloadOption {
 pub struct WalletOptions {
     pub(crate) download_memos: MemoDownloadOption,
     pub(crate) spam_threshold: i64,
+    pub(crate) trial_decryption_threads: u32,
+    pub(crate) validate_headers: bool,
+
+    // Lock the spending keys again after they haven't been used for this many seconds, or after this many sends since
+    // they were unlocked. 0 turns either off.
+    pub(crate) auto_lock_timeout: u64,
+    pub(crate) auto_lock_sends: u32,
 }
 
 impl Default for WalletOptions {
@@ -144,13 +160,17 @@ impl Default for WalletOptions {
         WalletOptions {
             download_memos: MemoDownloadOption::WalletMemos,
             spam_threshold: -1,
+            trial_decryption_threads: 0,
+            validate_headers: false,
+            auto_lock_timeout: 0,
+            auto_lock_sends: 0,
         }
     }
 }
 
 impl WalletOptions {
     pub fn serialized_version() -> u64 {
-        return 2;
+        return 5;
     }
 
     pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
@@ -174,9 +194,27 @@ impl WalletOptions {
             reader.read_i64::<LittleEndian>()?
         };
 
+        let trial_decryption_threads = if version <= 2 {
+            0
+        } else {
+            reader.read_u32::<LittleEndian>()?
+        };
+
+        let validate_headers = if version <= 3 { false } else { reader.read_u8()? != 0 };
+
+        let (auto_lock_timeout, auto_lock_sends) = if version <= 4 {
+            (0, 0)
+        } else {
+            (reader.read_u64::<LittleEndian>()?, reader.read_u32::<LittleEndian>()?)
+        };
+
         Ok(Self {
             download_memos,
             spam_threshold,
+            trial_decryption_threads,
+            validate_headers,
+            auto_lock_timeout,
+            auto_lock_sends,
         })
     }
 
@@ -186,7 +224,14 @@ impl WalletOptions {
 
         writer.write_u8(self.download_memos as u8)?;
 
-        writer.write_i64::<LittleEndian>(self.spam_threshold)
+        writer.write_i64::<LittleEndian>(self.spam_threshold)?;
+
+        writer.write_u32::<LittleEndian>(self.trial_decryption_threads)?;
+
+        writer.write_u8(self.validate_headers as u8)?;
+
+        writer.write_u64::<LittleEndian>(self.auto_lock_timeout)?;
+        writer.write_u32::<LittleEndian>(self.auto_lock_sends)
     }
 }
 
@@ -221,6 +266,13 @@ pub struct LightWallet<P> {
 
     // The current price of ZEC. (time_fetched, price in USD)
     pub price: Arc<RwLock<WalletZecPriceInfo>>,
+
+    // The key the whole wallet file is encrypted with, if it is. Not serialized, since it is needed to read the rest.
+    file_key: Arc<RwLock<Option<FileKey>>>,
+
+    // The keys were encrypted or decrypted since the wallet was last saved, so the older copies of the wallet file
+    // still have them the old way
+    encryption_changed: AtomicBool,
 }
 
 impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
@@ -231,11 +283,12 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
     pub fn new(
         config: LightClientConfig<P>,
         seed_phrase: Option<String>,
+        passphrase: String,
         height: u64,
         num_zaddrs: u32,
         num_oaddrs: u32,
     ) -> io::Result<Self> {
-        let keys = Keys::new(&config, seed_phrase, num_zaddrs, num_oaddrs)
+        let keys = Keys::new(&config, seed_phrase, passphrase, num_zaddrs, num_oaddrs)
             .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
 
         Ok(Self {
@@ -249,6 +302,8 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
             verified_tree: Arc::new(RwLock::new(None)),
             send_progress: Arc::new(RwLock::new(SendProgress::new(0))),
             price: Arc::new(RwLock::new(WalletZecPriceInfo::new())),
+            file_key: Arc::new(RwLock::new(None)),
+            encryption_changed: AtomicBool::new(false),
         })
     }
 
@@ -318,6 +373,14 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
         ))
     }
 
+    // Whether the serialized wallet in `bytes` was written by a newer version than this one can read
+    pub(crate) fn is_newer_version(mut bytes: &[u8]) -> bool {
+        bytes
+            .read_u64::<LittleEndian>()
+            .map(|version| version > Self::serialized_version())
+            .unwrap_or(false)
+    }
+
     pub async fn read<R: Read>(mut reader: R, config: &LightClientConfig<P>) -> io::Result<Self> {
         let version = reader.read_u64::<LittleEndian>()?;
         if version > Self::serialized_version() {
@@ -351,7 +414,8 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
 
         let chain_name = utils::read_string(&mut reader)?;
 
-        if chain_name != config.chain_name {
+        // Servers call the same chain by different names, and an offline config uses the name from the params
+        if !lightclient_config::is_same_chain(&chain_name, &config.chain_name) {
             return Err(Error::new(
                 ErrorKind::InvalidData,
                 format!(
@@ -421,6 +485,8 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
             verified_tree: Arc::new(RwLock::new(verified_tree)),
             send_progress: Arc::new(RwLock::new(SendProgress::new(0))),
             price: Arc::new(RwLock::new(price)),
+            file_key: Arc::new(RwLock::new(None)),
+            encryption_changed: AtomicBool::new(false),
         };
 
         // For old wallets, remove unused addresses
@@ -536,6 +602,22 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
         self.wallet_options.write().await.spam_threshold = value;
     }
 
+    pub async fn set_trial_decryption_threads(&self, value: u32) {
+        self.wallet_options.write().await.trial_decryption_threads = value;
+    }
+
+    pub async fn set_validate_headers(&self, value: bool) {
+        self.wallet_options.write().await.validate_headers = value;
+    }
+
+    pub async fn set_auto_lock_timeout(&self, secs: u64) {
+        self.wallet_options.write().await.auto_lock_timeout = secs;
+    }
+
+    pub async fn set_auto_lock_sends(&self, sends: u32) {
+        self.wallet_options.write().await.auto_lock_sends = sends;
+    }
+
     pub async fn get_birthday(&self) -> u64 {
         let birthday = self.birthday.load(std::sync::atomic::Ordering::SeqCst);
         if birthday == 0 {
@@ -670,7 +752,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
             .await
             .zkeys
             .iter()
-            .find(|&wk| wk.extsk.is_some() && wk.extsk.as_ref().unwrap() == &extsk.clone())
+            .find(|&wk| wk.extsk.is_some() && wk.extsk.as_ref().unwrap() == &extsk)
             .is_some()
         {
             return "Error: Key already exists".to_string();
@@ -1254,9 +1336,9 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
                 if note.spent.is_some() || note.unconfirmed_spent.is_some() {
                     None
                 } else {
-                    // Get the spending key for the selected fvk, if we have it
-                    let extsk = keys.get_extsk_for_extfvk(&note.extfvk);
-                    SpendableSaplingNote::from(txid, note, self.config.anchor_offset as usize, &extsk)
+                    // Only notes we can spend. The spending key itself is fetched later, for the notes that are spent
+                    let spendable = keys.have_sapling_spending_key(&note.extfvk);
+                    SpendableSaplingNote::from(txid, note, self.config.anchor_offset as usize, spendable)
                 }
             })
             .collect::<Vec<_>>();
@@ -1478,10 +1560,6 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
         let mut builder = Builder::new(self.config.get_params().clone(), target_height);
         builder.with_progress_notifier(progress_notifier);
 
-        // Create a map from address -> sk for all taddrs, so we can spend from the
-        // right address
-        let address_to_sk = self.keys.read().await.get_taddr_to_sk_map();
-
         // BitcoinZ doesn't support Orchard, so never prefer it
         let prefer_orchard = false;
 
@@ -1508,7 +1586,8 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
 
         let mut change = 0u64;
 
-        // Add all tinputs
+        // Add all tinputs. The secret keys are looked up one at a time, so the builder has the only copies
+        let keys = self.keys.read().await;
         utxos
             .iter()
             .map(|utxo| {
@@ -1519,10 +1598,10 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
                     script_pubkey: Script { 0: utxo.script.clone() },
                 };
 
-                match address_to_sk.get(&utxo.address) {
+                match keys.get_taddr_sk(&utxo.address) {
                     Some(sk) => {
                         change += u64::from(coin.value);
-                        builder.add_transparent_input(*sk, outpoint.clone(), coin.clone())
+                        builder.add_transparent_input(sk, outpoint.clone(), coin.clone())
                     }
                     None => {
                         // Something is very wrong
@@ -1535,6 +1614,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
             })
             .collect::<Result<Vec<_>, _>>()
             .map_err(|e| format!("{:?}", e))?;
+        drop(keys);
 
         // Skip Orchard notes for BitcoinZ
         if o_notes.len() > 0 {
@@ -1543,8 +1623,18 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
 
         // Add Sapling notes
         for selected in s_notes.iter() {
+            // Fetched here so that the spending key is only copied into the builder
+            let extsk = match self.keys.read().await.get_extsk_for_extfvk(&selected.extfvk) {
+                Some(extsk) => extsk,
+                None => {
+                    let e = "Wallet is locked".to_string();
+                    error!("{}", e);
+                    return Err(e);
+                }
+            };
+
             if let Err(e) = builder.add_sapling_spend(
-                selected.extsk.clone(),
+                extsk,
                 selected.diversifier,
                 selected.note.clone(),
                 selected.witness.path().unwrap(),
@@ -1781,7 +1871,15 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
         info!("Transaction size: {} bytes", raw_tx.len());
 
         info!("Broadcasting transaction to network...");
-        let txid = broadcast_fn(raw_tx.clone().into_boxed_slice()).await?;
+        let txid = match broadcast_fn(raw_tx.clone().into_boxed_slice()).await {
+            Ok(txid) => txid,
+            // An earlier try that seemed to fail got through after all
+            Err(e) if GrpcConnector::is_already_sent(&e) => {
+                warn!("Server already has the transaction: {}", e);
+                tx.txid().to_string()
+            }
+            Err(e) => return Err(e),
+        };
         info!("Transaction broadcast successful, txid: {}", txid);
 
         // Mark notes as spent.
@@ -1853,7 +1951,10 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
     }
 
     pub async fn encrypt(&self, passwd: String) -> io::Result<()> {
-        self.keys.write().await.encrypt(passwd)
+        self.keys.write().await.encrypt(passwd)?;
+
+        self.set_encryption_changed();
+        Ok(())
     }
 
     pub async fn lock(&self) -> io::Result<()> {
@@ -1865,7 +1966,82 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
     }
 
     pub async fn remove_encryption(&self, passwd: String) -> io::Result<()> {
-        self.keys.write().await.remove_encryption(passwd)
+        self.keys.write().await.remove_encryption(passwd)?;
+
+        // The wallet is stored in plaintext now, so the rest of the file is too
+        *self.file_key.write().await = None;
+
+        self.set_encryption_changed();
+        Ok(())
+    }
+
+    pub async fn change_password(&self, old_passwd: String, new_passwd: String) -> io::Result<()> {
+        let mut keys = self.keys.write().await;
+        let mut file_key = self.file_key.write().await;
+
+        // The file key is derived first, so that a failure leaves both the keys and the file on the old password
+        let new_file_key = match *file_key {
+            Some(_) => Some(FileKey::from_password(&new_passwd)?),
+            None => None,
+        };
+        keys.change_password(&old_passwd, &new_passwd)?;
+        *file_key = new_file_key;
+
+        self.set_encryption_changed();
+        Ok(())
+    }
+
+    // Encrypt the whole wallet file with the wallet's password from the next save on, including the addresses, txns
+    // and memos. The keys have to be encrypted already.
+    pub async fn encrypt_file(&self, passwd: String) -> io::Result<()> {
+        let keys = self.keys.read().await;
+        if !keys.encrypted {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "The wallet has to be encrypted before the whole file can be",
+            ));
+        }
+        keys.check_password(&passwd)?;
+
+        *self.file_key.write().await = Some(FileKey::from_password(&passwd)?);
+
+        self.set_encryption_changed();
+        Ok(())
+    }
+
+    pub async fn remove_file_encryption(&self, passwd: String) -> io::Result<()> {
+        if !self.is_file_encrypted().await {
+            return Err(Error::new(ErrorKind::AlreadyExists, "Wallet file is not encrypted"));
+        }
+        self.keys.read().await.check_password(&passwd)?;
+
+        *self.file_key.write().await = None;
+
+        self.set_encryption_changed();
+        Ok(())
+    }
+
+    pub async fn is_file_encrypted(&self) -> bool {
+        self.file_key.read().await.is_some()
+    }
+
+    pub(crate) async fn file_key(&self) -> Option<FileKey> {
+        self.file_key.read().await.clone()
+    }
+
+    // Whether the keys' encryption changed since this was last called. A save calls it before writing the wallet, and
+    // `set_encryption_changed` again if it fails.
+    pub(crate) fn take_encryption_changed(&self) -> bool {
+        self.encryption_changed.swap(false, std::sync::atomic::Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_encryption_changed(&self) {
+        self.encryption_changed.store(true, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    // The wallet was read from a file encrypted with `key`
+    pub(crate) async fn set_file_key(&self, key: Option<FileKey>) {
+        *self.file_key.write().await = key;
     }
 }
 
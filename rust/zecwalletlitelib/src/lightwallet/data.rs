@@ -14,7 +14,8 @@ use zcash_primitives::memo::MemoBytes;
 use zcash_primitives::sapling;
 
 use crate::blaze::fixed_size_buffer::FixedSizeBuffer;
-use zcash_primitives::{consensus::BlockHeight, zip32::ExtendedSpendingKey};
+use crate::blaze::header_validation::EQUIHASH_INPUT_LEN;
+use zcash_primitives::consensus::BlockHeight;
 use zcash_primitives::{
     memo::Memo,
     merkle_tree::{CommitmentTree, IncrementalWitness},
@@ -53,7 +54,8 @@ impl BlockData {
             }
         }
 
-        cb.header.clear();
+        // Keep the header's fields for the difficulty checks of the next sync, but not its Equihash solution
+        cb.header.truncate(EQUIHASH_INPUT_LEN);
         let height = cb.height;
 
         let mut ecb = vec![];
@@ -955,20 +957,16 @@ pub struct SpendableSaplingNote {
     pub diversifier: Diversifier,
     pub note: sapling::Note,
     pub witness: IncrementalWitness<Node>,
-    pub extsk: ExtendedSpendingKey,
+    // The spending key is looked up by this when the note is spent, rather than copied for every candidate note
+    pub extfvk: ExtendedFullViewingKey,
 }
 
 impl SpendableSaplingNote {
-    pub fn from(
-        txid: TxId,
-        nd: &SaplingNoteData,
-        anchor_offset: usize,
-        extsk: &Option<ExtendedSpendingKey>,
-    ) -> Option<Self> {
+    pub fn from(txid: TxId, nd: &SaplingNoteData, anchor_offset: usize, have_spending_key: bool) -> Option<Self> {
         // Include only notes that haven't been spent, or haven't been included in an unconfirmed spend yet.
         if nd.spent.is_none()
             && nd.unconfirmed_spent.is_none()
-            && extsk.is_some()
+            && have_spending_key
             && nd.witnesses.len() >= (anchor_offset + 1)
         {
             let witness = nd.witnesses.get(nd.witnesses.len() - anchor_offset - 1);
@@ -979,7 +977,7 @@ impl SpendableSaplingNote {
                 diversifier: nd.diversifier,
                 note: nd.note.clone(),
                 witness: w.clone(),
-                extsk: extsk.clone().unwrap(),
+                extfvk: nd.extfvk.clone(),
             })
         } else {
             None
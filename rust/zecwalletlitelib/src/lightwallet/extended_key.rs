@@ -1,6 +1,9 @@
 use lazy_static::lazy_static;
 use ring::hmac::{self, Context, Key};
 use secp256k1::{Error, PublicKey, Secp256k1, SecretKey, SignOnly};
+use zeroize::Zeroize;
+
+use super::utils;
 
 lazy_static! {
     static ref SECP256K1_SIGN_ONLY: Secp256k1<SignOnly> = Secp256k1::signing_only();
@@ -117,3 +120,11 @@ impl ExtendedPrivKey {
         })
     }
 }
+
+// Every step of a derivation path is a private key, so they are all wiped when dropped
+impl Drop for ExtendedPrivKey {
+    fn drop(&mut self) {
+        utils::wipe_secret_key(&mut self.private_key);
+        self.chain_code.zeroize();
+    }
+}
@@ -0,0 +1,185 @@
+use std::io::{self, ErrorKind, Read, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use sodiumoxide::crypto::{pwhash::argon2id13, secretbox};
+
+use super::keys::double_sha256;
+
+// How expensive it is to derive a key from a password. Every guess at the password costs this much as well, so this
+// is as much as a phone can afford when the wallet is unlocked. Tests use the cheapest settings argon2id allows.
+#[cfg(not(test))]
+pub const KDF_OPSLIMIT: u64 = argon2id13::OPSLIMIT_INTERACTIVE.0 as u64;
+#[cfg(not(test))]
+pub const KDF_MEMLIMIT: u64 = argon2id13::MEMLIMIT_INTERACTIVE.0 as u64;
+#[cfg(test)]
+pub const KDF_OPSLIMIT: u64 = 1;
+#[cfg(test)]
+pub const KDF_MEMLIMIT: u64 = 8192;
+
+/// How an encryption key is derived from the wallet's password. It is stored next to whatever it encrypted, so that
+/// a stronger derivation can be added later without making older wallets unreadable.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Kdf {
+    /// A double sha256 of the password, without a salt. Wallets and wallet files were encrypted like this before
+    /// argon2id, and it is only used to read those.
+    DoubleSha256,
+    Argon2id {
+        salt: [u8; argon2id13::SALTBYTES],
+        opslimit: u64,
+        memlimit: u64,
+    },
+}
+
+impl Kdf {
+    fn serialized_version() -> u8 {
+        return 1;
+    }
+
+    /// Argon2id with a new random salt and the default parameters
+    pub fn new() -> Self {
+        Self::argon2id(KDF_OPSLIMIT, KDF_MEMLIMIT)
+    }
+
+    /// Argon2id with a new random salt
+    pub fn argon2id(opslimit: u64, memlimit: u64) -> Self {
+        Kdf::Argon2id {
+            salt: argon2id13::gen_salt().0,
+            opslimit,
+            memlimit,
+        }
+    }
+
+    /// Whether this is a KDF that wallets should be moved off as soon as the password is known
+    pub fn is_legacy(&self) -> bool {
+        *self == Kdf::DoubleSha256
+    }
+
+    pub fn derive_key(&self, passwd: &[u8]) -> io::Result<secretbox::Key> {
+        match self {
+            Kdf::DoubleSha256 => Ok(secretbox::Key::from_slice(&double_sha256(passwd)).unwrap()),
+            Kdf::Argon2id {
+                salt,
+                opslimit,
+                memlimit,
+            } => {
+                let mut key = [0u8; secretbox::KEYBYTES];
+                argon2id13::derive_key(
+                    &mut key,
+                    passwd,
+                    &argon2id13::Salt(*salt),
+                    argon2id13::OpsLimit(*opslimit as usize),
+                    argon2id13::MemLimit(*memlimit as usize),
+                )
+                .map_err(|_| {
+                    io::Error::new(
+                        ErrorKind::Other,
+                        format!(
+                            "Couldn't derive a key from the password with {} bytes of memory",
+                            memlimit
+                        ),
+                    )
+                })?;
+
+                Ok(secretbox::Key(key))
+            }
+        }
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let version = reader.read_u8()?;
+        if version > Self::serialized_version() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Don't know how to read key derivation version {}", version),
+            ));
+        }
+
+        match reader.read_u8()? {
+            0 => Ok(Kdf::DoubleSha256),
+            1 => {
+                let mut salt = [0u8; argon2id13::SALTBYTES];
+                reader.read_exact(&mut salt)?;
+                let opslimit = reader.read_u64::<LittleEndian>()?;
+                let memlimit = reader.read_u64::<LittleEndian>()?;
+
+                // Don't let a damaged or crafted file make us spend unbounded time or memory on the password
+                if opslimit > argon2id13::OPSLIMIT_SENSITIVE.0 as u64
+                    || memlimit > argon2id13::MEMLIMIT_SENSITIVE.0 as u64
+                {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "Key derivation parameters are too large: {} ops, {} bytes",
+                            opslimit, memlimit
+                        ),
+                    ));
+                }
+
+                Ok(Kdf::Argon2id {
+                    salt,
+                    opslimit,
+                    memlimit,
+                })
+            }
+            n => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Unknown key derivation {}", n),
+            )),
+        }
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_u8(Self::serialized_version())?;
+
+        match self {
+            Kdf::DoubleSha256 => writer.write_u8(0),
+            Kdf::Argon2id {
+                salt,
+                opslimit,
+                memlimit,
+            } => {
+                writer.write_u8(1)?;
+                writer.write_all(salt)?;
+                writer.write_u64::<LittleEndian>(*opslimit)?;
+                writer.write_u64::<LittleEndian>(*memlimit)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Kdf;
+
+    #[test]
+    fn derive_and_serialize() {
+        let kdf = Kdf::new();
+        assert!(!kdf.is_legacy());
+
+        let key = kdf.derive_key(b"correct horse").unwrap();
+        assert_eq!(kdf.derive_key(b"correct horse").unwrap(), key);
+        assert_ne!(kdf.derive_key(b"wrong horse").unwrap(), key);
+
+        // Every new KDF gets its own salt
+        assert_ne!(Kdf::new().derive_key(b"correct horse").unwrap(), key);
+
+        let mut bytes = vec![];
+        kdf.write(&mut bytes).unwrap();
+        let read = Kdf::read(&bytes[..]).unwrap();
+        assert_eq!(read, kdf);
+        assert_eq!(read.derive_key(b"correct horse").unwrap(), key);
+
+        let mut bytes = vec![];
+        Kdf::DoubleSha256.write(&mut bytes).unwrap();
+        assert!(Kdf::read(&bytes[..]).unwrap().is_legacy());
+
+        // Derivations this version doesn't know are refused
+        bytes[1] = 7;
+        assert!(Kdf::read(&bytes[..]).is_err());
+
+        // Parameters no wallet would use are refused before any work is done
+        let mut bytes = vec![];
+        Kdf::argon2id(1, u64::MAX).write(&mut bytes).unwrap();
+        assert!(Kdf::read(&bytes[..]).is_err());
+    }
+}
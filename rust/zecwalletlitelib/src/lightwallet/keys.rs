@@ -1,11 +1,9 @@
-use std::{
-    collections::HashMap,
-    io::{self, Error, ErrorKind, Read, Write},
-};
+use std::io::{self, Error, ErrorKind, Read, Write};
 
 use base58::{FromBase58, ToBase58};
 use bip39::{Language, Mnemonic, Seed};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::info;
 use orchard::keys::{FullViewingKey, IncomingViewingKey, Scope};
 use rand::{rngs::OsRng, Rng};
 use ripemd160::Digest;
@@ -23,6 +21,7 @@ use zcash_primitives::{
     sapling::PaymentAddress,
     zip32::{ChildIndex, ExtendedFullViewingKey, ExtendedSpendingKey},
 };
+use zeroize::{Zeroize, Zeroizing};
 
 use crate::{
     lightclient::lightclient_config::{LightClientConfig, GAP_RULE_UNUSED_ADDRESSES},
@@ -30,11 +29,43 @@ use crate::{
 };
 
 use super::{
+    kdf::Kdf,
+    slip39,
     walletokey::WalletOKey,
     wallettkey::{WalletTKey, WalletTKeyType},
     walletzkey::{WalletZKey, WalletZKeyType},
 };
 
+// The BIP39 wordlists seed phrases are accepted in, English first
+const LANGUAGES: [Language; 8] = [
+    Language::English,
+    Language::ChineseSimplified,
+    Language::ChineseTraditional,
+    Language::French,
+    Language::Italian,
+    Language::Japanese,
+    Language::Korean,
+    Language::Spanish,
+];
+
+// The length of the salt in front of a `Keys::seed_check`
+const SEED_CHECK_SALT_LEN: usize = 16;
+
+/// What `Keys::split_seed` returns for a wallet whose seed phrase isn't English
+pub const SPLIT_SEED_NOT_ENGLISH: &str =
+    "Only wallets with an English seed phrase can be split into shares, since the shares restore an English phrase";
+
+fn language_code(language: Language) -> u8 {
+    LANGUAGES.iter().position(|l| *l == language).unwrap() as u8
+}
+
+fn language_from_code(code: u8) -> io::Result<Language> {
+    LANGUAGES
+        .get(code as usize)
+        .copied()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, format!("Unknown seed phrase language {}", code)))
+}
+
 /// Sha256(Sha256(value))
 pub fn double_sha256(payload: &[u8]) -> Vec<u8> {
     let h1 = Sha256::digest(&payload);
@@ -60,7 +91,11 @@ impl ToBase58Check for [u8] {
 
         let checksum = double_sha256(&payload);
         payload.append(&mut checksum[..4].to_vec());
-        payload.to_base58()
+        let encoded = payload.to_base58();
+
+        // The payload is often a secret key
+        payload.zeroize();
+        encoded
     }
 }
 
@@ -109,8 +144,20 @@ pub struct Keys<P> {
 
     enc_seed: [u8; 48], // If locked, this contains the encrypted seed
     nonce: Vec<u8>,     // Nonce used to encrypt the wallet.
+    kdf: Kdf,           // How the encryption key is derived from the password
+
+    seed: [u8; 32],     // Seed phrase for this wallet. If wallet is locked, this is 0
+    seed_len: usize,    // How much of `seed` is the seed phrase's entropy. Shorter phrases are padded with 0s
+    language: Language, // The wordlist of the seed phrase
+
+    // Optional BIP39 passphrase the keys are derived with. If the wallet is locked, this is empty and
+    // `enc_passphrase` has it, encrypted like the seed with its nonce in front.
+    passphrase: String,
+    enc_passphrase: Vec<u8>,
 
-    seed: [u8; 32], // Seed phrase for this wallet. If wallet is locked, this is 0
+    // A salt followed by a hash of the seed phrase, kept while the wallet is locked if it has a passphrase. Without the
+    // passphrase, the addresses can't be derived from a phrase to verify it, so it is checked against this instead.
+    seed_check: Vec<u8>,
 
     // List of keys, actually in this wallet. This is a combination of HD keys derived from the seed,
     // viewing keys and imported spending keys.
@@ -127,7 +174,7 @@ pub struct Keys<P> {
 
 impl<P: consensus::Parameters> Keys<P> {
     pub fn serialized_version() -> u64 {
-        return 22;
+        return 24;
     }
 
     #[cfg(test)]
@@ -139,7 +186,13 @@ impl<P: consensus::Parameters> Keys<P> {
             unlocked: true,
             enc_seed: [0; 48],
             nonce: vec![],
+            kdf: Kdf::DoubleSha256,
             seed: [0u8; 32],
+            seed_len: 32,
+            language: Language::English,
+            passphrase: String::new(),
+            enc_passphrase: vec![],
+            seed_check: vec![],
             zkeys: vec![],
             tkeys: vec![],
             okeys: vec![],
@@ -149,31 +202,25 @@ impl<P: consensus::Parameters> Keys<P> {
     pub fn new(
         config: &LightClientConfig<P>,
         seed_phrase: Option<String>,
+        passphrase: String,
         num_zaddrs: u32,
         num_oaddrs: u32,
     ) -> Result<Self, String> {
-        let mut seed_bytes = [0u8; 32];
-
-        if seed_phrase.is_none() {
-            // Create a random seed.
-            let mut system_rng = OsRng;
-            system_rng.fill(&mut seed_bytes);
-        } else {
-            let phrase = match Mnemonic::from_phrase(seed_phrase.unwrap().as_str(), Language::English) {
-                Ok(p) => p,
-                Err(e) => {
-                    let e = format!("Error parsing phrase: {}", e);
-                    //error!("{}", e);
-                    return Err(e);
-                }
-            };
+        let (seed_bytes, seed_len, language) = match seed_phrase {
+            None => {
+                // Create a random seed.
+                let mut seed_bytes = Zeroizing::new([0u8; 32]);
+                let mut system_rng = OsRng;
+                system_rng.fill(&mut *seed_bytes);
 
-            seed_bytes.copy_from_slice(&phrase.entropy());
-        }
+                (seed_bytes, 32, Language::English)
+            }
+            Some(phrase) => Self::entropy_from_phrase(&phrase)?,
+        };
 
         // The seed bytes is the raw entropy. To pass it to HD wallet generation,
         // we need to get the 64 byte bip39 entropy
-        let bip39_seed = Seed::new(&Mnemonic::from_entropy(&seed_bytes, Language::English).unwrap(), "");
+        let bip39_seed = Self::bip39_seed_from(&seed_bytes[..seed_len], language, &passphrase);
 
         // Derive only the first sk and address
         let tpk = WalletTKey::new_hdkey(config, 0, &bip39_seed.as_bytes());
@@ -201,7 +248,13 @@ impl<P: consensus::Parameters> Keys<P> {
             unlocked: true,
             enc_seed: [0; 48],
             nonce: vec![],
-            seed: seed_bytes,
+            kdf: Kdf::DoubleSha256,
+            seed: *seed_bytes,
+            seed_len,
+            language,
+            passphrase,
+            enc_passphrase: vec![],
+            seed_check: vec![],
             zkeys,
             tkeys: vec![tpk],
             okeys,
@@ -210,6 +263,7 @@ impl<P: consensus::Parameters> Keys<P> {
 
     pub fn read_old<R: Read>(version: u64, mut reader: R, config: &LightClientConfig<P>) -> io::Result<Self> {
         let encrypted = if version >= 4 { reader.read_u8()? > 0 } else { false };
+        let kdf = Kdf::DoubleSha256;
 
         let mut enc_seed = [0u8; 48];
         if version >= 4 {
@@ -327,7 +381,13 @@ impl<P: consensus::Parameters> Keys<P> {
             unlocked: !encrypted,
             enc_seed,
             nonce,
+            kdf,
             seed: seed_bytes,
+            seed_len: 32,
+            language: Language::English,
+            passphrase: String::new(),
+            enc_passphrase: vec![],
+            seed_check: vec![],
             zkeys,
             tkeys,
             okeys: vec![],
@@ -351,10 +411,34 @@ impl<P: consensus::Parameters> Keys<P> {
 
         let nonce = Vector::read(&mut reader, |r| r.read_u8())?;
 
+        let kdf = if version <= 22 {
+            Kdf::DoubleSha256
+        } else {
+            Kdf::read(&mut reader)?
+        };
+
         // Seed
         let mut seed_bytes = [0u8; 32];
         reader.read_exact(&mut seed_bytes)?;
 
+        let (seed_len, language, passphrase, enc_passphrase, seed_check) = if version <= 23 {
+            (32, Language::English, String::new(), vec![], vec![])
+        } else {
+            let seed_len = reader.read_u8()? as usize;
+            if seed_len > seed_bytes.len() {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Seed length {} is too long", seed_len),
+                ));
+            }
+            let language = language_from_code(reader.read_u8()?)?;
+            let passphrase = utils::read_string(&mut reader)?;
+            let enc_passphrase = Vector::read(&mut reader, |r| r.read_u8())?;
+            let seed_check = Vector::read(&mut reader, |r| r.read_u8())?;
+
+            (seed_len, language, passphrase, enc_passphrase, seed_check)
+        };
+
         let okeys = if version <= 21 {
             vec![]
         } else {
@@ -389,7 +473,13 @@ impl<P: consensus::Parameters> Keys<P> {
             unlocked: !encrypted,
             enc_seed,
             nonce,
+            kdf,
             seed: seed_bytes,
+            seed_len,
+            language,
+            passphrase,
+            enc_passphrase,
+            seed_check,
             zkeys,
             tkeys,
             okeys,
@@ -413,9 +503,26 @@ impl<P: consensus::Parameters> Keys<P> {
         // Write the nonce
         Vector::write(&mut writer, &self.nonce, |w, b| w.write_u8(*b))?;
 
+        // Write how the key is derived from the password
+        self.kdf.write(&mut writer)?;
+
         // Write the seed
         writer.write_all(&self.seed)?;
 
+        // Write the rest of the seed phrase. The passphrase is only written out if the wallet isn't encrypted,
+        // otherwise it is in enc_passphrase.
+        let no_passphrase = String::new();
+        let passphrase = if self.encrypted {
+            &no_passphrase
+        } else {
+            &self.passphrase
+        };
+        writer.write_u8(self.seed_len as u8)?;
+        writer.write_u8(language_code(self.language))?;
+        utils::write_string(&mut writer, passphrase)?;
+        Vector::write(&mut writer, &self.enc_passphrase, |w, b| w.write_u8(*b))?;
+        Vector::write(&mut writer, &self.seed_check, |w, b| w.write_u8(*b))?;
+
         // Flush after writing the seed, so in case of a disaster, we can still recover the seed.
         writer.flush()?;
 
@@ -435,15 +542,134 @@ impl<P: consensus::Parameters> Keys<P> {
         self.config.clone()
     }
 
-    pub fn get_seed_phrase(&self) -> String {
+    pub fn get_seed_phrase(&self) -> Zeroizing<String> {
         if !self.unlocked {
-            return "".to_string();
+            return Zeroizing::new("".to_string());
         }
 
-        Mnemonic::from_entropy(&self.seed, Language::English)
-            .unwrap()
-            .phrase()
-            .to_string()
+        Zeroizing::new(
+            Mnemonic::from_entropy(&self.seed[..self.seed_len], self.language)
+                .unwrap()
+                .phrase()
+                .to_string(),
+        )
+    }
+
+    /// Parse a BIP39 seed phrase of any length, in any of the BIP39 languages
+    pub fn parse_seed_phrase(phrase: &str) -> Result<Mnemonic, String> {
+        let mut english_err = None;
+        for language in LANGUAGES.iter() {
+            match Mnemonic::from_phrase(phrase, *language) {
+                Ok(m) => return Ok(m),
+                Err(e) if *language == Language::English => english_err = Some(e),
+                Err(_) => {}
+            }
+        }
+
+        Err(format!("Error parsing phrase: {}", english_err.unwrap()))
+    }
+
+    // The entropy of a BIP39 seed phrase, or of the SLIP-39 shares made by `split_seed`, padded to 32 bytes, with its
+    // length and language
+    fn entropy_from_phrase(phrase: &str) -> Result<(Zeroizing<[u8; 32]>, usize, Language), String> {
+        let mut seed_bytes = Zeroizing::new([0u8; 32]);
+
+        let (entropy, language) = match slip39::parse_shares(phrase) {
+            Some(shares) => (Zeroizing::new(slip39::combine(&shares)?), Language::English),
+            None => {
+                let phrase = Self::parse_seed_phrase(phrase)?;
+                (Zeroizing::new(phrase.entropy().to_vec()), phrase.language())
+            }
+        };
+
+        if entropy.len() > seed_bytes.len() {
+            return Err(format!("The seed is {} bytes, which is too long", entropy.len()));
+        }
+        seed_bytes[..entropy.len()].copy_from_slice(&entropy);
+
+        Ok((seed_bytes, entropy.len(), language))
+    }
+
+    // The seed bytes are the raw entropy. To pass it to HD wallet generation, we need the 64 byte bip39 seed,
+    // which also depends on the words and the passphrase
+    fn bip39_seed_from(entropy: &[u8], language: Language, passphrase: &str) -> Seed {
+        Seed::new(&Mnemonic::from_entropy(entropy, language).unwrap(), passphrase)
+    }
+
+    fn bip39_seed(&self) -> Seed {
+        Self::bip39_seed_from(&self.seed[..self.seed_len], self.language, &self.passphrase)
+    }
+
+    /// Split the seed into `count` SLIP-39 shares, any `threshold` of which restore the wallet. The BIP39 passphrase,
+    /// if any, is not part of the shares. The shares hold the entropy of the BIP39 phrase, and restoring them makes an
+    /// English phrase from it. The keys are derived from the words of the phrase, so a wallet with a phrase in another
+    /// language can't be split, and gets `SPLIT_SEED_NOT_ENGLISH` instead.
+    pub fn split_seed(&self, threshold: u8, count: u8) -> Result<Vec<String>, String> {
+        if !self.unlocked {
+            return Err("Wallet is locked".to_string());
+        }
+
+        if self.language != Language::English {
+            return Err(SPLIT_SEED_NOT_ENGLISH.to_string());
+        }
+
+        slip39::split(&self.seed[..self.seed_len], threshold, count)
+    }
+
+    /// Check whether `phrase` is this wallet's seed phrase, by deriving the first transparent and sapling addresses
+    /// from it and comparing them with the wallet's. Only the stored addresses are used, so this works while the
+    /// wallet is locked. A locked wallet with a BIP39 passphrase can't derive them, and checks the phrase against the
+    /// hash of the seed it kept when it was locked instead.
+    pub fn verify_seed_phrase(&self, phrase: &str) -> Result<bool, String> {
+        let locked_with_passphrase = !self.unlocked && self.has_passphrase();
+        if locked_with_passphrase && self.seed_check.len() <= SEED_CHECK_SALT_LEN {
+            return Err("The wallet has a passphrase, so it has to be unlocked to verify the seed phrase".to_string());
+        }
+
+        let first_tkey = self.tkeys.iter().find(|tk| tk.hdkey_num == Some(0));
+        let first_zkey = self.zkeys.iter().find(|zk| zk.hdkey_num == Some(0));
+        if first_tkey.is_none() && first_zkey.is_none() {
+            return Err("The wallet has no addresses from a seed phrase".to_string());
+        }
+
+        let (seed_bytes, seed_len, language) = match Self::entropy_from_phrase(phrase) {
+            Ok(entropy) => entropy,
+            // Not a seed phrase at all is a mismatch too
+            Err(_) => return Ok(false),
+        };
+        if locked_with_passphrase {
+            let salt = &self.seed_check[..SEED_CHECK_SALT_LEN];
+            return Ok(Self::seed_check_of(&seed_bytes[..seed_len], language, salt) == self.seed_check);
+        }
+        let bip39_seed = Self::bip39_seed_from(&seed_bytes[..seed_len], language, &self.passphrase);
+
+        let tmatch = first_tkey.map_or(true, |tk| {
+            let sk = WalletTKey::get_taddr_from_bip39seed(&self.config, bip39_seed.as_bytes(), 0);
+            WalletTKey::address_from_prefix_sk(&self.config.base58_pubkey_address(), &sk) == tk.address
+        });
+        let zmatch = first_zkey.map_or(true, |zk| {
+            let (_, _, address) = Self::get_zaddr_from_bip39seed(&self.config, bip39_seed.as_bytes(), 0);
+            address == zk.zaddress
+        });
+
+        Ok(tmatch && zmatch)
+    }
+
+    // `salt` followed by the hash of the seed phrase's entropy and language
+    fn seed_check_of(entropy: &[u8], language: Language, salt: &[u8]) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(b"BitcoinZ seed check");
+        hasher.update(salt);
+        hasher.update(&[language_code(language)]);
+        hasher.update(entropy);
+
+        let mut check = salt.to_vec();
+        check.extend_from_slice(&hasher.finalize());
+        check
+    }
+
+    pub fn has_passphrase(&self) -> bool {
+        !self.passphrase.is_empty() || !self.enc_passphrase.is_empty()
     }
 
     pub fn get_all_orchard_fvks(&self) -> Vec<FullViewingKey> {
@@ -526,11 +752,11 @@ impl<P: consensus::Parameters> Keys<P> {
             .flatten()
     }
 
-    pub fn get_taddr_to_sk_map(&self) -> HashMap<String, secp256k1::SecretKey> {
+    pub fn get_taddr_sk(&self, address: &String) -> Option<secp256k1::SecretKey> {
         self.tkeys
             .iter()
-            .map(|tk| (tk.address.clone(), tk.key.unwrap().clone()))
-            .collect()
+            .find(|tk| tk.address == *address)
+            .and_then(|tk| tk.key)
     }
 
     // If one of the last 'n' taddress was used, ensure we add the next HD taddress to the wallet.
@@ -615,7 +841,7 @@ impl<P: consensus::Parameters> Keys<P> {
             .max_by(|ok1, ok2| ok1.hdkey_num.unwrap().cmp(&ok2.hdkey_num.unwrap()))
             .map_or(0, |ok| ok.hdkey_num.unwrap() + 1);
 
-        let bip39_seed = bip39::Seed::new(&Mnemonic::from_entropy(&self.seed, Language::English).unwrap(), "");
+        let bip39_seed = self.bip39_seed();
 
         let spending_key =
             orchard::keys::SpendingKey::from_zip32_seed(&bip39_seed.as_bytes(), self.config.get_coin_type(), pos)
@@ -644,7 +870,7 @@ impl<P: consensus::Parameters> Keys<P> {
             .max_by(|zk1, zk2| zk1.hdkey_num.unwrap().cmp(&zk2.hdkey_num.unwrap()))
             .map_or(0, |zk| zk.hdkey_num.unwrap() + 1);
 
-        let bip39_seed = bip39::Seed::new(&Mnemonic::from_entropy(&self.seed, Language::English).unwrap(), "");
+        let bip39_seed = self.bip39_seed();
 
         let (extsk, _, _) = Self::get_zaddr_from_bip39seed(&self.config, &bip39_seed.as_bytes(), pos);
 
@@ -671,7 +897,7 @@ impl<P: consensus::Parameters> Keys<P> {
             .max_by(|sk1, sk2| sk1.hdkey_num.unwrap().cmp(&sk2.hdkey_num.unwrap()))
             .map_or(0, |sk| sk.hdkey_num.unwrap() + 1);
 
-        let bip39_seed = bip39::Seed::new(&Mnemonic::from_entropy(&self.seed, Language::English).unwrap(), "");
+        let bip39_seed = self.bip39_seed();
 
         let key = WalletTKey::new_hdkey(&self.config, pos, &bip39_seed.as_bytes());
         let address = key.address.clone();
@@ -681,18 +907,18 @@ impl<P: consensus::Parameters> Keys<P> {
     }
 
     // Get all z-address private keys. Returns a Vector of (address, privatekey, viewkey)
-    pub fn get_z_private_keys(&self) -> Vec<(String, String, String)> {
+    pub fn get_z_private_keys(&self) -> Vec<(String, Zeroizing<String>, String)> {
         let keys = self
             .zkeys
             .iter()
             .map(|k| {
                 let pkey = match k
                     .extsk
-                    .clone()
-                    .map(|extsk| encode_extended_spending_key(self.config.hrp_sapling_private_key(), &extsk))
+                    .as_ref()
+                    .map(|extsk| encode_extended_spending_key(self.config.hrp_sapling_private_key(), extsk))
                 {
-                    Some(pk) => pk,
-                    None => "".to_string(),
+                    Some(pk) => Zeroizing::new(pk),
+                    None => Zeroizing::new("".to_string()),
                 };
 
                 let vkey = encode_extended_full_viewing_key(self.config.hrp_sapling_viewing_key(), &k.extfvk);
@@ -703,17 +929,20 @@ impl<P: consensus::Parameters> Keys<P> {
                     vkey,
                 )
             })
-            .collect::<Vec<(String, String, String)>>();
+            .collect::<Vec<(String, Zeroizing<String>, String)>>();
 
         keys
     }
 
     /// Get all t-address private keys. Returns a Vector of (address, secretkey)
-    pub fn get_t_secret_keys(&self) -> Vec<(String, String)> {
+    pub fn get_t_secret_keys(&self) -> Vec<(String, Zeroizing<String>)> {
         self.tkeys
             .iter()
-            .map(|sk| (sk.address.clone(), sk.sk_as_string(&self.config).unwrap_or_default()))
-            .collect::<Vec<(String, String)>>()
+            .map(|sk| {
+                let sks = sk.sk_as_string(&self.config).unwrap_or_default();
+                (sk.address.clone(), Zeroizing::new(sks))
+            })
+            .collect::<Vec<(String, Zeroizing<String>)>>()
     }
 
     pub fn encrypt(&mut self, passwd: String) -> io::Result<()> {
@@ -721,29 +950,47 @@ impl<P: consensus::Parameters> Keys<P> {
             return Err(io::Error::new(ErrorKind::AlreadyExists, "Wallet is already encrypted"));
         }
 
-        // Get the doublesha256 of the password, which is the right length
-        let key = secretbox::Key::from_slice(&double_sha256(passwd.as_bytes())).unwrap();
+        let kdf = Kdf::new();
+        let key = kdf.derive_key(passwd.as_bytes())?;
+        self.encrypt_with(kdf, &key)?;
+
+        self.encrypted = true;
+        self.lock()?;
+
+        Ok(())
+    }
+
+    // Encrypt the seed and the individual keys with `key`, which was derived with `kdf`. This locks the individual keys.
+    fn encrypt_with(&mut self, kdf: Kdf, key: &secretbox::Key) -> io::Result<()> {
         let nonce = secretbox::gen_nonce();
 
-        let cipher = secretbox::seal(&self.seed, &nonce, &key);
+        let cipher = secretbox::seal(&self.seed, &nonce, key);
 
         self.enc_seed.copy_from_slice(&cipher);
         self.nonce = nonce.as_ref().to_vec();
+        self.kdf = kdf;
+
+        // The passphrase gets its own nonce, since it is encrypted with the same key
+        self.enc_passphrase = if self.passphrase.is_empty() {
+            vec![]
+        } else {
+            let nonce = secretbox::gen_nonce();
+            let mut enc_passphrase = nonce.as_ref().to_vec();
+            enc_passphrase.extend(secretbox::seal(self.passphrase.as_bytes(), &nonce, key));
+            enc_passphrase
+        };
 
         // Encrypt the individual keys
         self.zkeys
             .iter_mut()
-            .map(|k| k.encrypt(&key))
+            .map(|k| k.encrypt(key))
             .collect::<io::Result<Vec<()>>>()?;
 
         self.tkeys
             .iter_mut()
-            .map(|k| k.encrypt(&key))
+            .map(|k| k.encrypt(key))
             .collect::<io::Result<Vec<()>>>()?;
 
-        self.encrypted = true;
-        self.lock()?;
-
         Ok(())
     }
 
@@ -756,8 +1003,17 @@ impl<P: consensus::Parameters> Keys<P> {
             return Err(io::Error::new(ErrorKind::AlreadyExists, "Wallet is already locked"));
         }
 
+        // Keep what verify_seed_phrase needs once the seed is gone
+        self.seed_check = if self.passphrase.is_empty() {
+            vec![]
+        } else {
+            let salt = OsRng.gen::<[u8; SEED_CHECK_SALT_LEN]>();
+            Self::seed_check_of(&self.seed[..self.seed_len], self.language, &salt)
+        };
+
         // Empty the seed and the secret keys
-        self.seed.copy_from_slice(&[0u8; 32]);
+        self.seed.zeroize();
+        self.passphrase.zeroize();
 
         // Remove all the private key from the zkeys and tkeys
         self.tkeys
@@ -784,45 +1040,120 @@ impl<P: consensus::Parameters> Keys<P> {
             return Err(Error::new(ErrorKind::AlreadyExists, "Wallet is already unlocked"));
         }
 
-        // Get the doublesha256 of the password, which is the right length
-        let key = secretbox::Key::from_slice(&double_sha256(passwd.as_bytes())).unwrap();
-        let nonce = secretbox::Nonce::from_slice(&self.nonce).unwrap();
-
-        let seed = match secretbox::open(&self.enc_seed, &nonce, &key) {
-            Ok(s) => s,
-            Err(_) => {
-                return Err(io::Error::new(
-                    ErrorKind::InvalidData,
-                    "Decryption failed. Is your password correct?",
-                ));
-            }
-        };
+        let key = self.kdf.derive_key(passwd.as_bytes())?;
+        let (seed, passphrase) = self.decrypt_seed(&key)?;
 
         // Now that we have the seed, we'll generate the extsks and tkeys, and verify the fvks and addresses
         // respectively match
+        self.seed.copy_from_slice(&seed);
+        self.passphrase = passphrase.to_string();
+        if let Err(e) = self.unlock_keys(&key) {
+            self.seed.zeroize();
+            self.passphrase.zeroize();
+            return Err(e);
+        }
 
-        // The seed bytes is the raw entropy. To pass it to HD wallet generation,
-        // we need to get the 64 byte bip39 entropy
-        let bip39_seed = bip39::Seed::new(&Mnemonic::from_entropy(&seed, Language::English).unwrap(), "");
+        // Wallets encrypted with the old KDF are moved to the current one now that we have the password. The next
+        // save writes them out that way.
+        if self.kdf.is_legacy() {
+            let kdf = Kdf::new();
+            let key = kdf.derive_key(passwd.as_bytes())?;
+            self.encrypt_with(kdf, &key)?;
+            self.unlock_keys(&key)?;
+
+            info!("Upgraded the wallet's encryption to argon2id");
+        }
+
+        self.encrypted = true;
+        self.unlocked = true;
+
+        Ok(())
+    }
+
+    // Decrypt the seed and the passphrase
+    fn decrypt_seed(&self, key: &secretbox::Key) -> io::Result<(Zeroizing<Vec<u8>>, Zeroizing<String>)> {
+        let failed = || io::Error::new(ErrorKind::InvalidData, "Decryption failed. Is your password correct?");
+
+        let nonce = secretbox::Nonce::from_slice(&self.nonce).unwrap();
+        let seed = Zeroizing::new(secretbox::open(&self.enc_seed, &nonce, key).map_err(|_| failed())?);
+
+        if self.enc_passphrase.len() < secretbox::NONCEBYTES {
+            return Ok((seed, Zeroizing::new(String::new())));
+        }
+
+        let (nonce, cipher) = self.enc_passphrase.split_at(secretbox::NONCEBYTES);
+        let passphrase = secretbox::open(cipher, &secretbox::Nonce::from_slice(nonce).unwrap(), key)
+            .map_err(|_| failed())
+            .and_then(|p| String::from_utf8(p).map_err(|e| io::Error::new(ErrorKind::InvalidData, e)))?;
+
+        Ok((seed, Zeroizing::new(passphrase)))
+    }
+
+    // Put the spending keys back into the individual keys, from the seed for HD keys and by decrypting imported ones.
+    // The seed and passphrase have to be in place already.
+    fn unlock_keys(&mut self, key: &secretbox::Key) -> io::Result<()> {
+        let bip39_seed = self.bip39_seed();
         let config = self.config.clone();
 
         // Transparent keys
         self.tkeys
             .iter_mut()
-            .map(|tk| tk.unlock(&config, bip39_seed.as_bytes(), &key))
+            .map(|tk| tk.unlock(&config, bip39_seed.as_bytes(), key))
             .collect::<io::Result<Vec<()>>>()?;
 
         // Go over the zkeys, and add the spending keys again
         self.zkeys
             .iter_mut()
-            .map(|zk| zk.unlock(&config, bip39_seed.as_bytes(), &key))
+            .map(|zk| zk.unlock(&config, bip39_seed.as_bytes(), key))
             .collect::<io::Result<Vec<()>>>()?;
 
-        self.seed.copy_from_slice(&seed);
+        Ok(())
+    }
 
-        self.encrypted = true;
+    // Check the password against the encrypted seed, without unlocking the wallet
+    pub fn check_password(&self, passwd: &str) -> io::Result<()> {
+        if !self.encrypted {
+            return Err(Error::new(ErrorKind::AlreadyExists, "Wallet is not encrypted"));
+        }
+
+        let key = self.kdf.derive_key(passwd.as_bytes())?;
+        self.decrypt_seed(&key).map(|_| ())
+    }
+
+    /// How the encryption key is derived from the password
+    pub fn kdf(&self) -> &Kdf {
+        &self.kdf
+    }
+
+    /// Re-encrypt the wallet with `new_passwd`. The seed and every key are re-encrypted here, under the same lock, so
+    /// the wallet is never written out with some of them decrypted or under the old password. The wallet stays
+    /// locked or unlocked like it was.
+    pub fn change_password(&mut self, old_passwd: &str, new_passwd: &str) -> io::Result<()> {
+        if !self.encrypted {
+            return Err(Error::new(ErrorKind::AlreadyExists, "Wallet is not encrypted"));
+        }
+
+        // Everything that can fail is done before anything is changed
+        let old_key = self.kdf.derive_key(old_passwd.as_bytes())?;
+        let (seed, passphrase) = self.decrypt_seed(&old_key)?;
+        let kdf = Kdf::new();
+        let new_key = kdf.derive_key(new_passwd.as_bytes())?;
+
+        let was_unlocked = self.unlocked;
+        if !was_unlocked {
+            self.seed.copy_from_slice(&seed);
+            self.passphrase = passphrase.to_string();
+            self.unlock_keys(&old_key)?;
+        }
+
+        self.encrypt_with(kdf, &new_key)?;
+        self.unlock_keys(&new_key)?;
         self.unlocked = true;
 
+        if !was_unlocked {
+            self.lock()?;
+        }
+
         Ok(())
     }
 
@@ -853,6 +1184,8 @@ impl<P: consensus::Parameters> Keys<P> {
         self.encrypted = false;
         self.nonce = vec![];
         self.enc_seed.copy_from_slice(&[0u8; 48]);
+        self.enc_passphrase = vec![];
+        self.kdf = Kdf::DoubleSha256;
 
         Ok(())
     }
@@ -885,14 +1218,16 @@ impl<P: consensus::Parameters> Keys<P> {
     ) -> (ExtendedSpendingKey, ExtendedFullViewingKey, PaymentAddress) {
         assert_eq!(bip39_seed.len(), 64);
 
+        let mut master = ExtendedSpendingKey::master(bip39_seed);
         let extsk: ExtendedSpendingKey = ExtendedSpendingKey::from_path(
-            &ExtendedSpendingKey::master(bip39_seed),
+            &master,
             &[
                 ChildIndex::Hardened(32),
                 ChildIndex::Hardened(config.get_coin_type()),
                 ChildIndex::Hardened(pos),
             ],
         );
+        utils::wipe_extsk(&mut master);
         let extfvk = ExtendedFullViewingKey::from(&extsk);
         let address = extfvk.default_address().1;
 
@@ -907,3 +1242,192 @@ impl<P: consensus::Parameters> Keys<P> {
         }
     }
 }
+
+impl<P> Drop for Keys<P> {
+    fn drop(&mut self) {
+        self.seed.zeroize();
+        self.passphrase.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lightclient::{
+        lightclient_config::{LightClientConfig, UnitTestNetwork},
+        tests::TEST_SEED,
+    };
+    use crate::lightwallet::{kdf::Kdf, utils};
+    use bip39::{Language, Mnemonic, MnemonicType};
+
+    use super::{Keys, SPLIT_SEED_NOT_ENGLISH};
+
+    #[test]
+    fn upgrading_legacy_encryption() {
+        let config = LightClientConfig::create_unconnected(UnitTestNetwork, None);
+        let mut keys = Keys::new(&config, Some(TEST_SEED.to_string()), String::new(), 1, 0).unwrap();
+        let seed = keys.get_seed_phrase();
+
+        // Encrypted the way wallets were before the KDF was stored with them
+        let key = Kdf::DoubleSha256.derive_key(b"pw").unwrap();
+        keys.encrypt_with(Kdf::DoubleSha256, &key).unwrap();
+        keys.encrypted = true;
+        keys.lock().unwrap();
+
+        let mut bytes = vec![];
+        keys.write(&mut bytes).unwrap();
+        let mut keys = Keys::read(&bytes[..], &config).unwrap();
+        assert!(keys.kdf().is_legacy());
+        keys.check_password("pw").unwrap();
+        assert!(keys.check_password("wrong").is_err());
+
+        // Unlocking moves it to argon2id, without changing the password
+        assert!(keys.unlock("wrong".to_string()).is_err());
+        keys.unlock("pw".to_string()).unwrap();
+        assert_eq!(keys.get_seed_phrase(), seed);
+        assert!(!keys.kdf().is_legacy());
+
+        keys.lock().unwrap();
+        let mut bytes = vec![];
+        keys.write(&mut bytes).unwrap();
+        let mut keys = Keys::read(&bytes[..], &config).unwrap();
+        assert!(!keys.kdf().is_legacy());
+        assert!(keys.check_password("wrong").is_err());
+        keys.unlock("pw".to_string()).unwrap();
+        assert_eq!(keys.get_seed_phrase(), seed);
+
+        // New encryption never uses the old KDF
+        keys.remove_encryption("pw".to_string()).unwrap();
+        keys.encrypt("pw".to_string()).unwrap();
+        assert!(!keys.kdf().is_legacy());
+        keys.unlock("pw".to_string()).unwrap();
+        assert_eq!(keys.get_seed_phrase(), seed);
+    }
+
+    #[test]
+    fn changing_password() {
+        let config = LightClientConfig::create_unconnected(UnitTestNetwork, None);
+        let mut keys = Keys::new(&config, Some(TEST_SEED.to_string()), String::new(), 1, 0).unwrap();
+        let seed = keys.get_seed_phrase();
+
+        assert!(keys.change_password("pw", "new pw").is_err());
+        keys.encrypt("pw".to_string()).unwrap();
+        keys.unlock("pw".to_string()).unwrap();
+
+        // Changing the password keeps the wallet unlocked if it was
+        assert!(keys.change_password("wrong", "new pw").is_err());
+        keys.change_password("pw", "new pw").unwrap();
+        assert!(keys.is_unlocked_for_spending());
+        assert_eq!(keys.get_seed_phrase(), seed);
+        keys.lock().unwrap();
+        assert!(keys.unlock("pw".to_string()).is_err());
+        keys.unlock("new pw".to_string()).unwrap();
+        assert_eq!(keys.get_seed_phrase(), seed);
+
+        // ... and locked if it was
+        keys.lock().unwrap();
+        keys.change_password("new pw", "pw").unwrap();
+        assert!(!keys.is_unlocked_for_spending());
+        assert_eq!(*keys.get_seed_phrase(), "");
+        let mut bytes = vec![];
+        keys.write(&mut bytes).unwrap();
+        let mut keys = Keys::read(&bytes[..], &config).unwrap();
+        assert!(keys.check_password("new pw").is_err());
+        keys.unlock("pw".to_string()).unwrap();
+        assert_eq!(keys.get_seed_phrase(), seed);
+    }
+
+    #[test]
+    fn splitting_seed() {
+        let config = LightClientConfig::create_unconnected(UnitTestNetwork, None);
+        let addresses = |keys: &Keys<UnitTestNetwork>| (keys.get_all_taddrs(), keys.get_all_zaddresses());
+
+        // A 12 word phrase, split 3 of 5
+        let phrase = Mnemonic::new(MnemonicType::Words12, Language::English)
+            .phrase()
+            .to_string();
+        let keys = Keys::new(&config, Some(phrase.clone()), String::new(), 1, 0).unwrap();
+        let shares = keys.split_seed(3, 5).unwrap();
+        assert_eq!(shares.len(), 5);
+
+        let restored = Keys::new(&config, Some(shares[1..4].join("\n")), String::new(), 1, 0).unwrap();
+        assert_eq!(restored.get_seed_phrase(), phrase);
+        assert_eq!(addresses(&restored), addresses(&keys));
+
+        // Fewer shares than the threshold restore nothing
+        let err = Keys::new(&config, Some(shares[..2].join("\n")), String::new(), 1, 0)
+            .err()
+            .unwrap();
+        assert!(err.starts_with("Couldn't restore the seed from its shares"));
+
+        // A phrase in another language can't be split
+        let phrase = Mnemonic::new(MnemonicType::Words12, Language::Spanish)
+            .phrase()
+            .to_string();
+        let keys = Keys::new(&config, Some(phrase), String::new(), 1, 0).unwrap();
+        assert_eq!(keys.split_seed(2, 3), Err(SPLIT_SEED_NOT_ENGLISH.to_string()));
+    }
+
+    #[test]
+    fn bip39_lengths_languages_and_passphrase() {
+        let config = LightClientConfig::create_unconnected(UnitTestNetwork, None);
+        let addresses = |keys: &Keys<UnitTestNetwork>| (keys.get_all_taddrs(), keys.get_all_zaddresses());
+
+        for (mnemonic_type, language) in vec![
+            (MnemonicType::Words12, Language::English),
+            (MnemonicType::Words18, Language::Spanish),
+            (MnemonicType::Words24, Language::Japanese),
+        ] {
+            let phrase = Mnemonic::new(mnemonic_type, language).phrase().to_string();
+            let keys = Keys::new(&config, Some(phrase.clone()), String::new(), 1, 0).unwrap();
+            assert_eq!(*keys.get_seed_phrase(), phrase);
+            assert!(!keys.has_passphrase());
+
+            // The passphrase gives different keys, and is kept through encryption and a save
+            let mut with_passphrase = Keys::new(&config, Some(phrase.clone()), "25th word".to_string(), 1, 0).unwrap();
+            assert!(with_passphrase.has_passphrase());
+            assert_ne!(addresses(&with_passphrase), addresses(&keys));
+            let expected = addresses(&with_passphrase);
+
+            with_passphrase.encrypt("pw".to_string()).unwrap();
+            let mut bytes = vec![];
+            with_passphrase.write(&mut bytes).unwrap();
+            assert!(!bytes.windows(9).any(|w| w == b"25th word"));
+
+            let mut read = Keys::read(&bytes[..], &config).unwrap();
+            assert!(read.has_passphrase());
+            read.unlock("pw".to_string()).unwrap();
+            assert_eq!(*read.get_seed_phrase(), phrase);
+            assert_eq!(addresses(&read), expected);
+
+            // Keys added later are derived with the passphrase too
+            let taddr = read.add_taddr();
+            let mut without = Keys::new(&config, Some(phrase), String::new(), 1, 0).unwrap();
+            assert_ne!(without.add_taddr(), taddr);
+        }
+
+        assert!(Keys::new(&config, Some("not a seed phrase".to_string()), String::new(), 1, 0).is_err());
+    }
+
+    #[test]
+    fn locking_wipes_secrets() {
+        let config = LightClientConfig::create_unconnected(UnitTestNetwork, None);
+        let mut keys = Keys::new(&config, Some(TEST_SEED.to_string()), "25th word".to_string(), 1, 0).unwrap();
+        let taddr = keys.get_all_taddrs()[0].clone();
+        assert!(keys.get_taddr_sk(&taddr).is_some());
+
+        keys.encrypt("pw".to_string()).unwrap();
+        assert_eq!(keys.seed, [0u8; 32]);
+        assert!(keys.passphrase.is_empty());
+        assert!(keys.get_taddr_sk(&taddr).is_none());
+        assert!(keys.zkeys.iter().all(|zk| zk.extsk.is_none()));
+        assert!(keys.get_t_secret_keys().iter().all(|(_, sk)| sk.is_empty()));
+
+        keys.unlock("pw".to_string()).unwrap();
+        assert!(keys.get_taddr_sk(&taddr).is_some());
+
+        // Keys that don't implement Zeroize are overwritten in place
+        let mut sk = keys.get_taddr_sk(&taddr).unwrap();
+        utils::wipe_secret_key(&mut sk);
+        assert_eq!(sk, secp256k1::ONE_KEY);
+    }
+}
@@ -0,0 +1,125 @@
+// SLIP-39 shares of a wallet's seed. What the shares hold is the entropy of the wallet's BIP39 phrase, not a SLIP-39
+// master secret that the keys are derived from directly. So they can only be restored by this wallet: a hardware
+// wallet or another SLIP-39 implementation would combine them into the same bytes, but derive different keys from
+// them, and show an empty wallet.
+use sssmc39::{combine_mnemonics, generate_mnemonics};
+
+// How many PBKDF2 rounds the shares are encrypted with: 10000 * 2^ITERATION_EXPONENT. This is the default from the
+// SLIP-39 reference implementation, and is stored in every share.
+const ITERATION_EXPONENT: u8 = 1;
+
+// How many words SLIP-39 shares are for the entropy of a 12, 15, 18, 21 and 24 word BIP39 phrase. No BIP39 phrase has
+// any of these lengths.
+const SHARE_LENGTHS: [usize; 5] = [20, 23, 27, 30, 33];
+
+/// Split the seed's `entropy` into `count` SLIP-39 shares, any `threshold` of which can restore it. They are all in
+/// one group, and don't have a SLIP-39 passphrase, so they restore exactly the wallet they were made from, in this
+/// wallet only.
+pub fn split(entropy: &[u8], threshold: u8, count: u8) -> Result<Vec<String>, String> {
+    if threshold == 0 || threshold > count {
+        return Err(format!(
+            "The threshold has to be between 1 and the number of shares, not {} of {}",
+            threshold, count
+        ));
+    }
+    if count > 16 {
+        return Err(format!("At most 16 shares can be made, not {}", count));
+    }
+
+    let groups = generate_mnemonics(1, &[(threshold, count)], entropy, "", ITERATION_EXPONENT)
+        .map_err(|e| format!("Couldn't split the seed: {}", e))?;
+
+    let shares = groups[0]
+        .mnemonic_list()
+        .map_err(|e| format!("Couldn't split the seed: {}", e))?;
+
+    Ok(shares.into_iter().map(|words| words.join(" ")).collect())
+}
+
+/// Restore the entropy of a seed from enough of its SLIP-39 shares
+pub fn combine(shares: &[String]) -> Result<Vec<u8>, String> {
+    combine_with_passphrase(shares, "")
+}
+
+fn combine_with_passphrase(shares: &[String], passphrase: &str) -> Result<Vec<u8>, String> {
+    let shares = shares
+        .iter()
+        .map(|s| s.split_whitespace().map(|w| w.to_lowercase()).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+
+    combine_mnemonics(&shares, passphrase).map_err(|e| format!("Couldn't restore the seed from its shares: {}", e))
+}
+
+/// If `phrase` is one or more SLIP-39 shares, one per line or separated by ';', returns them
+pub fn parse_shares(phrase: &str) -> Option<Vec<String>> {
+    let shares = phrase
+        .split(|c| c == '\n' || c == ';')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>();
+
+    if !shares.is_empty()
+        && shares
+            .iter()
+            .all(|s| SHARE_LENGTHS.contains(&s.split_whitespace().count()))
+    {
+        Some(shares)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{combine, combine_with_passphrase, parse_shares, split};
+
+    #[test]
+    fn test_vectors() {
+        // From https://github.com/trezor/python-shamir-mnemonic/blob/master/vectors.json, all with the passphrase
+        // "TREZOR"
+        let valid = "duckling enlarge academic academic agency result length solution fridge kidney coal piece deal \
+                     husband erode duke ajar critical decision keyboard";
+        let bad_checksum = "duckling enlarge academic academic agency result length solution fridge kidney coal piece \
+                            deal husband erode duke ajar critical decision kidney";
+
+        assert_eq!(parse_shares(valid), Some(vec![valid.to_string()]));
+        assert_eq!(
+            hex::encode(combine_with_passphrase(&[valid.to_string()], "TREZOR").unwrap()),
+            "bb54aac4b89dc868ba37d9cc21b2cece"
+        );
+        assert!(combine_with_passphrase(&[bad_checksum.to_string()], "TREZOR").is_err());
+    }
+
+    #[test]
+    fn splitting_and_combining() {
+        for entropy in vec![vec![7u8; 16], vec![9u8; 20], (0..32).collect::<Vec<u8>>()] {
+            let shares = split(&entropy, 3, 5).unwrap();
+            assert_eq!(shares.len(), 5);
+            assert!(shares.iter().all(|s| parse_shares(s).is_some()));
+
+            // Any 3 shares restore the seed, in any order
+            assert_eq!(combine(&shares[..3]).unwrap(), entropy);
+            assert_eq!(combine(&shares[2..]).unwrap(), entropy);
+            assert_eq!(
+                combine(&[shares[4].clone(), shares[0].clone(), shares[2].clone()]).unwrap(),
+                entropy
+            );
+
+            // ... but 2 don't
+            assert!(combine(&shares[..2]).is_err());
+
+            // Shares can be given one per line
+            assert_eq!(parse_shares(&shares[..3].join("\n")).unwrap(), shares[..3].to_vec());
+        }
+
+        assert!(split(&[7u8; 16], 0, 5).is_err());
+        assert!(split(&[7u8; 16], 4, 3).is_err());
+
+        // A BIP39 phrase isn't mistaken for shares
+        assert!(parse_shares(
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about"
+        )
+        .is_none());
+    }
+}
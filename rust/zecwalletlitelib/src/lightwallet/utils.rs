@@ -1,6 +1,9 @@
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::io::{self, Read, Write};
+use std::ptr;
+use std::sync::atomic::{self, Ordering};
 use zcash_primitives::memo::MemoBytes;
+use zcash_primitives::zip32::ExtendedSpendingKey;
 
 pub fn read_string<R: Read>(mut reader: R) -> io::Result<String> {
     // Strings are written as <littleendian> len + bytes
@@ -19,6 +22,29 @@ pub fn write_string<W: Write>(mut writer: W, s: &String) -> io::Result<()> {
     writer.write_all(s.as_bytes())
 }
 
+// Wipe a secp256k1 secret key, which doesn't implement Zeroize, in a way the compiler can't optimise away even though
+// the key is about to be dropped
+pub(crate) fn wipe_secret_key(key: &mut secp256k1::SecretKey) {
+    // SAFETY: a SecretKey is a Copy byte array, so it has no memory or Drop of its own to skip
+    unsafe { overwrite(key, secp256k1::ONE_KEY) };
+}
+
+// Wipe a sapling spending key, like `wipe_secret_key`
+pub(crate) fn wipe_extsk(extsk: &mut ExtendedSpendingKey) {
+    // SAFETY: an ExtendedSpendingKey is only arrays, field elements and integers. It owns no heap memory and has no
+    // Drop, so skipping the drop of the old value doesn't leak any of it unwiped.
+    unsafe { overwrite(extsk, ExtendedSpendingKey::master(&[])) };
+}
+
+// Write `blank` over `secret` with a volatile write, which isn't optimised away.
+//
+// Safety: the old value isn't dropped, so `T` must own no heap memory and have no Drop, or that memory is leaked without
+// being wiped.
+unsafe fn overwrite<T>(secret: &mut T, blank: T) {
+    ptr::write_volatile(secret, blank);
+    atomic::compiler_fence(Ordering::SeqCst);
+}
+
 // Interpret a string or hex-encoded memo, and return a Memo object
 pub fn interpret_memo_string(memo_str: String) -> Result<MemoBytes, String> {
     // If the string starts with an "0x", and contains only hex chars ([a-f0-9]+) then
@@ -34,3 +60,21 @@ pub fn interpret_memo_string(memo_str: String) -> Result<MemoBytes, String> {
 
     MemoBytes::from_bytes(&s_bytes).map_err(|_| format!("Error creating output. Memo '{:?}' is too long", memo_str))
 }
+
+#[cfg(test)]
+mod tests {
+    use zcash_primitives::zip32::ExtendedSpendingKey;
+
+    use super::{wipe_extsk, wipe_secret_key};
+
+    #[test]
+    fn wipe_overwrites() {
+        let mut sk = secp256k1::SecretKey::from_slice(&[7u8; 32]).unwrap();
+        wipe_secret_key(&mut sk);
+        assert_eq!(sk, secp256k1::ONE_KEY);
+
+        let mut extsk = ExtendedSpendingKey::master(&[7u8; 32]);
+        wipe_extsk(&mut extsk);
+        assert_eq!(extsk, ExtendedSpendingKey::master(&[]));
+    }
+}
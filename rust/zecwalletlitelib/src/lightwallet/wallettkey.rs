@@ -7,6 +7,7 @@ use sha2::Sha256;
 use sodiumoxide::crypto::secretbox;
 use zcash_encoding::{Optional, Vector};
 use zcash_primitives::consensus;
+use zeroize::Zeroizing;
 
 use crate::{
     lightclient::lightclient_config::LightClientConfig,
@@ -235,7 +236,7 @@ impl WalletTKey {
         match self.keytype {
             WalletTKeyType::HdKey => {
                 // For HD keys, just empty out the keys, since they will be reconstructed from the hdkey_num
-                self.key = None;
+                self.forget_key();
                 self.locked = true;
             }
             WalletTKeyType::ImportedKey => {
@@ -247,7 +248,7 @@ impl WalletTKey {
                         "Can't lock when imported key is not encrypted",
                     ));
                 }
-                self.key = None;
+                self.forget_key();
                 self.locked = true;
             }
         }
@@ -255,6 +256,14 @@ impl WalletTKey {
         Ok(())
     }
 
+    // Wipe the secret key from memory, not just drop it
+    fn forget_key(&mut self) {
+        if let Some(key) = self.key.as_mut() {
+            utils::wipe_secret_key(key);
+        }
+        self.key = None;
+    }
+
     pub fn unlock<P: consensus::Parameters>(
         &mut self,
         config: &LightClientConfig<P>,
@@ -284,7 +293,7 @@ impl WalletTKey {
                 // For imported keys, we need to decrypt from the encrypted key
                 let nonce = secretbox::Nonce::from_slice(&self.nonce.as_ref().unwrap()).unwrap();
                 let sk_bytes = match secretbox::open(&self.enc_key.as_ref().unwrap(), &nonce, &key) {
-                    Ok(s) => s,
+                    Ok(s) => Zeroizing::new(s),
                     Err(_) => {
                         return Err(io::Error::new(
                             ErrorKind::InvalidData,
@@ -345,6 +354,12 @@ impl WalletTKey {
     }
 }
 
+impl Drop for WalletTKey {
+    fn drop(&mut self) {
+        self.forget_key();
+    }
+}
+
 #[cfg(test)]
 mod test {
 
@@ -380,4 +395,19 @@ mod test {
             assert_eq!(wtk.key.unwrap(), wtk2.key.unwrap());
         }
     }
+
+    #[test]
+    fn tkey_lock() {
+        let config = LightClientConfig::create_unconnected(UnitTestNetwork, None);
+
+        let sk = SecretKey::from_slice(&[1u8; 32]).unwrap();
+        let address = WalletTKey::address_from_prefix_sk(&config.base58_pubkey_address(), &sk);
+        let mut wtk = WalletTKey::from_raw(&sk, &address, 0);
+
+        // Locking an HD key forgets it, since it can be derived from the seed again
+        wtk.lock().unwrap();
+        assert!(wtk.key.is_none());
+        assert!(wtk.locked);
+        assert_eq!(wtk.address, address);
+    }
 }
@@ -11,10 +11,11 @@ use zcash_primitives::{
     sapling::PaymentAddress,
     zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
 };
+use zeroize::Zeroizing;
 
 use crate::lightclient::lightclient_config::LightClientConfig;
 
-use super::keys::Keys;
+use super::{keys::Keys, utils};
 
 #[derive(PartialEq, Debug, Clone)]
 pub enum WalletZKeyType {
@@ -182,7 +183,7 @@ impl WalletZKey {
         match self.keytype {
             WalletZKeyType::HdKey => {
                 // For HD keys, just empty out the keys, since they will be reconstructed from the hdkey_num
-                self.extsk = None;
+                self.forget_extsk();
                 self.locked = true;
             }
             WalletZKeyType::ImportedSpendingKey => {
@@ -194,7 +195,7 @@ impl WalletZKey {
                         "Can't lock when imported key is not encrypted",
                     ));
                 }
-                self.extsk = None;
+                self.forget_extsk();
                 self.locked = true;
             }
             WalletZKeyType::ImportedViewKey => {
@@ -206,6 +207,14 @@ impl WalletZKey {
         Ok(())
     }
 
+    // Wipe the spending key from memory, not just drop it
+    fn forget_extsk(&mut self) {
+        if let Some(extsk) = self.extsk.as_mut() {
+            utils::wipe_extsk(extsk);
+        }
+        self.extsk = None;
+    }
+
     pub fn unlock<P: consensus::Parameters>(
         &mut self,
         config: &LightClientConfig<P>,
@@ -247,7 +256,7 @@ impl WalletZKey {
                 // For imported keys, we need to decrypt from the encrypted key
                 let nonce = secretbox::Nonce::from_slice(&self.nonce.as_ref().unwrap()).unwrap();
                 let extsk_bytes = match secretbox::open(&self.enc_key.as_ref().unwrap(), &nonce, &key) {
-                    Ok(s) => s,
+                    Ok(s) => Zeroizing::new(s),
                     Err(_) => {
                         return Err(io::Error::new(
                             ErrorKind::InvalidData,
@@ -276,8 +285,8 @@ impl WalletZKey {
                 // For imported keys, encrypt the key into enckey
                 let nonce = secretbox::gen_nonce();
 
-                let mut sk_bytes = vec![];
-                self.extsk.as_ref().unwrap().write(&mut sk_bytes)?;
+                let mut sk_bytes = Zeroizing::new(vec![]);
+                self.extsk.as_ref().unwrap().write(&mut *sk_bytes)?;
 
                 self.enc_key = Some(secretbox::seal(&sk_bytes, &nonce, &key));
                 self.nonce = Some(nonce.as_ref().to_vec());
@@ -317,12 +326,19 @@ impl WalletZKey {
     }
 }
 
+impl Drop for WalletZKey {
+    fn drop(&mut self) {
+        self.forget_extsk();
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
     use sodiumoxide::crypto::secretbox;
     use zcash_client_backend::encoding::{
         decode_extended_full_viewing_key, decode_extended_spending_key, encode_payment_address,
     };
+    use zcash_primitives::zip32::ExtendedSpendingKey;
 
     use super::WalletZKey;
     use crate::lightclient::lightclient_config::{LightClientConfig, UnitTestNetwork};
@@ -330,10 +346,15 @@ pub mod tests {
     fn get_config() -> LightClientConfig<UnitTestNetwork> {
         LightClientConfig {
             server: "0.0.0.0:0".parse().unwrap(),
+            servers: Default::default(),
+            consistency: Default::default(),
             chain_name: "zs".to_string(),
             monitor_mempool: false,
             sapling_activation_height: 0,
             anchor_offset: 0,
+            connect_options: Default::default(),
+            offline: Default::default(),
+            checkpoints: Default::default(),
             data_dir: None,
             params: UnitTestNetwork,
         }
@@ -369,6 +390,20 @@ pub mod tests {
         }
     }
 
+    #[test]
+    fn test_lock_hdkey() {
+        let extsk = ExtendedSpendingKey::master(&[1u8; 32]);
+        let mut wzk = WalletZKey::new_hdkey(0, extsk);
+
+        // HD keys don't need encryption to lock, since they can be derived from the seed again
+        assert!(wzk.lock().is_ok());
+        {
+            assert!(wzk.extsk.is_none());
+            assert_eq!(wzk.locked, true);
+            assert!(wzk.have_spending_key());
+        }
+    }
+
     #[test]
     fn test_encrypt_decrypt_sk() {
         let config = get_config();
@@ -0,0 +1,568 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use futures::future::join_all;
+use json::{object, JsonValue};
+use log::{error, info, warn};
+
+use crate::compact_formats::LightdInfo;
+use crate::grpc_connector::{ConnectOptions, GrpcConnector};
+use crate::lightclient::lightclient_config::WrongChain;
+
+/// What the last health check, or the last failed call, found out about a server
+#[derive(Clone, Debug)]
+pub struct ServerHealth {
+    pub uri: http::Uri,
+
+    // Round trip time of the last GetLightdInfo. None if the server wasn't checked yet, or is down.
+    pub latency: Option<Duration>,
+    pub block_height: u64,
+    pub last_error: Option<String>,
+
+    // Whether the server was checked to be on the wallet's chain. Only servers that were can become current.
+    pub chain_verified: bool,
+}
+
+impl ServerHealth {
+    fn new(uri: http::Uri) -> Self {
+        Self {
+            uri,
+            latency: None,
+            block_height: 0,
+            last_error: None,
+            chain_verified: false,
+        }
+    }
+
+    pub fn is_down(&self) -> bool {
+        self.last_error.is_some()
+    }
+}
+
+#[derive(Debug, Default)]
+struct PoolState {
+    // In the order they were configured in
+    servers: Vec<ServerHealth>,
+    current: usize,
+
+    // Number of live PinGuards. While the pool is pinned, health checks don't switch to a faster server.
+    pins: usize,
+    last_check: Option<Instant>,
+}
+
+impl PoolState {
+    // Indexes of the servers from best to worst. The servers that are up come first, fastest first, then the ones that
+    // weren't checked yet and then the ones that are down, both in the configured order.
+    fn ranked(&self) -> Vec<usize> {
+        let mut ranked: Vec<usize> = (0..self.servers.len()).collect();
+        ranked.sort_by_key(|i| {
+            let s = &self.servers[*i];
+            match (s.is_down(), s.latency) {
+                (false, Some(latency)) => (0, latency),
+                (false, None) => (1, Duration::ZERO),
+                (true, _) => (2, Duration::ZERO),
+            }
+        });
+
+        ranked
+    }
+
+    fn server_mut(&mut self, uri: &http::Uri) -> Option<&mut ServerHealth> {
+        self.servers.iter_mut().find(|s| &s.uri == uri)
+    }
+
+    // Record the results of a health check, by server. The pool can change while a check is running, so results for
+    // servers that were removed since are ignored, and servers that were added since are left as they are.
+    fn record_health(&mut self, results: &HashMap<http::Uri, (Result<LightdInfo, String>, Duration)>) {
+        for server in self.servers.iter_mut() {
+            match results.get(&server.uri) {
+                Some((Ok(info), elapsed)) => {
+                    server.latency = Some(*elapsed);
+                    server.block_height = info.block_height;
+                    server.last_error = None;
+                }
+                Some((Err(e), _)) => {
+                    warn!("Server {} failed its health check: {}", server.uri, e);
+                    server.latency = None;
+                    server.last_error = Some(e.clone());
+                }
+                None => {}
+            }
+        }
+        self.last_check = Some(Instant::now());
+    }
+}
+
+/// An ordered list of servers, ranked by health and latency. All the clones of a pool share the same state, so a
+/// failover seen by one of them is seen by all.
+#[derive(Clone, Debug, Default)]
+pub struct ServerPool {
+    state: Arc<RwLock<PoolState>>,
+
+    // How the health checks connect to the servers, the same way as the config the pool belongs to
+    options: ConnectOptions,
+}
+
+impl ServerPool {
+    pub fn new(servers: Vec<http::Uri>, options: ConnectOptions) -> Self {
+        let state = PoolState {
+            servers: servers.into_iter().map(ServerHealth::new).collect(),
+            ..Default::default()
+        };
+
+        Self {
+            state: Arc::new(RwLock::new(state)),
+            options,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.read().unwrap().servers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The server that calls should go to right now
+    pub fn current(&self) -> Option<http::Uri> {
+        let state = self.state.read().unwrap();
+        state.servers.get(state.current).map(|s| s.uri.clone())
+    }
+
+    /// The health of all the servers, in the configured order
+    pub fn health(&self) -> Vec<ServerHealth> {
+        self.state.read().unwrap().servers.clone()
+    }
+
+    /// Keep using the current server until the returned guard is dropped, unless it goes down. Used to stick to one
+    /// server for a whole sync.
+    pub fn pin(&self) -> PinGuard {
+        self.state.write().unwrap().pins += 1;
+        PinGuard { pool: self.clone() }
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.state.read().unwrap().pins > 0
+    }
+
+    /// Call GetLightdInfo on all the servers, and rank them by how long it took. Unless the pool is pinned, switch to
+    /// the fastest server that is up and on the wallet's chain. Servers whose chain wasn't checked yet are checked with
+    /// `verify` before they are switched to. Returns the info from the server that is current after the check.
+    pub async fn check_health<V, Fut>(&self, verify: V) -> Result<LightdInfo, String>
+    where
+        V: Fn(http::Uri, LightdInfo) -> Fut,
+        Fut: Future<Output = Result<(), WrongChain>>,
+    {
+        let uris = self.health().into_iter().map(|s| s.uri).collect::<Vec<_>>();
+        if uris.is_empty() {
+            return Err("No servers configured".to_string());
+        }
+
+        let results = join_all(uris.into_iter().map(|uri| async move {
+            let start = Instant::now();
+            let r = GrpcConnector::new(uri.clone(), self.options.clone()).get_info().await;
+            (uri, (r, start.elapsed()))
+        }))
+        .await
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+
+        let switch = {
+            let mut state = self.state.write().unwrap();
+            state.record_health(&results);
+            if state.servers.is_empty() {
+                return Err("No servers configured".to_string());
+            }
+
+            // A pinned server is only switched away from if it is down
+            let current = &state.servers[state.current];
+            state.pins == 0 || current.is_down() || !current.chain_verified
+        };
+
+        if switch {
+            let infos = results
+                .iter()
+                .filter_map(|(uri, (r, _))| r.as_ref().ok().map(|info| (uri.clone(), info.clone())))
+                .collect();
+            self.switch_to_best(infos, &verify).await;
+        }
+
+        let state = self.state.read().unwrap();
+        let current = match state.servers.get(state.current) {
+            Some(current) => current,
+            None => return Err("None of the servers are on the wallet's chain".to_string()),
+        };
+        if !current.chain_verified {
+            return Err(current
+                .last_error
+                .clone()
+                .unwrap_or_else(|| "None of the servers are on the wallet's chain".to_string()));
+        }
+
+        match results.get(&current.uri) {
+            Some((r, _)) => r.clone(),
+            None => Err(format!("Server {} was added during the health check", current.uri)),
+        }
+    }
+
+    /// Run a health check if the last one is older than `max_age`
+    pub async fn check_health_if_stale<V, Fut>(&self, max_age: Duration, verify: V)
+    where
+        V: Fn(http::Uri, LightdInfo) -> Fut,
+        Fut: Future<Output = Result<(), WrongChain>>,
+    {
+        let stale = match self.state.read().unwrap().last_check {
+            Some(at) => at.elapsed() > max_age,
+            None => true,
+        };
+
+        if stale && !self.is_empty() {
+            let _ = self.check_health(verify).await;
+        }
+    }
+
+    /// Mark `failed` as down after it couldn't be reached. If it was the current server, switch to the best server
+    /// that is up and on the wallet's chain, checking the chain with `verify` first like `check_health` does. If they
+    /// are all down, go to the next server in the configured order that is known to be on the wallet's chain. This
+    /// happens even if the pool is pinned, since there is no point sticking to a server that is down. Returns the
+    /// server to use now.
+    pub async fn failover<V, Fut>(&self, failed: &http::Uri, error: &str, verify: V) -> Option<http::Uri>
+    where
+        V: Fn(http::Uri, LightdInfo) -> Fut,
+        Fut: Future<Output = Result<(), WrongChain>>,
+    {
+        let was_current = {
+            let mut state = self.state.write().unwrap();
+            if state.servers.is_empty() {
+                return None;
+            }
+
+            if let Some(s) = state.server_mut(failed) {
+                s.latency = None;
+                s.last_error = Some(error.to_string());
+            }
+            &state.servers[state.current].uri == failed
+        };
+
+        if was_current && !self.switch_to_best(HashMap::new(), &verify).await {
+            let mut state = self.state.write().unwrap();
+            let n = state.servers.len();
+            let next = (1..=n)
+                .map(|i| (state.current + i) % n)
+                .find(|i| state.servers[*i].chain_verified);
+            if let Some(next) = next {
+                state.current = next;
+            }
+        }
+
+        let current = self.current();
+        if was_current {
+            if let Some(next) = &current {
+                warn!("Server {} failed, switching to {}", failed, next);
+            }
+        }
+
+        current
+    }
+
+    // Switch to the best server that is up and on the wallet's chain. The chain of a server that wasn't checked yet is
+    // checked with `verify` first, with its info from `infos` or from a new GetLightdInfo. Servers on another chain are
+    // dropped from the pool, and servers whose chain can't be checked right now are marked down. Returns false if no
+    // server qualified, and then the current server stays.
+    async fn switch_to_best<V, Fut>(&self, mut infos: HashMap<http::Uri, LightdInfo>, verify: &V) -> bool
+    where
+        V: Fn(http::Uri, LightdInfo) -> Fut,
+        Fut: Future<Output = Result<(), WrongChain>>,
+    {
+        let candidates = {
+            let state = self.state.read().unwrap();
+            state
+                .ranked()
+                .into_iter()
+                .map(|i| &state.servers[i])
+                .filter(|s| !s.is_down())
+                .map(|s| (s.uri.clone(), s.chain_verified))
+                .collect::<Vec<_>>()
+        };
+
+        for (uri, verified) in candidates {
+            if !verified {
+                let checked = match infos.remove(&uri) {
+                    Some(info) => verify(uri.clone(), info).await,
+                    None => match GrpcConnector::new(uri.clone(), self.options.clone()).get_info().await {
+                        Ok(info) => verify(uri.clone(), info).await,
+                        Err(e) => Err(WrongChain::Unverifiable(e)),
+                    },
+                };
+
+                match checked {
+                    Ok(()) => {}
+                    Err(WrongChain::Unverifiable(e)) => {
+                        warn!("Couldn't check the chain of server {}: {}", uri, e);
+                        if let Some(s) = self.state.write().unwrap().server_mut(&uri) {
+                            s.latency = None;
+                            s.last_error = Some(e);
+                        }
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("Not using server {}: {}", uri, e);
+                        self.remove(&uri);
+                        continue;
+                    }
+                }
+            }
+
+            let mut state = self.state.write().unwrap();
+            let found = state.servers.iter().position(|s| s.uri == uri);
+            if let Some(i) = found {
+                state.servers[i].chain_verified = true;
+                if i != state.current {
+                    info!("Switching to server {}", uri);
+                    state.current = i;
+                }
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Drop `uri` from the pool for good, like a server that turned out to be on another chain. If it was the current
+    /// server, switch to the best of the rest that is known to be on the wallet's chain, or just the best of the rest if
+    /// none are. Returns the server to use now, if any are left.
+    pub fn remove(&self, uri: &http::Uri) -> Option<http::Uri> {
+        let mut state = self.state.write().unwrap();
+        if let Some(i) = state.servers.iter().position(|s| &s.uri == uri) {
+            let current = state.servers[state.current].uri.clone();
+            state.servers.remove(i);
+
+            state.current = match state.servers.iter().position(|s| s.uri == current) {
+                Some(c) => c,
+                None => {
+                    let ranked = state.ranked();
+                    ranked
+                        .iter()
+                        .find(|i| state.servers[**i].chain_verified)
+                        .or_else(|| ranked.first())
+                        .cloned()
+                        .unwrap_or(0)
+                }
+            };
+        }
+
+        state.servers.get(state.current).map(|s| s.uri.clone())
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let state = self.state.read().unwrap();
+        let ranked = state.ranked();
+
+        JsonValue::Array(
+            state
+                .servers
+                .iter()
+                .enumerate()
+                .map(|(i, s)| {
+                    object! {
+                        "uri" => s.uri.to_string(),
+                        "current" => i == state.current,
+                        "rank" => ranked.iter().position(|r| *r == i).unwrap() + 1,
+                        "latency_ms" => s.latency.map(|l| l.as_millis() as u64),
+                        "block_height" => s.block_height,
+                        "error" => s.last_error.clone(),
+                        "chain_verified" => s.chain_verified,
+                    }
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Keeps its ServerPool pinned to the current server while it is alive
+pub struct PinGuard {
+    pool: ServerPool,
+}
+
+impl Drop for PinGuard {
+    fn drop(&mut self) {
+        self.pool.state.write().unwrap().pins -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use super::ServerPool;
+    use crate::compact_formats::LightdInfo;
+    use crate::grpc_connector::ConnectOptions;
+    use crate::lightclient::lightclient_config::WrongChain;
+
+    fn pool_with_latencies(latencies: &[Option<u64>]) -> ServerPool {
+        let uris = (0..latencies.len())
+            .map(|i| format!("http://127.0.0.1:{}", 9000 + i).parse().unwrap())
+            .collect();
+        let pool = ServerPool::new(uris, ConnectOptions::default());
+
+        {
+            let mut state = pool.state.write().unwrap();
+            for (s, l) in state.servers.iter_mut().zip(latencies) {
+                s.chain_verified = true;
+                match l {
+                    Some(ms) => s.latency = Some(Duration::from_millis(*ms)),
+                    None => s.last_error = Some("down".to_string()),
+                }
+            }
+        }
+
+        pool
+    }
+
+    fn port(uri: Option<http::Uri>) -> u16 {
+        uri.unwrap().port_u16().unwrap()
+    }
+
+    async fn any_chain(_: http::Uri, _: LightdInfo) -> Result<(), WrongChain> {
+        Ok(())
+    }
+
+    #[test]
+    fn ranking() {
+        let pool = pool_with_latencies(&[Some(300), None, Some(50), Some(100)]);
+        assert_eq!(pool.state.read().unwrap().ranked(), vec![2, 3, 0, 1]);
+
+        // Servers that weren't checked yet come after the ones that are up, but before the ones that are down
+        pool.state.write().unwrap().servers[3].latency = None;
+        assert_eq!(pool.state.read().unwrap().ranked(), vec![2, 0, 3, 1]);
+
+        assert!(ServerPool::default().current().is_none());
+    }
+
+    #[tokio::test]
+    async fn failover() {
+        let pool = pool_with_latencies(&[Some(300), Some(200), Some(50)]);
+        assert_eq!(port(pool.current()), 9000);
+
+        // Failing over goes to the fastest server that is up
+        let p9000 = "http://127.0.0.1:9000".parse().unwrap();
+        assert_eq!(port(pool.failover(&p9000, "unreachable", any_chain).await), 9002);
+
+        // A server that isn't current failing doesn't switch
+        let p9001 = "http://127.0.0.1:9001".parse().unwrap();
+        assert_eq!(port(pool.failover(&p9001, "unreachable", any_chain).await), 9002);
+
+        // If they are all down, go round the configured order
+        let p9002 = "http://127.0.0.1:9002".parse().unwrap();
+        assert_eq!(port(pool.failover(&p9002, "unreachable", any_chain).await), 9000);
+        assert_eq!(port(pool.failover(&p9000, "unreachable", any_chain).await), 9001);
+        assert!(pool.health().iter().all(|s| s.is_down()));
+    }
+
+    #[tokio::test]
+    async fn pinning() {
+        let pool = pool_with_latencies(&[Some(300), Some(50)]);
+        {
+            let _pin = pool.pin();
+            let _pin2 = pool.clone().pin();
+            assert!(pool.is_pinned());
+        }
+        assert!(!pool.is_pinned());
+
+        // A pinned pool still fails over
+        let _pin = pool.pin();
+        let p9000 = "http://127.0.0.1:9000".parse().unwrap();
+        assert_eq!(port(pool.failover(&p9000, "unreachable", any_chain).await), 9001);
+    }
+
+    #[tokio::test]
+    async fn chain_check() {
+        let pool = pool_with_latencies(&[Some(300), Some(50), Some(100)]);
+        for s in pool.state.write().unwrap().servers[1..].iter_mut() {
+            s.chain_verified = false;
+        }
+
+        // Servers are only switched to after their chain was checked, and the ones on another chain are dropped
+        let infos = pool
+            .health()
+            .into_iter()
+            .map(|s| (s.uri, LightdInfo::default()))
+            .collect();
+        let verify = |uri: http::Uri, _: LightdInfo| async move {
+            match uri.port_u16() {
+                Some(9001) => Err(WrongChain::ChainName {
+                    expected: "main".to_string(),
+                    server: "ztestsapling".to_string(),
+                }),
+                _ => Ok(()),
+            }
+        };
+        assert!(pool.switch_to_best(infos, &verify).await);
+        assert_eq!(port(pool.current()), 9002);
+        assert_eq!(pool.len(), 2);
+        assert!(pool.health().iter().all(|s| s.chain_verified));
+
+        // A server whose chain can't be checked isn't failed over to, even if it is the only other one
+        let pool = pool_with_latencies(&[Some(300), Some(50)]);
+        pool.state.write().unwrap().servers[1].chain_verified = false;
+
+        let p9000 = "http://127.0.0.1:9000".parse().unwrap();
+        assert_eq!(port(pool.failover(&p9000, "unreachable", any_chain).await), 9000);
+        assert!(pool.health().iter().all(|s| s.is_down()));
+        assert!(!pool.health()[1].chain_verified);
+    }
+
+    #[test]
+    fn health_results_by_uri() {
+        let pool = pool_with_latencies(&[Some(300), Some(200), Some(50)]);
+
+        // Results for a server that was removed during the check are ignored, and a server that wasn't in the check
+        // is left alone
+        let mut results = HashMap::new();
+        let mut info = LightdInfo::default();
+        info.block_height = 100;
+        results.insert(
+            "http://127.0.0.1:9000".parse().unwrap(),
+            (Ok(info), Duration::from_millis(20)),
+        );
+        results.insert(
+            "http://127.0.0.1:9001".parse().unwrap(),
+            (Err("unreachable".to_string()), Duration::ZERO),
+        );
+        results.insert(
+            "http://127.0.0.1:9005".parse().unwrap(),
+            (Err("unreachable".to_string()), Duration::ZERO),
+        );
+        pool.state.write().unwrap().record_health(&results);
+
+        let health = pool.health();
+        assert_eq!(health.len(), 3);
+        assert_eq!(health[0].latency, Some(Duration::from_millis(20)));
+        assert_eq!(health[0].block_height, 100);
+        assert!(health[1].is_down());
+        assert_eq!(health[2].latency, Some(Duration::from_millis(50)));
+        assert!(pool.state.read().unwrap().last_check.is_some());
+    }
+
+    #[test]
+    fn removing() {
+        let pool = pool_with_latencies(&[Some(300), Some(200), Some(50)]);
+
+        // Removing another server keeps the current one
+        let p9001 = "http://127.0.0.1:9001".parse().unwrap();
+        assert_eq!(port(pool.remove(&p9001)), 9000);
+
+        // Removing the current one switches to the best that's left
+        let p9000 = "http://127.0.0.1:9000".parse().unwrap();
+        assert_eq!(port(pool.remove(&p9000)), 9002);
+        assert_eq!(pool.len(), 1);
+
+        let p9002 = "http://127.0.0.1:9002".parse().unwrap();
+        assert!(pool.remove(&p9002).is_none());
+        assert!(pool.is_empty());
+    }
+}
@@ -0,0 +1,464 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use hyper::service::Service;
+use json::{object, JsonValue};
+use rustls_pemfile::Item;
+use sha2::{Digest, Sha256};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::{Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+
+use crate::proxy::Socks5Proxy;
+use crate::ServerCert;
+
+/// How to trust https servers, for self hosted servers that don't have a certificate from a public CA, or that want
+/// their certificate pinned. With the default options, servers are trusted the way tonic does it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TlsOptions {
+    // PEM file with the CA certificates to trust, instead of the public CAs
+    pub ca_cert_path: Option<PathBuf>,
+
+    // SHA-256 hashes of SubjectPublicKeyInfos. If there are any, one of the certificates the server sends has to
+    // have one of these keys, on top of the certificate being trusted.
+    pub spki_pins: Vec<[u8; 32]>,
+
+    // PEM files with the certificate chain and private key to authenticate with, for servers that ask for one
+    pub client_cert_path: Option<PathBuf>,
+    pub client_key_path: Option<PathBuf>,
+}
+
+impl TlsOptions {
+    pub fn is_default(&self) -> bool {
+        *self == TlsOptions::default()
+    }
+
+    /// Parse a pin like "sha256/Ztxz3OO3hac62vs05GHgkf+zFbh8QL0wU8QkrQiRcAU=", the format HPKP used. This is what
+    /// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64`
+    /// prints for a certificate.
+    pub fn parse_pin(pin: &str) -> Result<[u8; 32], String> {
+        let hash = pin
+            .trim()
+            .strip_prefix("sha256/")
+            .ok_or_else(|| format!("Pin {} doesn't start with sha256/", pin))?;
+
+        let hash = base64::decode(hash).map_err(|e| format!("Pin {} isn't base64: {}", pin, e))?;
+        if hash.len() != 32 {
+            return Err(format!("Pin {} isn't a SHA-256 hash", pin));
+        }
+
+        let mut r = [0u8; 32];
+        r.copy_from_slice(&hash);
+        Ok(r)
+    }
+
+    pub fn pin_to_string(pin: &[u8; 32]) -> String {
+        format!("sha256/{}", base64::encode(pin))
+    }
+
+    /// The rustls config for these options. Fails if a file can't be read or has no certificates or key in it.
+    pub fn client_config(&self) -> Result<ClientConfig, String> {
+        let mut roots = RootCertStore::empty();
+        match &self.ca_cert_path {
+            Some(path) => {
+                let pem = fs::read(path).map_err(|e| format!("Couldn't read CA file {:?}: {}", path, e))?;
+                let certs = pem_certificates(&pem).map_err(|e| format!("Bad CA file {:?}: {}", path, e))?;
+                if certs.is_empty() {
+                    return Err(format!("No certificates in CA file {:?}", path));
+                }
+                for cert in certs {
+                    roots
+                        .add(&cert)
+                        .map_err(|e| format!("Bad certificate in CA file {:?}: {:?}", path, e))?;
+                }
+            }
+            None => {
+                roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+                    OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)
+                }));
+
+                // The embedded certificate is trusted too, like tonic does it
+                let embedded = ServerCert::get("fullchain.pem").unwrap().data;
+                for cert in pem_certificates(&embedded).unwrap_or_default() {
+                    let _ = roots.add(&cert);
+                }
+            }
+        }
+
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let mut config = match (&self.client_cert_path, &self.client_key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                let pem = fs::read(cert_path)
+                    .map_err(|e| format!("Couldn't read client certificate {:?}: {}", cert_path, e))?;
+                let certs = pem_certificates(&pem)
+                    .map_err(|e| format!("Bad client certificate file {:?}: {}", cert_path, e))?;
+                if certs.is_empty() {
+                    return Err(format!("No certificates in client certificate file {:?}", cert_path));
+                }
+
+                let pem = fs::read(key_path).map_err(|e| format!("Couldn't read client key {:?}: {}", key_path, e))?;
+                let key = pem_private_key(&pem)
+                    .map_err(|e| format!("Bad client key file {:?}: {}", key_path, e))?
+                    .ok_or_else(|| format!("No private key in {:?}", key_path))?;
+
+                builder
+                    .with_single_cert(certs, key)
+                    .map_err(|e| format!("Bad client certificate or key: {}", e))?
+            }
+            (None, None) => builder.with_no_client_auth(),
+            _ => return Err("A client certificate needs both the certificate and the key".to_string()),
+        };
+
+        // gRPC needs HTTP/2
+        config.alpn_protocols = vec![b"h2".to_vec()];
+
+        Ok(config)
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        object! {
+            "ca_cert_path" => self.ca_cert_path.as_ref().map(|p| p.to_string_lossy().to_string()),
+            "spki_pins" => self.spki_pins.iter().map(Self::pin_to_string).collect::<Vec<_>>(),
+            "client_cert_path" => self.client_cert_path.as_ref().map(|p| p.to_string_lossy().to_string()),
+        }
+    }
+}
+
+/// TLS options together with the rustls config made from them, so that the files are read once when the options are
+/// set, and not again on every connection
+#[derive(Clone)]
+pub struct PinnedTls {
+    options: TlsOptions,
+    config: Arc<ClientConfig>,
+}
+
+impl PinnedTls {
+    /// Fails like TlsOptions::client_config if the files can't be used
+    pub fn new(options: TlsOptions) -> Result<Self, String> {
+        let config = Arc::new(options.client_config()?);
+        Ok(Self { options, config })
+    }
+
+    pub fn options(&self) -> &TlsOptions {
+        &self.options
+    }
+
+    /// Connect to the server at `uri` directly or through `proxy`, do the TLS handshake and check the pins
+    pub async fn connect(&self, uri: &http::Uri, proxy: Option<&Socks5Proxy>) -> io::Result<TlsStream<TcpStream>> {
+        let host = uri
+            .host()
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, format!("No host in {}", uri)))?;
+        let server_name = ServerName::try_from(host.trim_start_matches('[').trim_end_matches(']'))
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, format!("Bad server name {}: {}", host, e)))?;
+
+        let tcp = match proxy {
+            Some(proxy) => proxy.connect(uri).await?,
+            None => TcpStream::connect((host, uri.port_u16().unwrap_or(443))).await?,
+        };
+        let stream = TlsConnector::from(self.config.clone())
+            .connect(server_name, tcp)
+            .await?;
+
+        let pins = &self.options.spki_pins;
+        if !pins.is_empty() {
+            let certs = stream.get_ref().1.peer_certificates().unwrap_or(&[]);
+            let keys = certs
+                .iter()
+                .map(|c| spki_hash(&c.0))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+
+            if !keys.iter().any(|k| pins.contains(k)) {
+                let list = |hashes: &[[u8; 32]]| {
+                    hashes
+                        .iter()
+                        .map(TlsOptions::pin_to_string)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                };
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Certificate pin mismatch for {}: the server's keys are [{}], but the pins are [{}]",
+                        host,
+                        list(&keys),
+                        list(pins),
+                    ),
+                ));
+            }
+        }
+
+        Ok(stream)
+    }
+}
+
+impl fmt::Debug for PinnedTls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PinnedTls").field("options", &self.options).finish()
+    }
+}
+
+/// Makes the TLS connections for tonic's Channel when there are TLS options, since tonic's own TLS config can't check
+/// pins. The Channel's uri has to be http://, so that tonic doesn't do TLS on top of this again.
+#[derive(Clone)]
+pub struct PinnedTlsConnector {
+    tls: PinnedTls,
+    proxy: Option<Socks5Proxy>,
+}
+
+impl PinnedTlsConnector {
+    pub fn new(tls: PinnedTls, proxy: Option<Socks5Proxy>) -> Self {
+        Self { tls, proxy }
+    }
+}
+
+impl Service<http::Uri> for PinnedTlsConnector {
+    type Response = TlsStream<TcpStream>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<TlsStream<TcpStream>>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: http::Uri) -> Self::Future {
+        let connector = self.clone();
+        Box::pin(async move { connector.tls.connect(&uri, connector.proxy.as_ref()).await })
+    }
+}
+
+// The certificates in a PEM file
+fn pem_certificates(pem: &[u8]) -> io::Result<Vec<Certificate>> {
+    Ok(rustls_pemfile::certs(&mut &pem[..])?
+        .into_iter()
+        .map(Certificate)
+        .collect())
+}
+
+// The first private key in a PEM file, in any of the encodings rustls takes
+fn pem_private_key(pem: &[u8]) -> io::Result<Option<PrivateKey>> {
+    Ok(rustls_pemfile::read_all(&mut &pem[..])?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(der) | Item::PKCS8Key(der) | Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        }))
+}
+
+// SHA-256 of the SubjectPublicKeyInfo in the DER certificate `cert`
+fn spki_hash(cert: &[u8]) -> Result<[u8; 32], String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).map_err(|e| format!("Bad certificate: {}", e))?;
+
+    let mut r = [0u8; 32];
+    r.copy_from_slice(&Sha256::digest(cert.public_key().raw));
+    Ok(r)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use tempdir::TempDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
+    use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+    use tokio_rustls::TlsAcceptor;
+
+    use super::{pem_certificates, pem_private_key, spki_hash, PinnedTls, TlsOptions};
+
+    // A CA, and a certificate for localhost and a client certificate it issued
+    const CA_PEM: &str = include_str!("../testdata/tls/ca.pem");
+    const SERVER_PEM: &str = include_str!("../testdata/tls/server.pem");
+    const SERVER_KEY: &str = include_str!("../testdata/tls/server.key");
+
+    // What openssl says the server's key hashes to
+    const SERVER_PIN: &str = "sha256/Ztxz3OO3hac62vs05GHgkf+zFbh8QL0wU8QkrQiRcAU=";
+
+    fn testdata(name: &str) -> Option<PathBuf> {
+        Some(
+            PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("testdata/tls")
+                .join(name),
+        )
+    }
+
+    // A TLS server on localhost that echoes a line back, optionally requiring a client certificate from the test CA
+    async fn start_tls_echo(require_client_cert: bool) -> http::Uri {
+        let certs = pem_certificates(SERVER_PEM.as_bytes()).unwrap();
+        let key = pem_private_key(SERVER_KEY.as_bytes()).unwrap().unwrap();
+
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let config = if require_client_cert {
+            let mut roots = RootCertStore::empty();
+            roots.add(&pem_certificates(CA_PEM.as_bytes()).unwrap()[0]).unwrap();
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+        } else {
+            builder.with_no_client_auth()
+        }
+        .with_single_cert(certs, key)
+        .unwrap();
+
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok(mut stream) = acceptor.accept(stream).await {
+                        let mut buf = [0u8; 5];
+                        if stream.read_exact(&mut buf).await.is_ok() {
+                            let _ = stream.write_all(&buf).await;
+                        }
+                    }
+                });
+            }
+        });
+
+        format!("https://localhost:{}", port).parse().unwrap()
+    }
+
+    async fn echo(options: &TlsOptions, uri: &http::Uri) -> Result<(), String> {
+        echo_with(&PinnedTls::new(options.clone())?, uri).await
+    }
+
+    async fn echo_with(tls: &PinnedTls, uri: &http::Uri) -> Result<(), String> {
+        let mut stream = tls.connect(uri, None).await.map_err(|e| e.to_string())?;
+        stream.write_all(b"hello").await.map_err(|e| e.to_string())?;
+
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.map_err(|e| e.to_string())?;
+        assert_eq!(&buf, b"hello");
+
+        Ok(())
+    }
+
+    #[test]
+    fn pins() {
+        let pin = TlsOptions::parse_pin(SERVER_PIN).unwrap();
+        assert_eq!(TlsOptions::pin_to_string(&pin), SERVER_PIN);
+        assert_eq!(
+            spki_hash(&pem_certificates(SERVER_PEM.as_bytes()).unwrap()[0].0),
+            Ok(pin)
+        );
+        assert_ne!(spki_hash(&pem_certificates(CA_PEM.as_bytes()).unwrap()[0].0), Ok(pin));
+
+        assert!(TlsOptions::parse_pin("Ztxz3OO3hac62vs05GHgkf+zFbh8QL0wU8QkrQiRcAU=").is_err());
+        assert!(TlsOptions::parse_pin("sha256/not base64").is_err());
+        assert!(TlsOptions::parse_pin("sha256/AAAA").is_err());
+
+        assert!(spki_hash(&[0x30, 0x82, 0xff]).is_err());
+        assert!(spki_hash(&[]).is_err());
+    }
+
+    #[test]
+    fn bad_files() {
+        let options = TlsOptions {
+            ca_cert_path: testdata("missing.pem"),
+            ..Default::default()
+        };
+        assert!(options
+            .client_config()
+            .unwrap_err()
+            .starts_with("Couldn't read CA file"));
+
+        let options = TlsOptions {
+            ca_cert_path: testdata("server.key"),
+            ..Default::default()
+        };
+        assert!(options
+            .client_config()
+            .unwrap_err()
+            .starts_with("No certificates in CA file"));
+
+        let options = TlsOptions {
+            client_cert_path: testdata("client.pem"),
+            ..Default::default()
+        };
+        assert!(options.client_config().is_err());
+
+        assert!(TlsOptions::default().client_config().is_ok());
+    }
+
+    #[tokio::test]
+    async fn connecting() {
+        let uri = start_tls_echo(false).await;
+
+        // The server's CA isn't a public one
+        assert!(echo(&TlsOptions::default(), &uri).await.is_err());
+
+        let options = TlsOptions {
+            ca_cert_path: testdata("ca.pem"),
+            ..Default::default()
+        };
+        assert_eq!(echo(&options, &uri).await, Ok(()));
+
+        // With the right pin
+        let options = TlsOptions {
+            spki_pins: vec![[0u8; 32], TlsOptions::parse_pin(SERVER_PIN).unwrap()],
+            ..options
+        };
+        assert_eq!(echo(&options, &uri).await, Ok(()));
+
+        // With the wrong one
+        let options = TlsOptions {
+            spki_pins: vec![[0u8; 32]],
+            ..options
+        };
+        let e = echo(&options, &uri).await.unwrap_err();
+        assert!(e.starts_with("Certificate pin mismatch for localhost"), "{}", e);
+        assert!(e.contains(SERVER_PIN));
+    }
+
+    #[tokio::test]
+    async fn client_certificates() {
+        let uri = start_tls_echo(true).await;
+
+        let options = TlsOptions {
+            ca_cert_path: testdata("ca.pem"),
+            ..Default::default()
+        };
+        assert!(echo(&options, &uri).await.is_err());
+
+        let options = TlsOptions {
+            client_cert_path: testdata("client.pem"),
+            client_key_path: testdata("client.key"),
+            ..options
+        };
+        assert_eq!(echo(&options, &uri).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn reading_files_once() {
+        let uri = start_tls_echo(false).await;
+
+        let dir = TempDir::new("tls").unwrap();
+        let ca_path = dir.path().join("ca.pem");
+        fs::write(&ca_path, CA_PEM).unwrap();
+
+        // The CA file is read when the options are set, and not again for every connection
+        let tls = PinnedTls::new(TlsOptions {
+            ca_cert_path: Some(ca_path.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+        fs::remove_file(&ca_path).unwrap();
+
+        assert_eq!(echo_with(&tls, &uri).await, Ok(()));
+        assert_eq!(echo_with(&tls, &uri).await, Ok(()));
+    }
+}